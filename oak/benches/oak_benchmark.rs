@@ -1,16 +1,18 @@
-use criterion::{
-    black_box, criterion_group, criterion_main, AxisScale, Criterion, PlotConfiguration,
+use criterion::{criterion_group, criterion_main, Criterion};
+use oak::dataset::{
+    OakIndexOptions, SimilaritySearchable, TopKSearchResultBatch, DEFAULT_EF_SEARCH,
 };
-use oak::dataset::{OakIndexOptions, SimilaritySearchable, TopKSearchResultBatch};
 use oak::fvecs::{FlattenedVecs, FvecsDataset};
 use oak::predicate::PredicateQuery;
 use oak::stubs::generate_random_vector;
+use std::ptr::addr_of;
 use std::sync::Once;
 
-use std::fs::File;
-use std::io::Write;
+// The number of searches run over each index before it is benchmarked.
+const WARMUP_QUERIES: usize = 100;
 
 static INIT: Once = Once::new();
+static INIT_GAMMA_Y: Once = Once::new();
 static mut DATASET_GAMMA_1: Option<FvecsDataset> = None;
 static mut DATASET_GAMMA_Y: Option<FvecsDataset> = None;
 
@@ -20,29 +22,33 @@ fn load_dataset_gamma_1() -> &'static FvecsDataset {
         let mut dataset = FvecsDataset::new("data/sift_base".to_string(), true).unwrap();
         let options = OakIndexOptions::default();
         let _ = dataset.initialize(&options);
+        let _ = dataset.warmup(WARMUP_QUERIES);
         unsafe {
             DATASET_GAMMA_1 = Some(dataset);
         }
     });
-    let ds = unsafe { DATASET_GAMMA_1.as_ref().unwrap() };
+    let ds = unsafe { (*addr_of!(DATASET_GAMMA_1)).as_ref().unwrap() };
     ds
 }
 
+#[allow(dead_code)]
 fn load_dataset_gamma_y() -> &'static FvecsDataset {
     // Only create the index once when benchmarking.
-    INIT.call_once(|| {
+    INIT_GAMMA_Y.call_once(|| {
         let mut dataset = FvecsDataset::new("data/sift_base".to_string(), true).unwrap();
         let options = OakIndexOptions {
             gamma: 12,
             m: 32,
             m_beta: 64,
+            ..Default::default()
         };
         let _ = dataset.initialize(&options);
+        let _ = dataset.warmup(WARMUP_QUERIES);
         unsafe {
-            DATASET_GAMMA_1 = Some(dataset);
+            DATASET_GAMMA_Y = Some(dataset);
         }
     });
-    let ds = unsafe { DATASET_GAMMA_1.as_ref().unwrap() };
+    let ds = unsafe { (*addr_of!(DATASET_GAMMA_Y)).as_ref().unwrap() };
     ds
 }
 
@@ -51,7 +57,7 @@ fn run_query(
     query: &FlattenedVecs,
     predicate: &Option<PredicateQuery>,
 ) -> TopKSearchResultBatch {
-    ds.search(query, predicate, 1).unwrap()
+    ds.search(query, predicate, 1, DEFAULT_EF_SEARCH).unwrap()
 }
 
 fn single_query_no_predicate_gamma_1(c: &mut Criterion) {
@@ -69,7 +75,7 @@ fn single_query_no_predicate_gamma_1(c: &mut Criterion) {
     // debug!("Predicate is: {first_predicate}");
 
     c.bench_function("single_query_no_predicate_gamma_1", |b| {
-        b.iter(|| run_query(&dataset, &queries, &first_predicate))
+        b.iter(|| run_query(dataset, &queries, &first_predicate))
     });
 }
 
@@ -86,7 +92,7 @@ fn single_query_equals_predicate_gamma_1(c: &mut Criterion) {
     };
 
     c.bench_function("single_query_equals_predicate", |b| {
-        b.iter(|| run_query(&dataset, &query, &predicate))
+        b.iter(|| run_query(dataset, &query, &predicate))
     });
 }
 
@@ -78,6 +78,7 @@ fn main() {
         "third_party/ACORN/faiss/invlists/DirectMap.cpp",
         "third_party/ACORN/faiss/invlists/InvertedLists.cpp",
         "third_party/ACORN/faiss/invlists/InvertedListsIOHook.cpp",
+        "third_party/ACORN/faiss/invlists/OnDiskInvertedLists.cpp",
         "third_party/ACORN/faiss/utils/Heap.cpp",
         "third_party/ACORN/faiss/utils/WorkerThread.cpp",
         "third_party/ACORN/faiss/utils/distances.cpp",
@@ -134,11 +135,11 @@ fn main() {
 
     build.compile("oak");
 
-    // println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=src/lib.rs");
     println!("cargo:rerun-if-changed=build.rs");
     // for src in sources {
     //     println!("cargo:rerun-if-changed={}", src);
     // }
-    // println!("cargo:rerun-if-changed=third_party/ACORN/faiss/IndexACORN.cc");
-    // println!("cargo:rerun-if-changed=third_party/ACORN/faiss/IndexACORN.h");
+    println!("cargo:rerun-if-changed=third_party/ACORN/faiss/IndexACORN.cpp");
+    println!("cargo:rerun-if-changed=third_party/ACORN/faiss/IndexACORN.h");
 }
@@ -1,19 +1,114 @@
 use crate::dataset::{
-    ConstructionError, HybridSearchMetadata, OakIndexOptions, SearchableError, TopKSearchResult,
+    ConstructionError, DistanceMetric, HybridSearchMetadata, IndexStats, OakIndexOptions,
+    PreTransform, SearchParams, SearchStats, SearchableError, TopKSearchResult,
 };
 use crate::ffi;
 use crate::fvecs::FlattenedVecs;
 
 use core::ffi::c_char;
-use slog_scope::debug;
+use rayon::prelude::*;
+use std::path::Path;
 
+/// The number of neighbors first requested for each query in a range search, which is doubled until
+/// the furthest of them lies outside the radius.
+const RANGE_SEARCH_INITIAL_K: usize = 16;
+
+// SAFETY: the index is only mutated through `&mut self` (when adding vectors), and searching it
+// from C++ only reads from it, so it can be shared between threads as any other Rust value.
+unsafe impl Send for ffi::IndexACORNFlat {}
+unsafe impl Sync for ffi::IndexACORNFlat {}
+
+/// Sets the number of threads that ACORN uses for its OpenMP parallel regions, in place of the
+/// `OMP_NUM_THREADS` environment variable. This is separate from the rayon threads that the queries
+/// of a batch are searched on (see `FvecsDataset::set_num_threads`).
+///
+/// NOTE: OpenMP keeps this setting per thread, so it applies to the work that ACORN starts from
+/// the calling thread, such as adding vectors while `initialize` builds an index.
+///
+/// # Panics
+/// - Panics if `num_threads` is zero.
+pub fn set_omp_threads(num_threads: usize) {
+    assert!(num_threads > 0, "There must be at least one OpenMP thread");
+    let num_threads = i32::try_from(num_threads).unwrap_or(i32::MAX);
+    ffi::set_omp_threads(num_threads).expect("The number of threads is positive");
+}
+
+/// The number of threads that ACORN uses for OpenMP parallel regions started from the calling
+/// thread, as set by `set_omp_threads` (or otherwise by `OMP_NUM_THREADS`).
+pub fn omp_threads() -> usize {
+    ffi::omp_threads() as usize
+}
+
+/// An ACORN index built by faiss. The C++ index is held in a `cxx::UniquePtr`, which calls its
+/// destructor when dropped, so it is freed whenever this is: when its dataset is dropped or
+/// rebuilt, and when construction fails part way (on an error from a later chunk, say). The C++
+/// index in turn owns its storage, including a product quantizer and the storage of an index read
+/// by `load`, so no explicit `Drop` is needed.
 #[allow(dead_code)]
 pub struct AcornHnswIndex {
     index: cxx::UniquePtr<ffi::IndexACORNFlat>,
     count: usize,
+    metric: DistanceMetric,
+    sanitize_non_finite_queries: bool,
+    search_batch_size: usize,
+    break_ties_by_id: bool,
+    refine_k_factor: usize,
+    dedup_queries: bool,
+}
+
+/// Sorts the `labels` of each run of neighbors at the same distance into ascending order. faiss
+/// returns neighbors closest first, so this orders them by (distance, id), as `sort_closest_first`
+/// would, without comparing distances that differ. The -1 labels that pad the results are left at
+/// the end.
+fn break_ties_by_id(labels: &mut [i64], distances: &[f32]) {
+    let found = labels.iter().take_while(|label| **label >= 0).count();
+    let mut start = 0;
+    while start < found {
+        let end = start
+            + distances[start..found]
+                .iter()
+                .take_while(|d| **d == distances[start])
+                .count();
+        labels[start..end].sort_unstable();
+        start = end;
+    }
+}
+
+/// The kind of a pre-transform, as `ffi::index_pre_transform_kind` gives it.
+fn pre_transform_kind(pre_transform: Option<&PreTransform>) -> i32 {
+    match pre_transform {
+        None => 0,
+        Some(PreTransform::Pca { whiten: false, .. }) => 1,
+        Some(PreTransform::Pca { whiten: true, .. }) => 2,
+        Some(PreTransform::Opq { .. }) => 3,
+        Some(PreTransform::Linear { .. }) => 4,
+    }
+}
+
+/// The dimensionality of the vectors that an index holds, once any pre-transform has been applied
+/// to vectors of `dimensionality`.
+fn transformed_dimensionality(dimensionality: i32, options: &OakIndexOptions) -> i32 {
+    match options.pre_transform {
+        Some(PreTransform::Linear {
+            dimensionality: transformed,
+            ..
+        })
+        | Some(PreTransform::Pca {
+            dimensionality: transformed,
+            ..
+        }) => transformed,
+        _ => dimensionality,
+    }
 }
 
 #[cfg(feature = "hnsw_faiss")]
+/// ACORN only understands a single i32 attribute per vector, so it is given the first, truncated.
+/// The truncation is harmless, as ACORN never filters on its metadata when searching: the filter
+/// map of each search is instead built from the full attributes in Rust.
+fn acorn_metadata(metadata: &HybridSearchMetadata) -> Vec<i32> {
+    metadata.rows().map(|row| row[0] as i32).collect()
+}
+
 impl AcornHnswIndex {
     pub fn new(
         dimensionality: i32,
@@ -21,21 +116,146 @@ impl AcornHnswIndex {
         flattened: &FlattenedVecs,
         options: &OakIndexOptions,
     ) -> Result<Self, ConstructionError> {
+        let mut index = Self::empty(dimensionality, metadata, options)?;
+        index.add_chunk(flattened)?;
+        Ok(index)
+    }
+
+    /// Like `new`, but the vectors are added to the index a chunk at a time, so that they need
+    /// never all be in memory on the Rust side at once. The `metadata` must still describe every
+    /// vector across all of the chunks.
+    pub fn new_from_chunks(
+        dimensionality: i32,
+        metadata: &HybridSearchMetadata,
+        chunks: impl IntoIterator<Item = FlattenedVecs>,
+        options: &OakIndexOptions,
+    ) -> Result<Self, ConstructionError> {
+        Self::new_from_chunks_with_progress(dimensionality, metadata, chunks, 0, options, &|_| {})
+    }
+
+    /// Like `new_from_chunks`, but calls `progress` with the fraction of the `total` vectors that
+    /// have been added: once before the first chunk, after each chunk, and with 1.0 once the index
+    /// is complete. ACORN adds each chunk in a single call, so progress is only as fine-grained as
+    /// the chunks.
+    pub fn new_from_chunks_with_progress(
+        dimensionality: i32,
+        metadata: &HybridSearchMetadata,
+        chunks: impl IntoIterator<Item = FlattenedVecs>,
+        total: usize,
+        options: &OakIndexOptions,
+        progress: &dyn Fn(f32),
+    ) -> Result<Self, ConstructionError> {
+        let mut index = Self::empty(dimensionality, metadata, options)?;
+        progress(0.);
+        for chunk in chunks {
+            index.add_chunk(&chunk)?;
+            if index.count < total {
+                progress(index.count as f32 / total as f32);
+            }
+        }
+        progress(1.);
+        Ok(index)
+    }
+
+    fn empty(
+        dimensionality: i32,
+        metadata: &HybridSearchMetadata,
+        options: &OakIndexOptions,
+    ) -> Result<Self, ConstructionError> {
+        // NOTE: ACORN aborts the process for some invalid options, rather than throwing.
+        options.validate()?;
+
+        let transformed = transformed_dimensionality(dimensionality, options);
+        if transformed > dimensionality
+            && matches!(options.pre_transform, Some(PreTransform::Pca { .. }))
+        {
+            return Err(ConstructionError::InvalidOptions(format!(
+                "a PCA cannot increase the dimensionality {dimensionality} to {transformed}"
+            )));
+        }
+
         let mut index = ffi::new_index_acorn(
-            dimensionality,
+            transformed,
             options.m,
             options.gamma,
             options.m_beta,
-            metadata.as_ref(),
+            &acorn_metadata(metadata),
+            options.metric.faiss_metric_type(),
         );
+        if let Some(seed) = options.seed {
+            ffi::set_index_seed(&mut index, seed);
+        }
+        match &options.pre_transform {
+            Some(PreTransform::Linear { matrix, bias, .. }) => {
+                if matrix.len() != (transformed * dimensionality) as usize {
+                    return Err(ConstructionError::InvalidOptions(format!(
+                        "a linear transform from {dimensionality} to {transformed} dimensions \
+                         needs a matrix of {} elements, but has {}",
+                        transformed * dimensionality,
+                        matrix.len()
+                    )));
+                }
+                ffi::set_index_linear_transform(&mut index, dimensionality, matrix, bias)?;
+            }
+            Some(PreTransform::Pca { whiten, .. }) => {
+                ffi::set_index_pca(&mut index, dimensionality, *whiten)?;
+            }
+            &Some(PreTransform::Opq { subquantizers }) => {
+                if dimensionality % subquantizers != 0 {
+                    return Err(ConstructionError::InvalidOptions(format!(
+                        "the {subquantizers} OPQ subquantizers must divide the dimensionality \
+                         {dimensionality}"
+                    )));
+                }
+                ffi::set_index_opq(&mut index, subquantizers)?;
+            }
+            None => {}
+        }
+        if let Some(pq) = options.product_quantization {
+            if transformed % pq.subquantizers != 0 {
+                return Err(ConstructionError::InvalidOptions(format!(
+                    "the {} subquantizers must divide the dimensionality {transformed}",
+                    pq.subquantizers
+                )));
+            }
+            ffi::set_index_pq(&mut index, pq.subquantizers, pq.nbits)?;
+        }
+        if options.refine_k_factor.is_some() {
+            ffi::set_index_refine_flat(&mut index)?;
+        }
         // debug!(
         //     "Constructed index with dimensionality: {dimensionality}, m: {}, gamma: {}, m_beta: {}",
         //     options.m, options.gamma, options.m_beta
         // );
 
+        Ok(Self {
+            index,
+            count: 0,
+            metric: options.metric,
+            sanitize_non_finite_queries: options.sanitize_non_finite_queries,
+            search_batch_size: options.search_batch_size,
+            break_ties_by_id: options.break_ties_by_id,
+            refine_k_factor: options.refine_k_factor.unwrap_or(1),
+            dedup_queries: options.dedup_queries,
+        })
+    }
+
+    /// Adds vectors whose metadata was given when the index was constructed.
+    fn add_chunk(&mut self, flattened: &FlattenedVecs) -> Result<(), ConstructionError> {
+        flattened.check_layout()?;
         // NOTE: this brings the data into memory.
         let num_fvecs = flattened.len();
 
+        // Cosine similarity is the inner product of normalized vectors, so the index only ever
+        // sees the normalized copy.
+        let normalized;
+        let flattened = if self.metric == DistanceMetric::Cosine {
+            normalized = flattened.to_normalized();
+            &normalized
+        } else {
+            flattened
+        };
+
         // assert_eq!(flattened.len(), metadata.len(), "When constructing a new index, there must be one and only one piece of metadata for each vector");
 
         // debug!("Adding {num_fvecs} vectors to the index...");
@@ -44,55 +264,679 @@ impl AcornHnswIndex {
         // we have constructed it appropriately.
         // TODO: is there a way to 'catch' segmentation faults in these unsafe functions?
         unsafe {
-            ffi::add_to_index(&mut index, num_fvecs as i64, flattened.data.as_ptr());
+            ffi::add_to_index(&mut self.index, num_fvecs as i64, flattened.data.as_ptr())?;
         }
         // debug!("Added {num_fvecs} vectors to the index.");
 
+        self.count += num_fvecs;
+        Ok(())
+    }
+
+    /// Adds further vectors to the index, which are assigned the ids following those of the
+    /// vectors already in it. The ACORN graph is extended incrementally, linking each new vector
+    /// in as it would have been had it been present at construction.
+    pub fn append(
+        &mut self,
+        metadata: &HybridSearchMetadata,
+        flattened: &FlattenedVecs,
+    ) -> Result<(), ConstructionError> {
+        flattened.check_layout()?;
+        let num_fvecs = flattened.len();
+
+        let normalized;
+        let flattened = if self.metric == DistanceMetric::Cosine {
+            normalized = flattened.to_normalized();
+            &normalized
+        } else {
+            flattened
+        };
+
+        // SAFETY: as in `new`, the pointer is to the contiguous data of `num_fvecs` vectors.
+        unsafe {
+            ffi::append_to_index(
+                &mut self.index,
+                num_fvecs as i64,
+                flattened.data.as_ptr(),
+                &acorn_metadata(metadata),
+            )?;
+        }
+        self.count += num_fvecs;
+
+        Ok(())
+    }
+
+    /// Restores an index previously written by `save`, checking that it was built with `options`
+    /// over `count` vectors of the given dimensionality.
+    ///
+    /// NOTE: the whole index is read into memory. The vendored faiss only honours `IO_FLAG_MMAP`
+    /// for the inverted lists of IVF indexes: an ACORN index keeps its vectors in an `IndexFlat`,
+    /// whose codes are an owned `std::vector` that is always read in full, and likewise its graph.
+    /// Memory-mapping an index (as a `load_mmap`) would first need faiss's storage to be able to
+    /// borrow a mapped region, as later versions of faiss allow with `IO_FLAG_MMAP_IFC`.
+    pub fn load(
+        path: &Path,
+        dimensionality: i32,
+        count: usize,
+        metadata: &HybridSearchMetadata,
+        options: &OakIndexOptions,
+    ) -> Result<Self, ConstructionError> {
+        let fname = path
+            .to_str()
+            .ok_or_else(|| ConstructionError::InvalidPath(path.to_path_buf()))?;
+        let index = ffi::read_index_acorn(fname, &acorn_metadata(metadata))?;
+        let pq = options.product_quantization;
+
+        let checks = [
+            (
+                "dimensionality",
+                dimensionality as i64,
+                ffi::index_input_dimensionality(&index) as i64,
+            ),
+            (
+                "pre-transform",
+                pre_transform_kind(options.pre_transform.as_ref()) as i64,
+                ffi::index_pre_transform_kind(&index) as i64,
+            ),
+            (
+                "transformed dimensionality",
+                transformed_dimensionality(dimensionality, options) as i64,
+                ffi::index_dimensionality(&index) as i64,
+            ),
+            ("vector count", count as i64, ffi::index_ntotal(&index)),
+            ("m", options.m as i64, ffi::index_m(&index) as i64),
+            (
+                "gamma",
+                options.gamma as i64,
+                ffi::index_gamma(&index) as i64,
+            ),
+            (
+                "m_beta",
+                options.m_beta as i64,
+                ffi::index_m_beta(&index) as i64,
+            ),
+            (
+                "metric type",
+                options.metric.faiss_metric_type() as i64,
+                ffi::index_metric_type(&index) as i64,
+            ),
+            (
+                "product quantization subquantizers",
+                pq.map_or(0, |pq| pq.subquantizers) as i64,
+                ffi::index_pq_m(&index) as i64,
+            ),
+            (
+                "product quantization bits",
+                pq.map_or(0, |pq| pq.nbits) as i64,
+                ffi::index_pq_nbits(&index) as i64,
+            ),
+            (
+                "refine flat storage",
+                options.refine_k_factor.is_some() as i64,
+                ffi::index_has_refine_flat(&index) as i64,
+            ),
+        ];
+        for (field, expected, found) in checks {
+            if expected != found {
+                return Err(ConstructionError::IndexMismatch {
+                    field,
+                    expected,
+                    found,
+                });
+            }
+        }
+
         Ok(Self {
             index,
-            count: num_fvecs as usize,
+            count,
+            metric: options.metric,
+            sanitize_non_finite_queries: options.sanitize_non_finite_queries,
+            search_batch_size: options.search_batch_size,
+            break_ties_by_id: options.break_ties_by_id,
+            refine_k_factor: options.refine_k_factor.unwrap_or(1),
+            dedup_queries: options.dedup_queries,
+        })
+    }
+
+    /// The distance metric that the index was built with.
+    pub fn metric(&self) -> DistanceMetric {
+        self.metric
+    }
+
+    /// Describes the structure of the graph, see `IndexStats`.
+    pub fn stats(&self) -> Result<IndexStats, SearchableError> {
+        let degrees = ffi::index_out_degrees(&self.index, 0)?;
+        let edges: usize = degrees.iter().map(|&d| d as usize).sum();
+        let mean_out_degree = match degrees.len() {
+            0 => 0.,
+            n => edges as f64 / n as f64,
+        };
+
+        Ok(IndexStats {
+            nodes: degrees.len(),
+            edges,
+            min_out_degree: degrees.iter().min().copied().unwrap_or_default() as usize,
+            max_out_degree: degrees.iter().max().copied().unwrap_or_default() as usize,
+            mean_out_degree,
+            // NOTE: an empty graph has a max level of -1.
+            levels: (ffi::index_max_level(&self.index) + 1) as usize,
+            bytes_per_vector: self.code_size() + std::mem::size_of::<i32>(),
+        })
+    }
+
+    /// The (directed) edges of the graph at `level`, where 0 is the base level that contains every
+    /// vector, as pairs of the id of a vector and that of one of its neighbors. There are none
+    /// above the highest level.
+    pub fn edges(&self, level: usize) -> Result<Vec<(usize, usize)>, SearchableError> {
+        let level = i32::try_from(level).unwrap_or(i32::MAX);
+        Ok(ffi::index_edges(&self.index, level)?
+            .chunks_exact(2)
+            .map(|edge| (edge[0] as usize, edge[1] as usize))
+            .collect())
+    }
+
+    /// The bytes that the storage of the index holds for each vector, which is a product-quantized
+    /// code if the index was built with `OakIndexOptions::product_quantization`, along with the
+    /// flat copy of the vector for `OakIndexOptions::refine_k_factor`.
+    fn code_size(&self) -> usize {
+        let flat = ffi::index_dimensionality(&self.index) as usize * std::mem::size_of::<f32>();
+        match ffi::index_pq_m(&self.index) {
+            0 => flat,
+            m => {
+                let code = (m as usize * ffi::index_pq_nbits(&self.index) as usize).div_ceil(8);
+                if ffi::index_has_refine_flat(&self.index) {
+                    code + flat
+                } else {
+                    code
+                }
+            }
+        }
+    }
+
+    /// Copies the vectors with the given `ids` out of the index, in the order of the `ids`. For an
+    /// index built with `OakIndexOptions::product_quantization`, the vectors are decoded from
+    /// their codes, and so only approximate those that were added, unless the index also keeps
+    /// them flat for `OakIndexOptions::refine_k_factor`. The vectors of an index with a
+    /// `OakIndexOptions::pre_transform` are transformed back, which is approximate for a transform
+    /// that drops dimensions, and is a `SearchableError::CppError` for one that cannot be reversed
+    /// (such as a whitened PCA).
+    pub fn reconstruct(&self, ids: &[usize]) -> Result<FlattenedVecs, SearchableError> {
+        if let Some(&id) = ids.iter().find(|&&id| id >= self.count) {
+            return Err(SearchableError::IdOutOfRange {
+                id,
+                len: self.count,
+            });
+        }
+
+        let ids: Vec<i64> = ids.iter().map(|&id| id as i64).collect();
+        Ok(FlattenedVecs {
+            dimensionality: ffi::index_input_dimensionality(&self.index) as usize,
+            data: ffi::reconstruct_vectors(&self.index, &ids)?,
         })
     }
 
+    /// Writes the index to `path`, including the parameters it was built with.
+    pub fn save(&self, path: &Path) -> Result<(), ConstructionError> {
+        let fname = path
+            .to_str()
+            .ok_or_else(|| ConstructionError::InvalidPath(path.to_path_buf()))?;
+        ffi::write_index_acorn(&self.index, fname)?;
+        Ok(())
+    }
+
+    /// Searches for the `k` nearest neighbors of each of the query vectors. The queries in a batch
+    /// are independent, so they are searched in parallel on the current rayon thread pool (see
+    /// `rayon::ThreadPool::install`), in groups of `OakIndexOptions::search_batch_size`, and the
+    /// results returned in the same order as the queries.
+    ///
+    /// Every query is searched over the same `filter_id_map`, which holds one entry per vector in
+    /// the index. If fewer than `k` neighbors of a query can be found, its row of results is
+    /// shorter than `k`. Neighbors at the same distance are in ascending order of id, unless the
+    /// index was built without `OakIndexOptions::break_ties_by_id`.
     pub fn search(
         &self,
         query_vectors: &FlattenedVecs,
-        filter_id_map: &mut Vec<c_char>,
+        filter_id_map: &[c_char],
         k: usize,
         efsearch: i64,
     ) -> Result<Vec<TopKSearchResult>, SearchableError> {
-        let number_of_query_vectors: usize = query_vectors.len();
-        // debug!("Searching queries: {number_of_query_vectors} in batch.");
-        let length_of_results = k * number_of_query_vectors;
-        // debug!("Length of results arrays: {length_of_results}.");
+        Ok(self
+            .search_with_stats(query_vectors, filter_id_map, k, efsearch)?
+            .0)
+    }
+
+    /// Like `search`, but also returns the work done by ACORN for each query.
+    pub fn search_with_stats(
+        &self,
+        query_vectors: &FlattenedVecs,
+        filter_id_map: &[c_char],
+        k: usize,
+        efsearch: i64,
+    ) -> Result<(Vec<TopKSearchResult>, Vec<SearchStats>), SearchableError> {
+        let params = SearchParams::default();
+        self.search_with_params(query_vectors, filter_id_map, k, efsearch, &params)
+    }
+
+    /// Like `search_with_stats`, but with the further `SearchParams` of the search.
+    pub fn search_with_params(
+        &self,
+        query_vectors: &FlattenedVecs,
+        filter_id_map: &[c_char],
+        k: usize,
+        efsearch: i64,
+        params: &SearchParams,
+    ) -> Result<(Vec<TopKSearchResult>, Vec<SearchStats>), SearchableError> {
+        let efsearch = params.scaled_efsearch(efsearch, filter_id_map);
+        let results = self.in_batches(query_vectors, filter_id_map, |query| {
+            self.search_query(query, filter_id_map, k, efsearch, params)
+        })?;
+        let (mut results, stats): (Vec<_>, _) = results.into_iter().unzip();
+        params.report_distances(self.metric, &mut results);
+        Ok((results, stats))
+    }
+
+    /// Applies `search` to each of the query vectors, in batches of `search_batch_size` queries.
+    /// Each batch is prepared for ACORN (see `prepare_batch`) and then searched in parallel, and
+    /// the results are returned in the same order as the queries. A `FilterMapLengthMismatch` is
+    /// returned if `filter_id_map` does not have an entry for each vector. With `dedup_queries`,
+    /// each distinct query is only searched once, and its result cloned for each of its copies.
+    fn in_batches<T: Send + Clone>(
+        &self,
+        query_vectors: &FlattenedVecs,
+        filter_id_map: &[c_char],
+        search: impl Fn(&[f32]) -> Result<T, SearchableError> + Sync,
+    ) -> Result<Vec<T>, SearchableError> {
+        // NOTE: faiss assumes that queries have the dimensionality of the index, and will
+        // otherwise read out of bounds, so mismatched queries must be caught before they reach C++.
+        let expected = ffi::index_input_dimensionality(&self.index) as usize;
+        if query_vectors.dimensionality != expected {
+            return Err(SearchableError::DimensionalityMismatch {
+                expected,
+                got: query_vectors.dimensionality,
+            });
+        }
+        // NOTE: likewise, ACORN reads the entry of the filter map of every vector it visits.
+        if filter_id_map.len() != self.count {
+            return Err(SearchableError::FilterMapLengthMismatch {
+                expected: self.count,
+                got: filter_id_map.len(),
+            });
+        }
+
+        if self.dedup_queries {
+            let (unique, copies) = query_vectors.dedup();
+            if unique.len() < query_vectors.len() {
+                let results = self
+                    .search_batches(&unique, search)
+                    .map_err(|err| match err {
+                        // NOTE: the error must name the query as it was given, not its distinct copy.
+                        SearchableError::NonFiniteQuery {
+                            query_index,
+                            component,
+                        } => SearchableError::NonFiniteQuery {
+                            query_index: copies.iter().position(|&c| c == query_index).unwrap(),
+                            component,
+                        },
+                        err => err,
+                    })?;
+                return Ok(copies.into_iter().map(|c| results[c].clone()).collect());
+            }
+        }
+        self.search_batches(query_vectors, search)
+    }
+
+    /// The body of `in_batches`, once the queries have been checked (and deduplicated).
+    fn search_batches<T: Send>(
+        &self,
+        query_vectors: &FlattenedVecs,
+        search: impl Fn(&[f32]) -> Result<T, SearchableError> + Sync,
+    ) -> Result<Vec<T>, SearchableError> {
+        let dimensionality = query_vectors.dimensionality;
+        let mut results = Vec::with_capacity(query_vectors.len());
+        for (i, batch) in query_vectors
+            .data
+            .chunks(self.search_batch_size * dimensionality)
+            .enumerate()
+        {
+            let prepared = self.prepare_batch(batch, dimensionality, i * self.search_batch_size)?;
+            let batch = prepared.as_ref().map_or(batch, |p| &p.data);
+            let searched = batch
+                .par_chunks_exact(dimensionality)
+                .map(&search)
+                .collect::<Result<Vec<_>, _>>()?;
+            results.extend(searched);
+        }
+        Ok(results)
+    }
 
+    /// Checks that a batch of queries, of which the first is query `offset` of the search, can be
+    /// searched, returning the copy of them that should be searched instead if they must be
+    /// sanitized (see `OakIndexOptions::sanitize_non_finite_queries`) or normalized (for
+    /// `DistanceMetric::Cosine`). faiss returns meaningless results for queries with NaN
+    /// components, so they are otherwise rejected.
+    fn prepare_batch(
+        &self,
+        batch: &[f32],
+        dimensionality: usize,
+        offset: usize,
+    ) -> Result<Option<FlattenedVecs>, SearchableError> {
+        let copy = || FlattenedVecs {
+            dimensionality,
+            data: batch.to_vec(),
+        };
+
+        let mut prepared = None;
+        if let Some(position) = batch.iter().position(|x| !x.is_finite()) {
+            if !self.sanitize_non_finite_queries {
+                return Err(SearchableError::NonFiniteQuery {
+                    query_index: offset + position / dimensionality,
+                    component: position % dimensionality,
+                });
+            }
+            let mut sanitized = copy();
+            sanitized.sanitize_non_finite();
+            prepared = Some(sanitized);
+        }
+
+        if self.metric == DistanceMetric::Cosine {
+            let mut normalized = prepared.unwrap_or_else(copy);
+            normalized.normalize_all();
+            prepared = Some(normalized);
+        }
+        Ok(prepared)
+    }
+
+    /// Like `search`, but returns only the ids of the neighbors of each query, without building
+    /// the (id, distance) tuples of its results.
+    pub fn search_ids(
+        &self,
+        query_vectors: &FlattenedVecs,
+        filter_id_map: &[c_char],
+        k: usize,
+        efsearch: i64,
+    ) -> Result<Vec<Vec<usize>>, SearchableError> {
+        self.in_batches(query_vectors, filter_id_map, |query| {
+            let params = SearchParams::default();
+            let (labels, _, _) = self.search_raw(query, filter_id_map, k, efsearch, &params)?;
+            Ok(labels
+                .into_iter()
+                .take_while(|label| *label >= 0)
+                .map(|label| label as usize)
+                .collect())
+        })
+    }
+
+    /// Searches for the `k` nearest neighbors of a single query vector, which must already have
+    /// been normalized if the index uses `DistanceMetric::Cosine`.
+    fn search_query(
+        &self,
+        query: &[f32],
+        filter_id_map: &[c_char],
+        k: usize,
+        efsearch: i64,
+        params: &SearchParams,
+    ) -> Result<(TopKSearchResult, SearchStats), SearchableError> {
+        let (labels, distances, ndis) =
+            self.search_raw(query, filter_id_map, k, efsearch, params)?;
+
+        // NOTE: when fewer than `k` vectors can be found (as there are fewer than `k` in the index,
+        // or that pass the filter), faiss pads the results with a label of -1, which must not be
+        // cast to a usize.
+        let neighbors = labels
+            .into_iter()
+            .zip(distances)
+            .filter(|(label, _)| *label >= 0)
+            .map(|(label, distance)| (label as usize, distance))
+            .collect();
+        Ok((
+            neighbors,
+            SearchStats {
+                ndis: ndis as usize,
+            },
+        ))
+    }
+
+    /// Calls ACORN to search for the `k` nearest neighbors of a single query vector, returning the
+    /// labels and distances as faiss gives them (padded with a label of -1 if fewer than `k` are
+    /// found), and the number of distances computed. Unless the index was built without
+    /// `OakIndexOptions::break_ties_by_id`, the labels of neighbors at the same distance are put in
+    /// ascending order.
+    fn search_raw(
+        &self,
+        query: &[f32],
+        filter_id_map: &[c_char],
+        k: usize,
+        efsearch: i64,
+        params: &SearchParams,
+    ) -> Result<(Vec<i64>, Vec<f32>, i64), SearchableError> {
         // These two arrays are where the outputs from the cpp methods will be stored
-        let mut distances: Vec<f32> = vec![0 as f32; length_of_results];
-        let mut labels: Vec<i64> = vec![0; length_of_results];
+        let mut distances: Vec<f32> = vec![0 as f32; k];
+        let mut labels: Vec<i64> = vec![0; k];
 
-        let filter_id_map_length = filter_id_map.len();
+        // let filter_id_map_length = filter_id_map.len();
         // debug!("Length of bitmap representing predicate: {filter_id_map_length}.");
 
-        unsafe {
+        // SAFETY: the output arrays have space for `k` results of the single query. faiss only
+        // reads from `filter_id_map`, despite taking it as mutable.
+        let ndis = unsafe {
             ffi::search_index(
                 &self.index,
-                number_of_query_vectors as i64,
-                query_vectors.data.as_ptr(),
+                1,
+                query.as_ptr(),
                 k as i64,
                 distances.as_mut_ptr(),
                 labels.as_mut_ptr(),
-                filter_id_map.as_mut_ptr(),
+                filter_id_map.as_ptr() as *mut c_char,
                 efsearch,
+                params
+                    .max_filtered_visits
+                    .map_or(-1, |visits| visits as i64),
+                self.refine_k_factor as i64,
             )?
-        }
+        };
 
         // debug!("Search complete");
 
-        let combined: Vec<(usize, f32)> = labels
-            .into_iter()
-            .map(|i| i as usize)
-            .zip(distances)
-            .collect();
-        Ok(combined.chunks(k).map(|chunk| chunk.to_vec()).collect())
+        if self.break_ties_by_id {
+            break_ties_by_id(&mut labels, &distances);
+        }
+        Ok((labels, distances, ndis))
+    }
+
+    /// Returns, for each query vector, every vector that lies within `radius` of it (in the same
+    /// units as the distances returned by `search`), closest first. As with `search`, the queries
+    /// are searched in parallel.
+    ///
+    /// ACORN has no native range search, so each query is searched with an increasing `k` until
+    /// the furthest neighbor returned lies outside the radius, or every vector has been returned.
+    pub fn range_search(
+        &self,
+        query_vectors: &FlattenedVecs,
+        filter_id_map: &[c_char],
+        radius: f32,
+        efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        self.in_batches(query_vectors, filter_id_map, |query| {
+            if self.count == 0 {
+                return Ok(vec![]);
+            }
+
+            let mut k = RANGE_SEARCH_INITIAL_K.min(self.count);
+            loop {
+                let (neighbors, _) =
+                    self.search_query(query, filter_id_map, k, efsearch, &SearchParams::default())?;
+                // NOTE: fewer than `k` neighbors are returned when no more pass the filter.
+                let exhausted = k == self.count
+                    || neighbors.len() < k
+                    || neighbors
+                        .last()
+                        .is_none_or(|(_, distance)| !self.metric.is_within(*distance, radius));
+                if exhausted {
+                    return Ok(neighbors
+                        .into_iter()
+                        .filter(|(_, distance)| self.metric.is_within(*distance, radius))
+                        .collect());
+                }
+                k = (k * 2).min(self.count);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_omp_threads() {
+        let before = omp_threads();
+        assert!(before > 0);
+
+        set_omp_threads(2);
+        assert_eq!(omp_threads(), 2);
+
+        set_omp_threads(before);
+        assert_eq!(omp_threads(), before);
+    }
+
+    #[test]
+    fn test_parallel_build_recall() {
+        use crate::metrics::{brute_force_knn, recall_at_k};
+        use crate::stubs::generate_random_vector;
+
+        let dimensionality = 16;
+        let vectors = FlattenedVecs {
+            dimensionality,
+            data: generate_random_vector(dimensionality * 2000),
+        };
+        let queries = FlattenedVecs {
+            dimensionality,
+            data: generate_random_vector(dimensionality * 200),
+        };
+        let metadata = HybridSearchMetadata::new(1, vec![0; vectors.len()]);
+        let ground_truth: Vec<Vec<usize>> =
+            brute_force_knn(&vectors, &queries, 10, DistanceMetric::L2)
+                .into_iter()
+                .map(|neighbors| neighbors.into_iter().map(|(id, _)| id).collect())
+                .collect();
+
+        let before = omp_threads();
+        let recall = |threads| {
+            set_omp_threads(threads);
+            let index = AcornHnswIndex::new(
+                dimensionality as i32,
+                &metadata,
+                &vectors,
+                &OakIndexOptions::default(),
+            )
+            .unwrap();
+            let results = index
+                .search(&queries, &vec![1; vectors.len()], 10, 128)
+                .unwrap();
+            recall_at_k(&results, &ground_truth, 10)
+        };
+        let serial = recall(1);
+        let parallel = recall(4);
+        set_omp_threads(before);
+
+        assert!(serial > 0.8, "{serial}");
+        assert!((serial - parallel).abs() < 0.1, "{serial} vs {parallel}");
+    }
+
+    #[test]
+    fn test_break_ties_by_id() {
+        let mut labels = vec![5, 2, 9, 4, 1, -1, -1];
+        let distances = [0., 1., 1., 1., 2., f32::MAX, f32::MAX];
+        break_ties_by_id(&mut labels, &distances);
+        assert_eq!(labels, vec![5, 2, 4, 9, 1, -1, -1]);
+
+        // Four vectors equally far from the query are returned in order of id, however they are
+        // found in the graph.
+        let vectors = FlattenedVecs {
+            dimensionality: 2,
+            data: vec![1., 0., 0., 1., -1., 0., 0., -1., 3., 3.],
+        };
+        let metadata = HybridSearchMetadata::new(1, vec![0; vectors.len()]);
+        let index =
+            AcornHnswIndex::new(2, &metadata, &vectors, &OakIndexOptions::default()).unwrap();
+        let query = FlattenedVecs {
+            dimensionality: 2,
+            data: vec![0., 0.],
+        };
+        let results = index.search(&query, &[1; 5], 5, 16).unwrap();
+        assert_eq!(
+            results[0],
+            vec![(0, 1.), (1, 1.), (2, 1.), (3, 1.), (4, 18.)]
+        );
+    }
+
+    #[test]
+    fn test_l1_distance() {
+        let vectors = FlattenedVecs {
+            dimensionality: 3,
+            data: vec![0., 0., 0., 1., 2., 3., 5., 0., 0., 1., 1., 1.],
+        };
+        let metadata = HybridSearchMetadata::new(1, vec![0; vectors.len()]);
+        let options = OakIndexOptions {
+            metric: DistanceMetric::L1,
+            ..OakIndexOptions::default()
+        };
+        let index = AcornHnswIndex::new(3, &metadata, &vectors, &options).unwrap();
+
+        // From (1, 0, 1), the vectors are 1 + 0 + 1 = 2, 0 + 2 + 2 = 4, 4 + 0 + 1 = 5 and
+        // 0 + 1 + 0 = 1 away.
+        let query = FlattenedVecs {
+            dimensionality: 3,
+            data: vec![1., 0., 1.],
+        };
+        let results = index.search(&query, &[1; 4], 4, 16).unwrap();
+        assert_eq!(results[0], vec![(3, 1.), (0, 2.), (1, 4.), (2, 5.)]);
+    }
+
+    /// The resident set size of this process in bytes, read from the `VmRSS` line (in kB) of
+    /// /proc/self/status.
+    #[cfg(target_os = "linux")]
+    fn rss() -> usize {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap();
+        let line = status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmRSS:"))
+            .unwrap();
+        let kb: usize = line.trim().trim_end_matches("kB").trim().parse().unwrap();
+        kb * 1024
+    }
+
+    // This measures the whole process, so it is only meaningful when no other tests allocate
+    // alongside it. Run it alone with:
+    //   cargo test test_dropped_indexes_are_freed -- --ignored --test-threads=1
+    #[test]
+    #[ignore = "measures process-wide memory, so must be run alone"]
+    #[cfg(target_os = "linux")]
+    fn test_dropped_indexes_are_freed() {
+        let count = 1000;
+        let vectors = FlattenedVecs {
+            dimensionality: 128,
+            data: crate::stubs::generate_random_vector(128 * count),
+        };
+        let metadata = HybridSearchMetadata::new(1, vec![0; count]);
+        let options = OakIndexOptions::default();
+        let build = || {
+            let index = AcornHnswIndex::new(128, &metadata, &vectors, &options).unwrap();
+            index.stats().unwrap().estimated_bytes()
+        };
+
+        // The first build warms up the allocator, after which each later index should reuse the
+        // memory freed by the one before it.
+        let size = build();
+        let before = rss();
+        let iterations = 20;
+        for _ in 0..iterations {
+            build();
+        }
+        let growth = rss().saturating_sub(before);
+        // Leaking every index would grow the process by `iterations * size`.
+        assert!(
+            growth < iterations * size / 2,
+            "the process grew by {growth} bytes over {iterations} indexes of about {size} bytes"
+        );
     }
 }
@@ -0,0 +1,170 @@
+use crate::fvecs::FlattenedVecs;
+
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AnnBenchmarksError {
+    #[error("The file does not contain the {0:?} dataset")]
+    MissingDataset(&'static str),
+    #[error("The {name:?} dataset has {ndim} dimensions, but a 2D array was expected")]
+    NotTwoDimensional { name: &'static str, ndim: usize },
+    #[error("The train vectors have dimensionality {train}, but the test vectors have {test}")]
+    DimensionalityMismatch { train: usize, test: usize },
+    #[error("There are {queries} test vectors, but neighbors were given for {neighbors}")]
+    NeighborsLengthMismatch { queries: usize, neighbors: usize },
+    #[error("The neighbors contain the negative id {0}")]
+    NegativeNeighbor(i64),
+    #[error("Underlying HDF5 error: {0}")]
+    Hdf5(#[from] hdf5::Error),
+}
+
+/// A dataset in the format distributed by ann-benchmarks, in which the `train` vectors are those
+/// to be indexed, the `test` vectors are the queries, and `neighbors` holds the ids of the true
+/// nearest train vectors of each query, closest first.
+pub struct AnnBenchmarksDataset {
+    pub train: FlattenedVecs,
+    pub test: FlattenedVecs,
+    pub neighbors: Vec<Vec<usize>>,
+}
+
+impl AnnBenchmarksDataset {
+    /// Reads the `train`, `test` and `neighbors` datasets from an ann-benchmarks HDF5 file.
+    pub fn open(path: &Path) -> Result<Self, AnnBenchmarksError> {
+        let file = hdf5::File::open(path)?;
+
+        let (train, train_dims) = read_2d::<f32>(&file, "train")?;
+        let (test, test_dims) = read_2d::<f32>(&file, "test")?;
+        if train_dims != test_dims {
+            return Err(AnnBenchmarksError::DimensionalityMismatch {
+                train: train_dims,
+                test: test_dims,
+            });
+        }
+
+        // NOTE: ann-benchmarks stores the neighbors as i32, but they are read as i64 so that
+        // files written with wider ids can be loaded too.
+        let (neighbors, k) = read_2d::<i64>(&file, "neighbors")?;
+        let test = FlattenedVecs {
+            dimensionality: test_dims,
+            data: test,
+        };
+        if k > 0 && neighbors.len() / k != test.len() {
+            return Err(AnnBenchmarksError::NeighborsLengthMismatch {
+                queries: test.len(),
+                neighbors: neighbors.len() / k,
+            });
+        }
+
+        let neighbors = if k == 0 {
+            vec![vec![]; test.len()]
+        } else {
+            neighbors
+                .chunks_exact(k)
+                .map(|row| {
+                    row.iter()
+                        .map(|&id| {
+                            usize::try_from(id)
+                                .map_err(|_| AnnBenchmarksError::NegativeNeighbor(id))
+                        })
+                        .collect()
+                })
+                .collect::<Result<_, _>>()?
+        };
+
+        Ok(Self {
+            train: FlattenedVecs {
+                dimensionality: train_dims,
+                data: train,
+            },
+            test,
+            neighbors,
+        })
+    }
+}
+
+/// Reads the dataset `name` as a row-major 2D array, returning its elements and the length of
+/// each row.
+fn read_2d<T: hdf5::H5Type>(
+    file: &hdf5::File,
+    name: &'static str,
+) -> Result<(Vec<T>, usize), AnnBenchmarksError> {
+    if !file.link_exists(name) {
+        return Err(AnnBenchmarksError::MissingDataset(name));
+    }
+
+    let dataset = file.dataset(name)?;
+    let shape = dataset.shape();
+    if shape.len() != 2 {
+        return Err(AnnBenchmarksError::NotTwoDimensional {
+            name,
+            ndim: shape.len(),
+        });
+    }
+
+    Ok((dataset.read_raw::<T>()?, shape[1]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_dataset<T: hdf5::H5Type>(
+        file: &hdf5::File,
+        name: &str,
+        shape: (usize, usize),
+        data: &[T],
+    ) {
+        file.new_dataset::<T>()
+            .shape(shape)
+            .create(name)
+            .unwrap()
+            .write_raw(data)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_open() {
+        let path = std::env::temp_dir().join("oak_test_ann_benchmarks_open.hdf5");
+        {
+            let file = hdf5::File::create(&path).unwrap();
+            write_dataset(&file, "train", (3, 2), &[0f32, 0., 1., 1., 2., 2.]);
+            write_dataset(&file, "test", (2, 2), &[0f32, 0.1, 2., 1.9]);
+            write_dataset(&file, "neighbors", (2, 2), &[0i32, 1, 2, 1]);
+        }
+
+        let dataset = AnnBenchmarksDataset::open(&path).unwrap();
+        assert_eq!(dataset.train.dimensionality, 2);
+        assert_eq!(dataset.train.len(), 3);
+        assert_eq!(dataset.test.data, vec![0., 0.1, 2., 1.9]);
+        assert_eq!(dataset.neighbors, vec![vec![0, 1], vec![2, 1]]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_validates_datasets() {
+        let path = std::env::temp_dir().join("oak_test_ann_benchmarks_validate.hdf5");
+        {
+            let file = hdf5::File::create(&path).unwrap();
+            write_dataset(&file, "train", (2, 2), &[0f32, 0., 1., 1.]);
+            write_dataset(&file, "test", (1, 3), &[0f32, 0., 0.]);
+        }
+        assert!(matches!(
+            AnnBenchmarksDataset::open(&path),
+            Err(AnnBenchmarksError::DimensionalityMismatch { train: 2, test: 3 })
+        ));
+
+        {
+            let file = hdf5::File::create(&path).unwrap();
+            write_dataset(&file, "train", (2, 2), &[0f32, 0., 1., 1.]);
+            write_dataset(&file, "test", (1, 2), &[0f32, 0.]);
+        }
+        assert!(matches!(
+            AnnBenchmarksDataset::open(&path),
+            Err(AnnBenchmarksError::MissingDataset("neighbors"))
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
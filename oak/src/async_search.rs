@@ -0,0 +1,64 @@
+use crate::dataset::{SearchableError, SimilaritySearchable, TopKSearchResult};
+use crate::fvecs::FlattenedVecs;
+use crate::predicate::PredicateQuery;
+
+use std::sync::Arc;
+
+/// Runs `SimilaritySearchable::search` on tokio's blocking thread pool, so that the (potentially
+/// long) FFI call does not block the async runtime. As the search outlives the caller's borrows,
+/// the dataset is shared through an `Arc` and the queries and predicate are moved in.
+///
+/// The returned future is `Send`, so it can be awaited from tasks on a multithreaded runtime.
+///
+/// # Panics
+/// - Panics if the search itself panics, with the same payload.
+pub async fn search_async<D>(
+    dataset: Arc<D>,
+    query_vectors: FlattenedVecs,
+    predicate_query: Option<PredicateQuery>,
+    topk: usize,
+    efsearch: i64,
+) -> Result<Vec<TopKSearchResult>, SearchableError>
+where
+    D: SimilaritySearchable + Send + Sync + ?Sized + 'static,
+{
+    let task = tokio::task::spawn_blocking(move || {
+        dataset.search(&query_vectors, &predicate_query, topk, efsearch)
+    });
+
+    match task.await {
+        Ok(result) => result,
+        Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+        Err(err) => Err(SearchableError::SearchTaskFailed(err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataset::OakIndexOptions;
+    use crate::fvecs::FvecsDataset;
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    #[tokio::test]
+    async fn test_search_async_matches_search() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let dataset = Arc::new(dataset);
+
+        let dimensionality = dataset.get_dimensionality();
+        let query = || FlattenedVecs {
+            dimensionality,
+            data: dataset.flat.data[..4 * dimensionality].to_vec(),
+        };
+        let predicate = Some(PredicateQuery::new(2));
+
+        let future = search_async(dataset.clone(), query(), predicate.clone(), 5, 32);
+        assert_send(&future);
+        let result = future.await.unwrap();
+
+        let expected = dataset.search(&query(), &predicate, 5, 32).unwrap();
+        assert_eq!(result, expected);
+    }
+}
@@ -9,7 +9,7 @@ use oak::fvecs::FvecsDatasetPartition;
 use oak::fvecs::{FlattenedVecs, FvecsDataset};
 use oak::predicate::PredicateQuery;
 use oak::router::Router;
-use slog_scope::{debug, info};
+use slog_scope::info;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -51,15 +51,16 @@ struct ExperimentResults {
     sub_recall: f64,
 }
 
+#[allow(clippy::too_many_arguments)]
 fn query_loop(
     dataset: &FvecsDataset,
     subdataset: &FvecsDatasetPartition,
     router: &Router,
-    queries: &Vec<FlattenedVecs>,
+    queries: &[FlattenedVecs],
     bitmask: &Bitmask,
     submask: &Bitmask,
     k: usize,
-    gt: &Vec<usize>,
+    gt: &[usize],
     efsearch: i64,
 ) -> Result<Vec<QueryStats>> {
     let mut results = vec![];
@@ -68,7 +69,7 @@ fn query_loop(
 
         // First we benchmark ACORN
         let now = tokio::time::Instant::now();
-        let result = dataset.search_with_bitmask(q, &bitmask, k, efsearch)?;
+        let result = dataset.search_with_bitmask(q, bitmask, k, efsearch)?;
         let end = now.elapsed();
         let acorn_latency = end.as_micros();
         let acorn_recall = calculate_recall_1_at_k(gt[i], &result, k)?;
@@ -76,15 +77,15 @@ fn query_loop(
         // Then we direct only to OI showing that searching in a smaller index is more performant
 
         let sub_now = tokio::time::Instant::now();
-        let sub_result = subdataset.search_with_bitmask(q, &submask, k, efsearch)?;
+        let sub_result = subdataset.search_with_bitmask(q, submask, k, efsearch)?;
         let sub_end = sub_now.elapsed();
         let sub_latency = sub_end.as_micros();
         let sub_result: Vec<_> = vec![sub_result[0]
             .iter()
             .map(|(index, distance)| {
                 (
-                    subdataset.original_indices.as_ref().unwrap()[index.clone()] as usize,
-                    distance.clone(),
+                    subdataset.original_indices.as_ref().unwrap()[*index],
+                    *distance,
                 )
             })
             .collect()];
@@ -105,7 +106,7 @@ fn query_loop(
         // We use a router that makes a decision based on performance gain
         // and loss of precision whether to direct it to one of the OIs
         let oak_now = tokio::time::Instant::now();
-        let oak_result = router.search_with_bitmask(q, &bitmask, k, efsearch)?;
+        let oak_result = router.search_with_bitmask(q, bitmask, k, efsearch)?;
         let oak_end = oak_now.elapsed();
         let oak_latency = oak_end.as_micros();
         let oak_recall = calculate_recall_1_at_k(gt[i], &oak_result, k)?;
@@ -208,6 +209,7 @@ fn main() -> Result<()> {
         gamma: 1,
         m: 32,
         m_beta: 64,
+        ..Default::default()
     };
 
     let _ = dataset.initialize(&opts);
@@ -219,8 +221,8 @@ fn main() -> Result<()> {
     let _ = subdataset.initialize(&opts);
     info!("Subindex as view constructed.");
 
-    let dimensionality = dataset.get_dimensionality() as usize;
-    // assert_eq!(dimensionality, subdataset.get_dimensionality() as usize);
+    // let dimensionality = dataset.get_dimensionality();
+    // assert_eq!(dimensionality, subdataset.get_dimensionality());
 
     let query_set = FvecsDataset::new(args.query, false)?;
     let batched_queries = FlattenedVecs::from(&query_set);
@@ -295,7 +297,7 @@ fn main() -> Result<()> {
     let mut wtr = Writer::from_path("experiments.csv")?;
 
     // Write the header
-    wtr.write_record(&[
+    wtr.write_record([
         "ACORN Latency (microseconds)",
         "ACORN Queries per microsecond",
         "ACORN Recall@10",
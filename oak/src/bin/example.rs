@@ -5,7 +5,7 @@ use dropshot::ConfigLoggingLevel;
 use slog_scope::info;
 use thiserror::Error;
 
-use oak::dataset::{OakIndexOptions, SimilaritySearchable};
+use oak::dataset::{format_results, OakIndexOptions, SimilaritySearchable, DEFAULT_EF_SEARCH};
 use oak::fvecs::{FlattenedVecs, FvecsDataset};
 use oak::predicate::PredicateQuery;
 use oak::stubs::generate_random_vector;
@@ -43,12 +43,13 @@ fn main() -> Result<()> {
         gamma: 1,
         m: 32,
         m_beta: 64,
+        ..Default::default()
     };
 
     let _ = dataset.initialize(&opts);
     info!("Seed index constructed.");
 
-    let dimensionality = dataset.get_dimensionality() as usize;
+    let dimensionality = dataset.get_dimensionality();
     info!("Constructing random vector to query with {dimensionality} dimensions");
     let query_vector = FlattenedVecs {
         dimensionality,
@@ -60,10 +61,13 @@ fn main() -> Result<()> {
 
     info!("Searching for {topk} similar vectors for {num_queries} random query, where attr is equal to 5...");
 
-    let result = dataset.search(&query_vector, &query, topk, 16);
+    let result = dataset.search(&query_vector, &query, topk, DEFAULT_EF_SEARCH)?;
 
     info!("Got results.");
-    info!("{:?}", result);
+    info!(
+        "\n{}",
+        format_results(&result, Some(dataset.get_metadata()))
+    );
 
     Ok(())
 }
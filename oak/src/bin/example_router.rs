@@ -8,7 +8,7 @@ use std::time::Instant;
 use thiserror::Error;
 
 use oak::bitmask::Bitmask;
-use oak::dataset::{OakIndexOptions, SimilaritySearchable};
+use oak::dataset::{OakIndexOptions, SimilaritySearchable, DEFAULT_EF_SEARCH};
 use oak::fvecs::{FlattenedVecs, FvecsDataset};
 use oak::predicate::PredicateQuery;
 use oak::router::Router;
@@ -47,27 +47,28 @@ fn main() -> Result<()> {
         gamma: 1,
         m: 32,
         m_beta: 64,
+        ..Default::default()
     };
 
     let _ = dataset.initialize(&opts);
     info!("Seed index constructed.");
 
-    let queries: Vec<_> = (1..7).map(|i| PredicateQuery::new(i)).collect();
+    let queries: Vec<_> = (1..7).map(PredicateQuery::new).collect();
 
     let subdatasets: Vec<_> = queries
         .iter()
         .enumerate()
         .map(|(idx, q)| {
             let idx = idx + 1;
-            let mut subdataset = dataset.view(&q);
+            let mut subdataset = dataset.view(q);
             let _ = subdataset.initialize(&opts);
             info!("Subindex {idx} as view constructed.");
             subdataset
         })
         .collect();
 
-    let dimensionality = dataset.get_dimensionality() as usize;
-    assert_eq!(dimensionality, subdatasets[0].get_dimensionality() as usize);
+    let dimensionality = dataset.get_dimensionality();
+    assert_eq!(dimensionality, subdatasets[0].get_dimensionality());
 
     // Experiments
     // --------=--
@@ -88,7 +89,7 @@ fn main() -> Result<()> {
             let mask_sub = Bitmask::new_full(subdataset);
             let mask_sub_2 = Bitmask::new(&queries[idx], subdataset);
 
-            let idx = idx + 1;
+            // let idx = idx + 1;
             // debug!(
             //     "Mask main 'equals {idx}' filled: {} / {}",
             //     mask_main.bitcount(),
@@ -124,13 +125,15 @@ fn main() -> Result<()> {
         debug!("Searching full dataset for {topk} similar vectors for {num_queries} random query , where attr is equal to 5...");
 
         let big_start = Instant::now();
-        let big_result = dataset.search_with_bitmask(&query_vector, &mask_main, topk, 16);
+        let big_result =
+            dataset.search_with_bitmask(&query_vector, mask_main, topk, DEFAULT_EF_SEARCH);
         let big_end = big_start.elapsed();
 
         debug!("Searching dataset partition for {topk} similar vectors for {num_queries} random query, with no predicate as we know all vectors match...");
 
         let small_start = Instant::now();
-        let small_result = subdataset.search_with_bitmask(&query_vector, &mask_sub, topk, 16);
+        let small_result =
+            subdataset.search_with_bitmask(&query_vector, mask_sub, topk, DEFAULT_EF_SEARCH);
         let small_end = small_start.elapsed();
 
         let big_mean_distance = big_result.unwrap()[0]
@@ -151,7 +154,8 @@ fn main() -> Result<()> {
         info!("Time taken: {:?}", small_end);
 
         let routed_start = Instant::now();
-        let routed_result = router.search_with_bitmask(&query_vector, &mask_main, topk, 16);
+        let routed_result =
+            router.search_with_bitmask(&query_vector, mask_main, topk, DEFAULT_EF_SEARCH);
         let routed_end = routed_start.elapsed();
 
         let routed_mean_distance = routed_result.unwrap()[0]
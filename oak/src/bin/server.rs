@@ -7,7 +7,6 @@ use dropshot::HttpResponseOk;
 use dropshot::RequestContext;
 use dropshot::ServerBuilder;
 use schemars::JsonSchema;
-use semver;
 use serde::Serialize;
 use slog_scope::info;
 use std::fs::OpenOptions;
@@ -126,12 +125,13 @@ async fn main() -> Result<()> {
         gamma: 1,
         m: 32,
         m_beta: 64,
+        ..Default::default()
     };
 
     let _ = dataset.initialize(&opts);
     info!("Seed index constructed.");
 
-    let dimensionality = dataset.get_dimensionality() as usize;
+    let dimensionality = dataset.get_dimensionality();
 
     let mut f = OpenOptions::new()
         .create(true)
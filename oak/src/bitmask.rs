@@ -1,7 +1,8 @@
-use crate::dataset::SimilaritySearchable;
-use crate::predicate::{PredicateOp, PredicateQuery};
+use crate::dataset::{HybridSearchMetadata, SearchableError, SimilaritySearchable};
+use crate::predicate::PredicateQuery;
 use core::ffi::c_char;
 use std::collections::HashSet;
+use std::ops::{BitAnd, BitOr, Not};
 
 pub struct Bitmask {
     pub map: Vec<i8>,
@@ -15,31 +16,87 @@ impl Bitmask {
     /// dataset. A value of 1 in the bitmap represents that the search query matches with the
     /// vector at that index in the dataset, and a value of 0 that it doesn't.
     ///
-    /// If an attribute matching the `lhs` of the query does not exist in the dataset, then an
-    /// error will be raised.
+    /// Predicates are evaluated against the attribute they refer to, which is the first of each
+    /// vector unless chosen otherwise with `PredicateQuery::on_attribute`.
+    ///
+    /// # Panics
+    /// - Panics if the predicate cannot be serialized or refers to an unknown attribute; see
+    ///   `try_new`.
     pub fn new<D: SimilaritySearchable>(pq: &PredicateQuery, dataset: &D) -> Self {
-        let ds_len = dataset.len();
-        let mut map: Vec<c_char> = vec![0; ds_len];
+        Self::try_new(pq, dataset).expect("The predicate must be valid for the dataset")
+    }
+
+    /// Like `new`, but returns a `PredicateSerializationError` rather than panicking if the
+    /// predicate's `op` is paired with the wrong kind of `rhs`, and an `UnknownAttribute` error if
+    /// it refers to an attribute that the dataset's vectors do not have. A `MissingMetadata` error
+    /// is returned if the dataset has vectors but no attributes at all, e.g. as it was loaded
+    /// without its CSV file and `set_metadata` has not yet been called.
+    pub fn try_new<D: SimilaritySearchable>(
+        pq: &PredicateQuery,
+        dataset: &D,
+    ) -> Result<Self, SearchableError> {
+        Self::evaluate(pq, dataset.get_metadata(), dataset.len())
+    }
+
+    /// Evaluates `pq` over the vectors described by `metadata`, in a map of length `len`. Any
+    /// vectors beyond those that `metadata` describes are left unset.
+    pub(crate) fn evaluate(
+        pq: &PredicateQuery,
+        metadata: &HybridSearchMetadata,
+        len: usize,
+    ) -> Result<Self, SearchableError> {
+        // NOTE: otherwise, every vector would silently fail to match.
+        if metadata.is_empty() && len > 0 {
+            return Err(SearchableError::MissingMetadata);
+        }
+        pq.validate(metadata.dimensionality())?;
 
-        // assert_eq!(dataset.get_metadata().len(), map.len());
+        let mut map: Vec<c_char> = vec![0; len];
+
+        // assert_eq!(metadata.len(), map.len());
 
-        let rhs: i32 = i32::from(&pq.rhs);
         let on_bit: c_char = 1;
         let mut bitcount: usize = 0;
 
-        for (i, xq) in dataset.get_metadata().as_ref().iter().enumerate() {
-            let bit = match pq.op {
-                PredicateOp::Equals => (xq == &rhs) as c_char,
-            };
+        for (i, attrs) in metadata.rows().enumerate() {
+            let bit = pq.matches_row(attrs, metadata.nulls_for(i))? as c_char;
             if bit.eq(&on_bit) {
                 bitcount += 1;
             }
             map[i] = bit;
         }
 
+        Ok(Self { map, bitcount })
+    }
+
+    /// Builds a bitmask over the vectors described by `metadata`, in which a vector is set if `f`
+    /// returns true for its attribute. This allows for filters that cannot be expressed as a
+    /// `PredicateQuery`; as with those, `f` is given the first attribute of each vector, and a
+    /// vector whose first attribute is null is never set.
+    pub fn from_predicate(metadata: &HybridSearchMetadata, f: impl Fn(i64) -> bool) -> Self {
+        let map: Vec<c_char> = metadata
+            .rows()
+            .enumerate()
+            .map(|(i, attrs)| (!metadata.is_null(i, 0) && f(attrs[0])) as c_char)
+            .collect();
+        let bitcount = map.iter().filter(|&&bit| bit == 1).count();
         Self { map, bitcount }
     }
 
+    /// The number of vectors that are set in the map. This is counted afresh, and so (unlike
+    /// `bitcount`) remains correct if `map` has been modified directly.
+    pub fn count_ones(&self) -> usize {
+        self.map.iter().filter(|&&bit| bit == 1).count()
+    }
+
+    /// The fraction of the dataset that is set in the map, or 0 for an empty dataset.
+    pub fn selectivity(&self) -> f32 {
+        if self.map.is_empty() {
+            return 0.;
+        }
+        self.bitcount as f32 / self.map.len() as f32
+    }
+
     pub fn capacity(&self) -> usize {
         self.map.len()
     }
@@ -54,6 +111,48 @@ impl Bitmask {
         Self { map, bitcount }
     }
 
+    /// The element-wise intersection of two bitmasks over the same dataset.
+    ///
+    /// # Panics
+    /// - Panics if the bitmasks are of different lengths.
+    pub fn and(&self, other: &Bitmask) -> Bitmask {
+        self.zip_with(other, "and", |a, b| a & b)
+    }
+
+    /// The element-wise union of two bitmasks over the same dataset.
+    ///
+    /// # Panics
+    /// - Panics if the bitmasks are of different lengths.
+    pub fn or(&self, other: &Bitmask) -> Bitmask {
+        self.zip_with(other, "or", |a, b| a | b)
+    }
+
+    /// The complement of the bitmask, which matches exactly the vectors that it does not.
+    pub fn not(&self) -> Bitmask {
+        let map: Vec<c_char> = self.map.iter().map(|&bit| (bit != 1) as c_char).collect();
+        let bitcount = map.len() - self.bitcount;
+        Self { map, bitcount }
+    }
+
+    fn zip_with(&self, other: &Bitmask, name: &str, op: impl Fn(bool, bool) -> bool) -> Bitmask {
+        assert_eq!(
+            self.capacity(),
+            other.capacity(),
+            "Cannot `{name}` bitmasks of different lengths; they must be over the same dataset"
+        );
+
+        let mut bitcount: usize = 0;
+        let map = std::iter::zip(&self.map, &other.map)
+            .map(|(&a, &b)| {
+                let bit = op(a == 1, b == 1);
+                bitcount += bit as usize;
+                bit as c_char
+            })
+            .collect();
+
+        Self { map, bitcount }
+    }
+
     pub fn to_hashset(&self) -> HashSet<i8> {
         self.map.clone().into_iter().collect()
     }
@@ -104,20 +203,246 @@ impl From<Vec<i8>> for Bitmask {
     }
 }
 
+impl BitAnd for &Bitmask {
+    type Output = Bitmask;
+
+    fn bitand(self, other: &Bitmask) -> Bitmask {
+        self.and(other)
+    }
+}
+
+impl BitAnd for Bitmask {
+    type Output = Bitmask;
+
+    fn bitand(self, other: Bitmask) -> Bitmask {
+        self.and(&other)
+    }
+}
+
+impl BitOr for &Bitmask {
+    type Output = Bitmask;
+
+    fn bitor(self, other: &Bitmask) -> Bitmask {
+        self.or(other)
+    }
+}
+
+impl BitOr for Bitmask {
+    type Output = Bitmask;
+
+    fn bitor(self, other: Bitmask) -> Bitmask {
+        self.or(&other)
+    }
+}
+
+impl Not for &Bitmask {
+    type Output = Bitmask;
+
+    fn not(self) -> Bitmask {
+        Bitmask::not(self)
+    }
+}
+
+impl Not for Bitmask {
+    type Output = Bitmask;
+
+    fn not(self) -> Bitmask {
+        Bitmask::not(&self)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fvecs::FvecsDataset;
+    use crate::predicate::{PredicateOp, PredicateRhs};
 
     #[test]
     fn test_serialize() {
-        let dataset = FvecsDataset::new("data/sift_query".to_string()).unwrap();
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
         let pq = PredicateQuery {
             op: PredicateOp::Equals,
             rhs: PredicateRhs::Number(10),
+            attribute: 0,
         };
 
         let bitmap = Bitmask::new(&pq, &dataset);
-        let one = 1 as c_char;
+        let _one = 1 as c_char;
+        assert_eq!(bitmap.capacity(), dataset.len());
         // assert!(bitmap.map.contains(&one));
     }
+
+    #[test]
+    fn test_combinators() {
+        let a = Bitmask::from(vec![1i8, 1, 0, 0]);
+        let b = Bitmask::from(vec![1i8, 0, 1, 0]);
+
+        let and = &a & &b;
+        assert_eq!(and.map, vec![1, 0, 0, 0]);
+        assert_eq!(and.bitcount(), 1);
+
+        let or = &a | &b;
+        assert_eq!(or.map, vec![1, 1, 1, 0]);
+        assert_eq!(or.bitcount(), 3);
+
+        let a_and_not_b = a & !b;
+        assert_eq!(a_and_not_b.map, vec![0, 1, 0, 0]);
+        assert_eq!(a_and_not_b.bitcount(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "bitmasks of different lengths")]
+    fn test_combinators_panic_on_length_mismatch() {
+        let a = Bitmask::from(vec![1i8, 1, 0]);
+        let b = Bitmask::from(vec![1i8, 0]);
+        let _ = a.or(&b);
+    }
+
+    #[test]
+    fn test_range_and_set_predicates() {
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        let attrs = dataset.get_metadata().column(0);
+
+        let range = Bitmask::new(&PredicateQuery::range(2, 4), &dataset);
+        let expected = attrs.iter().filter(|&&a| (2..=4).contains(&a)).count();
+        assert_eq!(range.bitcount(), expected);
+
+        let set = Bitmask::new(&PredicateQuery::in_set(vec![2, 3, 4]), &dataset);
+        assert_eq!(set.map, range.map);
+
+        let mismatched = PredicateQuery {
+            op: PredicateOp::Range,
+            rhs: PredicateRhs::Number(1),
+            attribute: 0,
+        };
+        assert_eq!(
+            Bitmask::try_new(&mismatched, &dataset).err(),
+            Some(SearchableError::PredicateSerializationError)
+        );
+    }
+
+    #[test]
+    fn test_negated_predicates() {
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+
+        let range = Bitmask::new(&PredicateQuery::range(2, 4), &dataset);
+        let not_range = Bitmask::new(
+            &PredicateQuery::negate(PredicateQuery::range(2, 4)),
+            &dataset,
+        );
+        assert_eq!(not_range.map, (!&range).map);
+        assert_eq!(range.bitcount() + not_range.bitcount(), dataset.len());
+
+        let twice = PredicateQuery::negate(PredicateQuery::negate(PredicateQuery::new(3)));
+        assert_eq!(
+            Bitmask::new(&twice, &dataset).map,
+            Bitmask::new(&PredicateQuery::new(3), &dataset).map
+        );
+
+        let mismatched = PredicateQuery::negate(PredicateQuery {
+            op: PredicateOp::Not,
+            rhs: PredicateRhs::Number(1),
+            attribute: 0,
+        });
+        assert_eq!(
+            Bitmask::try_new(&mismatched, &dataset).err(),
+            Some(SearchableError::PredicateSerializationError)
+        );
+    }
+
+    #[test]
+    fn test_null_attributes() {
+        let metadata = HybridSearchMetadata::from_nullable(
+            2,
+            vec![Some(3), None, None, Some(3), Some(0), None],
+        );
+        assert!(metadata.is_null(0, 1) && metadata.is_null(1, 0) && metadata.is_null(2, 1));
+        assert!(!metadata.is_null(1, 1));
+
+        let mask = |pq: PredicateQuery| pq.to_bitmask(&metadata).unwrap().map;
+        assert_eq!(mask(PredicateQuery::is_null()), vec![0, 1, 0]);
+        assert_eq!(
+            mask(PredicateQuery::is_null().on_attribute(1)),
+            vec![1, 0, 1]
+        );
+        assert_eq!(mask(PredicateQuery::is_not_null()), vec![1, 0, 1]);
+
+        // A null attribute reads as 0, but matches neither a predicate nor its negation.
+        assert_eq!(mask(PredicateQuery::new(0).on_attribute(1)), vec![0, 0, 0]);
+        assert_eq!(
+            mask(PredicateQuery::negate(PredicateQuery::new(0)).on_attribute(1)),
+            vec![0, 1, 0]
+        );
+        assert_eq!(
+            Bitmask::from_predicate(&metadata, |attr| attr == 0).map,
+            vec![0, 0, 1]
+        );
+    }
+
+    #[test]
+    fn test_to_bitmask() {
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        let metadata = dataset.get_metadata();
+
+        let range = PredicateQuery::range(2, 4);
+        let mask = range.to_bitmask(metadata).unwrap();
+        assert_eq!(mask.map, Bitmask::new(&range, &dataset).map);
+        assert_eq!(mask.bitcount(), mask.count_ones());
+
+        let three = PredicateQuery::new(3).to_bitmask(metadata).unwrap();
+        let combined = &mask & &!&three;
+        let expected = metadata
+            .column(0)
+            .iter()
+            .filter(|&&a| a == 2 || a == 4)
+            .count();
+        assert_eq!(combined.bitcount(), expected);
+
+        assert_eq!(
+            PredicateQuery::new(3)
+                .on_attribute(1)
+                .to_bitmask(metadata)
+                .err(),
+            Some(SearchableError::UnknownAttribute {
+                index: 1,
+                num_attributes: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_from_predicate() {
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        let metadata = dataset.get_metadata();
+
+        let even = Bitmask::from_predicate(metadata, |attr| attr % 2 == 0);
+        assert_eq!(even.capacity(), dataset.len());
+        assert_eq!(even.bitcount(), even.count_ones());
+        let expected = metadata.column(0).iter().filter(|&&a| a % 2 == 0).count();
+        assert_eq!(even.count_ones(), expected);
+
+        let equals = Bitmask::from_predicate(metadata, |attr| attr == 3);
+        assert_eq!(
+            equals.map,
+            Bitmask::new(&PredicateQuery::new(3), &dataset).map
+        );
+
+        assert_eq!(even.selectivity(), expected as f32 / dataset.len() as f32);
+        assert_eq!(Bitmask::from(Vec::<i8>::new()).selectivity(), 0.);
+        assert_eq!(
+            dataset.selectivity(&PredicateQuery::new(3)).unwrap(),
+            equals.selectivity()
+        );
+        assert_eq!(
+            dataset.count_matching(&PredicateQuery::new(3)).unwrap(),
+            equals.count_ones()
+        );
+        assert!(dataset
+            .count_matching(&PredicateQuery::new(3).on_attribute(1))
+            .is_err());
+
+        let mut modified = Bitmask::from(vec![1i8, 0, 1]);
+        modified.map[1] = 1;
+        assert_eq!(modified.count_ones(), 3);
+    }
 }
@@ -1,9 +1,22 @@
 use crate::bitmask::Bitmask;
-use crate::fvecs::FlattenedVecs;
+use crate::fvecs::{check_metadata_len, FlattenedVecs, Fvec};
+use crate::metrics::{
+    exact_distance, recall_at_k, sort_closest_first, sort_closest_first_by, Distance,
+};
 use crate::predicate::PredicateQuery;
+use crate::stubs::generate_random_vector;
 
 use anyhow::Result;
-use slog::info;
+use core::ffi::c_char;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 /// The errors that can be returned from searching an OAK dataset.
@@ -13,6 +26,42 @@ pub enum SearchableError {
     DatasetIsNotIndexed,
     #[error("Could not serialize the predicate")]
     PredicateSerializationError,
+    #[error("The index was built with the {index:?} metric, but was queried with {query:?}")]
+    MetricMismatch {
+        index: DistanceMetric,
+        query: DistanceMetric,
+    },
+    #[error("The query has {got} dimensions, but the dataset has {expected}")]
+    DimensionalityMismatch { expected: usize, got: usize },
+    #[error("There is no vector with id {id}, as the dataset has {len} vectors")]
+    IdOutOfRange { id: usize, len: usize },
+    #[error("The vector with id {0} has been removed")]
+    IdRemoved(usize),
+    #[error("There are {queries} queries, but {predicates} predicates were given")]
+    PredicateCountMismatch { queries: usize, predicates: usize },
+    #[error(
+        "The predicate refers to attribute {index}, but vectors have {num_attributes} attributes"
+    )]
+    UnknownAttribute { index: usize, num_attributes: usize },
+    #[error("The dataset has no attributes to filter on, as its metadata has not been set")]
+    MissingMetadata,
+    #[error("The filter map has {got} entries, but the dataset has {expected} vectors")]
+    FilterMapLengthMismatch { expected: usize, got: usize },
+    #[error("Component {component} of query {query_index} is not finite")]
+    NonFiniteQuery {
+        query_index: usize,
+        component: usize,
+    },
+    #[error("Only {found} neighbors were found, so there is no neighbor {k}")]
+    NotEnoughNeighbors { k: usize, found: usize },
+    #[error("The search was cancelled")]
+    Cancelled,
+    #[error("The vectors of this dataset are not held in memory")]
+    VectorsNotInMemory,
+    #[error("The dataset has {0} vectors, too many for their ids to fit in a u32")]
+    TooManyVectorsForU32(usize),
+    #[error("The search task did not run to completion: {0}")]
+    SearchTaskFailed(String),
     #[error("Underlying C++ error: {0}")]
     CppError(String),
 }
@@ -26,69 +75,1001 @@ impl From<cxx::Exception> for SearchableError {
 
 /// The errors that can be returned from constructing an OAK dataset.
 #[derive(Error, Debug)]
-pub enum ConstructionError {}
+pub enum ConstructionError {
+    #[error("You must index a dataset before its index can be saved or extended")]
+    DatasetIsNotIndexed,
+    #[error("This dataset does not own an index that can be saved or loaded")]
+    IndexNotOwned,
+    #[error("The path {0:?} is not valid UTF-8")]
+    InvalidPath(PathBuf),
+    #[error("The saved index has {field} {found}, but {expected} was expected")]
+    IndexMismatch {
+        field: &'static str,
+        expected: i64,
+        found: i64,
+    },
+    #[error("The vectors have dimensionality {got}, but {expected} was expected")]
+    DimensionalityMismatch { expected: usize, got: usize },
+    #[error("The {total} components of the vectors are not a whole number of {dimensionality}-dimensional vectors")]
+    RaggedVectors { total: usize, dimensionality: usize },
+    #[error("There is no vector with id {id}, as the dataset has {len} vectors")]
+    IdOutOfRange { id: usize, len: usize },
+    #[error("Invalid index options: {0}")]
+    InvalidOptions(String),
+    #[error("There are {vectors} vectors, but attributes were given for {attrs}")]
+    MetadataLengthMismatch { vectors: usize, attrs: usize },
+    #[error("The vectors have {got} attributes each, but {expected} were expected")]
+    AttributeCountMismatch { expected: usize, got: usize },
+    #[error("The {total} attributes are not a whole number of rows of {dimensionality}")]
+    RaggedAttributes { total: usize, dimensionality: usize },
+    #[error("The dataset has {len} vectors, but its {part} holds {found}")]
+    VectorCountMismatch {
+        part: &'static str,
+        len: usize,
+        found: usize,
+    },
+    #[error("Underlying C++ error: {0}")]
+    CppError(String),
+}
+
+impl From<cxx::Exception> for ConstructionError {
+    fn from(err: cxx::Exception) -> Self {
+        ConstructionError::CppError(err.to_string())
+    }
+}
+
+/// Orders the `candidates` of each of the `query_vectors` by their exact distances to the
+/// `vectors` under `metric`, keeping the closest `topk`.
+fn rerank(
+    vectors: &FlattenedVecs,
+    query_vectors: &FlattenedVecs,
+    candidates: TopKSearchResultBatch,
+    topk: usize,
+    metric: DistanceMetric,
+) -> TopKSearchResultBatch {
+    let normalized;
+    let query_vectors = if metric == DistanceMetric::Cosine {
+        normalized = query_vectors.to_normalized();
+        &normalized
+    } else {
+        query_vectors
+    };
+
+    let dimensionality = vectors.dimensionality;
+    std::iter::zip(query_vectors.data.chunks_exact(dimensionality), candidates)
+        .map(|(query, candidates)| {
+            let mut reranked: TopKSearchResult = candidates
+                .into_iter()
+                .map(|(id, _)| {
+                    let vector = &vectors.data[id * dimensionality..(id + 1) * dimensionality];
+                    let distance = if metric == DistanceMetric::Cosine {
+                        let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+                        exact_distance(metric, query, vector) / norm.max(f32::MIN_POSITIVE)
+                    } else {
+                        exact_distance(metric, query, vector)
+                    };
+                    (id, distance)
+                })
+                .collect();
+            sort_closest_first(metric, &mut reranked);
+            reranked.truncate(topk);
+            reranked
+        })
+        .collect()
+}
+
+/// Orders the `candidates` of each of the `query_vectors` by their distances to the `vectors` under
+/// a custom `distance`, keeping the closest `topk`.
+fn rerank_with(
+    vectors: &FlattenedVecs,
+    query_vectors: &FlattenedVecs,
+    candidates: TopKSearchResultBatch,
+    topk: usize,
+    distance: &dyn Distance,
+) -> TopKSearchResultBatch {
+    let dimensionality = vectors.dimensionality;
+    std::iter::zip(query_vectors.data.chunks_exact(dimensionality), candidates)
+        .map(|(query, candidates)| {
+            let mut reranked: TopKSearchResult = candidates
+                .into_iter()
+                .map(|(id, _)| {
+                    let vector = &vectors.data[id * dimensionality..(id + 1) * dimensionality];
+                    (id, distance.distance(query, vector))
+                })
+                .collect();
+            sort_closest_first_by(distance.larger_is_closer(), &mut reranked);
+            reranked.truncate(topk);
+            reranked
+        })
+        .collect()
+}
 
 /// t[0] is the index of the vector that is similar in the dataset, t[1] is a f32 representing the
-/// distance of the found vector from the original query.
+/// distance of the found vector from the original query, as measured by the `DistanceMetric` that
+/// the index was built with. For `L2` this is the squared L2 distance, as faiss reports it (unless
+/// `SearchParams::true_l2_distances` is set), and for `L1` the Manhattan distance (smaller is
+/// closer), but for `InnerProduct` and `Cosine` it is the inner product or cosine similarity
+/// (larger is closer).
+///
+/// As results are plain tuples and vecs, serde can already serialize them, e.g. to cache them.
 pub type SimilaritySearchResult = (usize, f32);
 
-// A vec of length `k` with tuples representing the similarity search results.
+// A vec of at most length `k` with tuples representing the similarity search results. It is
+// shorter than `k` if fewer than `k` vectors could be found, e.g. as `k` is larger than the
+// dataset, or than the number of vectors that match the predicate.
 pub type TopKSearchResult = Vec<SimilaritySearchResult>;
 
 // A batch of items with type `TopKSearchResult`.
 pub type TopKSearchResultBatch = Vec<TopKSearchResult>;
 
-/// The type in which the attributes for hybrid search are notated. At the moment the assumed
-/// constraint is that there is at most one attribute per vector, and it is always an i32.
+/// A `SimilaritySearchResult` joined with the (first) attribute of the vector that was found, as
+/// returned by `SimilaritySearchable::search_with_attrs`, or None if that attribute is null.
+pub type AttributedSearchResult = (usize, f32, Option<i64>);
+
+/// The id given to the padding that fills out a row of `FlatSearchResults` when fewer than `topk`
+/// vectors were found for its query. As no dataset can hold `usize::MAX + 1` vectors, it is never
+/// a real id.
+pub const FLAT_RESULT_PADDING_ID: usize = usize::MAX;
+
+/// The results of a batch of searches laid out as two flat, parallel arrays, as returned by
+/// `SimilaritySearchable::search_flat`. Each query has a row of exactly `topk` entries, so that
+/// the results of query `i` are at `i * topk..(i + 1) * topk` in both `ids` and `distances`,
+/// closest first. This is the layout in which faiss returns results, and suits exporting them
+/// without copying, e.g. to numpy.
+///
+/// A row whose query found fewer than `topk` vectors is padded at its end with entries whose id is
+/// `FLAT_RESULT_PADDING_ID` and whose distance is NaN, so that padding is never mistaken for a
+/// result whichever metric the index uses.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct FlatSearchResults {
+    pub ids: Vec<usize>,
+    pub distances: Vec<f32>,
+    /// The length of each row, i.e. the stride between the results of consecutive queries.
+    pub topk: usize,
+}
+
+impl FlatSearchResults {
+    /// The number of queries that there are results for.
+    pub fn num_queries(&self) -> usize {
+        self.ids.len().checked_div(self.topk).unwrap_or(0)
+    }
+
+    /// The ids and distances of the row of results of `query`, including any padding.
+    ///
+    /// # Panics
+    /// - Panics if `query` is not less than `num_queries`.
+    pub fn row(&self, query: usize) -> (&[usize], &[f32]) {
+        let range = query * self.topk..(query + 1) * self.topk;
+        (&self.ids[range.clone()], &self.distances[range])
+    }
+
+    /// The results of `query` without any padding, as they would be returned by `search`.
+    ///
+    /// # Panics
+    /// - Panics if `query` is not less than `num_queries`.
+    pub fn get(&self, query: usize) -> TopKSearchResult {
+        let (ids, distances) = self.row(query);
+        std::iter::zip(ids, distances)
+            .take_while(|(&id, _)| id != FLAT_RESULT_PADDING_ID)
+            .map(|(&id, &distance)| (id, distance))
+            .collect()
+    }
+}
+
+/// An iterator over the results of a batch of searches, one query at a time and in the order of
+/// the queries, as returned by `SimilaritySearchable::search_iter`. The queries are only searched
+/// when their results are asked for, `STREAMING_CHUNK_SIZE` at a time, so that at most the results
+/// of one chunk are held at once. If a chunk fails, its error is yielded in place of its results,
+/// and the iterator then ends.
+pub struct SearchIter<'a, D: SimilaritySearchable> {
+    dataset: &'a D,
+    query_vectors: &'a FlattenedVecs,
+    bitmask: Bitmask,
+    topk: usize,
+    efsearch: i64,
+    /// The index in `query_vectors` of the first query of the next chunk to be searched.
+    next_query: usize,
+    chunk: std::vec::IntoIter<TopKSearchResult>,
+    failed: bool,
+}
+
+impl<D: SimilaritySearchable> Iterator for SearchIter<'_, D> {
+    type Item = Result<TopKSearchResult, SearchableError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(result) = self.chunk.next() {
+            return Some(Ok(result));
+        }
+        if self.failed || self.next_query >= self.query_vectors.len() {
+            return None;
+        }
+
+        let dimensionality = self.query_vectors.dimensionality;
+        let end = (self.next_query + STREAMING_CHUNK_SIZE).min(self.query_vectors.len());
+        let chunk = FlattenedVecs {
+            dimensionality,
+            data: self.query_vectors.data[self.next_query * dimensionality..end * dimensionality]
+                .to_vec(),
+        };
+        self.next_query = end;
+        match self
+            .dataset
+            .search_with_bitmask(&chunk, &self.bitmask, self.topk, self.efsearch)
+        {
+            Ok(results) => {
+                self.chunk = results.into_iter();
+                self.chunk.next().map(Ok)
+            }
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Formats a batch of search results as a human-readable table, listing for each query the rank,
+/// id and distance of each result, aligned in columns. If `metadata` is given, the attributes of
+/// each result are listed alongside its id.
+pub fn format_results(
+    results: &TopKSearchResultBatch,
+    metadata: Option<&HybridSearchMetadata>,
+) -> String {
+    let rank_width = results
+        .iter()
+        .map(|row| row.len().to_string().len())
+        .max()
+        .unwrap_or_default()
+        .max("rank".len());
+    let id_width = results
+        .iter()
+        .flatten()
+        .map(|(id, _)| id.to_string().len())
+        .max()
+        .unwrap_or_default()
+        .max("id".len());
+    let distances: Vec<Vec<String>> = results
+        .iter()
+        .map(|row| row.iter().map(|(_, d)| format!("{d:.4}")).collect())
+        .collect();
+    let distance_width = distances
+        .iter()
+        .flatten()
+        .map(String::len)
+        .max()
+        .unwrap_or_default()
+        .max("distance".len());
+
+    let mut out = String::new();
+    for (query, (row, distances)) in std::iter::zip(results, &distances).enumerate() {
+        out.push_str(&format!("Query {query}:\n"));
+        if row.is_empty() {
+            out.push_str("  (no results)\n");
+            continue;
+        }
+
+        out.push_str(&format!(
+            "  {:>rank_width$}  {:>id_width$}  {:>distance_width$}",
+            "rank", "id", "distance"
+        ));
+        if metadata.is_some() {
+            out.push_str("  attrs");
+        }
+        out.push('\n');
+
+        for (rank, ((id, _), distance)) in std::iter::zip(row, distances).enumerate() {
+            out.push_str(&format!(
+                "  {:>rank_width$}  {:>id_width$}  {:>distance_width$}",
+                rank + 1,
+                id,
+                distance
+            ));
+            if let Some(metadata) = metadata {
+                // NOTE: the metadata of a partition is indexed by its inner ids, so if the ids
+                // have been mapped back to those of the base dataset they may be out of range.
+                if *id < metadata.len() {
+                    out.push_str(&format!("  {:?}", metadata.attrs_for(*id)));
+                } else {
+                    out.push_str("  -");
+                }
+            }
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// The type in which the attributes for hybrid search are notated. Each vector carries the same
+/// number of attributes (`dimensionality`), each an i64. As with `FlattenedVecs`, the attributes
+/// are stored in a single flattened buffer, so the attributes for the vector at index `i` are the
+/// `dimensionality` values starting at `i * dimensionality`.
+///
+/// An attribute may also be null, for a vector that genuinely has no value for it (see
+/// `from_nullable`). A null attribute is stored as 0 in the flattened buffer, but never matches a
+/// predicate other than `PredicateQuery::is_null`.
+#[derive(Clone)]
 pub struct HybridSearchMetadata {
-    attrs: Vec<i32>,
+    /// The number of attributes attached to each vector.
+    dimensionality: usize,
+    attrs: Vec<i64>,
+    /// Whether each of the `attrs` is null, or empty if none are.
+    nulls: Vec<bool>,
 }
 
 impl HybridSearchMetadata {
-    pub fn new(attrs: Vec<i32>) -> Self {
-        Self { attrs }
+    pub fn new(dimensionality: usize, attrs: Vec<i64>) -> Self {
+        Self {
+            dimensionality,
+            attrs,
+            nulls: vec![],
+        }
+    }
+
+    /// Like `new`, but each attribute may be `None` to mark it as null.
+    pub fn from_nullable(dimensionality: usize, attrs: Vec<Option<i64>>) -> Self {
+        let nulls = if attrs.iter().any(Option::is_none) {
+            attrs.iter().map(Option::is_none).collect()
+        } else {
+            vec![]
+        };
+        Self {
+            dimensionality,
+            attrs: attrs.into_iter().map(Option::unwrap_or_default).collect(),
+            nulls,
+        }
     }
 
+    /// Creates metadata with a single attribute per vector from string `labels`, such as
+    /// categories, each of which is hashed to an attribute with `hash_label`. The mapping from each
+    /// distinct label to its attribute is also returned, e.g. to build predicates over the labels.
+    pub fn from_strings<S: AsRef<str>>(
+        labels: impl IntoIterator<Item = S>,
+    ) -> (Self, HashMap<String, i64>) {
+        let mut mapping = HashMap::new();
+        let attrs = labels
+            .into_iter()
+            .map(|label| {
+                let label = label.as_ref();
+                *mapping
+                    .entry(label.to_string())
+                    .or_insert_with(|| hash_label(label))
+            })
+            .collect();
+
+        (Self::new(1, attrs), mapping)
+    }
+
+    /// Creates a new HybridSearchMetadata based on a bitmask and an original one. Only the rows
+    /// for vectors that match the bitmask are copied.
     pub fn new_from_bitmask(other: &Self, mask: &Bitmask) -> Self {
-        let filtered_attrs: Vec<i32> = other
-            .attrs
-            .iter()
+        let filtered_attrs: Vec<i64> = other
+            .rows()
             .zip(mask.map.iter())
-            .filter_map(|(&attr, &keep)| {
-                // info!("{}", keep);
+            .filter_map(|(row, &keep)| {
                 if keep == 1 {
-                    Some(attr) // Keep the attribute if the bitmask allows
+                    Some(row) // Keep the attributes if the bitmask allows
                 } else {
                     None
                 }
             })
+            .flat_map(|row| row.iter().copied())
             .collect();
+        let filtered_nulls = if other.nulls.is_empty() {
+            vec![]
+        } else {
+            (0..other.len())
+                .filter(|&idx| mask.map[idx] == 1)
+                .flat_map(|idx| other.nulls_for(idx).iter().copied())
+                .collect()
+        };
 
         HybridSearchMetadata {
+            dimensionality: other.dimensionality,
             attrs: filtered_attrs,
+            nulls: filtered_nulls,
         }
     }
 
+    /// Appends the attributes of further vectors, laid out in the same flattened form as `attrs`.
+    /// None of them are null.
+    pub fn extend_from_slice(&mut self, attrs: &[i64]) {
+        self.attrs.extend_from_slice(attrs);
+        if !self.nulls.is_empty() {
+            self.nulls.resize(self.attrs.len(), false);
+        }
+    }
+
+    /// Appends the attributes of the vectors that `other` describes, keeping those that are null.
+    ///
+    /// # Panics
+    /// - Panics if both describe vectors, but with different numbers of attributes.
+    pub(crate) fn append(&mut self, other: &Self) {
+        assert!(
+            self.is_empty() || other.is_empty() || self.dimensionality == other.dimensionality,
+            "cannot append vectors with {} attributes to vectors with {}",
+            other.dimensionality,
+            self.dimensionality
+        );
+        self.dimensionality = self.dimensionality.max(other.dimensionality);
+        if !other.nulls.is_empty() {
+            self.nulls.resize(self.attrs.len(), false);
+        }
+        self.attrs.extend_from_slice(&other.attrs);
+        if !self.nulls.is_empty() {
+            self.nulls.extend_from_slice(&other.nulls);
+            self.nulls.resize(self.attrs.len(), false);
+        }
+    }
+
+    /// The number of vectors that this metadata describes.
     pub fn len(&self) -> usize {
-        self.attrs.len()
+        self.attrs
+            .len()
+            .checked_div(self.dimensionality)
+            .unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of attributes attached to each vector.
+    pub fn dimensionality(&self) -> usize {
+        self.dimensionality
+    }
+
+    /// The attributes of the vector at index `idx`.
+    ///
+    /// # Panics
+    /// - Panics if `idx` is out of range.
+    pub fn attrs_for(&self, idx: usize) -> &[i64] {
+        let start = idx * self.dimensionality;
+        &self.attrs[start..start + self.dimensionality]
+    }
+
+    /// Replaces the attributes of the vector at index `idx` with `attrs`. An error is returned if
+    /// `idx` is out of range, or if `attrs` is not one attribute for each of `dimensionality`.
+    pub fn set_attrs_for(&mut self, idx: usize, attrs: &[i64]) -> Result<(), ConstructionError> {
+        if idx >= self.len() {
+            return Err(ConstructionError::IdOutOfRange {
+                id: idx,
+                len: self.len(),
+            });
+        }
+        if attrs.len() != self.dimensionality {
+            return Err(ConstructionError::AttributeCountMismatch {
+                expected: self.dimensionality,
+                got: attrs.len(),
+            });
+        }
+
+        let start = idx * self.dimensionality;
+        self.attrs[start..start + self.dimensionality].copy_from_slice(attrs);
+        if !self.nulls.is_empty() {
+            self.nulls[start..start + self.dimensionality].fill(false);
+        }
+        Ok(())
+    }
+
+    /// Whether the attribute at position `attr` of the vector at index `idx` is null.
+    ///
+    /// # Panics
+    /// - Panics if `idx` or `attr` is out of range.
+    pub fn is_null(&self, idx: usize, attr: usize) -> bool {
+        assert!(
+            idx < self.len() && attr < self.dimensionality,
+            "attribute {attr} of vector {idx} is out of range"
+        );
+        self.nulls_for(idx).get(attr).copied().unwrap_or_default()
+    }
+
+    /// Whether each of the attributes of the vector at index `idx` is null, or an empty slice if
+    /// none of the attributes of any vector are.
+    pub(crate) fn nulls_for(&self, idx: usize) -> &[bool] {
+        if self.nulls.is_empty() {
+            return &[];
+        }
+        let start = idx * self.dimensionality;
+        &self.nulls[start..start + self.dimensionality]
+    }
+
+    /// Iterate over the attributes of each vector, in index order.
+    pub fn rows(&self) -> impl Iterator<Item = &[i64]> {
+        // NOTE: `chunks_exact` panics on a zero chunk size, and metadata with no attributes has no
+        // rows anyway.
+        self.attrs.chunks_exact(self.dimensionality.max(1))
+    }
+
+    /// The value of the attribute at position `attr` for every vector, in index order. A null
+    /// attribute reads as 0 (see `is_null`).
+    pub fn column(&self, attr: usize) -> Vec<i64> {
+        self.rows().map(|row| row[attr]).collect()
+    }
+
+    /// The distinct values of the attribute at position `attr`, each with the number of vectors
+    /// that have it, e.g. to see how selective a predicate over the attribute would be. Null
+    /// attributes are not counted.
+    ///
+    /// # Panics
+    /// - Panics if `attr` is not less than `dimensionality`.
+    pub fn attribute_histogram(&self, attr: usize) -> HashMap<i64, usize> {
+        self.rows()
+            .enumerate()
+            .filter(|(idx, _)| !self.nulls_for(*idx).get(attr).copied().unwrap_or_default())
+            .fold(HashMap::new(), |mut histogram, (_, row)| {
+                *histogram.entry(row[attr]).or_default() += 1;
+                histogram
+            })
+    }
+
+    /// Writes the metadata to `path` in a compact binary format, e.g. alongside an index saved
+    /// with `SimilaritySearchable::save_index`, so that the index can be searched with predicates
+    /// once it is loaded again. The file begins with the magic bytes `OAKM` and a version byte,
+    /// followed by the number of attributes per vector and the number of vectors as little-endian
+    /// u64s, and a byte that is 1 if any attribute is null. Then come the attributes as
+    /// little-endian i64s, and (if any are null) a byte per attribute that is 1 if it is null. It
+    /// can be read back with `load`.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(METADATA_MAGIC)?;
+        writer.write_all(&[METADATA_VERSION])?;
+        writer.write_all(&(self.dimensionality as u64).to_le_bytes())?;
+        writer.write_all(&(self.len() as u64).to_le_bytes())?;
+        writer.write_all(&[!self.nulls.is_empty() as u8])?;
+        for attr in &self.attrs {
+            writer.write_all(&attr.to_le_bytes())?;
+        }
+        for &null in &self.nulls {
+            writer.write_all(&[null as u8])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Reads metadata written by `save`. An error is returned if the file is not in that format,
+    /// was written by a different version of it, or does not hold as many attributes as its header
+    /// says.
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+        if bytes.len() < METADATA_HEADER_LEN || !bytes.starts_with(METADATA_MAGIC) {
+            anyhow::bail!("{path:?} is not a file of metadata");
+        }
+
+        let version = bytes[METADATA_MAGIC.len()];
+        if version != METADATA_VERSION {
+            anyhow::bail!(
+                "{path:?} was written in version {version} of the format, but only version \
+                 {METADATA_VERSION} can be read"
+            );
+        }
+
+        let header = &bytes[METADATA_MAGIC.len() + 1..METADATA_HEADER_LEN];
+        let dimensionality = usize::try_from(u64::from_le_bytes(header[..8].try_into()?))?;
+        let count = usize::try_from(u64::from_le_bytes(header[8..16].try_into()?))?;
+        let has_nulls = header[16] == 1;
+
+        let data = &bytes[METADATA_HEADER_LEN..];
+        let bytes_per_attr = if has_nulls { 9 } else { 8 };
+        let total = count.checked_mul(dimensionality);
+        if total.and_then(|total| total.checked_mul(bytes_per_attr)) != Some(data.len()) {
+            anyhow::bail!(
+                "{path:?} should hold {dimensionality} attributes for each of {count} vectors, \
+                 but has {} bytes of attributes",
+                data.len()
+            );
+        }
+
+        let (attrs, nulls) = data.split_at(total.unwrap_or_default() * 8);
+        Ok(Self {
+            dimensionality,
+            attrs: attrs
+                .chunks_exact(8)
+                .map(|attr| i64::from_le_bytes(attr.try_into().unwrap()))
+                .collect(),
+            nulls: nulls.iter().map(|&null| null == 1).collect(),
+        })
     }
 }
 
-impl AsRef<Vec<i32>> for HybridSearchMetadata {
-    fn as_ref(&self) -> &Vec<i32> {
-        self.attrs.as_ref()
+/// Hashes a string label to an attribute. Unlike the hashers of the standard library, the hash
+/// (64-bit FNV-1a) is stable across runs, platforms and versions, so it is safe to persist.
+pub fn hash_label(label: &str) -> i64 {
+    let hash = label.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    hash as i64
+}
+
+/// Collects a single attribute per vector, which is the case that ACORN supports.
+impl FromIterator<i64> for HybridSearchMetadata {
+    fn from_iter<I: IntoIterator<Item = i64>>(iter: I) -> Self {
+        Self::new(1, iter.into_iter().collect())
+    }
+}
+
+/// Yields the attributes in their flattened form, i.e. the `dimensionality` attributes of each
+/// vector in turn.
+impl IntoIterator for HybridSearchMetadata {
+    type Item = i64;
+    type IntoIter = std::vec::IntoIter<i64>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.attrs.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a HybridSearchMetadata {
+    type Item = i64;
+    type IntoIter = std::iter::Copied<std::slice::Iter<'a, i64>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.attrs.iter().copied()
     }
 }
 
+/// Diagnostics over the structure of the ACORN graph of an index. The degrees are those of the
+/// base level of the graph, which contains every vector.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct IndexStats {
+    /// The number of vectors in the graph.
+    pub nodes: usize,
+    /// The number of (directed) edges in the base level of the graph.
+    pub edges: usize,
+    pub min_out_degree: usize,
+    pub max_out_degree: usize,
+    pub mean_out_degree: f64,
+    /// The number of levels in the hierarchy of the graph, including the base level.
+    pub levels: usize,
+    /// The bytes that the index holds for each vector besides its edges, e.g. its (possibly
+    /// compressed) copy of the vector and its attribute.
+    pub bytes_per_vector: usize,
+}
+
+impl IndexStats {
+    /// A rough estimate of the bytes held by the index: `bytes_per_vector` for each node, and an
+    /// id per edge. It ignores the upper levels of the graph and any unused neighbor slots, so the
+    /// true usage is somewhat higher.
+    pub fn estimated_bytes(&self) -> usize {
+        // NOTE: faiss stores the ids of neighbors as i32.
+        self.nodes * self.bytes_per_vector + self.edges * std::mem::size_of::<i32>()
+    }
+}
+
+/// What it cost to build an index, as returned by `SimilaritySearchable::initialize_with_report`,
+/// e.g. to compare the cost of different `OakIndexOptions`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct BuildReport {
+    /// The wall-clock time taken to build the index.
+    pub duration: Duration,
+    /// The number of vectors in the index.
+    pub num_vectors: usize,
+    /// The number of (directed) edges in the base level of the graph, as in `IndexStats::edges`.
+    pub num_edges: usize,
+    /// A rough estimate of the bytes held by the index once built, see
+    /// `IndexStats::estimated_bytes`.
+    pub peak_memory_estimate: usize,
+}
+
+/// The recall of a search estimated by `SimilaritySearchable::search_with_recall`, from the
+/// queries whose exact neighbors were computed alongside their results.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct RecallEstimate {
+    /// The number of queries that were sampled.
+    pub sampled: usize,
+    /// The mean recall@topk of the sampled queries (see `metrics::recall_at_k`), or None if no
+    /// query was sampled.
+    pub recall: Option<f32>,
+}
+
+/// The latencies of the searches run by `SimilaritySearchable::benchmark_search`, each of which is
+/// a single query.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct LatencyReport {
+    /// The number of searches that were timed.
+    pub searches: usize,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    /// The number of queries searched per second, one after another.
+    pub qps: f64,
+}
+
+impl LatencyReport {
+    /// Summarizes the `latencies` of a number of searches. The percentiles are nearest-rank, i.e.
+    /// the pth percentile is the smallest latency that at least p% of the searches were as fast as.
+    fn from_latencies(mut latencies: Vec<Duration>) -> Self {
+        if latencies.is_empty() {
+            return Self::default();
+        }
+        latencies.sort();
+
+        let total: Duration = latencies.iter().sum();
+        let percentile = |p: usize| latencies[(p * latencies.len()).div_ceil(100).max(1) - 1];
+        Self {
+            searches: latencies.len(),
+            mean: total / latencies.len() as u32,
+            p50: percentile(50),
+            p95: percentile(95),
+            p99: percentile(99),
+            qps: latencies.len() as f64 / total.as_secs_f64(),
+        }
+    }
+}
+
+/// Further parameters of a search (see `SimilaritySearchable::search_with_params`), which trade
+/// its recall for its latency or change how its results are reported. The default leaves the
+/// search as it otherwise is.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct SearchParams {
+    /// Stop searching the base level of the graph once the distances to this many vectors that
+    /// pass the predicate have been computed (see `SearchStats::ndis`), e.g. a small multiple of
+    /// `topk`. Under a very selective predicate, ACORN may otherwise explore much of the graph for
+    /// few matches. The budget is only checked between the expansions of candidates, and so may be
+    /// exceeded by up to a neighbor list.
+    pub max_filtered_visits: Option<usize>,
+    /// Whether the distances of a search under `DistanceMetric::L2` are the true Euclidean
+    /// distances, rather than the squared distances that faiss computes (and that are returned by
+    /// default). The square root is taken of each distance once the search is done, so the
+    /// neighbors and their order are the same either way. Other metrics are unaffected.
+    pub true_l2_distances: bool,
+    /// Widen the search in proportion to how selective its predicate is, up to an `efsearch` of
+    /// this cap: the `efsearch` of the search is divided by the fraction of the vectors that pass
+    /// the predicate, so that a predicate that only 10% of vectors pass is searched with ten times
+    /// the `efsearch`. A predicate that every vector passes leaves it as it is.
+    ///
+    /// NOTE: this stands in for adjusting `OakIndexOptions::gamma` at search time, which ACORN
+    /// does not support. The graph is built with `gamma` times the neighbors of each vector, and
+    /// searching it only asks whether `gamma` is 1, not how large it is, so there is no runtime
+    /// multiplier to expose. Scaling `efsearch` instead lets the breadth of the traversal follow
+    /// the selectivity of each query.
+    pub scale_efsearch_by_selectivity: Option<i64>,
+}
+
+impl SearchParams {
+    /// The `efsearch` of a search over the vectors set in `filter_id_map`, once scaled by the
+    /// selectivity of the filter if `scale_efsearch_by_selectivity` is set. It is never scaled
+    /// below `efsearch`, nor when no vector passes the filter.
+    pub(crate) fn scaled_efsearch(&self, efsearch: i64, filter_id_map: &[c_char]) -> i64 {
+        let Some(cap) = self.scale_efsearch_by_selectivity else {
+            return efsearch;
+        };
+        let passing = filter_id_map.iter().filter(|&&bit| bit != 0).count();
+        if passing == 0 {
+            return efsearch;
+        }
+        let scaled = (efsearch as f64 * filter_id_map.len() as f64 / passing as f64).ceil() as i64;
+        scaled.min(cap).max(efsearch)
+    }
+
+    /// Takes the square root of each of the distances in `results` if `true_l2_distances` is set
+    /// and they are under `DistanceMetric::L2`.
+    pub(crate) fn report_distances(
+        &self,
+        metric: DistanceMetric,
+        results: &mut [TopKSearchResult],
+    ) {
+        if self.true_l2_distances && metric == DistanceMetric::L2 {
+            for (_, distance) in results.iter_mut().flatten() {
+                *distance = distance.sqrt();
+            }
+        }
+    }
+}
+
+/// The work done by ACORN to answer a single query.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct SearchStats {
+    /// The number of distances computed between the query and vectors in the index.
+    pub ndis: usize,
+}
+
+/// The measure of distance between vectors that an index is built with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum DistanceMetric {
+    /// Squared Euclidean distance.
+    #[default]
+    L2,
+    /// The inner product of the vectors.
+    InnerProduct,
+    /// The inner product of the vectors once normalized to unit length. The vectors are normalized
+    /// before they are added to the index, and queries before they are searched.
+    Cosine,
+    /// The Manhattan distance, i.e. the sum of the absolute differences of the components.
+    L1,
+}
+
+impl DistanceMetric {
+    /// The faiss `MetricType` that an index using this metric is built with. Note that `Cosine`
+    /// is built as an inner product index over normalized vectors.
+    pub fn faiss_metric_type(&self) -> i32 {
+        match self {
+            DistanceMetric::InnerProduct | DistanceMetric::Cosine => 0,
+            DistanceMetric::L2 => 1,
+            DistanceMetric::L1 => 2,
+        }
+    }
+
+    /// Whether a result at `distance` from the query lies within `radius` of it.
+    pub fn is_within(&self, distance: f32, radius: f32) -> bool {
+        match self {
+            DistanceMetric::L2 | DistanceMetric::L1 => distance < radius,
+            DistanceMetric::InnerProduct | DistanceMetric::Cosine => distance > radius,
+        }
+    }
+}
+
+/// The bytes that begin a file written by `HybridSearchMetadata::save`.
+const METADATA_MAGIC: &[u8; 4] = b"OAKM";
+
+/// The version of the format that `HybridSearchMetadata::save` writes and `load` reads, which
+/// should be bumped whenever the layout changes.
+const METADATA_VERSION: u8 = 1;
+
+/// The length of the header of a file written by `HybridSearchMetadata::save`: the magic bytes,
+/// the version byte, the number of attributes per vector and the number of vectors as u64s, and
+/// the byte that marks whether any attribute is null.
+const METADATA_HEADER_LEN: usize = METADATA_MAGIC.len() + 1 + 2 * 8 + 1;
+
+/// The `efsearch` that an index is searched with when there is no reason to choose otherwise; it is
+/// the efSearch that ACORN itself defaults to. Unlike the `OakIndexOptions`, which are fixed when
+/// the index is built, `efsearch` is given anew to every search (and passed to ACORN as its
+/// `SearchParameters`), so it can be swept over without rebuilding the index. Higher values
+/// explore more of the graph, trading latency for recall, and values below `topk` behave as
+/// `topk`.
+pub const DEFAULT_EF_SEARCH: i64 = 16;
+
+/// The number of queries that `SimilaritySearchable::search_streaming` searches at a time, and so
+/// the most results that it holds at once.
+pub const STREAMING_CHUNK_SIZE: usize = 1024;
+
+/// The default `OakIndexOptions::search_batch_size`.
+pub const DEFAULT_SEARCH_BATCH_SIZE: usize = 1024;
+
 /// These parameters are currently essentially ACORN parameters, taken from
 /// https://github.com/csirianni/ACORN/blob/main/README.md
+///
+/// Options can be constructed (and validated) with `OakIndexOptions::builder()`. With the
+/// `serialize` feature, they can also be deserialized, in which case any missing fields take their
+/// default values.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serialize",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
 pub struct OakIndexOptions {
     /// Degree bound for traversed nodes during ACORN search
     pub m: i32,
-    /// Neighbor expansion factor for ACORN index
+    /// Neighbor expansion factor for ACORN index. A `gamma` of 1 builds ACORN-1, and any larger
+    /// value ACORN-gamma (see `AcornVariant`).
     pub gamma: i32,
-    /// Compression parameter for ACORN index
+    /// Compression parameter for ACORN index: the `m_beta` nearest neighbors of each vector on
+    /// the base level are kept as they are, and the rest are pruned where they are already
+    /// neighbors of those kept. Searches of ACORN-gamma only expand the neighbors beyond the first
+    /// `m_beta` to their own neighbors, whereas ACORN-1 expands every neighbor, whatever `m_beta`.
     pub m_beta: i32,
+    /// Which of ACORN's variants the index is built as. With `None`, as by default, the variant
+    /// follows `gamma`, and so is ACORN-1 with the default `gamma` of 1. Naming a variant makes
+    /// `validate` check that `gamma` agrees with it.
+    pub variant: Option<AcornVariant>,
+    /// The distance metric that the index is built with
+    pub metric: DistanceMetric,
+    /// Seeds the randomness of index construction, so that indexes built with the same seed over
+    /// the same vectors are identical. Seeded indexes are built on a single thread. With `None`,
+    /// vectors are added to the graph in parallel, so the graph depends on how the threads are
+    /// scheduled and is not reproducible.
+    pub seed: Option<u64>,
+    /// Whether NaN or infinite components of query vectors are replaced with zero before searching.
+    /// Otherwise, as by default, searching with such a query is a `SearchableError::NonFiniteQuery`.
+    pub sanitize_non_finite_queries: bool,
+    /// The number of queries in a batch that are searched at a time. The queries of a search are
+    /// split into groups of this size, each of which is prepared (e.g. normalized) and searched in
+    /// parallel before the next, which bounds the memory that a large batch of queries needs.
+    pub search_batch_size: usize,
+    /// If set, the index stores each vector as a product-quantized code rather than as f32s, which
+    /// takes far less memory but loses precision: the distances returned by searches are
+    /// approximations computed against the codes (asymmetrically, as the queries are not
+    /// quantized), recall is lower, and the vectors that `SimilaritySearchable::reconstruct`
+    /// returns are decoded from the codes rather than exact. The quantizer is trained on the
+    /// first vectors added to the index. `search_rerank` re-ranks by the exact distances, for a
+    /// dataset that keeps its vectors in memory. Only `DistanceMetric::L2` is supported.
+    pub product_quantization: Option<ProductQuantization>,
+    /// If set, an index with `product_quantization` also keeps a flat copy of its vectors, as
+    /// faiss's `IndexRefineFlat` does: each search finds `topk` times this factor of candidates
+    /// over the codes, and returns the closest `topk` of them by their exact distances to the
+    /// flat copy. This is done within faiss, so unlike `search_rerank` it needs no vectors in
+    /// memory on the Rust side, but the index holds the vectors in full as well as their codes.
+    /// `SimilaritySearchable::reconstruct` returns the exact vectors.
+    pub refine_k_factor: Option<usize>,
+    /// If set, the vectors are transformed (e.g. by a PCA) before they are added to the index,
+    /// and each query is likewise transformed before it is searched, so that the graph and the
+    /// distances returned are those of the transformed vectors. The transform is trained by faiss
+    /// on the first vectors added to the index, and is saved and loaded along with it.
+    pub pre_transform: Option<PreTransform>,
+    /// Whether neighbors at the same distance from a query are returned in ascending order of id,
+    /// as by default, so that the same search always returns its results in the same order.
+    /// Otherwise they are returned in the order that faiss found them, which may differ between
+    /// runs.
+    pub break_ties_by_id: bool,
+    /// Whether the identical queries of a batch are only searched once, with the results of each
+    /// copied to every position at which it was asked, as is worthwhile for batches in which the
+    /// same (e.g. popular) queries recur. Queries are identical only if the bit patterns of all of
+    /// their components are, so near-duplicates are still searched apart, as are queries that
+    /// differ only in the sign of a zero. Off by default, as hashing every query costs time that
+    /// a batch without duplicates gains nothing for. Only ACORN indexes honour this.
+    pub dedup_queries: bool,
+}
+
+/// The variants of ACORN described in its paper, between which the index trades the cost of its
+/// construction against the quality of its filtered searches (see `OakIndexOptions::variant`).
+/// The vendored ACORN builds both with the same constructor, choosing between them by `gamma`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum AcornVariant {
+    /// ACORN-1, built with a `gamma` of 1: each vector is linked to about `2 * m` neighbors, as
+    /// in HNSW, and filtered searches make up for the sparser graph by expanding every neighbor
+    /// to its own neighbors. It is the cheaper to build and the smaller, but its searches compute
+    /// more distances under selective predicates.
+    Acorn1,
+    /// ACORN-gamma, built with a `gamma` above 1: each vector is linked from `2 * m * gamma`
+    /// candidate neighbors, compressed beyond the first `m_beta` of them, so that a predicate
+    /// that only `1 / gamma` of the vectors pass still leaves a connected graph, and searches
+    /// need only expand the compressed neighbors. It takes more time and memory to build, for
+    /// better recall under selective predicates.
+    AcornGamma,
+}
+
+/// The product quantizer that an index compresses its vectors with (see
+/// `OakIndexOptions::product_quantization`). Each vector is split into `subquantizers` equal parts,
+/// each of which is stored as a code of `nbits` bits, so each vector takes
+/// `subquantizers * nbits / 8` bytes rather than four per component.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProductQuantization {
+    /// The number of parts that each vector is split into, which must divide the dimensionality.
+    pub subquantizers: i32,
+    /// The number of bits in the code of each part, at most 8. The quantizer must be trained on
+    /// at least `2^nbits` vectors.
+    pub nbits: i32,
+}
+
+/// A linear transform that an index applies to its vectors, and to the queries it is searched for
+/// (see `OakIndexOptions::pre_transform`), as faiss's `IndexPreTransform` does.
+///
+/// NOTE: faiss trains a PCA and an OPQ rotation with LAPACK, so building an index with either
+/// needs a BLAS library that provides LAPACK (as OpenBLAS does). A `Linear` transform needs only
+/// the matrix multiplication that searches already use.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum PreTransform {
+    /// A fixed transform (e.g. a PCA or whitening computed elsewhere) to `dimensionality`
+    /// dimensions, which maps each vector `x` to `matrix * x + bias`. The `matrix` is row-major,
+    /// with a row of the dimensionality of the vectors for each output dimension, and the `bias`
+    /// is either empty or of length `dimensionality`. The vectors can only be reconstructed if
+    /// the rows of `matrix` are orthonormal.
+    Linear {
+        dimensionality: i32,
+        matrix: Vec<f32>,
+        bias: Vec<f32>,
+    },
+    /// Projects the vectors onto their first `dimensionality` principal components, at most as
+    /// many as they have, so that the index holds the smaller vectors. If `whiten`, each component
+    /// is also scaled to unit variance, after which the vectors cannot be reconstructed.
+    Pca { dimensionality: i32, whiten: bool },
+    /// Rotates the vectors so that they are better suited to a product quantizer of
+    /// `subquantizers` (see `OakIndexOptions::product_quantization`), as OPQ learns. The number
+    /// of subquantizers must divide the dimensionality, and the rotation must be trained on at
+    /// least 256 vectors.
+    Opq { subquantizers: i32 },
 }
 
 /// The default options for OAK are the options suggested in the ACORN readme: https://github.com/csirianni/ACORN/blob/main/README.md
@@ -98,38 +1079,645 @@ impl Default for OakIndexOptions {
             gamma: 1,
             m: 32,
             m_beta: 64,
+            variant: None,
+            metric: DistanceMetric::L2,
+            seed: None,
+            sanitize_non_finite_queries: false,
+            search_batch_size: DEFAULT_SEARCH_BATCH_SIZE,
+            product_quantization: None,
+            refine_k_factor: None,
+            pre_transform: None,
+            break_ties_by_id: true,
+            dedup_queries: false,
         }
     }
 }
 
-/// Trait for a dataset of vectors.
+impl OakIndexOptions {
+    /// Starts building options from the defaults.
+    pub fn builder() -> OakIndexOptionsBuilder {
+        OakIndexOptionsBuilder::default()
+    }
+
+    /// The variant of ACORN that the index is built as: the `variant` if one is named, and
+    /// otherwise that which `gamma` gives.
+    pub fn acorn_variant(&self) -> AcornVariant {
+        match self.variant {
+            Some(variant) => variant,
+            None if self.gamma == 1 => AcornVariant::Acorn1,
+            None => AcornVariant::AcornGamma,
+        }
+    }
 
+    /// Checks that the options can be used to build an index: `m`, `gamma`, `m_beta` and
+    /// `search_batch_size` must all be positive, and `m_beta` must be at least `m` and at most
+    /// `2 * m * gamma`. A named `variant` needs a `gamma` of 1 for `AcornVariant::Acorn1`, and
+    /// above 1 for `AcornVariant::AcornGamma`. Any `product_quantization` must have a positive number of subquantizers of
+    /// 1 to 8 bits, and can only be used with `DistanceMetric::L2`. A `refine_k_factor` must be
+    /// positive, and is only allowed alongside `product_quantization`. A `PreTransform::Linear` or
+    /// `PreTransform::Pca` must keep a positive number of dimensions (with a bias of that length,
+    /// if any), and a `PreTransform::Opq` have a positive number of subquantizers.
+    pub fn validate(&self) -> Result<(), ConstructionError> {
+        let invalid = |reason: String| Err(ConstructionError::InvalidOptions(reason));
+        for (name, value) in [
+            ("m", self.m),
+            ("gamma", self.gamma),
+            ("m_beta", self.m_beta),
+        ] {
+            if value <= 0 {
+                return invalid(format!("{name} must be positive, but is {value}"));
+            }
+        }
+        if self.search_batch_size == 0 {
+            return invalid("search_batch_size must be positive".to_string());
+        }
+        match self.variant {
+            Some(AcornVariant::Acorn1) if self.gamma != 1 => {
+                return invalid(format!(
+                    "ACORN-1 needs a gamma of 1, but gamma is {}",
+                    self.gamma
+                ));
+            }
+            Some(AcornVariant::AcornGamma) if self.gamma == 1 => {
+                return invalid("ACORN-gamma needs a gamma above 1".to_string());
+            }
+            _ => {}
+        }
+        if self.m_beta < self.m {
+            return invalid(format!(
+                "m_beta ({}) must be at least m ({})",
+                self.m_beta, self.m
+            ));
+        }
+        // NOTE: ACORN's levels are computed from `2 * m * gamma` neighbors, of which at most
+        // `m_beta` are compressed.
+        if self.m_beta as i64 > 2 * self.m as i64 * self.gamma as i64 {
+            return invalid(format!(
+                "m_beta ({}) must be at most 2 * m * gamma ({})",
+                self.m_beta,
+                2 * self.m as i64 * self.gamma as i64
+            ));
+        }
+        if let Some(pq) = self.product_quantization {
+            if pq.subquantizers <= 0 {
+                return invalid(format!(
+                    "product quantization needs a positive number of subquantizers, but has {}",
+                    pq.subquantizers
+                ));
+            }
+            if !(1..=8).contains(&pq.nbits) {
+                return invalid(format!(
+                    "product quantization needs between 1 and 8 bits per subquantizer, but has {}",
+                    pq.nbits
+                ));
+            }
+            // NOTE: the graph is linked using faiss's table of distances between codes, which is
+            // only computed for L2.
+            if self.metric != DistanceMetric::L2 {
+                return invalid(format!(
+                    "product quantization only supports the L2 metric, not {:?}",
+                    self.metric
+                ));
+            }
+        }
+        if let Some(k_factor) = self.refine_k_factor {
+            if k_factor == 0 {
+                return invalid("refine_k_factor must be positive".to_string());
+            }
+            // NOTE: the flat storage of an index without product quantization is already exact.
+            if self.product_quantization.is_none() {
+                return invalid(
+                    "refine_k_factor can only be used with product quantization".to_string(),
+                );
+            }
+        }
+        match &self.pre_transform {
+            Some(PreTransform::Linear { dimensionality, .. })
+            | Some(PreTransform::Pca { dimensionality, .. })
+                if *dimensionality <= 0 =>
+            {
+                return invalid(format!(
+                    "a pre-transform must keep a positive number of dimensions, but keeps \
+                     {dimensionality}"
+                ));
+            }
+            Some(PreTransform::Linear {
+                dimensionality,
+                bias,
+                ..
+            }) if !bias.is_empty() && bias.len() != *dimensionality as usize => {
+                return invalid(format!(
+                    "the bias of a linear transform to {dimensionality} dimensions has length {}",
+                    bias.len()
+                ));
+            }
+            Some(PreTransform::Opq { subquantizers }) if *subquantizers <= 0 => {
+                return invalid(format!(
+                    "OPQ needs a positive number of subquantizers, but has {subquantizers}"
+                ));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// A builder for `OakIndexOptions`, which starts from the defaults and validates the options when
+/// they are built.
+#[derive(Clone, Debug, Default)]
+pub struct OakIndexOptionsBuilder {
+    options: OakIndexOptions,
+}
+
+impl OakIndexOptionsBuilder {
+    pub fn m(mut self, m: i32) -> Self {
+        self.options.m = m;
+        self
+    }
+
+    pub fn gamma(mut self, gamma: i32) -> Self {
+        self.options.gamma = gamma;
+        self
+    }
+
+    pub fn m_beta(mut self, m_beta: i32) -> Self {
+        self.options.m_beta = m_beta;
+        self
+    }
+
+    pub fn variant(mut self, variant: AcornVariant) -> Self {
+        self.options.variant = Some(variant);
+        self
+    }
+
+    pub fn metric(mut self, metric: DistanceMetric) -> Self {
+        self.options.metric = metric;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.options.seed = Some(seed);
+        self
+    }
+
+    pub fn sanitize_non_finite_queries(mut self, sanitize: bool) -> Self {
+        self.options.sanitize_non_finite_queries = sanitize;
+        self
+    }
+
+    pub fn dedup_queries(mut self, dedup: bool) -> Self {
+        self.options.dedup_queries = dedup;
+        self
+    }
+
+    pub fn search_batch_size(mut self, search_batch_size: usize) -> Self {
+        self.options.search_batch_size = search_batch_size;
+        self
+    }
+
+    pub fn product_quantization(mut self, subquantizers: i32, nbits: i32) -> Self {
+        self.options.product_quantization = Some(ProductQuantization {
+            subquantizers,
+            nbits,
+        });
+        self
+    }
+
+    pub fn refine_k_factor(mut self, k_factor: usize) -> Self {
+        self.options.refine_k_factor = Some(k_factor);
+        self
+    }
+
+    pub fn pre_transform(mut self, pre_transform: PreTransform) -> Self {
+        self.options.pre_transform = Some(pre_transform);
+        self
+    }
+
+    pub fn break_ties_by_id(mut self, break_ties_by_id: bool) -> Self {
+        self.options.break_ties_by_id = break_ties_by_id;
+        self
+    }
+
+    /// Returns the options, or an error if they are invalid (see `OakIndexOptions::validate`).
+    pub fn build(self) -> Result<OakIndexOptions, ConstructionError> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
+}
+
+/// Trait for a dataset of vectors.
 pub trait SimilaritySearchable {
     /// Provide the number of vectors that have been added to the dataset.
     fn len(&self) -> usize;
 
+    /// Whether the dataset contains no vectors.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// Provide the dimensionality of the vectors in the dataset.
     fn get_dimensionality(&self) -> usize;
 
     /// Get the metadata that represents the attributes over the vectors (for hybrid search).
     fn get_metadata(&self) -> &HybridSearchMetadata;
 
+    /// Provide the distance metric of the index associated with this dataset, if it has been built.
+    fn get_metric(&self) -> Option<DistanceMetric>;
+
+    /// Whether the index associated with this dataset has been built (or loaded), and so whether
+    /// it can be searched rather than returning `SearchableError::DatasetIsNotIndexed`.
+    fn is_indexed(&self) -> bool {
+        self.get_metric().is_some()
+    }
+
+    /// Provide the vectors of this dataset, indexed by the same ids as search results, if they are
+    /// held in memory (rather than only in the index, or on disk).
+    fn vectors(&self) -> Option<&FlattenedVecs> {
+        None
+    }
+
+    /// The vector with id `idx`, borrowed from `vectors` without copying it. None is returned if
+    /// `idx` is out of range, or if the vectors are not held in memory.
+    fn get_vector(&self, idx: usize) -> Option<&[f32]> {
+        self.vectors()?.get(idx)
+    }
+
+    /// The bitmask over the vectors that a search with `predicate_query` may return, i.e. those
+    /// that match it (or every vector, if there is no predicate). Datasets that can remove vectors
+    /// leave those out of the mask.
+    fn search_mask(
+        &self,
+        predicate_query: &Option<PredicateQuery>,
+    ) -> Result<Bitmask, SearchableError>
+    where
+        Self: Sized,
+    {
+        match predicate_query {
+            None => Ok(Bitmask::new_full(self)),
+            Some(pq) => Bitmask::try_new(pq, self),
+        }
+    }
+
+    /// Copies the vectors with the given `ids` out of the index, in the order of the `ids`. As the
+    /// index stores normalized vectors for `DistanceMetric::Cosine`, so are those returned. An
+    /// index built with `OakIndexOptions::product_quantization` is lossy, and returns
+    /// approximations of the vectors.
+    fn reconstruct(&self, ids: &[usize]) -> Result<FlattenedVecs, SearchableError>;
+
+    /// Describe the structure of the graph of the index associated with this dataset, for instance
+    /// to check whether the `OakIndexOptions` produced a reasonable graph.
+    fn index_stats(&self) -> Result<IndexStats, SearchableError>;
+
+    /// The (directed) edges of the graph of the index associated with this dataset at `level`,
+    /// where 0 is the base level that contains every vector, as pairs of the id of a vector and that
+    /// of one of its neighbors. There are none above the highest level (see `IndexStats::levels`).
+    fn graph_edges(&self, level: usize) -> Result<Vec<(usize, usize)>, SearchableError>;
+
+    /// The graph at `level` (see `graph_edges`) in the DOT format of GraphViz, to visualize the
+    /// structure of the graph over a small dataset, e.g. with `dot -Tsvg`.
+    fn export_graph(&self, level: usize) -> Result<String, SearchableError> {
+        let mut dot = format!("digraph level_{level} {{\n");
+        for (from, to) in self.graph_edges(level)? {
+            dot.push_str(&format!("    {from} -> {to};\n"));
+        }
+        dot.push_str("}\n");
+        Ok(dot)
+    }
+
+    /// The approximate bytes held by this dataset: the vectors it holds in memory, its metadata,
+    /// and its index (if it has been built), as estimated by `IndexStats::estimated_bytes`. A
+    /// dataset that borrows the vectors or index of another counts those too.
+    fn memory_usage(&self) -> usize {
+        let vectors = self
+            .vectors()
+            .map_or(0, |vectors| std::mem::size_of_val(vectors.data.as_slice()));
+        let metadata = std::mem::size_of_val(self.get_metadata().attrs.as_slice());
+        let index = self
+            .index_stats()
+            .map_or(0, |stats| stats.estimated_bytes());
+        vectors + metadata + index
+    }
+
+    /// Checks that this dataset is internally consistent, e.g. after it has been built, loaded or
+    /// merged, returning the first inconsistency found: that the vectors held in memory are a whole
+    /// number of vectors of `get_dimensionality`, one for each of `len`, that the metadata (if
+    /// there is any) holds a whole row of attributes for each vector, and that the index (if it
+    /// has been built) holds every vector.
+    fn verify(&self) -> Result<(), ConstructionError> {
+        let len = self.len();
+        let dimensionality = self.get_dimensionality();
+        if let Some(vectors) = self.vectors() {
+            if vectors.dimensionality != dimensionality {
+                return Err(ConstructionError::DimensionalityMismatch {
+                    expected: dimensionality,
+                    got: vectors.dimensionality,
+                });
+            }
+            let total = vectors.data.len();
+            if !total.is_multiple_of(dimensionality) {
+                return Err(ConstructionError::RaggedVectors {
+                    total,
+                    dimensionality,
+                });
+            }
+            let found = total.checked_div(dimensionality).unwrap_or(0);
+            if found != len {
+                return Err(ConstructionError::VectorCountMismatch {
+                    part: "vector buffer",
+                    len,
+                    found,
+                });
+            }
+        }
+
+        let metadata = self.get_metadata();
+        let ragged = !metadata
+            .attrs
+            .len()
+            .is_multiple_of(metadata.dimensionality())
+            || (!metadata.nulls.is_empty() && metadata.nulls.len() != metadata.attrs.len());
+        if ragged {
+            return Err(ConstructionError::RaggedAttributes {
+                total: metadata.attrs.len(),
+                dimensionality: metadata.dimensionality(),
+            });
+        }
+        check_metadata_len(len, metadata)?;
+
+        if self.is_indexed() {
+            let stats = self
+                .index_stats()
+                .map_err(|err| ConstructionError::CppError(err.to_string()))?;
+            if stats.nodes != len {
+                return Err(ConstructionError::VectorCountMismatch {
+                    part: "index",
+                    len,
+                    found: stats.nodes,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// The fraction of this dataset that matches `predicate_query`, computed from the metadata
+    /// alone (see `Bitmask::selectivity`). ACORN suggests building an index with a `gamma` of
+    /// about `1 / s`, where `s` is the lowest selectivity of the predicates it is expected to serve.
+    fn selectivity(&self, predicate_query: &PredicateQuery) -> Result<f32, SearchableError>
+    where
+        Self: Sized,
+    {
+        Ok(Bitmask::try_new(predicate_query, self)?.selectivity())
+    }
+
+    /// The number of vectors in this dataset that match `predicate_query`, computed from the
+    /// metadata alone without searching, e.g. to show how many results a filter can return before
+    /// it is searched with. Unlike `selectivity` this is exact.
+    fn count_matching(&self, predicate_query: &PredicateQuery) -> Result<usize, SearchableError>
+    where
+        Self: Sized,
+    {
+        Ok(Bitmask::try_new(predicate_query, self)?.bitcount())
+    }
+
     /// Build the index associated with this dataset. If an index has not been built, all search
     /// methods will throw an error.
+    ///
+    /// Unless `OakIndexOptions::seed` is set, ACORN inserts the vectors into the graph in parallel,
+    /// on as many OpenMP threads as `acorn::set_omp_threads` sets for the calling thread.
     fn initialize(&mut self, opts: &OakIndexOptions) -> Result<(), ConstructionError>;
 
+    /// Like `initialize`, but calls `progress` with the fraction of the vectors that have been
+    /// added to the index as it is built, ending with 1.0 once it is complete. Unless a dataset
+    /// builds its index in chunks, the only reports are 0.0 when the build starts and 1.0 when it
+    /// ends.
+    fn initialize_with_progress(
+        &mut self,
+        opts: &OakIndexOptions,
+        progress: &dyn Fn(f32),
+    ) -> Result<(), ConstructionError> {
+        progress(0.);
+        self.initialize(opts)?;
+        progress(1.);
+        Ok(())
+    }
+
+    /// Like `initialize`, but reports how long the index took to build and how large it is.
+    fn initialize_with_report(
+        &mut self,
+        opts: &OakIndexOptions,
+    ) -> Result<BuildReport, ConstructionError> {
+        let start = Instant::now();
+        self.initialize(opts)?;
+        let duration = start.elapsed();
+
+        let stats = self.index_stats().map_err(|err| match err {
+            SearchableError::DatasetIsNotIndexed => ConstructionError::DatasetIsNotIndexed,
+            err => ConstructionError::CppError(err.to_string()),
+        })?;
+        Ok(BuildReport {
+            duration,
+            num_vectors: stats.nodes,
+            num_edges: stats.edges,
+            peak_memory_estimate: stats.estimated_bytes(),
+        })
+    }
+
+    /// Free the index associated with this dataset, while keeping its vectors and metadata, e.g. to
+    /// build it again with different options. Until it is rebuilt, searches return
+    /// `SearchableError::DatasetIsNotIndexed`.
+    fn clear_index(&mut self);
+
+    /// Write the index associated with this dataset to `path`, so that it can later be restored
+    /// with `load_index` rather than rebuilt. The file records the dimensionality of the vectors
+    /// and the options that the index was built with.
+    fn save_index(&self, path: &Path) -> Result<(), ConstructionError>;
+
+    /// Restore an index previously written by `save_index` in place of calling `initialize`. An
+    /// error is returned if the saved index was built with different `opts`, or over vectors of a
+    /// different dimensionality or count to those in this dataset.
+    fn load_index(&mut self, path: &Path, opts: &OakIndexOptions) -> Result<(), ConstructionError>;
+
     /// Takes a Vec<Fvec> and returns a Vec<Vec<(usize, f32)>>, whereby each inner Vec<(usize, f32)> is an array
     /// of tuples in which t[0] is the index of the resthe `topk` vectors returned from the result.
+    /// The `efsearch` sets the breadth of the search (see `DEFAULT_EF_SEARCH`).
     fn search(
         &self,
         query_vectors: &FlattenedVecs,
         predicate_query: &Option<PredicateQuery>,
         topk: usize,
         efsearch: i64,
-    ) -> Result<Vec<TopKSearchResult>, SearchableError>;
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        Ok(self
+            .search_with_stats(query_vectors, predicate_query, topk, efsearch)?
+            .0)
+    }
+
+    /// Like `search`, but also returns the `SearchStats` of each query, in the same order as the
+    /// results.
+    fn search_with_stats(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+    ) -> Result<(Vec<TopKSearchResult>, Vec<SearchStats>), SearchableError>;
+
+    /// Like `search_with_stats`, but with the further `SearchParams` of the search. A dataset that
+    /// is not searched through an ACORN graph (such as a `FlatDataset`) ignores those that bound
+    /// or widen the search, but still honours `SearchParams::true_l2_distances`.
+    fn search_with_params(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+        params: &SearchParams,
+    ) -> Result<(Vec<TopKSearchResult>, Vec<SearchStats>), SearchableError> {
+        let (mut results, stats) =
+            self.search_with_stats(query_vectors, predicate_query, topk, efsearch)?;
+        params.report_distances(self.get_metric().unwrap_or_default(), &mut results);
+        Ok((results, stats))
+    }
+
+    /// Like `search`, but returns only the ids of the neighbors of each query, closest first, for
+    /// callers that have no use for the distances. Datasets backed by an ACORN index override this
+    /// to avoid building the (id, distance) tuples of the results.
+    fn search_ids(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+    ) -> Result<Vec<Vec<usize>>, SearchableError> {
+        Ok(self
+            .search(query_vectors, predicate_query, topk, efsearch)?
+            .into_iter()
+            .map(|neighbors| neighbors.into_iter().map(|(id, _)| id).collect())
+            .collect())
+    }
+
+    /// Like `search`, but with the ids of the results as u32s rather than usizes, which halves the
+    /// memory that the ids of a large batch of results take on a 64-bit platform. The queries are
+    /// searched as by `search_streaming`, so that only one chunk of results is ever held with
+    /// usize ids. A `SearchableError::TooManyVectorsForU32` is returned if the dataset has more
+    /// vectors than a u32 can number.
+    fn search_u32(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+    ) -> Result<Vec<Vec<(u32, f32)>>, SearchableError>
+    where
+        Self: Sized,
+    {
+        // NOTE: the ids run from 0 to `len - 1`, so `len` itself may be one more than u32::MAX.
+        if self.len() > u32::MAX as usize + 1 {
+            return Err(SearchableError::TooManyVectorsForU32(self.len()));
+        }
+        let mut results = Vec::with_capacity(query_vectors.len());
+        self.search_streaming(
+            query_vectors,
+            predicate_query,
+            topk,
+            efsearch,
+            |_, neighbors| {
+                results.push(
+                    neighbors
+                        .into_iter()
+                        .map(|(id, distance)| (id as u32, distance))
+                        .collect(),
+                );
+            },
+        )?;
+        Ok(results)
+    }
+
+    /// Like `search`, but with the results laid out as a `FlatSearchResults`, i.e. as flat arrays
+    /// of ids and distances with `topk` entries per query, padded as described there. The queries
+    /// are searched as by `search_streaming`, and each chunk of results is copied straight into
+    /// the flat arrays, which are allocated once up front.
+    fn search_flat(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+    ) -> Result<FlatSearchResults, SearchableError>
+    where
+        Self: Sized,
+    {
+        let len = query_vectors.len() * topk;
+        let mut flat = FlatSearchResults {
+            ids: vec![FLAT_RESULT_PADDING_ID; len],
+            distances: vec![f32::NAN; len],
+            topk,
+        };
+        self.search_streaming(
+            query_vectors,
+            predicate_query,
+            topk,
+            efsearch,
+            |query, neighbors| {
+                let start = query * topk;
+                for (rank, (id, distance)) in neighbors.into_iter().take(topk).enumerate() {
+                    flat.ids[start + rank] = id;
+                    flat.distances[start + rank] = distance;
+                }
+            },
+        )?;
+        Ok(flat)
+    }
+
+    /// Like `search`, but for a single query vector, returning its results directly rather than
+    /// as a batch of one.
+    fn search_one(
+        &self,
+        query: &Fvec,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+    ) -> Result<TopKSearchResult, SearchableError> {
+        let expected = self.get_dimensionality();
+        if query.dimensionality() != expected {
+            return Err(SearchableError::DimensionalityMismatch {
+                expected,
+                got: query.dimensionality(),
+            });
+        }
+
+        let mut results = self.search(&query.into(), predicate_query, topk, efsearch)?;
+        Ok(results.pop().unwrap_or_default())
+    }
+
+    /// The distance from `query` to its `k`th nearest neighbor (counting from 1) that matches the
+    /// `predicate_query`, as found by a search with `DEFAULT_EF_SEARCH`, e.g. to choose the radius
+    /// of a `range_search`. The distance is in the same units as those returned by `search`. A
+    /// `SearchableError::NotEnoughNeighbors` is returned if fewer than `k` neighbors are found
+    /// (and so always if `k` is 0).
+    fn kth_distance(
+        &self,
+        query: &Fvec,
+        k: usize,
+        predicate_query: &Option<PredicateQuery>,
+    ) -> Result<f32, SearchableError> {
+        // NOTE: faiss rejects a search for no neighbors.
+        if k == 0 {
+            return Err(SearchableError::NotEnoughNeighbors { k, found: 0 });
+        }
+        let neighbors = self.search_one(query, predicate_query, k, DEFAULT_EF_SEARCH)?;
+        match neighbors.get(k - 1) {
+            Some(&(_, distance)) => Ok(distance),
+            None => Err(SearchableError::NotEnoughNeighbors {
+                k,
+                found: neighbors.len(),
+            }),
+        }
+    }
 
     /// Takes a Vec<Fvec> and returns a Vec<Vec<(usize, f32)>>, whereby each inner Vec<(usize, f32)> is an array
     /// of tuples in which t[0] is the index of the resthe `topk` vectors returned from the result.
+    /// The `efsearch` sets the breadth of the search (see `DEFAULT_EF_SEARCH`).
     fn search_with_bitmask(
         &self,
         query_vectors: &FlattenedVecs,
@@ -137,4 +1725,719 @@ pub trait SimilaritySearchable {
         topk: usize,
         efsearch: i64,
     ) -> Result<Vec<TopKSearchResult>, SearchableError>;
+
+    /// Like `search_with_bitmask`, but the filter is given as a raw `filter_id_map`, as ACORN
+    /// itself takes it, e.g. when it has been computed outside of OAK. The map holds one byte per
+    /// vector in the dataset, in index order: the vector at `i` may be returned if
+    /// `filter_id_map[i]` is nonzero, and is skipped if it is zero. The same map is used for every
+    /// query. No predicate is evaluated; the map is only copied once into a `Bitmask`.
+    ///
+    /// A `FilterMapLengthMismatch` is returned if the map is not of the dataset's length.
+    fn search_with_filter_map(
+        &self,
+        query_vectors: &FlattenedVecs,
+        filter_id_map: &[u8],
+        topk: usize,
+        efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        if filter_id_map.len() != self.len() {
+            return Err(SearchableError::FilterMapLengthMismatch {
+                expected: self.len(),
+                got: filter_id_map.len(),
+            });
+        }
+
+        let map: Vec<i8> = filter_id_map
+            .iter()
+            .map(|&byte| (byte != 0) as i8)
+            .collect();
+        let bitcount = map.iter().filter(|&&bit| bit == 1).count();
+        self.search_with_bitmask(query_vectors, &Bitmask { map, bitcount }, topk, efsearch)
+    }
+
+    /// Like `search`, but each query is searched with its own predicate: `predicates[i]` filters
+    /// the results of query `i`. As a filter must be built over the whole dataset for each query,
+    /// queries that share a predicate are better searched together with `search`.
+    fn search_per_query(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicates: &[Option<PredicateQuery>],
+        topk: usize,
+        efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError>
+    where
+        Self: Sized + Sync,
+    {
+        if predicates.len() != query_vectors.len() {
+            return Err(SearchableError::PredicateCountMismatch {
+                queries: query_vectors.len(),
+                predicates: predicates.len(),
+            });
+        }
+
+        predicates
+            .par_iter()
+            .enumerate()
+            .map(|(i, predicate)| {
+                let bitmask = match predicate {
+                    None => Bitmask::new_full(self),
+                    Some(pq) => Bitmask::try_new(pq, self)?,
+                };
+                let dimensionality = query_vectors.dimensionality;
+                let query = FlattenedVecs {
+                    dimensionality,
+                    data: query_vectors.data[i * dimensionality..(i + 1) * dimensionality].to_vec(),
+                };
+                let mut results = self.search_with_bitmask(&query, &bitmask, topk, efsearch)?;
+                Ok(results.remove(0))
+            })
+            .collect()
+    }
+
+    /// Like `search`, but each result also carries the first attribute of the vector that was
+    /// found, as looked up in `get_metadata`, or None if it is null. A
+    /// `SearchableError::MissingMetadata` is returned if the dataset has no attributes to look up,
+    /// as when it is loaded without its CSV file.
+    fn search_with_attrs(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+    ) -> Result<Vec<Vec<AttributedSearchResult>>, SearchableError> {
+        let metadata = self.get_metadata();
+        if metadata.is_empty() && !self.is_empty() {
+            return Err(SearchableError::MissingMetadata);
+        }
+        let results = self.search(query_vectors, predicate_query, topk, efsearch)?;
+        Ok(results
+            .into_iter()
+            .map(|result| {
+                result
+                    .into_iter()
+                    .map(|(id, distance)| {
+                        let attr = (!metadata.is_null(id, 0)).then(|| metadata.attrs_for(id)[0]);
+                        (id, distance, attr)
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// Like `search`, but fetches `topk * rerank_factor` candidates from the index, and then returns
+    /// the `topk` of those that are closest by the exact distance to the original vectors. This
+    /// trades latency for recall, as with a higher `efsearch`, and will also correct for any error
+    /// in the distances of an index that does not store the vectors exactly.
+    ///
+    /// If the dataset does not hold its `vectors` in memory, there is nothing to re-rank against, and
+    /// so the closest `topk` candidates according to the index are returned instead.
+    fn search_rerank(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        rerank_factor: usize,
+        efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        let candidates = self.search(
+            query_vectors,
+            predicate_query,
+            topk * rerank_factor.max(1),
+            efsearch,
+        )?;
+        let Some(vectors) = self.vectors() else {
+            return Ok(candidates
+                .into_iter()
+                .map(|mut candidates| {
+                    candidates.truncate(topk);
+                    candidates
+                })
+                .collect());
+        };
+
+        let metric = self.get_metric().unwrap_or_default();
+        Ok(rerank(vectors, query_vectors, candidates, topk, metric))
+    }
+
+    /// Like `search_rerank`, but re-ranks the candidates by a custom `distance` (see
+    /// `metrics::Distance`), in whose units the distances of the results are. The candidates are
+    /// still found through the index, by its own metric, so `distance` should broadly agree with
+    /// it for the closest by `distance` to be among them.
+    ///
+    /// There is nothing to re-rank against for a dataset that does not hold its `vectors` in
+    /// memory, so `SearchableError::VectorsNotInMemory` is returned for one.
+    fn search_rerank_with(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        rerank_factor: usize,
+        efsearch: i64,
+        distance: &dyn Distance,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        let vectors = self.vectors().ok_or(SearchableError::VectorsNotInMemory)?;
+        let candidates = self.search(
+            query_vectors,
+            predicate_query,
+            topk * rerank_factor.max(1),
+            efsearch,
+        )?;
+        Ok(rerank_with(
+            vectors,
+            query_vectors,
+            candidates,
+            topk,
+            distance,
+        ))
+    }
+
+    /// Like `search`, but re-ranks the `topk` results of each query by their exact distances under
+    /// `metric`, rather than under the metric that the index was built with. The results are the
+    /// same vectors, ordered closest first by `metric` and with distances in its units, e.g. to
+    /// compare how the neighbors of a query rank by L2 and by inner product without building a
+    /// second index.
+    ///
+    /// The distances are computed against the `vectors` that the dataset holds in memory, so
+    /// `SearchableError::VectorsNotInMemory` is returned for a dataset that does not.
+    fn search_with_metric_override(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+        metric: DistanceMetric,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        let vectors = self.vectors().ok_or(SearchableError::VectorsNotInMemory)?;
+        let candidates = self.search(query_vectors, predicate_query, topk, efsearch)?;
+        Ok(rerank(vectors, query_vectors, candidates, topk, metric))
+    }
+
+    /// Runs `num_queries` throwaway searches for random vectors, to fault the index into memory (and
+    /// warm the caches) before searches are timed, as cold searches are otherwise much slower.
+    fn warmup(&self, num_queries: usize) -> Result<(), SearchableError> {
+        let dimensionality = self.get_dimensionality();
+        let queries = FlattenedVecs {
+            dimensionality,
+            data: generate_random_vector(dimensionality * num_queries),
+        };
+        // NOTE: the results are discarded, so the topk is only large enough to be realistic.
+        self.search(&queries, &None, 10, DEFAULT_EF_SEARCH)?;
+        Ok(())
+    }
+
+    /// Selects `n` distinct vectors of this dataset at random (or every vector, if it has fewer
+    /// than `n`) to be used as queries, which makes for far more realistic benchmarks than random
+    /// vectors. The same `seed` always selects the same vectors. If `jitter` is given, each
+    /// component of the queries is perturbed by up to that much in either direction, so that they
+    /// are close to vectors in the dataset rather than exactly equal to them.
+    ///
+    /// The vectors are copied from `vectors` if the dataset holds them in memory, and are
+    /// otherwise reconstructed from the index (see `reconstruct`).
+    fn sample_queries(
+        &self,
+        n: usize,
+        seed: u64,
+        jitter: Option<f32>,
+    ) -> Result<FlattenedVecs, SearchableError> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let ids = rand::seq::index::sample(&mut rng, self.len(), n.min(self.len())).into_vec();
+
+        let mut queries = match self.vectors() {
+            Some(vectors) => {
+                let dimensionality = vectors.dimensionality;
+                FlattenedVecs {
+                    dimensionality,
+                    data: ids
+                        .iter()
+                        .flat_map(|&id| {
+                            &vectors.data[id * dimensionality..(id + 1) * dimensionality]
+                        })
+                        .copied()
+                        .collect(),
+                }
+            }
+            None => self.reconstruct(&ids)?,
+        };
+        if let Some(jitter) = jitter.filter(|&jitter| jitter > 0.) {
+            for x in queries.data.iter_mut() {
+                *x += rng.gen_range(-jitter..=jitter);
+            }
+        }
+        Ok(queries)
+    }
+
+    /// Times `iterations` passes of searching each of the `query_vectors` on its own, after warming
+    /// up the index (see `warmup`), to give a quick snapshot of search performance. Only the
+    /// searches themselves are timed: the filter of the `predicate_query` is built once, up front.
+    fn benchmark_search(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+        iterations: usize,
+    ) -> Result<LatencyReport, SearchableError>
+    where
+        Self: Sized,
+    {
+        let bitmask = match predicate_query {
+            None => Bitmask::new_full(self),
+            Some(pq) => Bitmask::try_new(pq, self)?,
+        };
+        let queries: Vec<_> = query_vectors
+            .data
+            .chunks_exact(query_vectors.dimensionality)
+            .map(|query| FlattenedVecs {
+                dimensionality: query_vectors.dimensionality,
+                data: query.to_vec(),
+            })
+            .collect();
+        self.warmup(queries.len())?;
+
+        let mut latencies = Vec::with_capacity(iterations * queries.len());
+        for _ in 0..iterations {
+            for query in &queries {
+                let start = Instant::now();
+                self.search_with_bitmask(query, &bitmask, topk, efsearch)?;
+                latencies.push(start.elapsed());
+            }
+        }
+        Ok(LatencyReport::from_latencies(latencies))
+    }
+
+    /// Like `search`, but also estimates the recall of the results, e.g. to monitor it as a
+    /// service runs. Each query is sampled with probability `sample_rate` (drawn from `seed`), and
+    /// the exact neighbors of the sampled queries among the vectors that match the
+    /// `predicate_query` are found by brute force, to compare their results against. The vectors
+    /// must be held in memory (see `vectors`), or a `SearchableError::VectorsNotInMemory` is
+    /// returned.
+    fn search_with_recall(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+        sample_rate: f32,
+        seed: u64,
+    ) -> Result<(TopKSearchResultBatch, RecallEstimate), SearchableError>
+    where
+        Self: Sized,
+    {
+        let metric = self
+            .get_metric()
+            .ok_or(SearchableError::DatasetIsNotIndexed)?;
+        let vectors = self.vectors().ok_or(SearchableError::VectorsNotInMemory)?;
+        let bitmask = self.search_mask(predicate_query)?;
+        let results = self.search_with_bitmask(query_vectors, &bitmask, topk, efsearch)?;
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let sampled: Vec<usize> = (0..results.len())
+            .filter(|_| rng.gen::<f32>() < sample_rate)
+            .collect();
+        if sampled.is_empty() {
+            return Ok((results, RecallEstimate::default()));
+        }
+
+        let sampled_queries = FlattenedVecs {
+            dimensionality: query_vectors.dimensionality,
+            data: sampled
+                .iter()
+                .flat_map(|&i| query_vectors.get(i).unwrap_or_default())
+                .copied()
+                .collect(),
+        };
+        let matching: TopKSearchResult = (0..bitmask.capacity())
+            .filter(|&id| bitmask.map[id] == 1)
+            .map(|id| (id, 0.))
+            .collect();
+        let exact = rerank(
+            vectors,
+            &sampled_queries,
+            vec![matching; sampled.len()],
+            topk,
+            metric,
+        );
+        let ground_truth: Vec<Vec<usize>> = exact
+            .into_iter()
+            .map(|neighbors| neighbors.into_iter().map(|(id, _)| id).collect())
+            .collect();
+        let approximate: TopKSearchResultBatch =
+            sampled.iter().map(|&i| results[i].clone()).collect();
+
+        let estimate = RecallEstimate {
+            sampled: sampled.len(),
+            recall: Some(recall_at_k(&approximate, &ground_truth, topk)),
+        };
+        Ok((results, estimate))
+    }
+
+    /// Like `search`, but rather than returning the results for every query at once, passes the
+    /// results of each query to `f` along with the index of that query in `query_vectors`, in
+    /// order. The queries are searched `STREAMING_CHUNK_SIZE` at a time, so that only the results
+    /// of one chunk are held at once. If a chunk fails, its error is returned and no further
+    /// results are passed to `f`.
+    fn search_streaming(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+        mut f: impl FnMut(usize, TopKSearchResult),
+    ) -> Result<(), SearchableError>
+    where
+        Self: Sized,
+    {
+        // NOTE: the bitmask is only built once, rather than once per chunk as `search` would.
+        let bitmask = match predicate_query {
+            Some(pq) => Bitmask::try_new(pq, self)?,
+            None => Bitmask::new_full(self),
+        };
+
+        let chunk_len = STREAMING_CHUNK_SIZE * query_vectors.dimensionality.max(1);
+        let mut offset = 0;
+        for chunk in query_vectors.data.chunks(chunk_len) {
+            let chunk = FlattenedVecs {
+                dimensionality: query_vectors.dimensionality,
+                data: chunk.to_vec(),
+            };
+            let results = self.search_with_bitmask(&chunk, &bitmask, topk, efsearch)?;
+            for result in results {
+                f(offset, result);
+                offset += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `search_streaming`, but rather than passing the results to a callback, returns an
+    /// iterator over them, so that the caller drives the search: each chunk of queries is only
+    /// searched once the results of the last have been taken. The results are yielded in the
+    /// order of the queries. An error is returned straight away if the predicate is invalid for
+    /// the dataset, and otherwise the errors of the searches are yielded by the iterator.
+    fn search_iter<'a>(
+        &'a self,
+        query_vectors: &'a FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+    ) -> Result<SearchIter<'a, Self>, SearchableError>
+    where
+        Self: Sized,
+    {
+        let bitmask = match predicate_query {
+            Some(pq) => Bitmask::try_new(pq, self)?,
+            None => Bitmask::new_full(self),
+        };
+        Ok(SearchIter {
+            dataset: self,
+            query_vectors,
+            bitmask,
+            topk,
+            efsearch,
+            next_query: 0,
+            chunk: Vec::new().into_iter(),
+            failed: false,
+        })
+    }
+
+    /// Like `search`, but can be abandoned part way through by setting `cancel`, e.g. from another
+    /// thread that holds it in an `Arc` once the client that asked for the search has gone away.
+    /// The queries are searched `STREAMING_CHUNK_SIZE` at a time, and `cancel` is checked before
+    /// each chunk; if it is set, `SearchableError::Cancelled` is returned and the results of any
+    /// chunks already searched are discarded. A chunk that has started is always finished.
+    fn search_cancellable(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+        cancel: &AtomicBool,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError>
+    where
+        Self: Sized,
+    {
+        let bitmask = match predicate_query {
+            Some(pq) => Bitmask::try_new(pq, self)?,
+            None => Bitmask::new_full(self),
+        };
+
+        let chunk_len = STREAMING_CHUNK_SIZE * query_vectors.dimensionality.max(1);
+        let mut results = Vec::with_capacity(query_vectors.len());
+        for chunk in query_vectors.data.chunks(chunk_len) {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(SearchableError::Cancelled);
+            }
+            let chunk = FlattenedVecs {
+                dimensionality: query_vectors.dimensionality,
+                data: chunk.to_vec(),
+            };
+            results.extend(self.search_with_bitmask(&chunk, &bitmask, topk, efsearch)?);
+        }
+        Ok(results)
+    }
+
+    /// Like `search`, but rather than the `topk` closest vectors, returns every vector that lies
+    /// within `radius` of the query: a distance less than `radius` for `L2` and `L1`, or a
+    /// similarity greater than it for `InnerProduct` and `Cosine`. The distances are in the same
+    /// units as those returned by `search`, and as such the inner Vecs may each be of a different
+    /// length. Only vectors that also match the `predicate_query` are returned, e.g. to find every
+    /// item within a distance of the query that is in a given category.
+    fn range_search(
+        &self,
+        query_vectors: &FlattenedVecs,
+        radius: f32,
+        predicate_query: &Option<PredicateQuery>,
+        efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_iterators() {
+        let metadata: HybridSearchMetadata = (1..=4).collect();
+        assert_eq!(metadata.dimensionality(), 1);
+        assert_eq!(metadata.len(), 4);
+        assert_eq!(metadata.attrs_for(2), &[3]);
+        assert!(!metadata.is_empty());
+
+        assert_eq!((&metadata).into_iter().sum::<i64>(), 10);
+        assert_eq!(metadata.into_iter().collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+
+        let empty: HybridSearchMetadata = std::iter::empty().collect();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_latency_report() {
+        let report =
+            LatencyReport::from_latencies((1..=100).rev().map(Duration::from_millis).collect());
+        assert_eq!(report.searches, 100);
+        assert_eq!(report.mean, Duration::from_micros(50_500));
+        assert_eq!(report.p50, Duration::from_millis(50));
+        assert_eq!(report.p95, Duration::from_millis(95));
+        assert_eq!(report.p99, Duration::from_millis(99));
+        assert!((report.qps - 100. / 5.05).abs() < 1e-9);
+
+        let single = LatencyReport::from_latencies(vec![Duration::from_millis(3)]);
+        assert_eq!(single.p50, Duration::from_millis(3));
+        assert_eq!(single.p99, Duration::from_millis(3));
+        assert_eq!(
+            LatencyReport::from_latencies(vec![]),
+            LatencyReport::default()
+        );
+    }
+
+    #[test]
+    fn test_scaled_efsearch() {
+        let quarter: Vec<c_char> = (0..100).map(|i| (i % 4 == 0) as c_char).collect();
+        assert_eq!(SearchParams::default().scaled_efsearch(16, &quarter), 16);
+
+        let params = SearchParams {
+            scale_efsearch_by_selectivity: Some(1000),
+            ..Default::default()
+        };
+        assert_eq!(params.scaled_efsearch(16, &quarter), 64);
+        assert_eq!(params.scaled_efsearch(16, &[1; 100]), 16);
+        assert_eq!(params.scaled_efsearch(16, &[0; 100]), 16);
+
+        // The cap bounds the scaling, but never lowers the efsearch that was asked for.
+        let capped = SearchParams {
+            scale_efsearch_by_selectivity: Some(40),
+            ..Default::default()
+        };
+        assert_eq!(capped.scaled_efsearch(16, &quarter), 40);
+        assert_eq!(capped.scaled_efsearch(64, &quarter), 64);
+    }
+
+    #[test]
+    fn test_attribute_histogram() {
+        let metadata = HybridSearchMetadata::new(2, vec![1, 10, 2, 10, 1, 30]);
+        assert_eq!(
+            metadata.attribute_histogram(0),
+            HashMap::from([(1, 2), (2, 1)])
+        );
+        assert_eq!(
+            metadata.attribute_histogram(1),
+            HashMap::from([(10, 2), (30, 1)])
+        );
+        assert!(HybridSearchMetadata::new(1, vec![])
+            .attribute_histogram(0)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_from_strings() {
+        let (metadata, mapping) = HybridSearchMetadata::from_strings(["cat", "dog", "cat"]);
+        assert_eq!(metadata.len(), 3);
+        assert_eq!(mapping.len(), 2);
+        assert_eq!(metadata.attrs_for(0), metadata.attrs_for(2));
+        assert_ne!(metadata.attrs_for(0), metadata.attrs_for(1));
+        assert_eq!(metadata.attrs_for(1), &[mapping["dog"]]);
+
+        // The hashes must never change, as they may have been persisted.
+        assert_eq!(hash_label(""), 0xcbf29ce484222325u64 as i64);
+        assert_eq!(hash_label("a"), 0xaf63dc4c8601ec8cu64 as i64);
+
+        let cats = Bitmask::from_predicate(&metadata, |attr| attr == mapping["cat"]);
+        assert_eq!(cats.map, vec![1, 0, 1]);
+    }
+
+    #[test]
+    fn test_options_builder() {
+        let options = OakIndexOptions::builder()
+            .m(16)
+            .gamma(2)
+            .m_beta(32)
+            .metric(DistanceMetric::Cosine)
+            .build()
+            .unwrap();
+        assert_eq!((options.m, options.gamma, options.m_beta), (16, 2, 32));
+        assert_eq!(options.metric, DistanceMetric::Cosine);
+
+        assert_eq!(options.acorn_variant(), AcornVariant::AcornGamma);
+
+        let options = OakIndexOptions::builder().build().unwrap();
+        assert_eq!(options.acorn_variant(), AcornVariant::Acorn1);
+        let options = OakIndexOptions::builder()
+            .variant(AcornVariant::AcornGamma)
+            .gamma(4)
+            .build()
+            .unwrap();
+        assert_eq!(options.acorn_variant(), AcornVariant::AcornGamma);
+        for invalid in [
+            OakIndexOptions::builder().gamma(0),
+            OakIndexOptions::builder().m_beta(16),
+            OakIndexOptions::builder().m(16).m_beta(64),
+            OakIndexOptions::builder()
+                .variant(AcornVariant::Acorn1)
+                .gamma(4),
+            OakIndexOptions::builder().variant(AcornVariant::AcornGamma),
+        ] {
+            assert!(matches!(
+                invalid.build(),
+                Err(ConstructionError::InvalidOptions(_))
+            ));
+        }
+    }
+
+    #[cfg(feature = "serialize")]
+    #[test]
+    fn test_options_serde() {
+        let options = OakIndexOptions::builder()
+            .gamma(2)
+            .metric(DistanceMetric::Cosine)
+            .seed(7)
+            .build()
+            .unwrap();
+        let json = serde_json::to_string(&options).unwrap();
+        assert_eq!(
+            serde_json::from_str::<OakIndexOptions>(&json).unwrap(),
+            options
+        );
+
+        // Missing fields take their defaults.
+        let partial: OakIndexOptions = serde_json::from_str(r#"{"m": 16, "m_beta": 32}"#).unwrap();
+        assert_eq!(
+            partial,
+            OakIndexOptions {
+                m: 16,
+                m_beta: 32,
+                ..OakIndexOptions::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_format_results() {
+        let results = vec![vec![(3, 0.5), (12, 1.25)], vec![]];
+        let metadata = HybridSearchMetadata::new(1, (0..13).collect());
+
+        let formatted = format_results(&results, Some(&metadata));
+        let expected = "\
+Query 0:
+  rank  id  distance  attrs
+     1   3    0.5000  [3]
+     2  12    1.2500  [12]
+Query 1:
+  (no results)
+";
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn test_new_from_bitmask_filters_rows() {
+        let metadata = HybridSearchMetadata::new(2, vec![1, 10, 2, 20, 3, 30]);
+        let mask = Bitmask::from(vec![1i8, 0, 1]);
+
+        let filtered = HybridSearchMetadata::new_from_bitmask(&metadata, &mask);
+
+        assert_eq!(filtered.len(), 2);
+        assert_eq!(filtered.dimensionality(), 2);
+        assert_eq!(filtered.attrs_for(0), &[1, 10]);
+        assert_eq!(filtered.attrs_for(1), &[3, 30]);
+        assert_eq!(filtered.column(1), vec![10, 30]);
+    }
+
+    #[test]
+    fn test_save_and_load_metadata() {
+        let path = std::env::temp_dir().join("oak_test_metadata.bin");
+        let metadata = HybridSearchMetadata::new(2, vec![1, -10, i64::MAX, 20, 3, i64::MIN]);
+        metadata.save(&path).unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len() as usize,
+            METADATA_HEADER_LEN + 6 * 8
+        );
+        let loaded = HybridSearchMetadata::load(&path).unwrap();
+        assert_eq!(loaded.dimensionality(), 2);
+        assert_eq!(loaded.attrs, metadata.attrs);
+        assert!(loaded.nulls.is_empty());
+
+        let nullable = HybridSearchMetadata::from_nullable(1, vec![Some(4), None, Some(6)]);
+        nullable.save(&path).unwrap();
+        let loaded = HybridSearchMetadata::load(&path).unwrap();
+        assert_eq!(loaded.attrs, vec![4, 0, 6]);
+        assert_eq!(loaded.nulls, vec![false, true, false]);
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.pop();
+        std::fs::write(&path, &bytes).unwrap();
+        let err = HybridSearchMetadata::load(&path).err().unwrap();
+        assert!(err.to_string().contains("for each of 3 vectors"), "{err}");
+
+        bytes[METADATA_MAGIC.len()] = METADATA_VERSION + 1;
+        std::fs::write(&path, &bytes).unwrap();
+        let err = HybridSearchMetadata::load(&path).err().unwrap();
+        assert!(err.to_string().contains("version"));
+
+        std::fs::write(&path, b"OAKV").unwrap();
+        assert!(HybridSearchMetadata::load(&path).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_nullable_metadata_keeps_nulls() {
+        let metadata =
+            HybridSearchMetadata::from_nullable(2, vec![Some(1), None, Some(2), Some(20)]);
+        let filtered =
+            HybridSearchMetadata::new_from_bitmask(&metadata, &Bitmask::from(vec![1i8, 0]));
+        assert!(filtered.is_null(0, 1));
+
+        let mut appended = HybridSearchMetadata::new(2, vec![3, 30]);
+        appended.append(&metadata);
+        appended.extend_from_slice(&[4, 40]);
+        assert_eq!(appended.column(1), vec![30, 0, 20, 40]);
+        assert_eq!(
+            (0..appended.len())
+                .filter(|&i| appended.is_null(i, 1))
+                .collect::<Vec<_>>(),
+            vec![1]
+        );
+
+        appended.set_attrs_for(1, &[5, 50]).unwrap();
+        assert!(!appended.is_null(1, 1));
+    }
 }
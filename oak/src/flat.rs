@@ -0,0 +1,472 @@
+use crate::bitmask::Bitmask;
+use crate::dataset::{
+    ConstructionError, DistanceMetric, HybridSearchMetadata, IndexStats, OakIndexOptions,
+    SearchStats, SearchableError, SimilaritySearchable, TopKSearchResult,
+};
+use crate::fvecs::{check_metadata_len, FlattenedVecs};
+use crate::metrics::{exact_distance, sort_closest_first};
+use crate::predicate::PredicateQuery;
+
+use rayon::prelude::*;
+use std::path::Path;
+
+/// A dataset that is searched exhaustively rather than through an ACORN index, so that its results
+/// are exact. It shares the interface of the indexed datasets, and so can stand in for them over
+/// small datasets, or serve as a baseline to check their results against.
+///
+/// `initialize` only records how the dataset is searched, which is its `DistanceMetric` and whether
+/// non-finite queries are sanitized (the other `OakIndexOptions` do not apply), and `efsearch` is
+/// ignored.
+pub struct FlatDataset {
+    metric: Option<DistanceMetric>,
+    sanitize_non_finite_queries: bool,
+    /// The norm of each vector, which is only needed for `DistanceMetric::Cosine`.
+    norms: Vec<f32>,
+    pub metadata: HybridSearchMetadata,
+    pub flat: FlattenedVecs,
+}
+
+impl FlatDataset {
+    /// An error is returned if `flat` is not a whole number of vectors; see
+    /// `FlattenedVecs::check_layout`.
+    pub fn new(
+        flat: FlattenedVecs,
+        metadata: HybridSearchMetadata,
+    ) -> Result<Self, ConstructionError> {
+        flat.check_layout()?;
+        Ok(Self {
+            metric: None,
+            sanitize_non_finite_queries: false,
+            norms: vec![],
+            metadata,
+            flat,
+        })
+    }
+
+    fn indexed_metric(&self) -> Result<DistanceMetric, SearchableError> {
+        self.metric.ok_or(SearchableError::DatasetIsNotIndexed)
+    }
+
+    /// Checks the queries as an index would, returning the copy of them that should be searched
+    /// instead if they must be sanitized or normalized.
+    fn prepare_queries(
+        &self,
+        metric: DistanceMetric,
+        query_vectors: &FlattenedVecs,
+    ) -> Result<Option<FlattenedVecs>, SearchableError> {
+        if query_vectors.dimensionality != self.flat.dimensionality {
+            return Err(SearchableError::DimensionalityMismatch {
+                expected: self.flat.dimensionality,
+                got: query_vectors.dimensionality,
+            });
+        }
+
+        let mut prepared = None;
+        if let Some((query_index, component)) = query_vectors.find_non_finite() {
+            if !self.sanitize_non_finite_queries {
+                return Err(SearchableError::NonFiniteQuery {
+                    query_index,
+                    component,
+                });
+            }
+            let mut sanitized = FlattenedVecs {
+                dimensionality: query_vectors.dimensionality,
+                data: query_vectors.data.clone(),
+            };
+            sanitized.sanitize_non_finite();
+            prepared = Some(sanitized);
+        }
+
+        // As for an index, cosine similarity is the inner product of the normalized vectors.
+        if metric == DistanceMetric::Cosine {
+            prepared = Some(match prepared {
+                Some(mut sanitized) => {
+                    sanitized.normalize_all();
+                    sanitized
+                }
+                None => query_vectors.to_normalized(),
+            });
+        }
+        Ok(prepared)
+    }
+
+    fn mask(&self, predicate_query: &Option<PredicateQuery>) -> Result<Bitmask, SearchableError> {
+        Ok(match predicate_query {
+            None => Bitmask::new_full(self),
+            Some(pq) => Bitmask::try_new(pq, self)?,
+        })
+    }
+
+    /// Computes the distance from each query to every vector that passes `filter_id_map`, returning
+    /// them closest first. As for an index, a `SearchableError::FilterMapLengthMismatch` is
+    /// returned if `filter_id_map` does not have an entry for each vector.
+    fn scan(
+        &self,
+        metric: DistanceMetric,
+        query_vectors: &FlattenedVecs,
+        filter_id_map: &[i8],
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        if filter_id_map.len() != self.flat.len() {
+            return Err(SearchableError::FilterMapLengthMismatch {
+                expected: self.flat.len(),
+                got: filter_id_map.len(),
+            });
+        }
+        let prepared = self.prepare_queries(metric, query_vectors)?;
+        let query_vectors = prepared.as_ref().unwrap_or(query_vectors);
+
+        Ok(query_vectors
+            .data
+            .par_chunks_exact(query_vectors.dimensionality)
+            .map(|query| {
+                let mut neighbors: TopKSearchResult = self
+                    .flat
+                    .data
+                    .chunks_exact(self.flat.dimensionality)
+                    .enumerate()
+                    .filter(|(id, _)| filter_id_map[*id] != 0)
+                    .map(|(id, vector)| {
+                        let distance = exact_distance(metric, query, vector);
+                        match metric {
+                            DistanceMetric::Cosine if self.norms[id] > 0. => {
+                                (id, distance / self.norms[id])
+                            }
+                            _ => (id, distance),
+                        }
+                    })
+                    .collect();
+                sort_closest_first(metric, &mut neighbors);
+                neighbors
+            })
+            .collect())
+    }
+}
+
+impl std::ops::Index<usize> for FlatDataset {
+    type Output = [f32];
+
+    /// The vector with id `idx`, e.g. `dataset[idx]`, as for `SimilaritySearchable::get_vector`.
+    ///
+    /// # Panics
+    /// - Panics if `idx` is out of range. `get_vector` returns None instead.
+    fn index(&self, idx: usize) -> &[f32] {
+        self.flat.get(idx).unwrap_or_else(|| {
+            panic!(
+                "index {idx} is out of range for {} vectors",
+                self.flat.len()
+            )
+        })
+    }
+}
+
+impl SimilaritySearchable for FlatDataset {
+    fn len(&self) -> usize {
+        self.flat.len()
+    }
+
+    fn get_dimensionality(&self) -> usize {
+        self.flat.dimensionality
+    }
+
+    fn get_metadata(&self) -> &HybridSearchMetadata {
+        &self.metadata
+    }
+
+    fn get_metric(&self) -> Option<DistanceMetric> {
+        self.metric
+    }
+
+    fn vectors(&self) -> Option<&FlattenedVecs> {
+        Some(&self.flat)
+    }
+
+    fn reconstruct(&self, ids: &[usize]) -> Result<FlattenedVecs, SearchableError> {
+        let metric = self.indexed_metric()?;
+
+        let mut data = Vec::with_capacity(ids.len() * self.flat.dimensionality);
+        for &id in ids {
+            let vector = self.flat.get(id).ok_or(SearchableError::IdOutOfRange {
+                id,
+                len: self.len(),
+            })?;
+            data.extend_from_slice(vector);
+        }
+
+        let vectors = FlattenedVecs {
+            dimensionality: self.flat.dimensionality,
+            data,
+        };
+        Ok(match metric {
+            DistanceMetric::Cosine => vectors.to_normalized(),
+            _ => vectors,
+        })
+    }
+
+    /// A flat dataset has no graph, so its stats report every vector as a node without any edges
+    /// or levels. The only bytes it holds besides the vectors are their norms, for
+    /// `DistanceMetric::Cosine`.
+    fn index_stats(&self) -> Result<IndexStats, SearchableError> {
+        self.indexed_metric()?;
+        Ok(IndexStats {
+            nodes: self.len(),
+            edges: 0,
+            min_out_degree: 0,
+            max_out_degree: 0,
+            mean_out_degree: 0.,
+            levels: 0,
+            bytes_per_vector: if self.norms.is_empty() {
+                0
+            } else {
+                std::mem::size_of::<f32>()
+            },
+        })
+    }
+
+    /// A flat dataset has no graph, and so no edges at any level.
+    fn graph_edges(&self, _level: usize) -> Result<Vec<(usize, usize)>, SearchableError> {
+        self.indexed_metric()?;
+        Ok(vec![])
+    }
+
+    fn initialize(&mut self, opts: &OakIndexOptions) -> Result<(), ConstructionError> {
+        check_metadata_len(self.len(), &self.metadata)?;
+        self.norms = match opts.metric {
+            DistanceMetric::Cosine => self
+                .flat
+                .data
+                .chunks_exact(self.flat.dimensionality)
+                .map(|vector| vector.iter().map(|x| x * x).sum::<f32>().sqrt())
+                .collect(),
+            _ => vec![],
+        };
+        self.metric = Some(opts.metric);
+        self.sanitize_non_finite_queries = opts.sanitize_non_finite_queries;
+        Ok(())
+    }
+
+    fn clear_index(&mut self) {
+        self.metric = None;
+        self.norms = vec![];
+    }
+
+    fn save_index(&self, _path: &Path) -> Result<(), ConstructionError> {
+        Err(ConstructionError::IndexNotOwned)
+    }
+
+    fn load_index(
+        &mut self,
+        _path: &Path,
+        _opts: &OakIndexOptions,
+    ) -> Result<(), ConstructionError> {
+        Err(ConstructionError::IndexNotOwned)
+    }
+
+    fn search_with_stats(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+    ) -> Result<(Vec<TopKSearchResult>, Vec<SearchStats>), SearchableError> {
+        self.indexed_metric()?;
+        let mask = self.mask(predicate_query)?;
+        let results = self.search_with_bitmask(query_vectors, &mask, topk, efsearch)?;
+
+        // NOTE: every vector that passes the predicate is compared with every query.
+        let stats = vec![
+            SearchStats {
+                ndis: mask.count_ones()
+            };
+            results.len()
+        ];
+        Ok((results, stats))
+    }
+
+    fn search_with_bitmask(
+        &self,
+        query_vectors: &FlattenedVecs,
+        bitmask: &Bitmask,
+        topk: usize,
+        _efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        let metric = self.indexed_metric()?;
+        let mut results = self.scan(metric, query_vectors, &bitmask.map)?;
+        results
+            .iter_mut()
+            .for_each(|neighbors| neighbors.truncate(topk));
+        Ok(results)
+    }
+
+    fn range_search(
+        &self,
+        query_vectors: &FlattenedVecs,
+        radius: f32,
+        predicate_query: &Option<PredicateQuery>,
+        _efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        let metric = self.indexed_metric()?;
+        let mask = self.mask(predicate_query)?;
+        let mut results = self.scan(metric, query_vectors, &mask.map)?;
+        results
+            .iter_mut()
+            .for_each(|neighbors| neighbors.retain(|(_, d)| metric.is_within(*d, radius)));
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fvecs::FvecsDataset;
+    use crate::metrics::brute_force_knn;
+    use crate::test_utils::assert_results_approx_eq;
+
+    fn load() -> (FvecsDataset, FlatDataset) {
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        let metadata = (&dataset.metadata).into_iter().collect();
+        let flat = FlatDataset::new(FlattenedVecs::from(&dataset), metadata).unwrap();
+        (dataset, flat)
+    }
+
+    #[test]
+    fn test_search() {
+        let (dataset, mut flat) = load();
+        let queries = FlattenedVecs {
+            dimensionality: flat.get_dimensionality(),
+            data: flat.flat.data[..3 * flat.get_dimensionality()].to_vec(),
+        };
+        assert!(matches!(
+            flat.search(&queries, &None, 10, 16),
+            Err(SearchableError::DatasetIsNotIndexed)
+        ));
+
+        flat.initialize(&OakIndexOptions::default()).unwrap();
+        let results = flat.search(&queries, &None, 10, 16).unwrap();
+        assert_results_approx_eq(
+            &results,
+            &brute_force_knn(&flat.flat, &queries, 10, DistanceMetric::L2),
+            1e-3,
+        );
+        for (i, neighbors) in results.iter().enumerate() {
+            assert_eq!(neighbors[0], (i, 0.));
+        }
+        assert_eq!(&flat[2], queries.get(2).unwrap());
+
+        // Only vectors that pass the predicate are returned.
+        let attr = dataset.metadata.attrs_for(0)[0];
+        let predicate = Some(PredicateQuery::new(attr as u8));
+        let (filtered, stats) = flat
+            .search_with_stats(&queries, &predicate, 10, 16)
+            .unwrap();
+        let matching = Bitmask::new(predicate.as_ref().unwrap(), &flat).count_ones();
+        assert!(filtered.iter().all(|neighbors| neighbors
+            .iter()
+            .all(|(id, _)| flat.metadata.attrs_for(*id)[0] == attr)));
+        assert!(stats.iter().all(|s| s.ndis == matching));
+    }
+
+    #[test]
+    fn test_cosine_and_range_search() {
+        let (_, mut flat) = load();
+        flat.initialize(&OakIndexOptions {
+            metric: DistanceMetric::Cosine,
+            ..OakIndexOptions::default()
+        })
+        .unwrap();
+
+        let query = FlattenedVecs {
+            dimensionality: flat.get_dimensionality(),
+            data: flat.get_vector(7).unwrap().iter().map(|x| x * 3.).collect(),
+        };
+        let results = flat.search(&query, &None, 5, 16).unwrap();
+        assert_eq!(results[0][0].0, 7);
+        assert!((results[0][0].1 - 1.).abs() < 1e-5);
+
+        let in_range = flat.range_search(&query, 0.99, &None, 16).unwrap();
+        assert!(in_range[0].iter().any(|(id, _)| *id == 7));
+        assert!(in_range[0].iter().all(|(_, d)| *d > 0.99));
+    }
+
+    #[test]
+    fn test_predicates_over_attributes() {
+        let vectors = FlattenedVecs {
+            dimensionality: 2,
+            data: vec![0., 0., 1., 1., 2., 2.],
+        };
+        let metadata = HybridSearchMetadata::new(2, vec![1, 10, 2, 20, 1, 30]);
+        let mut flat = FlatDataset::new(vectors, metadata).unwrap();
+        flat.initialize(&OakIndexOptions::default()).unwrap();
+        let query = FlattenedVecs {
+            dimensionality: 2,
+            data: vec![0., 0.],
+        };
+
+        let first = Some(PredicateQuery::new(1));
+        let results = flat.search(&query, &first, 3, 16).unwrap();
+        assert_eq!(results[0].iter().map(|r| r.0).collect::<Vec<_>>(), [0, 2]);
+
+        let second = Some(PredicateQuery::range(15, 35).on_attribute(1));
+        let results = flat.search(&query, &second, 3, 16).unwrap();
+        assert_eq!(results[0].iter().map(|r| r.0).collect::<Vec<_>>(), [1, 2]);
+
+        let negated = Some(PredicateQuery::negate(PredicateQuery::new(20)).on_attribute(1));
+        let results = flat.search(&query, &negated, 3, 16).unwrap();
+        assert_eq!(results[0].iter().map(|r| r.0).collect::<Vec<_>>(), [0, 2]);
+
+        for predicate in [
+            PredicateQuery::new(1).on_attribute(2),
+            PredicateQuery::negate(PredicateQuery::in_set(vec![1]).on_attribute(5)),
+        ] {
+            let err = flat.search(&query, &Some(predicate), 3, 16).err().unwrap();
+            assert!(matches!(
+                err,
+                SearchableError::UnknownAttribute {
+                    num_attributes: 2,
+                    ..
+                }
+            ));
+        }
+    }
+
+    #[test]
+    fn test_short_bitmask_is_rejected() {
+        let (_, mut flat) = load();
+        flat.initialize(&OakIndexOptions::default()).unwrap();
+        let queries = FlattenedVecs {
+            dimensionality: flat.get_dimensionality(),
+            data: flat.flat.get(0).unwrap().to_vec(),
+        };
+
+        let short = Bitmask::from(vec![1i8; 3]);
+        assert_eq!(
+            flat.search_with_bitmask(&queries, &short, 10, 16).err(),
+            Some(SearchableError::FilterMapLengthMismatch {
+                expected: flat.len(),
+                got: 3
+            })
+        );
+
+        // A mask built for a dataset with fewer vectors no longer covers the whole dataset.
+        let len = flat.len();
+        let smaller = FlatDataset::new(
+            FlattenedVecs {
+                dimensionality: flat.get_dimensionality(),
+                data: flat.flat.data[..(len - 1) * flat.get_dimensionality()].to_vec(),
+            },
+            (&flat.metadata).into_iter().take(len - 1).collect(),
+        )
+        .unwrap();
+        let stale = Bitmask::new_full(&smaller);
+        assert_eq!(
+            flat.search_with_bitmask(&queries, &stale, 10, 16).err(),
+            Some(SearchableError::FilterMapLengthMismatch {
+                expected: len,
+                got: len - 1
+            })
+        );
+
+        // The mask of a predicate always covers the whole dataset.
+        let predicate = Some(PredicateQuery::range(0, 100));
+        assert!(flat.range_search(&queries, 1e9, &predicate, 16).is_ok());
+    }
+}
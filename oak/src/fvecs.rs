@@ -1,43 +1,305 @@
 use crate::acorn::AcornHnswIndex;
 use crate::bitmask::Bitmask;
 use crate::dataset::{
-    ConstructionError, HybridSearchMetadata, OakIndexOptions, SearchableError,
-    SimilaritySearchable, TopKSearchResult,
+    ConstructionError, DistanceMetric, HybridSearchMetadata, IndexStats, OakIndexOptions,
+    SearchParams, SearchStats, SearchableError, SimilaritySearchable, TopKSearchResult,
+    TopKSearchResultBatch,
 };
 use crate::predicate::PredicateQuery;
-use slog_scope::{debug, info};
-
 use anyhow::Result;
 use byteorder::{ByteOrder, LittleEndian};
 use csv::ReaderBuilder;
+use half::f16;
 use memmap2::Mmap;
+use rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
+use std::collections::HashMap;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
 const FOUR_BYTES: usize = std::mem::size_of::<f32>();
 
-fn read_csv_to_vec(file_path: &PathBuf) -> Result<Vec<i32>> {
+/// The number of vectors that `FvecsMmapDataset` copies out of the file at a time while building
+/// its index.
+const MMAP_CHUNK_SIZE: usize = 10_000;
+
+/// The number of vectors that `FvecsDataset::initialize_with_progress` adds to its index between
+/// reports of progress.
+const PROGRESS_CHUNK_SIZE: usize = 10_000;
+
+/// The bytes that begin a file written by `FlattenedVecs::write_to`.
+const FLATTENED_MAGIC: &[u8; 4] = b"OAKV";
+
+/// The version of the format that `FlattenedVecs::write_to` writes and `read_from` reads, which
+/// should be bumped whenever the layout changes.
+const FLATTENED_VERSION: u8 = 1;
+
+/// The length of the header of a file written by `FlattenedVecs::write_to`: the magic bytes, the
+/// version byte, and then the dimensionality and the number of vectors as u64s.
+const FLATTENED_HEADER_LEN: usize = FLATTENED_MAGIC.len() + 1 + 2 * 8;
+
+/// The bytes that begin every gzip-compressed file.
+const GZIP_MAGIC: &[u8; 2] = &[0x1f, 0x8b];
+
+/// The path of "{fname}.fvecs", or of a gzip-compressed "{fname}.fvecs.gz" if only that exists.
+fn fvecs_path(fname: &str) -> PathBuf {
+    let path = PathBuf::from(format!("{fname}.fvecs"));
+    let gzipped = PathBuf::from(format!("{fname}.fvecs.gz"));
+    if !path.exists() && gzipped.exists() {
+        gzipped
+    } else {
+        path
+    }
+}
+
+/// Memory-maps the file at `path`. A gzip-compressed file (recognized by its first bytes, whatever
+/// its extension) is instead decompressed into anonymous memory, which requires the `gzip` feature,
+/// so that the rest of the parsing is the same for both.
+fn map_file(path: &Path) -> Result<Mmap> {
+    let f = File::open(path)?;
+
+    // SAFETY: For the purposes of our benchmarking suite, we are assuming that the underlying
+    // file will not be modified throughout the duration of the program, as we control the file
+    // system.
+    let mmap = unsafe { Mmap::map(&f)? };
+    if mmap.starts_with(GZIP_MAGIC) {
+        return gunzip(&mmap, path);
+    }
+    Ok(mmap)
+}
+
+#[cfg(feature = "gzip")]
+fn gunzip(compressed: &[u8], path: &Path) -> Result<Mmap> {
+    use std::io::Read;
+
+    let mut data = Vec::new();
+    flate2::read::MultiGzDecoder::new(compressed)
+        .read_to_end(&mut data)
+        .map_err(|e| anyhow::anyhow!("{path:?} could not be decompressed: {e}"))?;
+    let mut mmap = memmap2::MmapMut::map_anon(data.len())?;
+    mmap.copy_from_slice(&data);
+    Ok(mmap.make_read_only()?)
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gunzip(_compressed: &[u8], path: &Path) -> Result<Mmap> {
+    anyhow::bail!("{path:?} is gzip-compressed, which requires the `gzip` feature")
+}
+
+fn read_csv_to_metadata(file_path: &PathBuf) -> Result<HybridSearchMetadata> {
     // Open the file
     let file = File::open(file_path)?;
 
-    // Create a CSV reader
+    // Create a CSV reader. Unless the reader is made `flexible`, it will return an error if any
+    // row has a different number of columns to the first.
     let mut reader = ReaderBuilder::new()
         .has_headers(false) // No headers in this example
         .from_reader(file);
 
-    // Collect integers from the CSV
+    // Collect integers from the CSV, one column per attribute, where an empty field is null
+    let mut dimensionality = 0;
     let mut numbers = Vec::new();
     for result in reader.records() {
         let record = result?;
-        if let Some(field) = record.get(0) {
-            // Assuming a single column
-            numbers.push(field.parse::<i32>()?);
+        dimensionality = record.len();
+        for field in record.iter() {
+            if field.trim().is_empty() {
+                numbers.push(None);
+            } else {
+                numbers.push(Some(field.parse::<i64>()?));
+            }
         }
     }
 
     // debug!("{} attributes loaded from CSV.", numbers.len());
 
-    Ok(numbers)
+    Ok(HybridSearchMetadata::from_nullable(dimensionality, numbers))
+}
+
+/// Runs `op` on `pool` if there is one, or else on the global rayon thread pool.
+fn in_pool<T: Send>(pool: &Option<ThreadPool>, op: impl FnOnce() -> T + Send) -> T {
+    match pool {
+        Some(pool) => pool.install(op),
+        None => op(),
+    }
+}
+
+/// Checks that `metadata` holds the attributes of exactly `vectors` vectors, as otherwise filtered
+/// searches would silently attribute the wrong attributes to vectors. Metadata without any
+/// attributes (as for a dataset loaded without its CSV file) is allowed, as such datasets can still
+/// be searched without a predicate.
+pub(crate) fn check_metadata_len(
+    vectors: usize,
+    metadata: &HybridSearchMetadata,
+) -> Result<(), ConstructionError> {
+    if !metadata.is_empty() && metadata.len() != vectors {
+        return Err(ConstructionError::MetadataLengthMismatch {
+            vectors,
+            attrs: metadata.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Replaces `current` with `metadata`, which must hold the attributes of exactly `vectors` vectors.
+/// Unlike `check_metadata_len`, empty metadata is rejected (for a non-empty dataset), as there is
+/// no point setting it.
+fn set_metadata_checked(
+    current: &mut HybridSearchMetadata,
+    vectors: usize,
+    metadata: HybridSearchMetadata,
+) -> Result<(), ConstructionError> {
+    if metadata.len() != vectors {
+        return Err(ConstructionError::MetadataLengthMismatch {
+            vectors,
+            attrs: metadata.len(),
+        });
+    }
+    *current = metadata;
+    Ok(())
+}
+
+/// Works out the dimensionality and number of the vectors in the contents of an .fvecs, .bvecs or
+/// .ivecs file, where each vector is a 4-byte little-endian dimensionality followed by that many
+/// components of `component_size` bytes each. An error is returned if the vectors do not all share
+/// the dimensionality of the first, or if the file ends part way through a vector.
+fn read_vecs_layout(bytes: &[u8], component_size: usize, path: &Path) -> Result<(usize, usize)> {
+    if bytes.is_empty() {
+        return Ok((0, 0));
+    }
+    if bytes.len() < FOUR_BYTES {
+        anyhow::bail!("{path:?} ends part way through a vector");
+    }
+
+    let dimensionality = LittleEndian::read_u32(&bytes[..FOUR_BYTES]) as usize;
+    let vec_len_in_bytes = FOUR_BYTES + dimensionality * component_size;
+    if !bytes.len().is_multiple_of(vec_len_in_bytes) {
+        anyhow::bail!(
+            "{path:?} is {} bytes long, which is not a whole number of {dimensionality}-dimensional vectors",
+            bytes.len()
+        );
+    }
+
+    // NOTE: only the header of each vector is read here, not the vector itself.
+    for header in bytes.chunks_exact(vec_len_in_bytes) {
+        let got = LittleEndian::read_u32(&header[..FOUR_BYTES]) as usize;
+        if got != dimensionality {
+            return Err(ConstructionError::DimensionalityMismatch {
+                expected: dimensionality,
+                got,
+            }
+            .into());
+        }
+    }
+
+    Ok((dimensionality, bytes.len() / vec_len_in_bytes))
+}
+
+/// Reads vectors and their attributes together from a CSV file without headers, in which each row
+/// is an id, the components of a vector, and then a single integer attribute. The ids are not
+/// retained: as elsewhere, the vector on each row is identified by its position in the file. The
+/// dimensionality is inferred from the first row, and an error naming the offending line is
+/// returned if any row has a different number of components or a value cannot be parsed.
+pub fn read_csv(path: &Path) -> Result<(FlattenedVecs, HybridSearchMetadata)> {
+    // NOTE: the reader is made `flexible` so that a row of the wrong length is reported below,
+    // along with the dimensionality that was expected.
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(path)?;
+
+    let mut dimensionality = None;
+    let mut data = Vec::new();
+    let mut attrs = Vec::new();
+    for result in reader.records() {
+        let record = result?;
+        let line = record.position().map_or(0, |pos| pos.line());
+
+        if record.len() < 3 {
+            anyhow::bail!(
+                "{path:?} line {line}: expected an id, a vector and an attribute, but found {} fields",
+                record.len()
+            );
+        }
+        let got = record.len() - 2;
+        let expected = *dimensionality.get_or_insert(got);
+        if got != expected {
+            anyhow::bail!(
+                "{path:?} line {line}: the vector has dimensionality {got}, but {expected} was expected"
+            );
+        }
+
+        for field in record.iter().skip(1).take(got) {
+            data.push(field.trim().parse::<f32>().map_err(|e| {
+                anyhow::anyhow!("{path:?} line {line}: invalid vector component {field:?}: {e}")
+            })?);
+        }
+        let attr = &record[record.len() - 1];
+        attrs.push(attr.trim().parse::<i64>().map_err(|e| {
+            anyhow::anyhow!("{path:?} line {line}: invalid attribute {attr:?}: {e}")
+        })?);
+    }
+
+    Ok((
+        FlattenedVecs {
+            dimensionality: dimensionality.unwrap_or_default(),
+            data,
+        },
+        HybridSearchMetadata::new(1, attrs),
+    ))
+}
+
+/// Reads a .bvecs file, in which each component of the vectors is a u8 rather than an f32 (as in
+/// the larger SIFT datasets), converting each component to an f32.
+pub fn read_bvecs(path: &Path) -> Result<FlattenedVecs> {
+    let bytes = std::fs::read(path)?;
+    let (dimensionality, count) = read_vecs_layout(&bytes, 1, path)?;
+
+    let mut data = Vec::with_capacity(count * dimensionality);
+    for bvec in bytes.chunks_exact(FOUR_BYTES + dimensionality) {
+        data.extend(bvec[FOUR_BYTES..].iter().map(|&component| component as f32));
+    }
+
+    Ok(FlattenedVecs {
+        dimensionality,
+        data,
+    })
+}
+
+/// Reads an .ivecs file, such as the ground truth for the SIFT datasets. Each vector in the file is
+/// a 4-byte little-endian dimensionality followed by that many i32 values, which for ground truth
+/// are the ids of the nearest neighbors of a query, closest first.
+pub fn read_ivecs(path: &Path) -> Result<Vec<Vec<usize>>> {
+    let bytes = std::fs::read(path)?;
+    let (dimensionality, _) = read_vecs_layout(&bytes, FOUR_BYTES, path)?;
+
+    bytes
+        .chunks_exact((dimensionality + 1) * FOUR_BYTES)
+        .map(|ivec| {
+            ivec[FOUR_BYTES..]
+                .chunks_exact(FOUR_BYTES)
+                .map(|id| Ok(usize::try_from(LittleEndian::read_i32(id))?))
+                .collect()
+        })
+        .collect()
+}
+
+/// Writes the ids of the results of each query in `results` to an .ivecs file, in the same layout
+/// that `read_ivecs` reads (so that results can be compared with other tools' ground truth). Each
+/// query is written with its own dimensionality, so a query with fewer than `k` results is written
+/// as a shorter vector; note that `read_ivecs` expects every vector to have the same length. An
+/// error is returned if an id does not fit in an i32.
+pub fn write_ivecs(results: &TopKSearchResultBatch, path: &Path) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for neighbors in results {
+        writer.write_all(&i32::try_from(neighbors.len())?.to_le_bytes())?;
+        for (id, _) in neighbors {
+            writer.write_all(&i32::try_from(*id)?.to_le_bytes())?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
 }
 
 /// Converts a slice of `u8` into a `Vec<f32>` assuming little-endian format.
@@ -66,12 +328,129 @@ pub fn parse_u8_to_f32(data: &[u8]) -> Vec<f32> {
     result
 }
 
-#[allow(dead_code)]
 pub struct Fvec {
     dimensionality: usize,
     data: Vec<f32>,
 }
 
+impl Fvec {
+    pub fn dimensionality(&self) -> usize {
+        self.dimensionality
+    }
+
+    pub fn data(&self) -> &[f32] {
+        &self.data
+    }
+
+    /// Scales this vector to unit L2 length in place. A vector with a length of zero is left as it
+    /// is, rather than divided into NaNs.
+    pub fn normalize(&mut self) {
+        normalize(&mut self.data);
+    }
+
+    /// The (true, Euclidean) L2 distance between this vector and `other`. NOTE: searches report
+    /// the squared L2 distance, so it is the square of this that is comparable with their results.
+    ///
+    /// # Panics
+    /// - In debug builds, panics if the vectors have different dimensionalities.
+    pub fn l2_distance(&self, other: &Fvec) -> f32 {
+        debug_assert_eq!(self.dimensionality, other.dimensionality);
+        std::iter::zip(&self.data, &other.data)
+            .map(|(x, y)| (x - y) * (x - y))
+            .sum::<f32>()
+            .sqrt()
+    }
+
+    /// The inner product of this vector and `other`, as searches with
+    /// `DistanceMetric::InnerProduct` report it.
+    ///
+    /// # Panics
+    /// - In debug builds, panics if the vectors have different dimensionalities.
+    pub fn dot(&self, other: &Fvec) -> f32 {
+        debug_assert_eq!(self.dimensionality, other.dimensionality);
+        std::iter::zip(&self.data, &other.data)
+            .map(|(x, y)| x * y)
+            .sum()
+    }
+}
+
+fn normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0. {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+/// A memory-mapped .fvecs file, from which individual vectors are only read (and copied into
+/// memory) when they are accessed. This makes it possible to work with files larger than RAM.
+pub struct FvecsMmap {
+    mmap: Mmap,
+    count: usize,
+    dimensionality: usize,
+}
+
+impl FvecsMmap {
+    /// Memory-map the .fvecs file at `path`. Each vector in the file is a 4-byte little-endian
+    /// dimensionality followed by that many f32 values, and an error is returned if the vectors do
+    /// not all share the dimensionality of the first. A gzip-compressed file can be opened with the
+    /// `gzip` feature, but is decompressed into memory up front rather than read lazily.
+    pub fn open(path: &Path) -> Result<Self> {
+        let mmap = map_file(path)?;
+
+        let (dimensionality, count) = read_vecs_layout(&mmap, FOUR_BYTES, path)?;
+        Ok(Self {
+            mmap,
+            count,
+            dimensionality,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn dimensionality(&self) -> usize {
+        self.dimensionality
+    }
+
+    /// Read the vector at index `idx` from the file.
+    ///
+    /// # Panics
+    /// - Panics if `idx` is out of range.
+    pub fn get(&self, idx: usize) -> Fvec {
+        assert!(
+            idx < self.count,
+            "index {idx} is out of range for {} vectors",
+            self.count
+        );
+        let fvec_len_in_bytes = (self.dimensionality + 1) * FOUR_BYTES;
+        let start = idx * fvec_len_in_bytes + FOUR_BYTES;
+        Fvec {
+            dimensionality: self.dimensionality,
+            data: parse_u8_to_f32(&self.mmap[start..start + fvec_len_in_bytes - FOUR_BYTES]),
+        }
+    }
+
+    /// Iterate over the vectors in the file, reading each only as it is reached.
+    pub fn iter(&self) -> impl Iterator<Item = Fvec> + '_ {
+        (0..self.count).map(|idx| self.get(idx))
+    }
+
+    /// Read the vectors with indices in `range` into a flattened representation.
+    ///
+    /// # Panics
+    /// - Panics if `range` is out of range.
+    pub fn read_range(&self, range: Range<usize>) -> FlattenedVecs {
+        let fvec_len_in_bytes = (self.dimensionality + 1) * FOUR_BYTES;
+        let bytes = &self.mmap[range.start * fvec_len_in_bytes..range.end * fvec_len_in_bytes];
+        FlattenedVecs::read_from_mmap(bytes, range.len(), self.dimensionality)
+    }
+}
+
 pub struct FlattenedVecs {
     // The length of each vector in the flattened representation.
     pub dimensionality: usize,
@@ -84,6 +463,30 @@ impl FlattenedVecs {
         self.data.len() / self.dimensionality
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Checks that the data is a whole number of vectors of the dimensionality, returning a
+    /// `RaggedVectors` error if not; otherwise every vector after a misalignment would be read
+    /// wrongly. A dimensionality of zero is never valid.
+    pub fn check_layout(&self) -> Result<(), ConstructionError> {
+        if self.dimensionality == 0 || !self.data.len().is_multiple_of(self.dimensionality) {
+            return Err(ConstructionError::RaggedVectors {
+                total: self.data.len(),
+                dimensionality: self.dimensionality,
+            });
+        }
+        Ok(())
+    }
+
+    /// The vector at `idx`, borrowed from the flattened data, or None if `idx` is out of range.
+    pub fn get(&self, idx: usize) -> Option<&[f32]> {
+        let start = idx.checked_mul(self.dimensionality)?;
+        self.data
+            .get(start..start.checked_add(self.dimensionality)?)
+    }
+
     pub fn to_vec(self) -> Vec<FlattenedVecs> {
         self.data
             .chunks(self.dimensionality)
@@ -94,6 +497,74 @@ impl FlattenedVecs {
             .collect()
     }
 
+    /// Creates a half-precision copy of the vectors.
+    pub fn to_f16(&self) -> FlattenedVecsF16 {
+        FlattenedVecsF16 {
+            dimensionality: self.dimensionality,
+            data: self.data.iter().map(|&x| f16::from_f32(x)).collect(),
+        }
+    }
+
+    /// The position of the first NaN or infinite component, as the index of its vector and of the
+    /// component within that vector.
+    pub fn find_non_finite(&self) -> Option<(usize, usize)> {
+        let position = self.data.iter().position(|x| !x.is_finite())?;
+        Some((
+            position / self.dimensionality,
+            position % self.dimensionality,
+        ))
+    }
+
+    /// The distinct vectors, in the order in which each first appears, along with the index among
+    /// them of each of the vectors. Vectors are only the same if the bit patterns of all of their
+    /// components are.
+    pub(crate) fn dedup(&self) -> (FlattenedVecs, Vec<usize>) {
+        let mut seen: HashMap<Vec<u32>, usize> = HashMap::new();
+        let mut unique = Vec::new();
+        let copies = self
+            .data
+            .chunks_exact(self.dimensionality)
+            .map(|vector| {
+                let bits = vector.iter().map(|x| x.to_bits()).collect();
+                *seen.entry(bits).or_insert_with(|| {
+                    unique.extend_from_slice(vector);
+                    unique.len() / self.dimensionality - 1
+                })
+            })
+            .collect();
+        let unique = FlattenedVecs {
+            dimensionality: self.dimensionality,
+            data: unique,
+        };
+        (unique, copies)
+    }
+
+    /// Replaces every NaN or infinite component with zero.
+    pub fn sanitize_non_finite(&mut self) {
+        self.data
+            .iter_mut()
+            .filter(|x| !x.is_finite())
+            .for_each(|x| *x = 0.);
+    }
+
+    /// Scales every vector to unit L2 length in place, as is required for cosine similarity.
+    /// Vectors with a length of zero are left as they are.
+    pub fn normalize_all(&mut self) {
+        self.data
+            .chunks_exact_mut(self.dimensionality)
+            .for_each(normalize);
+    }
+
+    /// Like `normalize_all`, but creates a normalized copy rather than modifying these vectors.
+    pub fn to_normalized(&self) -> Self {
+        let mut normalized = Self {
+            dimensionality: self.dimensionality,
+            data: self.data.clone(),
+        };
+        normalized.normalize_all();
+        normalized
+    }
+
     /// Creates a new FlattenedVecs based on a bitmask and an original one.
     /// Only the necessary items (items that match the bitmask) are copied.
     pub fn clone_via_bitmask(&self, bitmask: &Bitmask) -> (Vec<usize>, Self) {
@@ -123,16 +594,71 @@ impl FlattenedVecs {
         )
     }
 
+    /// Writes the vectors to `path` in a compact binary format, which (unlike .fvecs) stores the
+    /// dimensionality once for all vectors rather than once per vector. The file begins with the
+    /// magic bytes `OAKV` and a version byte, followed by the dimensionality and the number of
+    /// vectors as little-endian u64s, and then the components of every vector as little-endian
+    /// f32s. It can be read back with `read_from`.
+    pub fn write_to(&self, path: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(FLATTENED_MAGIC)?;
+        writer.write_all(&[FLATTENED_VERSION])?;
+        writer.write_all(&(self.dimensionality as u64).to_le_bytes())?;
+        writer.write_all(&(self.len() as u64).to_le_bytes())?;
+        for component in &self.data {
+            writer.write_all(&component.to_le_bytes())?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Reads vectors written by `write_to`. An error is returned if the file is not in that format,
+    /// was written by a different version of it, or is not as long as its header says.
+    pub fn read_from(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+        if bytes.len() < FLATTENED_HEADER_LEN || !bytes.starts_with(FLATTENED_MAGIC) {
+            anyhow::bail!("{path:?} is not a file of flattened vectors");
+        }
+
+        let version = bytes[FLATTENED_MAGIC.len()];
+        if version != FLATTENED_VERSION {
+            anyhow::bail!(
+                "{path:?} was written in version {version} of the format, but only version \
+                 {FLATTENED_VERSION} can be read"
+            );
+        }
+
+        let header = &bytes[FLATTENED_MAGIC.len() + 1..FLATTENED_HEADER_LEN];
+        let dimensionality = usize::try_from(LittleEndian::read_u64(&header[..8]))?;
+        let count = usize::try_from(LittleEndian::read_u64(&header[8..]))?;
+        let data = &bytes[FLATTENED_HEADER_LEN..];
+        let expected = count
+            .checked_mul(dimensionality)
+            .and_then(|components| components.checked_mul(FOUR_BYTES));
+        if expected != Some(data.len()) {
+            anyhow::bail!(
+                "{path:?} should hold {count} {dimensionality}-dimensional vectors, but has {} \
+                 bytes of vector data",
+                data.len()
+            );
+        }
+
+        Ok(Self {
+            dimensionality,
+            data: parse_u8_to_f32(data),
+        })
+    }
+
     /// Create a 'flattened' representation of fvecs (meaning that the vectors are simply contiguous to
     /// each other in memory, rather than prepended by their dimensionality explicitly as in the .fvecs
     /// representation). This is necessary reformatting for calling ACORN methods via FFI, and the
     /// transformation also ensures that the vectors are in memory (rather than on disk).
-    pub fn read_from_mmap(mmap: &Mmap, count: usize, dimensionality: usize) -> Self {
+    pub fn read_from_mmap(mmap: &[u8], count: usize, dimensionality: usize) -> Self {
         let mut all_fvecs = Vec::with_capacity(count * dimensionality);
         let fvec_len_in_bytes = (dimensionality + 1) * FOUR_BYTES;
         let mut current_index = 0;
         for i in mmap.chunks_exact(fvec_len_in_bytes) {
-            let mut fvecs = parse_u8_to_f32(&i);
+            let mut fvecs = parse_u8_to_f32(i);
             // skip the dimensionality, we don't need it in the flattened.
             fvecs.drain(0..1);
             all_fvecs.splice(current_index..current_index, fvecs);
@@ -146,9 +672,100 @@ impl FlattenedVecs {
     }
 }
 
+#[cfg(feature = "ndarray")]
+impl FlattenedVecs {
+    /// Flatten an (n, d) array of n vectors of dimensionality d, in row-major order regardless of
+    /// the memory layout of `arr`.
+    pub fn from_ndarray(arr: &ndarray::Array2<f32>) -> Self {
+        Self {
+            dimensionality: arr.ncols(),
+            data: arr.iter().copied().collect(),
+        }
+    }
+
+    /// The inverse of `from_ndarray`, which copies the vectors into an (n, d) array.
+    pub fn to_ndarray(&self) -> ndarray::Array2<f32> {
+        ndarray::Array2::from_shape_vec((self.len(), self.dimensionality), self.data.clone())
+            .expect("the data of FlattenedVecs is a multiple of its dimensionality")
+    }
+}
+
+/// A half-precision counterpart to `FlattenedVecs`, which takes half of the memory to hold the
+/// same vectors at the cost of some precision. ACORN only understands f32 vectors, so the vectors
+/// are converted back to f32 when they are passed across the FFI, either all at once with
+/// `to_f32`, or a chunk at a time with `chunks_f32` (to build an index with
+/// `AcornHnswIndex::new_from_chunks` without converting the whole set at once).
+///
+/// Note that ACORN keeps its own f32 copy of every vector in an index, so this only reduces the
+/// memory used by the vectors held on the Rust side.
+pub struct FlattenedVecsF16 {
+    // The length of each vector in the flattened representation.
+    pub dimensionality: usize,
+    // This data is the flattened representation of all vectors of size `dimensionality`.
+    pub data: Vec<f16>,
+}
+
+impl FlattenedVecsF16 {
+    pub fn len(&self) -> usize {
+        self.data.len() / self.dimensionality
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn to_f32(&self) -> FlattenedVecs {
+        FlattenedVecs {
+            dimensionality: self.dimensionality,
+            data: self.data.iter().map(|x| x.to_f32()).collect(),
+        }
+    }
+
+    /// Converts the vectors to f32 `chunk_size` vectors at a time.
+    pub fn chunks_f32(&self, chunk_size: usize) -> impl Iterator<Item = FlattenedVecs> + '_ {
+        self.data
+            .chunks(chunk_size * self.dimensionality)
+            .map(|chunk| FlattenedVecs {
+                dimensionality: self.dimensionality,
+                data: chunk.iter().map(|x| x.to_f32()).collect(),
+            })
+    }
+}
+
+impl From<&Fvec> for FlattenedVecs {
+    fn from(fvec: &Fvec) -> Self {
+        FlattenedVecs {
+            dimensionality: fvec.dimensionality,
+            data: fvec.data.clone(),
+        }
+    }
+}
+
 impl From<&FvecsDataset> for FlattenedVecs {
     fn from(dataset: &FvecsDataset) -> Self {
-        FlattenedVecs::read_from_mmap(&dataset.mmap, dataset.count, dataset.dimensionality)
+        // NOTE: the vectors are copied from `flat` rather than re-read from the file, so that any
+        // added with `add_vectors` are included.
+        FlattenedVecs {
+            dimensionality: dataset.dimensionality,
+            data: dataset.flat.data.clone(),
+        }
+    }
+}
+
+impl std::ops::Index<usize> for FvecsDataset {
+    type Output = [f32];
+
+    /// The vector with id `idx`, e.g. `dataset[idx]`, as for `SimilaritySearchable::get_vector`.
+    ///
+    /// # Panics
+    /// - Panics if `idx` is out of range. `get_vector` returns None instead.
+    fn index(&self, idx: usize) -> &[f32] {
+        self.flat.get(idx).unwrap_or_else(|| {
+            panic!(
+                "index {idx} is out of range for {} vectors",
+                self.flat.len()
+            )
+        })
     }
 }
 
@@ -159,13 +776,12 @@ impl From<&FvecsDataset> for Vec<PredicateQuery> {
     fn from(dataset: &FvecsDataset) -> Self {
         dataset
             .metadata
-            .as_ref()
-            .iter()
+            .rows()
             // NOTE: we assume here that the attribute loaded is safe to cast to a u8, as we have
             // generated the attributes as such. The reason that `dataset.metadata` is a u32 is
             // because this is what the ACORN code expects (and as such should probably be changed
             // to a u8 so that implementation details aren't leaky to higher-level abstractions).
-            .map(|x| PredicateQuery::new((*x).try_into().unwrap()))
+            .map(|attrs| PredicateQuery::new(attrs[0].try_into().unwrap()))
             .collect()
     }
 }
@@ -176,6 +792,10 @@ pub struct FvecsDataset {
     count: usize,
     dimensionality: usize,
     index: Option<AcornHnswIndex>,
+    /// The thread pool that searches are run on, if one has been set with `set_num_threads`.
+    pool: Option<ThreadPool>,
+    /// A 1 marks a vector that has been removed with `remove_vectors`.
+    removed: Bitmask,
     pub metadata: HybridSearchMetadata,
     pub flat: FlattenedVecs,
 }
@@ -185,17 +805,16 @@ impl FvecsDataset {
     /// filename that corresponds to both a "{fname}.fvecs" that contains the vectors, and a
     /// "{fname}.csv" that contains the attributes (over which predicates can be constructed) for
     /// those vectors. Each row in the CSV corresponds to the vector at the same index in the fvecs
-    /// file, and each column represents an attribute on that vector.
+    /// file, and each column represents an attribute on that vector. Every row must have the same
+    /// number of columns. With the `gzip` feature, the vectors may instead be in a gzip-compressed
+    /// "{fname}.fvecs.gz".
+    ///
+    /// As the vectors are copied into memory (and faiss keeps a copy of its own), building the
+    /// index needs twice the memory of the vectors. `FvecsMmapDataset` instead streams the vectors
+    /// from the file into the index a chunk at a time, for datasets too large for that.
     pub fn new(fname: String, load_csv: bool) -> Result<Self> {
-        let mut fvecs_fname = PathBuf::new();
-        fvecs_fname.push(&format!("{}.fvecs", fname));
-
-        let f = File::open(fvecs_fname)?;
-
-        // SAFETY: For the purposes of our benchmarking suite, we are assuming that the underlying
-        // file will not be modified throughout the duration of the program, as we control the file
-        // system.
-        let mmap = unsafe { Mmap::map(&f)? };
+        let path = fvecs_path(&fname);
+        let mmap = map_file(&path)?;
 
         // In OAK, we are assuming that our datasets are always in-memory for the first set of
         // experiments.
@@ -204,33 +823,25 @@ impl FvecsDataset {
         // This will throw an error if RAM is not large enough.
         // let _ = mmap.lock()?;
 
-        let dimensionality = LittleEndian::read_u32(&mmap[..FOUR_BYTES]) as usize;
-        // assert_eq!(
-        //     dimensionality,
-        //     LittleEndian::read_u32(&mmap[0..FOUR_BYTES]) as usize
-        // );
-
         // Each fvec is a dimensionality (4 bytes) followed by `dimensionality` number of f32
-        // values. Fvecs are contiguous in the file.
-        let count = &mmap[..].len() / ((1 + dimensionality) * FOUR_BYTES);
-        // debug!(
-        //     "First dimensionality read from file is {dimensionality}; assuming the same for all remaining."
-        // );
-        // debug!("The file read has {count} vectors.");
+        // values. Fvecs are contiguous in the file, and the header of each is checked, so that a
+        // file of mixed dimensionalities, or that ends part way through a vector, is rejected.
+        let flat = flat_from_fvecs(&mmap, &path)?;
+        let (count, dimensionality) = (flat.len(), flat.dimensionality);
 
         let metadata = if load_csv {
             let mut metadata_fname = PathBuf::new();
-            metadata_fname.push(&format!("{}.csv", fname));
-            let metadata_vec = read_csv_to_vec(&metadata_fname)?;
-            HybridSearchMetadata::new(metadata_vec)
+            metadata_fname.push(format!("{}.csv", fname));
+            read_csv_to_metadata(&metadata_fname)?
         } else {
             // NOTE: this is a bad hack. It should really be an option
-            HybridSearchMetadata::new(vec![])
+            HybridSearchMetadata::new(1, vec![])
         };
 
-        let flat = FlattenedVecs::read_from_mmap(&mmap, count, dimensionality);
         Ok(Self {
             index: None,
+            pool: None,
+            removed: Bitmask::from(vec![0i8; count]),
             count,
             mmap,
             dimensionality,
@@ -239,6 +850,29 @@ impl FvecsDataset {
         })
     }
 
+    /// Like `new`, but rather than loading the attributes from a "{fname}.csv", uses the given
+    /// `metadata`. An error is returned if it does not describe exactly the vectors in the file.
+    pub fn with_metadata(fname: String, metadata: HybridSearchMetadata) -> Result<Self> {
+        let mut dataset = Self::new(fname, false)?;
+        check_metadata_len(dataset.count, &metadata)?;
+        dataset.metadata = metadata;
+        Ok(dataset)
+    }
+
+    /// Sets the attributes of the vectors once they are known, for a dataset whose vectors were
+    /// loaded before its attributes were computed (e.g. with `load_csv` false). Until then, the
+    /// dataset can be indexed and searched without a predicate, but a filtered search returns a
+    /// `SearchableError::MissingMetadata`. Any attributes that were already set are replaced, and
+    /// an index that has already been built is kept, as ACORN only filters on the mask of each
+    /// search. An error is returned if `metadata` does not describe exactly the vectors of the
+    /// dataset.
+    pub fn set_metadata(
+        &mut self,
+        metadata: HybridSearchMetadata,
+    ) -> Result<(), ConstructionError> {
+        set_metadata_checked(&mut self.metadata, self.count, metadata)
+    }
+
     #[allow(dead_code)]
     fn get_data(&self) -> Result<Vec<Fvec>> {
         let vecs = self
@@ -253,8 +887,160 @@ impl FvecsDataset {
         Ok(vecs)
     }
 
-    pub fn view(&self, pq: &PredicateQuery) -> FvecsDatasetPartition {
-        let mask = Bitmask::new(pq, self);
+    /// Sets the number of threads that the queries in a batch are searched with. Unless this is
+    /// called, searches are run on the global rayon thread pool.
+    ///
+    /// Each of these threads searches one query at a time, for which ACORN does not start any
+    /// OpenMP threads, so this is the number of threads that a search runs on. The OpenMP threads
+    /// of `acorn::set_omp_threads` are only used to build the index.
+    pub fn set_num_threads(&mut self, num_threads: usize) -> Result<(), ThreadPoolBuildError> {
+        self.pool = Some(ThreadPoolBuilder::new().num_threads(num_threads).build()?);
+        Ok(())
+    }
+
+    /// Iterates over the id, vector and attributes of each vector in the dataset, in order of their
+    /// ids, borrowing rather than copying them. Vectors that have been removed are skipped. If the
+    /// dataset was loaded without its attributes, every vector has an empty slice of attributes.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &[f32], &[i64])> {
+        self.flat
+            .data
+            .chunks_exact(self.dimensionality)
+            .enumerate()
+            .filter(|(id, _)| self.removed.map[*id] == 0)
+            .map(|(id, vector)| {
+                let attrs = if self.metadata.is_empty() {
+                    &[][..]
+                } else {
+                    self.metadata.attrs_for(id)
+                };
+                (id, vector, attrs)
+            })
+    }
+
+    /// Appends `vecs` to the dataset and its index, returning the range of ids assigned to them.
+    /// The `metadata` holds the attributes for each of the vectors, flattened in the same way as
+    /// those loaded from the CSV file.
+    ///
+    /// The ACORN graph is extended incrementally, so the index does not need to be rebuilt and
+    /// can be searched over the new vectors as soon as this returns. An error is returned if the
+    /// dataset has not yet been indexed.
+    pub fn add_vectors(
+        &mut self,
+        vecs: &FlattenedVecs,
+        metadata: &[i64],
+    ) -> Result<Range<usize>, ConstructionError> {
+        vecs.check_layout()?;
+        if vecs.dimensionality != self.dimensionality {
+            return Err(ConstructionError::DimensionalityMismatch {
+                expected: self.dimensionality,
+                got: vecs.dimensionality,
+            });
+        }
+        let attrs_per_vector = self.metadata.dimensionality();
+        if metadata.len() != vecs.len() * attrs_per_vector {
+            return Err(ConstructionError::MetadataLengthMismatch {
+                vectors: vecs.len(),
+                attrs: metadata.len() / attrs_per_vector.max(1),
+            });
+        }
+        let index = self
+            .index
+            .as_mut()
+            .ok_or(ConstructionError::DatasetIsNotIndexed)?;
+
+        let added = HybridSearchMetadata::new(attrs_per_vector, metadata.to_vec());
+        index.append(&added, vecs)?;
+
+        let ids = self.count..self.count + vecs.len();
+        self.removed.map.resize(ids.end, 0);
+        self.metadata.extend_from_slice(metadata);
+        self.flat.data.extend_from_slice(&vecs.data);
+        self.count = ids.end;
+
+        Ok(ids)
+    }
+
+    /// Appends the vectors and attributes of `other` to this dataset, so that the vector with id
+    /// `i` in `other` has the id `self.len() + i` here. Vectors that were removed from `other`
+    /// remain removed.
+    ///
+    /// The vectors of `other` are not in the index of this dataset, so any index is discarded, and
+    /// `initialize` must be called again before the merged dataset can be searched. An error is
+    /// returned if the datasets have different dimensionalities, or different attributes.
+    pub fn merge(&mut self, other: FvecsDataset) -> Result<(), ConstructionError> {
+        if other.dimensionality != self.dimensionality {
+            return Err(ConstructionError::DimensionalityMismatch {
+                expected: self.dimensionality,
+                got: other.dimensionality,
+            });
+        }
+        let (expected, got) = (
+            self.metadata.dimensionality(),
+            other.metadata.dimensionality(),
+        );
+        if !self.metadata.is_empty() && !other.metadata.is_empty() && expected != got {
+            return Err(ConstructionError::AttributeCountMismatch { expected, got });
+        }
+        let count = self.count + other.count;
+        let mut metadata = self.metadata.clone();
+        metadata.append(&other.metadata);
+        // NOTE: this rejects merging a dataset that has attributes with one that does not.
+        check_metadata_len(count, &metadata)?;
+
+        // NOTE: the counts are summed rather than recounted by `Bitmask::from`, which sums the map
+        // as i8s and so overflows past 127 removed vectors.
+        let bitcount = self.removed.bitcount + other.removed.bitcount;
+        let mut map = std::mem::take(&mut self.removed.map);
+        map.extend(other.removed.map);
+        self.removed = Bitmask { map, bitcount };
+        self.metadata = metadata;
+        self.flat.data.extend(other.flat.data);
+        self.count = count;
+        self.index = None;
+
+        Ok(())
+    }
+
+    /// Removes the vectors with the given `ids` from the dataset, returning how many were removed
+    /// (ignoring ids that are out of range or were already removed).
+    ///
+    /// ACORN cannot delete vectors from its graph, and so removed vectors instead remain in the
+    /// index and metadata (keeping the ids of the other vectors stable), but are excluded from the
+    /// filter of every subsequent search, and from partitions created with `view`. As such, `len`
+    /// still counts the removed vectors, and removed ids are never reused. Note that the removals
+    /// are not recorded by `save_index`.
+    pub fn remove_vectors(&mut self, ids: &[usize]) -> Result<usize, ConstructionError> {
+        let mut count = 0;
+        for &id in ids {
+            if id < self.count && self.removed.map[id] == 0 {
+                self.removed.map[id] = 1;
+                count += 1;
+            }
+        }
+        self.removed.bitcount += count;
+        Ok(count)
+    }
+
+    /// Changes the attribute of the vector with the given `id` to `attr`, for a dataset with a
+    /// single attribute per vector (see `HybridSearchMetadata::set_attrs_for` otherwise). Searches
+    /// filter on the attributes held here rather than in the index, so the change applies to every
+    /// subsequent search without rebuilding the index. An error is returned if `id` is out of
+    /// range.
+    pub fn set_attribute(&mut self, id: usize, attr: i64) -> Result<(), ConstructionError> {
+        self.metadata.set_attrs_for(id, &[attr])
+    }
+
+    /// Excludes any removed vectors from `mask`.
+    fn without_removed(&self, mask: Bitmask) -> Bitmask {
+        if self.removed.bitcount() == 0 {
+            mask
+        } else {
+            mask.and(&self.removed.not())
+        }
+    }
+
+    pub fn view(&self, pq: &PredicateQuery) -> FvecsDatasetPartition<'_> {
+        let mask = self.without_removed(Bitmask::new(pq, self));
         let metadata = HybridSearchMetadata::new_from_bitmask(&self.metadata, &mask);
 
         // assert_eq!(metadata.len(), mask.bitcount());
@@ -264,39 +1050,286 @@ impl FvecsDataset {
             mask,
             flat: None,
             index: None,
+            pool: None,
             original_indices: None,
             metadata,
         }
     }
+
+    /// A builder for a dataset whose vectors and attributes come from separate sources, rather
+    /// than from the "{fname}.fvecs" and "{fname}.csv" of `new`.
+    pub fn builder() -> DatasetBuilder {
+        DatasetBuilder::default()
+    }
 }
 
-impl SimilaritySearchable for FvecsDataset {
-    fn len(&self) -> usize {
-        self.count
+/// Where a `DatasetBuilder` reads its vectors from.
+enum VectorSource {
+    Flattened(FlattenedVecs),
+    File(PathBuf),
+    Mmap(Mmap),
+}
+
+/// Where a `DatasetBuilder` reads its attributes from.
+enum MetadataSource {
+    Metadata(HybridSearchMetadata),
+    Csv(PathBuf),
+}
+
+/// Builds an `FvecsDataset` from vectors and attributes given separately, and (if it is given
+/// `OakIndexOptions`) its index. Nothing is read or checked until `build`. Each setter replaces
+/// any source of the same kind that was set before.
+#[derive(Default)]
+pub struct DatasetBuilder {
+    vectors: Option<VectorSource>,
+    metadata: Option<MetadataSource>,
+    options: Option<OakIndexOptions>,
+}
+
+impl DatasetBuilder {
+    /// The vectors of the dataset, already in memory.
+    pub fn vectors(mut self, vectors: FlattenedVecs) -> Self {
+        self.vectors = Some(VectorSource::Flattened(vectors));
+        self
     }
 
-    fn get_dimensionality(&self) -> usize {
-        self.dimensionality as usize
+    /// Read the vectors from the .fvecs file at `path` (or, with the `gzip` feature, a
+    /// gzip-compressed one).
+    pub fn vectors_from_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.vectors = Some(VectorSource::File(path.into()));
+        self
     }
 
-    fn get_metadata(&self) -> &HybridSearchMetadata {
-        &self.metadata
+    /// Read the vectors from the contents of a .fvecs file that has already been mapped.
+    pub fn vectors_from_mmap(mut self, mmap: Mmap) -> Self {
+        self.vectors = Some(VectorSource::Mmap(mmap));
+        self
     }
 
-    fn initialize(&mut self, opts: &OakIndexOptions) -> Result<(), ConstructionError> {
-        let dimensionality = self.dimensionality as i32;
-        let index = AcornHnswIndex::new(dimensionality, &self.metadata, &self.flat, opts)?;
-        self.index = Some(index);
-        Ok(())
+    pub fn metadata(mut self, metadata: HybridSearchMetadata) -> Self {
+        self.metadata = Some(MetadataSource::Metadata(metadata));
+        self
     }
 
-    fn search(
-        &self,
-        query_vectors: &FlattenedVecs,
-        predicate_query: &Option<PredicateQuery>,
+    /// A single attribute for each vector, in the order of the vectors.
+    pub fn attributes(mut self, attrs: impl IntoIterator<Item = i64>) -> Self {
+        self.metadata = Some(MetadataSource::Metadata(attrs.into_iter().collect()));
+        self
+    }
+
+    /// Read the attributes from a CSV file at `path`, laid out as for `FvecsDataset::new`.
+    pub fn metadata_from_csv(mut self, path: impl Into<PathBuf>) -> Self {
+        self.metadata = Some(MetadataSource::Csv(path.into()));
+        self
+    }
+
+    /// Build the index with `options` once the dataset has been constructed.
+    pub fn options(mut self, options: OakIndexOptions) -> Self {
+        self.options = Some(options);
+        self
+    }
+
+    /// Reads the vectors and attributes, and checks that they describe the same vectors: the
+    /// vectors must be a whole number of vectors of the same dimensionality, and there must be
+    /// attributes for exactly those vectors (or none at all, as with `FvecsDataset::new` when
+    /// `load_csv` is false). If `options` were given, the index is then built with them.
+    pub fn build(self) -> Result<FvecsDataset> {
+        let (mmap, flat) = match self.vectors {
+            None => anyhow::bail!("The dataset has no vectors"),
+            Some(VectorSource::Flattened(flat)) => {
+                flat.check_layout()?;
+                (fvecs_mmap(&flat)?, flat)
+            }
+            Some(VectorSource::File(path)) => {
+                let mmap = map_file(&path)?;
+                let flat = flat_from_fvecs(&mmap, &path)?;
+                (mmap, flat)
+            }
+            Some(VectorSource::Mmap(mmap)) => {
+                let flat = flat_from_fvecs(&mmap, Path::new("the memory map"))?;
+                (mmap, flat)
+            }
+        };
+
+        let metadata = match self.metadata {
+            None => HybridSearchMetadata::new(1, vec![]),
+            Some(MetadataSource::Metadata(metadata)) => metadata,
+            Some(MetadataSource::Csv(path)) => read_csv_to_metadata(&path)?,
+        };
+        let count = flat.len();
+        check_metadata_len(count, &metadata)?;
+
+        let mut dataset = FvecsDataset {
+            index: None,
+            pool: None,
+            removed: Bitmask::from(vec![0i8; count]),
+            count,
+            mmap,
+            dimensionality: flat.dimensionality,
+            metadata,
+            flat,
+        };
+        if let Some(options) = &self.options {
+            dataset.initialize(options)?;
+        }
+        Ok(dataset)
+    }
+}
+
+/// Reads the vectors in the contents of a .fvecs file, checking that they are laid out correctly.
+fn flat_from_fvecs(bytes: &[u8], path: &Path) -> Result<FlattenedVecs> {
+    let (dimensionality, count) = read_vecs_layout(bytes, FOUR_BYTES, path)?;
+    let flat = FlattenedVecs::read_from_mmap(bytes, count, dimensionality);
+    flat.check_layout()?;
+    Ok(flat)
+}
+
+/// Lays out `flat` in memory as the contents of a .fvecs file would be.
+fn fvecs_mmap(flat: &FlattenedVecs) -> Result<Mmap> {
+    let vec_len_in_bytes = (1 + flat.dimensionality) * FOUR_BYTES;
+    let mut mmap = memmap2::MmapMut::map_anon(flat.len() * vec_len_in_bytes)?;
+    for (fvec, vector) in std::iter::zip(
+        mmap.chunks_exact_mut(vec_len_in_bytes),
+        flat.data.chunks_exact(flat.dimensionality),
+    ) {
+        LittleEndian::write_u32(&mut fvec[..FOUR_BYTES], flat.dimensionality as u32);
+        LittleEndian::write_f32_into(vector, &mut fvec[FOUR_BYTES..]);
+    }
+    Ok(mmap.make_read_only()?)
+}
+
+impl SimilaritySearchable for FvecsDataset {
+    fn len(&self) -> usize {
+        self.count
+    }
+
+    fn get_dimensionality(&self) -> usize {
+        self.dimensionality
+    }
+
+    fn get_metadata(&self) -> &HybridSearchMetadata {
+        &self.metadata
+    }
+
+    fn vectors(&self) -> Option<&FlattenedVecs> {
+        Some(&self.flat)
+    }
+
+    fn search_mask(
+        &self,
+        predicate_query: &Option<PredicateQuery>,
+    ) -> Result<Bitmask, SearchableError> {
+        Ok(self.without_removed(match predicate_query {
+            None => Bitmask::new_full(self),
+            Some(pq) => Bitmask::try_new(pq, self)?,
+        }))
+    }
+
+    fn get_metric(&self) -> Option<DistanceMetric> {
+        self.index.as_ref().map(AcornHnswIndex::metric)
+    }
+
+    fn reconstruct(&self, ids: &[usize]) -> Result<FlattenedVecs, SearchableError> {
+        let index = self
+            .index
+            .as_ref()
+            .ok_or(SearchableError::DatasetIsNotIndexed)?;
+        // NOTE: removed vectors remain in the index, so they must be rejected here.
+        if let Some(&id) = ids
+            .iter()
+            .find(|&&id| id < self.count && self.removed.map[id] != 0)
+        {
+            return Err(SearchableError::IdRemoved(id));
+        }
+        index.reconstruct(ids)
+    }
+
+    fn index_stats(&self) -> Result<IndexStats, SearchableError> {
+        match &self.index {
+            None => Err(SearchableError::DatasetIsNotIndexed),
+            Some(index) => index.stats(),
+        }
+    }
+
+    fn graph_edges(&self, level: usize) -> Result<Vec<(usize, usize)>, SearchableError> {
+        match &self.index {
+            None => Err(SearchableError::DatasetIsNotIndexed),
+            Some(index) => index.edges(level),
+        }
+    }
+
+    fn initialize(&mut self, opts: &OakIndexOptions) -> Result<(), ConstructionError> {
+        check_metadata_len(self.count, &self.metadata)?;
+        let dimensionality = self.dimensionality as i32;
+        let index = AcornHnswIndex::new(dimensionality, &self.metadata, &self.flat, opts)?;
+        self.index = Some(index);
+        Ok(())
+    }
+
+    fn initialize_with_progress(
+        &mut self,
+        opts: &OakIndexOptions,
+        progress: &dyn Fn(f32),
+    ) -> Result<(), ConstructionError> {
+        check_metadata_len(self.count, &self.metadata)?;
+        let dimensionality = self.dimensionality;
+        let chunks = self
+            .flat
+            .data
+            .chunks(PROGRESS_CHUNK_SIZE * dimensionality)
+            .map(|chunk| FlattenedVecs {
+                dimensionality,
+                data: chunk.to_vec(),
+            });
+        let index = AcornHnswIndex::new_from_chunks_with_progress(
+            dimensionality as i32,
+            &self.metadata,
+            chunks,
+            self.count,
+            opts,
+            progress,
+        )?;
+        self.index = Some(index);
+        Ok(())
+    }
+
+    fn clear_index(&mut self) {
+        self.index = None;
+    }
+
+    fn save_index(&self, path: &Path) -> Result<(), ConstructionError> {
+        match &self.index {
+            None => Err(ConstructionError::DatasetIsNotIndexed),
+            Some(index) => index.save(path),
+        }
+    }
+
+    fn load_index(&mut self, path: &Path, opts: &OakIndexOptions) -> Result<(), ConstructionError> {
+        let dimensionality = self.dimensionality as i32;
+        let index = AcornHnswIndex::load(path, dimensionality, self.len(), &self.metadata, opts)?;
+        self.index = Some(index);
+        Ok(())
+    }
+
+    fn search_with_stats(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
         topk: usize,
         efsearch: i64,
-    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+    ) -> Result<(Vec<TopKSearchResult>, Vec<SearchStats>), SearchableError> {
+        let params = SearchParams::default();
+        self.search_with_params(query_vectors, predicate_query, topk, efsearch, &params)
+    }
+
+    fn search_with_params(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+        params: &SearchParams,
+    ) -> Result<(Vec<TopKSearchResult>, Vec<SearchStats>), SearchableError> {
         if self.index.is_none() {
             return Err(SearchableError::DatasetIsNotIndexed);
         }
@@ -304,15 +1337,36 @@ impl SimilaritySearchable for FvecsDataset {
         // debug!("query_vectors len: {}", query_vectors.len());
         // debug!("fvecs dataset len: {}", self.len());
 
-        let mut mask = match predicate_query {
+        let mask = self.without_removed(match predicate_query {
             None => Bitmask::new_full(self),
-            Some(pq) => Bitmask::new(pq, self),
-        };
+            Some(pq) => Bitmask::try_new(pq, self)?,
+        });
 
-        self.index
+        let index = self.index.as_ref().unwrap();
+        in_pool(&self.pool, || {
+            index.search_with_params(query_vectors, &mask.map, topk, efsearch, params)
+        })
+    }
+
+    fn search_ids(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+    ) -> Result<Vec<Vec<usize>>, SearchableError> {
+        let index = self
+            .index
             .as_ref()
-            .unwrap()
-            .search(query_vectors, &mut mask.map, topk, efsearch)
+            .ok_or(SearchableError::DatasetIsNotIndexed)?;
+        let mask = self.without_removed(match predicate_query {
+            None => Bitmask::new_full(self),
+            Some(pq) => Bitmask::try_new(pq, self)?,
+        });
+
+        in_pool(&self.pool, || {
+            index.search_ids(query_vectors, &mask.map, topk, efsearch)
+        })
     }
 
     fn search_with_bitmask(
@@ -322,84 +1376,215 @@ impl SimilaritySearchable for FvecsDataset {
         topk: usize,
         efsearch: i64,
     ) -> Result<Vec<TopKSearchResult>, SearchableError> {
-        let mut filter_id_map = Vec::<i8>::from(bitmask);
+        if self.index.is_none() {
+            return Err(SearchableError::DatasetIsNotIndexed);
+        }
 
-        // TODO: this & to filter_id_map should not have to be mutable
-        self.index
-            .as_ref()
-            .unwrap()
-            .search(query_vectors, &mut filter_id_map, topk, efsearch)
+        let filtered;
+        let filter_id_map = if self.removed.bitcount() == 0 {
+            &bitmask.map
+        } else {
+            filtered = bitmask.and(&self.removed.not());
+            &filtered.map
+        };
+
+        let index = self.index.as_ref().unwrap();
+        in_pool(&self.pool, || {
+            index.search(query_vectors, filter_id_map, topk, efsearch)
+        })
+    }
+
+    fn range_search(
+        &self,
+        query_vectors: &FlattenedVecs,
+        radius: f32,
+        predicate_query: &Option<PredicateQuery>,
+        efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        if self.index.is_none() {
+            return Err(SearchableError::DatasetIsNotIndexed);
+        }
+
+        let mask = self.without_removed(match predicate_query {
+            None => Bitmask::new_full(self),
+            Some(pq) => Bitmask::try_new(pq, self)?,
+        });
+
+        let index = self.index.as_ref().unwrap();
+        in_pool(&self.pool, || {
+            index.range_search(query_vectors, &mask.map, radius, efsearch)
+        })
     }
 }
 
-/// A 'partition' of the FvecsDataset, originally represented just by a base dataset and a Bitmask.
-pub struct FvecsDatasetPartition<'a> {
-    base: &'a FvecsDataset,
-    mask: Bitmask,
-    pub original_indices: Option<Vec<usize>>,
+/// Dataset sourced from a .fvecs file, which unlike `FvecsDataset` is never read into memory in
+/// its entirety. While building the index, the vectors are copied out of the file a chunk at a
+/// time. Note that ACORN itself still keeps a copy of every vector, so while the file can be larger
+/// than the memory available to Rust, the index cannot be larger than RAM.
+pub struct FvecsMmapDataset {
+    pub vectors: FvecsMmap,
     index: Option<AcornHnswIndex>,
-    /// We have an Option here so that the copying of the base vectors can be deferred to the point
-    /// at which we decide to build the index. This is an implementation detail, as one could
-    /// imagine a pure Rust implementation of the search methods that does not require this
-    /// original copy.
-    flat: Option<FlattenedVecs>,
-    /// The same with the metadata
-    metadata: HybridSearchMetadata,
+    /// The thread pool that searches are run on, if one has been set with `set_num_threads`.
+    pool: Option<ThreadPool>,
+    pub metadata: HybridSearchMetadata,
 }
 
-impl<'a> SimilaritySearchable for FvecsDatasetPartition<'a> {
+impl FvecsMmapDataset {
+    /// Open a dataset from a "{fname}.fvecs" and (if `load_csv`) a "{fname}.csv", laid out as for
+    /// `FvecsDataset::new`. Only the attributes are loaded into memory.
+    pub fn new(fname: String, load_csv: bool) -> Result<Self> {
+        let vectors = FvecsMmap::open(&fvecs_path(&fname))?;
+
+        let metadata = if load_csv {
+            let mut metadata_fname = PathBuf::new();
+            metadata_fname.push(format!("{}.csv", fname));
+            read_csv_to_metadata(&metadata_fname)?
+        } else {
+            HybridSearchMetadata::new(1, vec![])
+        };
+
+        Ok(Self {
+            vectors,
+            index: None,
+            pool: None,
+            metadata,
+        })
+    }
+}
+
+impl FvecsMmapDataset {
+    /// Sets the attributes of the vectors once they are known, as for `FvecsDataset::set_metadata`.
+    pub fn set_metadata(
+        &mut self,
+        metadata: HybridSearchMetadata,
+    ) -> Result<(), ConstructionError> {
+        set_metadata_checked(&mut self.metadata, self.vectors.len(), metadata)
+    }
+
+    /// Sets the number of threads that the queries in a batch are searched with, as for
+    /// `FvecsDataset::set_num_threads`.
+    pub fn set_num_threads(&mut self, num_threads: usize) -> Result<(), ThreadPoolBuildError> {
+        self.pool = Some(ThreadPoolBuilder::new().num_threads(num_threads).build()?);
+        Ok(())
+    }
+}
+
+impl SimilaritySearchable for FvecsMmapDataset {
     fn len(&self) -> usize {
-        self.mask.bitcount()
+        self.vectors.len()
+    }
+
+    fn get_dimensionality(&self) -> usize {
+        self.vectors.dimensionality()
     }
 
     fn get_metadata(&self) -> &HybridSearchMetadata {
         &self.metadata
     }
 
-    fn get_dimensionality(&self) -> usize {
-        self.base.dimensionality
+    fn get_metric(&self) -> Option<DistanceMetric> {
+        self.index.as_ref().map(AcornHnswIndex::metric)
     }
 
-    fn initialize(&mut self, opts: &OakIndexOptions) -> Result<(), ConstructionError> {
-        let og = &self.base.flat;
-        let (original_indices, flat) = og.clone_via_bitmask(&self.mask);
+    fn reconstruct(&self, ids: &[usize]) -> Result<FlattenedVecs, SearchableError> {
+        match &self.index {
+            None => Err(SearchableError::DatasetIsNotIndexed),
+            Some(index) => index.reconstruct(ids),
+        }
+    }
 
-        // assert_eq!(flat.len(), self.mask.bitcount());
-        // assert_eq!(self.metadata.len(), self.mask.bitcount());
+    fn index_stats(&self) -> Result<IndexStats, SearchableError> {
+        match &self.index {
+            None => Err(SearchableError::DatasetIsNotIndexed),
+            Some(index) => index.stats(),
+        }
+    }
 
-        let dimensionality = self.get_dimensionality() as i32;
-        let index = AcornHnswIndex::new(dimensionality, &self.metadata, &flat, opts)?;
+    fn graph_edges(&self, level: usize) -> Result<Vec<(usize, usize)>, SearchableError> {
+        match &self.index {
+            None => Err(SearchableError::DatasetIsNotIndexed),
+            Some(index) => index.edges(level),
+        }
+    }
 
+    fn initialize(&mut self, opts: &OakIndexOptions) -> Result<(), ConstructionError> {
+        self.initialize_with_progress(opts, &|_| {})
+    }
+
+    fn initialize_with_progress(
+        &mut self,
+        opts: &OakIndexOptions,
+        progress: &dyn Fn(f32),
+    ) -> Result<(), ConstructionError> {
+        let dimensionality = self.get_dimensionality() as i32;
+        let count = self.len();
+        check_metadata_len(count, &self.metadata)?;
+        let chunks = (0..count).step_by(MMAP_CHUNK_SIZE).map(|start| {
+            self.vectors
+                .read_range(start..(start + MMAP_CHUNK_SIZE).min(count))
+        });
+        let index = AcornHnswIndex::new_from_chunks_with_progress(
+            dimensionality,
+            &self.metadata,
+            chunks,
+            count,
+            opts,
+            progress,
+        )?;
         self.index = Some(index);
-        self.flat = Some(flat);
-        self.original_indices = Some(original_indices);
+        Ok(())
+    }
+
+    fn clear_index(&mut self) {
+        self.index = None;
+    }
 
+    fn save_index(&self, path: &Path) -> Result<(), ConstructionError> {
+        match &self.index {
+            None => Err(ConstructionError::DatasetIsNotIndexed),
+            Some(index) => index.save(path),
+        }
+    }
+
+    fn load_index(&mut self, path: &Path, opts: &OakIndexOptions) -> Result<(), ConstructionError> {
+        let dimensionality = self.get_dimensionality() as i32;
+        let index = AcornHnswIndex::load(path, dimensionality, self.len(), &self.metadata, opts)?;
+        self.index = Some(index);
         Ok(())
     }
 
-    fn search(
+    fn search_with_stats(
         &self,
         query_vectors: &FlattenedVecs,
         predicate_query: &Option<PredicateQuery>,
         topk: usize,
         efsearch: i64,
-    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+    ) -> Result<(Vec<TopKSearchResult>, Vec<SearchStats>), SearchableError> {
+        let params = SearchParams::default();
+        self.search_with_params(query_vectors, predicate_query, topk, efsearch, &params)
+    }
+
+    fn search_with_params(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+        params: &SearchParams,
+    ) -> Result<(Vec<TopKSearchResult>, Vec<SearchStats>), SearchableError> {
         if self.index.is_none() {
             return Err(SearchableError::DatasetIsNotIndexed);
         }
 
-        // debug!("query_vectors len: {}", query_vectors.len());
-        // debug!("fvecs dataset len: {}", self.len());
-
-        let mut mask = match predicate_query {
+        let mask = match predicate_query {
             None => Bitmask::new_full(self),
-            Some(pq) => Bitmask::new(pq, self),
+            Some(pq) => Bitmask::try_new(pq, self)?,
         };
 
-        self.index
-            .as_ref()
-            .unwrap()
-            .search(query_vectors, &mut mask.map, topk, efsearch)
+        let index = self.index.as_ref().unwrap();
+        in_pool(&self.pool, || {
+            index.search_with_params(query_vectors, &mask.map, topk, efsearch, params)
+        })
     }
 
     fn search_with_bitmask(
@@ -409,46 +1594,2619 @@ impl<'a> SimilaritySearchable for FvecsDatasetPartition<'a> {
         topk: usize,
         efsearch: i64,
     ) -> Result<Vec<TopKSearchResult>, SearchableError> {
-        let mut filter_id_map = Vec::<i8>::from(bitmask);
+        if self.index.is_none() {
+            return Err(SearchableError::DatasetIsNotIndexed);
+        }
 
-        // TODO: this & to filter_id_map should not have to be mutable
-        self.index
-            .as_ref()
-            .unwrap()
-            .search(query_vectors, &mut filter_id_map, topk, efsearch)
+        let filter_id_map = &bitmask.map;
+
+        let index = self.index.as_ref().unwrap();
+        in_pool(&self.pool, || {
+            index.search(query_vectors, filter_id_map, topk, efsearch)
+        })
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::stubs::generate_random_vector;
-    use std::sync::Once;
+    fn range_search(
+        &self,
+        query_vectors: &FlattenedVecs,
+        radius: f32,
+        predicate_query: &Option<PredicateQuery>,
+        efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        if self.index.is_none() {
+            return Err(SearchableError::DatasetIsNotIndexed);
+        }
 
-    #[test]
-    fn test_not_initialized_error() {
-        let dataset = FvecsDataset::new("data/sift_query".to_string(), false).unwrap();
-        let predicate: Option<PredicateQuery> = None;
-        let dimensionality = dataset.dimensionality;
-        let query_vector = FlattenedVecs {
-            dimensionality,
-            data: generate_random_vector(dimensionality),
+        let mask = match predicate_query {
+            None => Bitmask::new_full(self),
+            Some(pq) => Bitmask::try_new(pq, self)?,
         };
 
-        assert!(dataset.index.is_none());
-        // TODO: the following gives a build error; something to do with
-        // undefined reference to `typeinfo for faiss::FaissException'
-        // i.e. the handling of errors across the FFI boundary.
-        // let result = dataset.search(query_vector, predicate, 1);
-        // assert_eq!(result, Err(SearchableError::DatasetIsNotIndexed));
+        let index = self.index.as_ref().unwrap();
+        in_pool(&self.pool, || {
+            index.range_search(query_vectors, &mask.map, radius, efsearch)
+        })
     }
+}
 
-    #[test]
-    fn test_fvecs_to_flattened_vec() {
-        let dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
-        let dataset_len = dataset.len();
-        let vecs = FlattenedVecs::from(&dataset);
-
-        assert_eq!(vecs.len(), dataset_len);
+/// A 'partition' of the FvecsDataset, originally represented just by a base dataset and a Bitmask.
+pub struct FvecsDatasetPartition<'a> {
+    base: &'a FvecsDataset,
+    mask: Bitmask,
+    pub original_indices: Option<Vec<usize>>,
+    index: Option<AcornHnswIndex>,
+    /// The thread pool that searches are run on, if one has been set with `set_num_threads`.
+    pool: Option<ThreadPool>,
+    /// We have an Option here so that the copying of the base vectors can be deferred to the point
+    /// at which we decide to build the index. This is an implementation detail, as one could
+    /// imagine a pure Rust implementation of the search methods that does not require this
+    /// original copy.
+    flat: Option<FlattenedVecs>,
+    /// The same with the metadata
+    metadata: HybridSearchMetadata,
+}
+
+impl FvecsDatasetPartition<'_> {
+    /// Sets the number of threads that the queries in a batch are searched with, as for
+    /// `FvecsDataset::set_num_threads`.
+    pub fn set_num_threads(&mut self, num_threads: usize) -> Result<(), ThreadPoolBuildError> {
+        self.pool = Some(ThreadPoolBuilder::new().num_threads(num_threads).build()?);
+        Ok(())
+    }
+}
+
+impl<'a> SimilaritySearchable for FvecsDatasetPartition<'a> {
+    fn len(&self) -> usize {
+        self.mask.bitcount()
+    }
+
+    fn get_metadata(&self) -> &HybridSearchMetadata {
+        &self.metadata
+    }
+
+    fn vectors(&self) -> Option<&FlattenedVecs> {
+        self.flat.as_ref()
+    }
+
+    fn get_metric(&self) -> Option<DistanceMetric> {
+        self.index.as_ref().map(AcornHnswIndex::metric)
+    }
+
+    fn reconstruct(&self, ids: &[usize]) -> Result<FlattenedVecs, SearchableError> {
+        match &self.index {
+            None => Err(SearchableError::DatasetIsNotIndexed),
+            Some(index) => index.reconstruct(ids),
+        }
+    }
+
+    fn index_stats(&self) -> Result<IndexStats, SearchableError> {
+        match &self.index {
+            None => Err(SearchableError::DatasetIsNotIndexed),
+            Some(index) => index.stats(),
+        }
+    }
+
+    fn graph_edges(&self, level: usize) -> Result<Vec<(usize, usize)>, SearchableError> {
+        match &self.index {
+            None => Err(SearchableError::DatasetIsNotIndexed),
+            Some(index) => index.edges(level),
+        }
+    }
+
+    fn get_dimensionality(&self) -> usize {
+        self.base.dimensionality
+    }
+
+    fn initialize(&mut self, opts: &OakIndexOptions) -> Result<(), ConstructionError> {
+        let og = &self.base.flat;
+        let (original_indices, flat) = og.clone_via_bitmask(&self.mask);
+
+        // assert_eq!(flat.len(), self.mask.bitcount());
+        // assert_eq!(self.metadata.len(), self.mask.bitcount());
+
+        let dimensionality = self.get_dimensionality() as i32;
+        let index = AcornHnswIndex::new(dimensionality, &self.metadata, &flat, opts)?;
+
+        self.index = Some(index);
+        self.flat = Some(flat);
+        self.original_indices = Some(original_indices);
+
+        Ok(())
+    }
+
+    fn clear_index(&mut self) {
+        self.index = None;
+    }
+
+    fn save_index(&self, path: &Path) -> Result<(), ConstructionError> {
+        match &self.index {
+            None => Err(ConstructionError::DatasetIsNotIndexed),
+            Some(index) => index.save(path),
+        }
+    }
+
+    fn load_index(&mut self, path: &Path, opts: &OakIndexOptions) -> Result<(), ConstructionError> {
+        let og = &self.base.flat;
+        let (original_indices, flat) = og.clone_via_bitmask(&self.mask);
+
+        let dimensionality = self.get_dimensionality() as i32;
+        let index = AcornHnswIndex::load(path, dimensionality, flat.len(), &self.metadata, opts)?;
+
+        self.index = Some(index);
+        self.flat = Some(flat);
+        self.original_indices = Some(original_indices);
+
+        Ok(())
+    }
+
+    fn search_with_stats(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+    ) -> Result<(Vec<TopKSearchResult>, Vec<SearchStats>), SearchableError> {
+        let params = SearchParams::default();
+        self.search_with_params(query_vectors, predicate_query, topk, efsearch, &params)
+    }
+
+    fn search_with_params(
+        &self,
+        query_vectors: &FlattenedVecs,
+        predicate_query: &Option<PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+        params: &SearchParams,
+    ) -> Result<(Vec<TopKSearchResult>, Vec<SearchStats>), SearchableError> {
+        if self.index.is_none() {
+            return Err(SearchableError::DatasetIsNotIndexed);
+        }
+
+        // debug!("query_vectors len: {}", query_vectors.len());
+        // debug!("fvecs dataset len: {}", self.len());
+
+        let mask = match predicate_query {
+            None => Bitmask::new_full(self),
+            Some(pq) => Bitmask::try_new(pq, self)?,
+        };
+
+        let index = self.index.as_ref().unwrap();
+        in_pool(&self.pool, || {
+            index.search_with_params(query_vectors, &mask.map, topk, efsearch, params)
+        })
+    }
+
+    fn search_with_bitmask(
+        &self,
+        query_vectors: &FlattenedVecs,
+        bitmask: &Bitmask,
+        topk: usize,
+        efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        if self.index.is_none() {
+            return Err(SearchableError::DatasetIsNotIndexed);
+        }
+
+        let filter_id_map = &bitmask.map;
+
+        let index = self.index.as_ref().unwrap();
+        in_pool(&self.pool, || {
+            index.search(query_vectors, filter_id_map, topk, efsearch)
+        })
+    }
+
+    fn range_search(
+        &self,
+        query_vectors: &FlattenedVecs,
+        radius: f32,
+        predicate_query: &Option<PredicateQuery>,
+        efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        if self.index.is_none() {
+            return Err(SearchableError::DatasetIsNotIndexed);
+        }
+
+        let mask = match predicate_query {
+            None => Bitmask::new_full(self),
+            Some(pq) => Bitmask::try_new(pq, self)?,
+        };
+
+        let index = self.index.as_ref().unwrap();
+        in_pool(&self.pool, || {
+            index.range_search(query_vectors, &mask.map, radius, efsearch)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataset::{PreTransform, DEFAULT_EF_SEARCH, FLAT_RESULT_PADDING_ID};
+    use crate::stubs::generate_random_vector;
+
+    #[test]
+    fn test_search_with_attrs() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let dimensionality = dataset.get_dimensionality();
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: generate_random_vector(dimensionality * 2),
+        };
+        let predicate = Some(PredicateQuery::range(2, 5));
+        let expected = dataset.search(&query_vector, &predicate, 5, 16).unwrap();
+        let results = dataset
+            .search_with_attrs(&query_vector, &predicate, 5, 16)
+            .unwrap();
+
+        assert_eq!(results.len(), expected.len());
+        for (result, expected) in std::iter::zip(results, expected) {
+            assert_eq!(result.len(), expected.len());
+            for ((id, distance, attr), (expected_id, expected_distance)) in
+                std::iter::zip(result, expected)
+            {
+                assert_eq!((id, distance), (expected_id, expected_distance));
+                assert_eq!(attr, Some(dataset.get_metadata().attrs_for(id)[0]));
+                assert!((2..=5).contains(&attr.unwrap()));
+            }
+        }
+
+        // Null attributes are reported as such, rather than as the 0 that stands in for them.
+        let count = dataset.len();
+        let attrs = (0..count)
+            .map(|id| (id % 2 == 0).then_some(id as i64 + 1))
+            .collect();
+        dataset
+            .set_metadata(HybridSearchMetadata::from_nullable(1, attrs))
+            .unwrap();
+        let queries = FlattenedVecs {
+            dimensionality,
+            data: dataset.flat.data[..2 * dimensionality].to_vec(),
+        };
+        let results = dataset.search_with_attrs(&queries, &None, 5, 16).unwrap();
+        assert_eq!(results[0][0], (0, 0., Some(1)));
+        assert_eq!(results[1][0], (1, 0., None));
+
+        let mut without_attrs = FvecsDataset::new("data/sift_query".to_string(), false).unwrap();
+        without_attrs
+            .initialize(&OakIndexOptions::default())
+            .unwrap();
+        assert_eq!(
+            without_attrs.search_with_attrs(&queries, &None, 5, 16),
+            Err(SearchableError::MissingMetadata)
+        );
+    }
+
+    #[test]
+    fn test_set_metadata() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), false).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let queries = FlattenedVecs {
+            dimensionality: dataset.get_dimensionality(),
+            data: dataset.flat.data[..2 * dataset.get_dimensionality()].to_vec(),
+        };
+        let predicate = Some(PredicateQuery::new(1));
+
+        // Before the attributes are set, only unfiltered searches can be run.
+        dataset.search(&queries, &None, 5, 16).unwrap();
+        assert_eq!(
+            dataset.search(&queries, &predicate, 5, 16),
+            Err(SearchableError::MissingMetadata)
+        );
+
+        let count = dataset.len();
+        assert!(matches!(
+            dataset.set_metadata(HybridSearchMetadata::new(1, vec![1; count + 1])),
+            Err(ConstructionError::MetadataLengthMismatch { vectors, attrs })
+                if vectors == count && attrs == count + 1
+        ));
+        assert!(dataset.get_metadata().is_empty());
+
+        let attrs = (0..count as i64).map(|id| id % 2).collect();
+        dataset
+            .set_metadata(HybridSearchMetadata::new(1, attrs))
+            .unwrap();
+        let results = dataset.search(&queries, &predicate, 5, 16).unwrap();
+        assert!(results
+            .iter()
+            .flatten()
+            .all(|&(id, _)| dataset.get_metadata().attrs_for(id)[0] == 1));
+        assert_eq!(results[1][0].0, 1);
+    }
+
+    #[test]
+    fn test_metadata_length_is_checked() {
+        let count = FvecsDataset::new("data/sift_query".to_string(), false)
+            .unwrap()
+            .len();
+
+        let metadata = HybridSearchMetadata::new(1, vec![1; count]);
+        let dataset = FvecsDataset::with_metadata("data/sift_query".to_string(), metadata).unwrap();
+        assert_eq!(dataset.get_metadata().len(), count);
+
+        let metadata = HybridSearchMetadata::new(1, vec![1; count - 1]);
+        let err = FvecsDataset::with_metadata("data/sift_query".to_string(), metadata)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<ConstructionError>(),
+            Some(ConstructionError::MetadataLengthMismatch { vectors, attrs })
+                if *vectors == count && *attrs == count - 1
+        ));
+
+        // A CSV file with too few rows is only caught when the index is built.
+        let fname = std::env::temp_dir().join("oak_test_metadata_length");
+        let fname = fname.to_str().unwrap().to_string();
+        std::fs::copy("data/sift_query.fvecs", format!("{fname}.fvecs")).unwrap();
+        std::fs::write(format!("{fname}.csv"), "1\n2\n3\n").unwrap();
+        let mut dataset = FvecsDataset::new(fname.clone(), true).unwrap();
+        assert!(matches!(
+            dataset.initialize(&OakIndexOptions::default()),
+            Err(ConstructionError::MetadataLengthMismatch { attrs: 3, .. })
+        ));
+
+        std::fs::remove_file(format!("{fname}.fvecs")).unwrap();
+        std::fs::remove_file(format!("{fname}.csv")).unwrap();
+    }
+
+    #[test]
+    fn test_filtered_search_matches_brute_force() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let queries = FlattenedVecs::from(&dataset);
+
+        for predicate in [PredicateQuery::new(1), PredicateQuery::range(2, 5)] {
+            let bitmask = Bitmask::new(&predicate, &dataset);
+            let results = dataset
+                .search_with_bitmask(&queries, &bitmask, 10, 64)
+                .unwrap();
+            let exact = crate::metrics::brute_force_search_with_bitmask(
+                &dataset.flat,
+                &queries,
+                &bitmask,
+                10,
+                DistanceMetric::L2,
+            );
+            assert!(results
+                .iter()
+                .flatten()
+                .all(|(id, _)| bitmask.map[*id] == 1));
+            let recall = crate::metrics::recall_at_k(
+                &results,
+                &exact
+                    .iter()
+                    .map(|row| row.iter().map(|(id, _)| *id).collect())
+                    .collect::<Vec<_>>(),
+                10,
+            );
+            assert!(recall > 0.9, "recall was {recall}");
+        }
+    }
+
+    #[test]
+    fn test_search_rerank() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let dimensionality = dataset.get_dimensionality();
+        let queries = FlattenedVecs {
+            dimensionality,
+            data: generate_random_vector(dimensionality * 3),
+        };
+        let results = dataset.search_rerank(&queries, &None, 5, 4, 16).unwrap();
+        let exact = crate::metrics::brute_force_knn(
+            &FlattenedVecs::from(&dataset),
+            &queries,
+            5,
+            DistanceMetric::L2,
+        );
+
+        // The distances are recomputed exactly, so they agree with brute force wherever the ids do.
+        for (result, exact) in std::iter::zip(&results, &exact) {
+            assert_eq!(result.len(), 5);
+            assert!(result.windows(2).all(|w| w[0].1 <= w[1].1));
+            for (id, distance) in result {
+                if let Some((_, expected)) = exact.iter().find(|(exact_id, _)| exact_id == id) {
+                    assert!((distance - expected).abs() <= expected * 1e-4);
+                }
+            }
+        }
+        let recall = crate::metrics::recall_at_k(
+            &results,
+            &exact
+                .iter()
+                .map(|row| row.iter().map(|(id, _)| *id).collect())
+                .collect::<Vec<_>>(),
+            5,
+        );
+        assert!(recall > 0.9, "recall was {recall}");
+
+        // Re-ranking by a custom distance that agrees with the metric changes nothing.
+        let custom = dataset
+            .search_rerank_with(&queries, &None, 5, 4, 16, &DistanceMetric::L2)
+            .unwrap();
+        assert_eq!(custom, results);
+    }
+
+    #[test]
+    fn test_search_with_metric_override() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let dimensionality = dataset.get_dimensionality();
+        let queries = FlattenedVecs {
+            dimensionality,
+            data: generate_random_vector(dimensionality * 3),
+        };
+        let results = dataset.search(&queries, &None, 10, 16).unwrap();
+        let overridden = dataset
+            .search_with_metric_override(&queries, &None, 10, 16, DistanceMetric::InnerProduct)
+            .unwrap();
+
+        // The same neighbors are returned, but ranked by (descending) inner product instead.
+        for (query, (result, overridden)) in std::iter::zip(&results, &overridden).enumerate() {
+            let mut ids: Vec<_> = result.iter().map(|(id, _)| *id).collect();
+            let mut overridden_ids: Vec<_> = overridden.iter().map(|(id, _)| *id).collect();
+            ids.sort();
+            overridden_ids.sort();
+            assert_eq!(ids, overridden_ids);
+
+            assert!(overridden.windows(2).all(|w| w[0].1 >= w[1].1));
+            for (id, distance) in overridden {
+                let expected = crate::metrics::exact_distance(
+                    DistanceMetric::InnerProduct,
+                    queries.get(query).unwrap(),
+                    dataset.get_vector(*id).unwrap(),
+                );
+                assert!((distance - expected).abs() <= expected.abs() * 1e-4);
+            }
+        }
+
+        // Overriding the metric with the one the index was built with changes nothing.
+        let same = dataset
+            .search_with_metric_override(&queries, &None, 10, 16, DistanceMetric::L2)
+            .unwrap();
+        crate::test_utils::assert_results_approx_eq(&same, &results, 10.);
+
+        let mmap_dataset = FvecsMmapDataset::new("data/sift_query".to_string(), true).unwrap();
+        assert_eq!(
+            mmap_dataset.search_with_metric_override(
+                &queries,
+                &None,
+                10,
+                16,
+                DistanceMetric::InnerProduct
+            ),
+            Err(SearchableError::VectorsNotInMemory)
+        );
+    }
+
+    #[test]
+    fn test_initialize_with_progress() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        let reports = std::cell::RefCell::new(vec![]);
+        dataset
+            .initialize_with_progress(&OakIndexOptions::default(), &|p| {
+                reports.borrow_mut().push(p)
+            })
+            .unwrap();
+        assert_eq!(reports.borrow().first(), Some(&0.));
+        assert_eq!(reports.borrow().last(), Some(&1.));
+        assert!(dataset.index_stats().is_ok());
+
+        // Progress is reported after each chunk.
+        let reports = std::cell::RefCell::new(vec![]);
+        let count = dataset.len();
+        AcornHnswIndex::new_from_chunks_with_progress(
+            dataset.dimensionality as i32,
+            dataset.get_metadata(),
+            FlattenedVecs::from(&dataset).to_vec(),
+            count,
+            &OakIndexOptions::default(),
+            &|p| reports.borrow_mut().push(p),
+        )
+        .unwrap();
+        let reports = reports.into_inner();
+        assert_eq!(reports.len(), count + 1);
+        assert!(reports.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(reports[1], 1. / count as f32);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let mut other = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        other.remove_vectors(&[1]).unwrap();
+        // More removed vectors across the two datasets than an i8 can count.
+        let removed: Vec<usize> = (100..200).collect();
+        dataset.remove_vectors(&removed).unwrap();
+        other.remove_vectors(&removed).unwrap();
+        let count = dataset.len();
+
+        dataset.merge(other).unwrap();
+        assert_eq!(dataset.len(), 2 * count);
+        assert_eq!(dataset.removed.bitcount(), 201);
+        assert_eq!(dataset.removed.count_ones(), 201);
+        assert_eq!(dataset.get_metadata().len(), 2 * count);
+        assert_eq!(
+            dataset.get_metadata().attrs_for(count + 2),
+            dataset.get_metadata().attrs_for(2)
+        );
+        assert_eq!(
+            dataset.index_stats(),
+            Err(SearchableError::DatasetIsNotIndexed)
+        );
+
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let dimensionality = dataset.dimensionality;
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: dataset.flat.data[dimensionality..2 * dimensionality].to_vec(),
+        };
+        // The copy of vector 1 in the second half was removed before the merge.
+        let result = dataset.search(&query_vector, &None, 2, 64).unwrap();
+        assert_eq!(result[0][0], (1, 0.));
+        assert_ne!(result[0][1].0, count + 1);
+
+        let without_attrs = FvecsDataset::new("data/sift_query".to_string(), false).unwrap();
+        assert!(matches!(
+            dataset.merge(without_attrs),
+            Err(ConstructionError::MetadataLengthMismatch { .. })
+        ));
+        assert_eq!(dataset.len(), 2 * count);
+    }
+
+    #[test]
+    fn test_get_vector() {
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        let dimensionality = dataset.dimensionality;
+        let mmap = FvecsMmap::open(Path::new("data/sift_query.fvecs")).unwrap();
+
+        assert_eq!(dataset.get_vector(3), Some(mmap.get(3).data()));
+        assert_eq!(dataset.get_vector(3).unwrap().len(), dimensionality);
+        assert_eq!(dataset.get_vector(dataset.len()), None);
+        assert_eq!(dataset.get_vector(usize::MAX), None);
+        assert_eq!(&dataset[3], mmap.get(3).data());
+
+        // The vectors of a dataset that is not held in memory cannot be borrowed.
+        let mmap_dataset = FvecsMmapDataset::new("data/sift_query".to_string(), true).unwrap();
+        assert_eq!(mmap_dataset.get_vector(3), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "index 200 is out of range for 200 vectors")]
+    fn test_index_out_of_range() {
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), false).unwrap();
+        assert_eq!(dataset.len(), 200);
+        let _ = &dataset[200];
+    }
+
+    #[test]
+    fn test_reconstruct() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        assert!(matches!(
+            dataset.reconstruct(&[0]),
+            Err(SearchableError::DatasetIsNotIndexed)
+        ));
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let vecs = dataset.reconstruct(&[5, 2, 5]).unwrap();
+        assert_eq!(vecs.dimensionality, dataset.dimensionality);
+        assert_eq!(vecs.len(), 3);
+        assert_eq!(vecs.get(0), dataset.get_vector(5));
+        assert_eq!(vecs.get(1), dataset.get_vector(2));
+        assert_eq!(vecs.get(2), dataset.get_vector(5));
+
+        let len = dataset.len();
+        assert!(matches!(
+            dataset.reconstruct(&[1, len]),
+            Err(SearchableError::IdOutOfRange { id, len: l }) if id == len && l == len
+        ));
+
+        dataset.remove_vectors(&[2]).unwrap();
+        assert!(matches!(
+            dataset.reconstruct(&[1, 2]),
+            Err(SearchableError::IdRemoved(2))
+        ));
+    }
+
+    #[test]
+    fn test_fvec_distances() {
+        let a = Fvec {
+            dimensionality: 3,
+            data: vec![1., 2., 3.],
+        };
+        let b = Fvec {
+            dimensionality: 3,
+            data: vec![4., 6., 3.],
+        };
+        assert_eq!(a.l2_distance(&b), 5.);
+        assert_eq!(a.l2_distance(&a), 0.);
+        assert_eq!(a.dot(&b), 25.);
+        assert_eq!(
+            a.dot(&b),
+            crate::metrics::exact_distance(DistanceMetric::InnerProduct, a.data(), b.data())
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+        let mut vector = Fvec {
+            dimensionality: 3,
+            data: vec![3., 0., 4.],
+        };
+        vector.normalize();
+        assert_eq!(vector.data(), [0.6, 0., 0.8]);
+
+        let mut vecs = FlattenedVecs {
+            dimensionality: 128,
+            data: generate_random_vector(128 * 4),
+        };
+        vecs.data[128..256].fill(0.);
+        let copy = vecs.to_normalized();
+        vecs.normalize_all();
+        assert_eq!(vecs.data, copy.data);
+        for (i, v) in vecs.data.chunks_exact(128).enumerate() {
+            if i == 1 {
+                // The zero vector is left as it is, rather than becoming NaNs.
+                assert!(v.iter().all(|&x| x == 0.));
+            } else {
+                assert!((norm(v) - 1.).abs() < 1e-5, "{}", norm(v));
+            }
+        }
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        assert_eq!(dataset.iter().count(), dataset.len());
+        for (id, vector, attrs) in dataset.iter().take(5) {
+            assert_eq!(Some(vector), dataset.get_vector(id));
+            assert_eq!(attrs, dataset.metadata.attrs_for(id));
+        }
+
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        dataset.remove_vectors(&[1]).unwrap();
+        let ids: Vec<_> = dataset.iter().map(|(id, _, _)| id).take(3).collect();
+        assert_eq!(ids, vec![0, 2, 3]);
+
+        let without_attrs = FvecsDataset::new("data/sift_query".to_string(), false).unwrap();
+        assert!(without_attrs.iter().all(|(_, _, attrs)| attrs.is_empty()));
+    }
+
+    #[test]
+    fn test_search_per_query() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let dimensionality = dataset.dimensionality;
+        let queries = FlattenedVecs {
+            dimensionality,
+            data: dataset.flat.data[..3 * dimensionality].to_vec(),
+        };
+        let predicates = vec![
+            None,
+            Some(PredicateQuery::new(1)),
+            Some(PredicateQuery::new(2)),
+        ];
+        let results = dataset
+            .search_per_query(&queries, &predicates, 10, 64)
+            .unwrap();
+        assert_eq!(results.len(), 3);
+        for (i, predicate) in predicates.iter().enumerate() {
+            let query = FlattenedVecs {
+                dimensionality,
+                data: queries.data[i * dimensionality..(i + 1) * dimensionality].to_vec(),
+            };
+            let expected = dataset.search(&query, predicate, 10, 64).unwrap();
+            assert_eq!(results[i], expected[0]);
+        }
+
+        assert_eq!(
+            dataset.search_per_query(&queries, &predicates[..2], 10, 64),
+            Err(SearchableError::PredicateCountMismatch {
+                queries: 3,
+                predicates: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_clear_index() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        assert!(!dataset.is_indexed());
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        assert!(dataset.is_indexed());
+
+        let query = FlattenedVecs {
+            dimensionality: dataset.dimensionality,
+            data: dataset.get_vector(0).unwrap().to_vec(),
+        };
+        assert!(dataset.search(&query, &None, 10, 16).is_ok());
+
+        dataset.clear_index();
+        assert!(!dataset.is_indexed());
+        assert_eq!(
+            dataset.search(&query, &None, 10, 16),
+            Err(SearchableError::DatasetIsNotIndexed)
+        );
+        assert_eq!(
+            dataset.search_with_bitmask(&query, &Bitmask::new_full(&dataset), 10, 16),
+            Err(SearchableError::DatasetIsNotIndexed)
+        );
+        assert_eq!(dataset.len(), dataset.metadata.len());
+
+        let opts = OakIndexOptions::builder().m(16).m_beta(32).build().unwrap();
+        dataset.initialize(&opts).unwrap();
+        assert_eq!(dataset.search(&query, &None, 10, 16).unwrap()[0][0].0, 0);
+    }
+
+    #[test]
+    fn test_warmup() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        assert_eq!(dataset.warmup(5), Err(SearchableError::DatasetIsNotIndexed));
+
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        assert_eq!(dataset.warmup(5), Ok(()));
+    }
+
+    #[test]
+    fn test_benchmark_search() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let queries = dataset.sample_queries(5, 0, None).unwrap();
+        let predicate = Some(PredicateQuery::new(dataset.metadata.attrs_for(0)[0] as u8));
+        let report = dataset
+            .benchmark_search(&queries, &predicate, 10, 16, 3)
+            .unwrap();
+        assert_eq!(report.searches, 15);
+        assert!(report.p50 <= report.p95 && report.p95 <= report.p99);
+        assert!(report.mean > std::time::Duration::ZERO);
+        assert!(report.qps > 0.);
+    }
+
+    #[test]
+    fn test_sample_queries() {
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), false).unwrap();
+        let queries = dataset.sample_queries(10, 42, None).unwrap();
+        assert_eq!(queries.dimensionality, dataset.dimensionality);
+        assert_eq!(queries.len(), 10);
+        assert_eq!(
+            dataset.sample_queries(10, 42, None).unwrap().data,
+            queries.data
+        );
+
+        // Each query is a distinct vector of the dataset.
+        let mut ids: Vec<_> = (0..queries.len())
+            .map(|i| {
+                (0..dataset.len())
+                    .find(|&id| dataset.get_vector(id) == queries.get(i))
+                    .unwrap()
+            })
+            .collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 10);
+
+        let jittered = dataset.sample_queries(10, 42, Some(0.5)).unwrap();
+        assert_ne!(jittered.data, queries.data);
+        assert!(std::iter::zip(&jittered.data, &queries.data).all(|(a, b)| (a - b).abs() <= 0.5));
+
+        assert_eq!(
+            dataset
+                .sample_queries(dataset.len() + 5, 0, None)
+                .unwrap()
+                .len(),
+            dataset.len()
+        );
+
+        // Without the vectors in memory, they are reconstructed from the index.
+        let mut mmap_dataset = FvecsMmapDataset::new("data/sift_query".to_string(), true).unwrap();
+        assert!(matches!(
+            mmap_dataset.sample_queries(10, 42, None),
+            Err(SearchableError::DatasetIsNotIndexed)
+        ));
+        mmap_dataset
+            .initialize(&OakIndexOptions::default())
+            .unwrap();
+        assert_eq!(
+            mmap_dataset.sample_queries(10, 42, None).unwrap().data,
+            queries.data
+        );
+    }
+
+    #[test]
+    fn test_not_initialized_error() {
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), false).unwrap();
+        let _predicate: Option<PredicateQuery> = None;
+        let dimensionality = dataset.dimensionality;
+        let _query_vector = FlattenedVecs {
+            dimensionality,
+            data: generate_random_vector(dimensionality),
+        };
+
+        assert!(dataset.index.is_none());
+        // TODO: the following gives a build error; something to do with
+        // undefined reference to `typeinfo for faiss::FaissException'
+        // i.e. the handling of errors across the FFI boundary.
+        // let result = dataset.search(query_vector, predicate, 1);
+        // assert_eq!(result, Err(SearchableError::DatasetIsNotIndexed));
+    }
+
+    #[test]
+    fn test_fvecs_to_flattened_vec() {
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        let dataset_len = dataset.len();
+        let vecs = FlattenedVecs::from(&dataset);
+
+        assert_eq!(vecs.len(), dataset_len);
+    }
+
+    #[test]
+    fn test_save_and_load_index() {
+        let opts = OakIndexOptions::default();
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&opts).unwrap();
+
+        let path = std::env::temp_dir().join("oak_test_save_and_load_index.idx");
+        dataset.save_index(&path).unwrap();
+
+        let mut loaded = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        loaded.load_index(&path, &opts).unwrap();
+
+        let dimensionality = dataset.dimensionality;
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: generate_random_vector(dimensionality),
+        };
+        let expected = dataset.search(&query_vector, &None, 10, 16).unwrap();
+        let actual = loaded.search(&query_vector, &None, 10, 16).unwrap();
+        assert_eq!(expected, actual);
+
+        let other_opts = OakIndexOptions {
+            gamma: 2,
+            ..OakIndexOptions::default()
+        };
+        let result = loaded.load_index(&path, &other_opts);
+        assert!(matches!(
+            result,
+            Err(ConstructionError::IndexMismatch { field: "gamma", .. })
+        ));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_seeded_builds_are_identical() {
+        let build = |seed: u64, name: &str| {
+            let opts = OakIndexOptions::builder().seed(seed).build().unwrap();
+            let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+            dataset.initialize(&opts).unwrap();
+            let path = std::env::temp_dir().join(name);
+            dataset.save_index(&path).unwrap();
+            let bytes = std::fs::read(&path).unwrap();
+            std::fs::remove_file(path).unwrap();
+            bytes
+        };
+
+        let first = build(42, "oak_test_seeded_builds_1.idx");
+        let second = build(42, "oak_test_seeded_builds_2.idx");
+        assert!(first == second);
+    }
+
+    #[test]
+    fn test_range_search() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let dimensionality = dataset.dimensionality;
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: dataset.flat.data[..dimensionality].to_vec(),
+        };
+        let topk = dataset.search(&query_vector, &None, 20, 64).unwrap();
+        let radius = topk[0].last().unwrap().1 + 1.0;
+
+        let in_range = dataset
+            .range_search(&query_vector, radius, &None, 64)
+            .unwrap();
+        assert_eq!(in_range.len(), 1);
+        assert!(in_range[0].len() >= 20);
+        assert!(in_range[0].iter().all(|(_, distance)| *distance < radius));
+
+        // With a predicate, exactly the vectors in range that match it are returned.
+        let attr = dataset.metadata.attrs_for(0)[0];
+        let predicate = Some(PredicateQuery::new(attr as u8));
+        let filtered = dataset
+            .range_search(&query_vector, radius, &predicate, 64)
+            .unwrap();
+        let mut expected: Vec<_> = in_range[0]
+            .iter()
+            .filter(|(id, _)| dataset.metadata.attrs_for(*id)[0] == attr)
+            .map(|(id, _)| *id)
+            .collect();
+        let mut ids: Vec<_> = filtered[0].iter().map(|(id, _)| *id).collect();
+        expected.sort();
+        ids.sort();
+        assert!(ids.contains(&0));
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn test_cosine_search() {
+        let opts = OakIndexOptions {
+            metric: DistanceMetric::Cosine,
+            ..OakIndexOptions::default()
+        };
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&opts).unwrap();
+        assert_eq!(dataset.get_metric(), Some(DistanceMetric::Cosine));
+
+        // A scaled copy of a vector in the dataset is perfectly similar to it.
+        let dimensionality = dataset.dimensionality;
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: dataset.flat.data[..dimensionality]
+                .iter()
+                .map(|x| x * 3.)
+                .collect(),
+        };
+        let result = dataset.search(&query_vector, &None, 10, 64).unwrap();
+        assert_eq!(result[0][0].0, 0);
+        assert!((result[0][0].1 - 1.).abs() < 1e-4);
+        assert!(result[0].windows(2).all(|w| w[0].1 >= w[1].1));
+
+        let path = std::env::temp_dir().join("oak_test_cosine_search.idx");
+        dataset.save_index(&path).unwrap();
+        let mut loaded = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        let result = loaded.load_index(&path, &OakIndexOptions::default());
+        assert!(matches!(
+            result,
+            Err(ConstructionError::IndexMismatch {
+                field: "metric type",
+                ..
+            })
+        ));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_product_quantization() {
+        let opts = OakIndexOptions::builder()
+            .product_quantization(32, 2)
+            .build()
+            .unwrap();
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&opts).unwrap();
+
+        // The distances are approximate, but each vector should still be among the closest to
+        // itself.
+        let queries = FlattenedVecs {
+            dimensionality: dataset.dimensionality,
+            data: dataset.flat.data[..10 * dataset.dimensionality].to_vec(),
+        };
+        let results = dataset.search(&queries, &None, 10, 64).unwrap();
+        let found = results
+            .iter()
+            .enumerate()
+            .filter(|(i, neighbors)| neighbors.iter().any(|(id, _)| id == i))
+            .count();
+        assert!(found >= 8, "only {found} of 10 vectors were found");
+
+        // The reconstructed vectors are decoded from their codes.
+        let reconstructed = dataset.reconstruct(&[0]).unwrap();
+        assert_eq!(reconstructed.dimensionality, dataset.dimensionality);
+        assert_ne!(reconstructed.data, queries.get(0).unwrap());
+
+        let path = std::env::temp_dir().join("oak_test_product_quantization.idx");
+        dataset.save_index(&path).unwrap();
+        let mut loaded = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        loaded.load_index(&path, &opts).unwrap();
+        assert_eq!(loaded.search(&queries, &None, 10, 64).unwrap(), results);
+        assert!(matches!(
+            loaded.load_index(&path, &OakIndexOptions::default()),
+            Err(ConstructionError::IndexMismatch {
+                field: "product quantization subquantizers",
+                ..
+            })
+        ));
+        std::fs::remove_file(path).unwrap();
+
+        for invalid in [
+            OakIndexOptions::builder()
+                .product_quantization(0, 8)
+                .build(),
+            OakIndexOptions::builder()
+                .product_quantization(16, 9)
+                .build(),
+            OakIndexOptions::builder()
+                .metric(DistanceMetric::L1)
+                .product_quantization(16, 8)
+                .build(),
+            OakIndexOptions::builder()
+                .metric(DistanceMetric::InnerProduct)
+                .product_quantization(16, 8)
+                .build(),
+        ] {
+            assert!(matches!(invalid, Err(ConstructionError::InvalidOptions(_))));
+        }
+        let indivisible = OakIndexOptions::builder()
+            .product_quantization(3, 4)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            dataset.initialize(&indivisible),
+            Err(ConstructionError::InvalidOptions(_))
+        ));
+
+        // The quantizer cannot be trained on fewer vectors than it has centroids.
+        let too_few = OakIndexOptions::builder()
+            .product_quantization(16, 8)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            dataset.initialize(&too_few),
+            Err(ConstructionError::CppError(_))
+        ));
+    }
+
+    #[test]
+    fn test_refine_k_factor() {
+        let opts = OakIndexOptions::builder()
+            .product_quantization(32, 2)
+            .refine_k_factor(4)
+            .build()
+            .unwrap();
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&opts).unwrap();
+
+        // The candidates are re-ranked by their exact distances, so each vector that is found
+        // is closest to itself, at a distance of zero.
+        let queries = FlattenedVecs {
+            dimensionality: dataset.dimensionality,
+            data: dataset.flat.data[..10 * dataset.dimensionality].to_vec(),
+        };
+        let results = dataset.search(&queries, &None, 10, 64).unwrap();
+        let found = results
+            .iter()
+            .enumerate()
+            .filter(|(i, neighbors)| neighbors[0] == (*i, 0.))
+            .count();
+        assert!(found >= 8, "only {found} of 10 vectors were found");
+        for (query, neighbors) in results.iter().enumerate() {
+            assert_eq!(neighbors.len(), 10);
+            for (id, distance) in neighbors {
+                let expected = crate::metrics::exact_distance(
+                    DistanceMetric::L2,
+                    queries.get(query).unwrap(),
+                    dataset.get_vector(*id).unwrap(),
+                );
+                assert!((distance - expected).abs() <= expected * 1e-4);
+            }
+        }
+
+        // The vectors are reconstructed from the flat copy, rather than decoded.
+        let reconstructed = dataset.reconstruct(&[0]).unwrap();
+        assert_eq!(reconstructed.data, queries.get(0).unwrap());
+
+        let path = std::env::temp_dir().join("oak_test_refine_k_factor.idx");
+        dataset.save_index(&path).unwrap();
+        let mut loaded = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        loaded.load_index(&path, &opts).unwrap();
+        assert_eq!(loaded.search(&queries, &None, 10, 64).unwrap(), results);
+        let unrefined = OakIndexOptions::builder()
+            .product_quantization(32, 2)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            loaded.load_index(&path, &unrefined),
+            Err(ConstructionError::IndexMismatch {
+                field: "refine flat storage",
+                ..
+            })
+        ));
+        std::fs::remove_file(path).unwrap();
+
+        for invalid in [
+            OakIndexOptions::builder().refine_k_factor(4).build(),
+            OakIndexOptions::builder()
+                .product_quantization(32, 2)
+                .refine_k_factor(0)
+                .build(),
+        ] {
+            assert!(matches!(invalid, Err(ConstructionError::InvalidOptions(_))));
+        }
+    }
+
+    #[test]
+    fn test_pre_transform() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        let dimensionality = dataset.dimensionality;
+
+        // Projects the vectors onto their first half of dimensions, and shifts them.
+        let kept = dimensionality / 2;
+        let mut matrix = vec![0.; kept * dimensionality];
+        for i in 0..kept {
+            matrix[i * dimensionality + i] = 1.;
+        }
+        let linear = |matrix: Vec<f32>| {
+            OakIndexOptions::builder()
+                .pre_transform(PreTransform::Linear {
+                    dimensionality: kept as i32,
+                    matrix,
+                    bias: vec![1.; kept],
+                })
+                .build()
+                .unwrap()
+        };
+        let opts = linear(matrix.clone());
+        dataset.initialize(&opts).unwrap();
+        assert_eq!(
+            dataset.index_stats().unwrap().bytes_per_vector,
+            kept * 4 + 4
+        );
+
+        // The queries are transformed as the vectors were, so the distances are those between
+        // the halves that are kept.
+        let queries = FlattenedVecs {
+            dimensionality,
+            data: dataset.flat.data[..10 * dimensionality].to_vec(),
+        };
+        let results = dataset.search(&queries, &None, 10, 64).unwrap();
+        for (query, neighbors) in results.iter().enumerate() {
+            for (id, distance) in neighbors {
+                let expected = crate::metrics::exact_distance(
+                    DistanceMetric::L2,
+                    &queries.get(query).unwrap()[..kept],
+                    &dataset.get_vector(*id).unwrap()[..kept],
+                );
+                assert!((distance - expected).abs() <= expected * 1e-4);
+            }
+        }
+        let found = results
+            .iter()
+            .enumerate()
+            .filter(|(i, neighbors)| neighbors[0] == (*i, 0.))
+            .count();
+        assert!(found >= 8, "only {found} of 10 vectors were found");
+
+        // The reconstructed vectors are transformed back (up to the rounding of the bias), with
+        // the dropped dimensions lost.
+        let reconstructed = dataset.reconstruct(&[0]).unwrap();
+        assert_eq!(reconstructed.dimensionality, dimensionality);
+        for (x, expected) in std::iter::zip(&reconstructed.data[..kept], queries.get(0).unwrap()) {
+            assert!((x - expected).abs() < 1e-4);
+        }
+        assert!(reconstructed.data[kept..].iter().all(|x| *x == 0.));
+
+        let path = std::env::temp_dir().join("oak_test_pre_transform.idx");
+        dataset.save_index(&path).unwrap();
+        let mut loaded = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        loaded.load_index(&path, &opts).unwrap();
+        assert_eq!(loaded.search(&queries, &None, 10, 64).unwrap(), results);
+        assert!(matches!(
+            loaded.load_index(&path, &OakIndexOptions::default()),
+            Err(ConstructionError::IndexMismatch {
+                field: "pre-transform",
+                ..
+            })
+        ));
+        std::fs::remove_file(path).unwrap();
+
+        // A transform whose rows are not orthonormal cannot be reversed.
+        dataset
+            .initialize(&linear(matrix.iter().map(|x| x * 2.).collect()))
+            .unwrap();
+        assert!(matches!(
+            dataset.reconstruct(&[0]),
+            Err(SearchableError::CppError(_))
+        ));
+
+        for invalid in [
+            PreTransform::Pca {
+                dimensionality: 0,
+                whiten: false,
+            },
+            PreTransform::Opq { subquantizers: 0 },
+            PreTransform::Linear {
+                dimensionality: kept as i32,
+                matrix: matrix.clone(),
+                bias: vec![1.; 3],
+            },
+        ] {
+            assert!(matches!(
+                OakIndexOptions::builder().pre_transform(invalid).build(),
+                Err(ConstructionError::InvalidOptions(_))
+            ));
+        }
+        for invalid in [
+            PreTransform::Pca {
+                dimensionality: 2 * dimensionality as i32,
+                whiten: false,
+            },
+            PreTransform::Opq { subquantizers: 3 },
+            PreTransform::Linear {
+                dimensionality: kept as i32,
+                matrix: matrix[1..].to_vec(),
+                bias: vec![],
+            },
+        ] {
+            let options = OakIndexOptions::builder()
+                .pre_transform(invalid)
+                .build()
+                .unwrap();
+            assert!(matches!(
+                dataset.initialize(&options),
+                Err(ConstructionError::InvalidOptions(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_add_vectors() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        let count = dataset.len();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let dimensionality = dataset.dimensionality;
+        let mut data = generate_random_vector(dimensionality);
+        data.extend(generate_random_vector(dimensionality));
+        let vecs = FlattenedVecs {
+            dimensionality,
+            data,
+        };
+
+        let ids = dataset.add_vectors(&vecs, &[3, 4]).unwrap();
+        assert_eq!(ids, count..count + 2);
+        assert_eq!(dataset.len(), count + 2);
+        assert_eq!(dataset.get_metadata().len(), count + 2);
+        assert_eq!(dataset.get_metadata().attrs_for(count + 1), &[4]);
+
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: vecs.data[dimensionality..].to_vec(),
+        };
+        let result = dataset
+            .search(&query_vector, &Some(PredicateQuery::new(4)), 1, 64)
+            .unwrap();
+        assert_eq!(result[0][0], (count + 1, 0.));
+
+        // Attributes are not limited to the range of the i32 that ACORN stores.
+        let wide = 1 << 40;
+        let ids = dataset.add_vectors(&vecs, &[wide, wide + 1]).unwrap();
+        let result = dataset
+            .search(
+                &query_vector,
+                &Some(PredicateQuery::in_set(vec![wide + 1])),
+                10,
+                64,
+            )
+            .unwrap();
+        assert_eq!(result[0], vec![(ids.end - 1, 0.)]);
+
+        let result = dataset.add_vectors(&vecs, &[3]);
+        assert!(matches!(
+            result,
+            Err(ConstructionError::MetadataLengthMismatch {
+                vectors: 2,
+                attrs: 1
+            })
+        ));
+
+        let ragged = FlattenedVecs {
+            dimensionality,
+            data: vecs.data[1..].to_vec(),
+        };
+        let result = dataset.add_vectors(&ragged, &[3]);
+        assert!(matches!(
+            result,
+            Err(ConstructionError::RaggedVectors { total, dimensionality: d })
+                if total == 2 * dimensionality - 1 && d == dimensionality
+        ));
+    }
+
+    #[test]
+    fn test_set_attribute() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let dimensionality = dataset.dimensionality;
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: dataset.flat.data[..dimensionality].to_vec(),
+        };
+        let unused = Some(PredicateQuery::in_set(vec![1 << 40]));
+        assert!(dataset.search(&query_vector, &unused, 1, 64).unwrap()[0].is_empty());
+
+        dataset.set_attribute(0, 1 << 40).unwrap();
+        assert_eq!(dataset.get_metadata().attrs_for(0), &[1 << 40]);
+        let result = dataset.search(&query_vector, &unused, 1, 64).unwrap();
+        assert_eq!(result[0], vec![(0, 0.)]);
+
+        let len = dataset.len();
+        assert!(matches!(
+            dataset.set_attribute(len, 1),
+            Err(ConstructionError::IdOutOfRange { id, len: l }) if id == len && l == len
+        ));
+
+        let mut metadata = HybridSearchMetadata::new(2, vec![1, 10, 2, 20]);
+        metadata.set_attrs_for(1, &[3, 30]).unwrap();
+        assert_eq!(metadata.attrs_for(1), &[3, 30]);
+        assert!(matches!(
+            metadata.set_attrs_for(0, &[4]),
+            Err(ConstructionError::AttributeCountMismatch {
+                expected: 2,
+                got: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn test_ragged_vectors_are_rejected() {
+        let ragged = FlattenedVecs {
+            dimensionality: 3,
+            data: vec![0.; 7],
+        };
+        assert!(matches!(
+            ragged.check_layout(),
+            Err(ConstructionError::RaggedVectors {
+                total: 7,
+                dimensionality: 3
+            })
+        ));
+
+        let metadata = HybridSearchMetadata::new(1, vec![0; 2]);
+        let result = AcornHnswIndex::new(3, &metadata, &ragged, &OakIndexOptions::default());
+        assert!(matches!(
+            result,
+            Err(ConstructionError::RaggedVectors { total: 7, .. })
+        ));
+        assert!(matches!(
+            crate::flat::FlatDataset::new(ragged, metadata),
+            Err(ConstructionError::RaggedVectors { total: 7, .. })
+        ));
+
+        let no_dimensions = FlattenedVecs {
+            dimensionality: 0,
+            data: vec![],
+        };
+        assert!(no_dimensions.check_layout().is_err());
+    }
+
+    #[test]
+    fn test_fvecs_mmap() {
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        let vectors = FvecsMmap::open(Path::new("data/sift_query.fvecs")).unwrap();
+        let dimensionality = dataset.dimensionality;
+
+        assert_eq!(vectors.len(), dataset.len());
+        assert_eq!(vectors.dimensionality(), dimensionality);
+        assert_eq!(
+            vectors.get(3).data(),
+            &dataset.flat.data[3 * dimensionality..4 * dimensionality]
+        );
+        assert_eq!(vectors.iter().count(), dataset.len());
+        assert_eq!(
+            vectors.read_range(2..5).data,
+            vectors.read_range(0..5).data[2 * dimensionality..]
+        );
+
+        let mut mmap_dataset = FvecsMmapDataset::new("data/sift_query".to_string(), true).unwrap();
+        mmap_dataset
+            .initialize(&OakIndexOptions::default())
+            .unwrap();
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: vectors.get(5).data().to_vec(),
+        };
+        let result = mmap_dataset.search(&query_vector, &None, 1, 64).unwrap();
+        assert_eq!(result[0][0], (5, 0.));
+    }
+
+    #[test]
+    fn test_fvecs_mmap_rejects_inconsistent_dimensionality() {
+        let path = std::env::temp_dir().join("oak_test_fvecs_mmap_inconsistent.fvecs");
+        let mut bytes = vec![];
+        for (dimensionality, value) in [(2u32, 1f32), (3, 2.)] {
+            bytes.extend(dimensionality.to_le_bytes());
+            bytes.extend(value.to_le_bytes());
+            bytes.extend(value.to_le_bytes());
+        }
+        std::fs::write(&path, bytes).unwrap();
+
+        let err = FvecsMmap::open(&path).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<ConstructionError>(),
+            Some(ConstructionError::DimensionalityMismatch {
+                expected: 2,
+                got: 3
+            })
+        ));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_new_checks_every_vector() {
+        let fname = std::env::temp_dir()
+            .join("oak_test_new_checks_every_vector")
+            .to_str()
+            .unwrap()
+            .to_string();
+        let path = format!("{fname}.fvecs");
+        let vector = |dimensionality: u32| {
+            let mut bytes = dimensionality.to_le_bytes().to_vec();
+            bytes.extend(vec![0u8; dimensionality as usize * FOUR_BYTES]);
+            bytes
+        };
+
+        // The second header claims 3 dimensions, though its vector takes the space of 2.
+        let mut mixed = vector(2);
+        mixed.extend(3u32.to_le_bytes());
+        mixed.extend(&vector(2)[FOUR_BYTES..]);
+        std::fs::write(&path, &mixed).unwrap();
+        let err = FvecsDataset::new(fname.clone(), false).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<ConstructionError>(),
+            Some(ConstructionError::DimensionalityMismatch {
+                expected: 2,
+                got: 3
+            })
+        ));
+
+        // A trailing partial vector, a file too short for a header and an empty file are all
+        // rejected, rather than dropped or panicked on.
+        let mut truncated = vector(2).repeat(2);
+        truncated.extend(&vector(2)[..6]);
+        for bytes in [truncated, vec![2, 0], vec![]] {
+            std::fs::write(&path, &bytes).unwrap();
+            assert!(FvecsDataset::new(fname.clone(), false).is_err());
+        }
+
+        std::fs::write(&path, vector(2).repeat(3)).unwrap();
+        let dataset = FvecsDataset::new(fname.clone(), false).unwrap();
+        assert_eq!((dataset.len(), dataset.get_dimensionality()), (3, 2));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_gzipped_fvecs() {
+        let fname = std::env::temp_dir()
+            .join("oak_test_gzipped")
+            .to_str()
+            .unwrap()
+            .to_string();
+        let path = PathBuf::from(format!("{fname}.fvecs.gz"));
+
+        #[cfg(feature = "gzip")]
+        {
+            let mut encoder = flate2::write::GzEncoder::new(
+                File::create(&path).unwrap(),
+                flate2::Compression::default(),
+            );
+            encoder
+                .write_all(&std::fs::read("data/sift_query.fvecs").unwrap())
+                .unwrap();
+            encoder.finish().unwrap();
+
+            // The ".gz" is found without being named, and parses to the same vectors.
+            let plain = FvecsDataset::new("data/sift_query".to_string(), false).unwrap();
+            let dataset = FvecsDataset::new(fname, false).unwrap();
+            assert_eq!(dataset.get_dimensionality(), plain.get_dimensionality());
+            assert_eq!(dataset.flat.data, plain.flat.data);
+
+            let mmap = FvecsMmap::open(&path).unwrap();
+            assert_eq!(mmap.len(), plain.len());
+            assert_eq!(mmap.get(5).data(), plain.flat.get(5).unwrap());
+        }
+
+        // Without the feature, a compressed file is rejected rather than parsed as vectors.
+        #[cfg(not(feature = "gzip"))]
+        {
+            std::fs::write(&path, [0x1f, 0x8b, 8, 0]).unwrap();
+            let err = FvecsDataset::new(fname, false).err().unwrap();
+            assert!(err.to_string().contains("`gzip` feature"), "{err}");
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_batch_search_preserves_order() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        dataset.set_num_threads(4).unwrap();
+
+        let dimensionality = dataset.dimensionality;
+        let queries = FlattenedVecs {
+            dimensionality,
+            data: dataset.flat.data[..20 * dimensionality].to_vec(),
+        };
+        let predicate = Some(PredicateQuery::new(3));
+        let batched = dataset.search(&queries, &predicate, 5, 32).unwrap();
+
+        assert_eq!(batched.len(), 20);
+        for (query, result) in queries.to_vec().iter().zip(&batched) {
+            let single = dataset.search(query, &predicate, 5, 32).unwrap();
+            assert_eq!(&single[0], result);
+        }
+    }
+
+    #[test]
+    fn test_read_ivecs() {
+        let path = std::env::temp_dir().join("oak_test_read_ivecs.ivecs");
+        let mut bytes = vec![];
+        for ids in [vec![3i32, 1, 2], vec![0, 4, 5]] {
+            bytes.extend((ids.len() as u32).to_le_bytes());
+            for id in ids {
+                bytes.extend(id.to_le_bytes());
+            }
+        }
+        std::fs::write(&path, bytes).unwrap();
+
+        let ground_truth = read_ivecs(&path).unwrap();
+        assert_eq!(ground_truth, vec![vec![3, 1, 2], vec![0, 4, 5]]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_ivecs() {
+        let path = std::env::temp_dir().join("oak_test_write_ivecs.ivecs");
+        let results = vec![vec![(3, 0.), (1, 0.5)], vec![(0, 0.), (4, 2.)]];
+        write_ivecs(&results, &path).unwrap();
+        assert_eq!(read_ivecs(&path).unwrap(), vec![vec![3, 1], vec![0, 4]]);
+
+        assert!(write_ivecs(&vec![vec![(usize::MAX, 0.)]], &path).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_to_and_read_from() {
+        let path = std::env::temp_dir().join("oak_test_flattened_vecs.bin");
+        let vecs = FlattenedVecs {
+            dimensionality: 3,
+            data: vec![0., 1.5, -2., f32::MAX, 1e-9, 7.],
+        };
+        vecs.write_to(&path).unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len() as usize,
+            FLATTENED_HEADER_LEN + vecs.data.len() * FOUR_BYTES
+        );
+
+        let read = FlattenedVecs::read_from(&path).unwrap();
+        assert_eq!(read.dimensionality, 3);
+        assert_eq!(read.data, vecs.data);
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.pop();
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(FlattenedVecs::read_from(&path).is_err());
+
+        bytes[FLATTENED_MAGIC.len()] = FLATTENED_VERSION + 1;
+        std::fs::write(&path, &bytes).unwrap();
+        let err = FlattenedVecs::read_from(&path).err().unwrap();
+        assert!(err.to_string().contains("version"));
+
+        std::fs::write(&path, b"fvecs").unwrap();
+        assert!(FlattenedVecs::read_from(&path).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_bvecs() {
+        let path = std::env::temp_dir().join("oak_test_read_bvecs.bvecs");
+        let bvecs: [[u8; 3]; 2] = [[0, 1, 255], [7, 8, 9]];
+        let mut bytes = vec![];
+        for bvec in bvecs {
+            bytes.extend(3u32.to_le_bytes());
+            bytes.extend(bvec);
+        }
+        std::fs::write(&path, &bytes).unwrap();
+
+        let vecs = read_bvecs(&path).unwrap();
+        assert_eq!(vecs.dimensionality, 3);
+        assert_eq!(vecs.len(), 2);
+        assert_eq!(vecs.data, vec![0., 1., 255., 7., 8., 9.]);
+
+        bytes.pop();
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(read_bvecs(&path).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_search_truncates_when_topk_exceeds_matches() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let dimensionality = dataset.dimensionality;
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: generate_random_vector(dimensionality),
+        };
+
+        let topk = dataset.len() + 10;
+        let result = dataset.search(&query_vector, &None, topk, 16).unwrap();
+        assert!(result[0].len() <= dataset.len());
+        assert!(result[0].iter().all(|(id, _)| *id < dataset.len()));
+
+        let predicate = PredicateQuery::new(3);
+        let matching = Bitmask::new(&predicate, &dataset).bitcount();
+        let result = dataset
+            .search(&query_vector, &Some(predicate), topk, 16)
+            .unwrap();
+        assert!(result[0].len() <= matching);
+        assert!(result[0]
+            .iter()
+            .all(|(id, _)| dataset.get_metadata().attrs_for(*id)[0] == 3));
+    }
+
+    #[test]
+    fn test_search_ids() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        let dimensionality = dataset.dimensionality;
+        let query_vectors = FlattenedVecs {
+            dimensionality,
+            data: generate_random_vector(dimensionality * 3),
+        };
+        assert_eq!(
+            dataset.search_ids(&query_vectors, &None, 10, 16).err(),
+            Some(SearchableError::DatasetIsNotIndexed)
+        );
+
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        dataset.remove_vectors(&[0, 1]).unwrap();
+        for predicate in [None, Some(PredicateQuery::range(2, 4))] {
+            let expected: Vec<Vec<usize>> = dataset
+                .search(&query_vectors, &predicate, 10, 16)
+                .unwrap()
+                .into_iter()
+                .map(|neighbors| neighbors.into_iter().map(|(id, _)| id).collect())
+                .collect();
+            let ids = dataset
+                .search_ids(&query_vectors, &predicate, 10, 16)
+                .unwrap();
+            assert_eq!(ids, expected);
+        }
+
+        // A dataset with no ACORN index falls back on `search`.
+        let mut flat = crate::flat::FlatDataset::new(
+            FlattenedVecs::from(&dataset),
+            (&dataset.metadata).into_iter().collect(),
+        )
+        .unwrap();
+        flat.initialize(&OakIndexOptions::default()).unwrap();
+        let ids = flat.search_ids(&query_vectors, &None, 10, 16).unwrap();
+        let results = flat.search(&query_vectors, &None, 10, 16).unwrap();
+        for (ids, neighbors) in std::iter::zip(ids, results) {
+            assert!(std::iter::zip(ids, neighbors).all(|(id, (expected, _))| id == expected));
+        }
+    }
+
+    #[test]
+    fn test_search_with_filter_map() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let dimensionality = dataset.dimensionality;
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: generate_random_vector(dimensionality),
+        };
+
+        // Any nonzero byte lets a vector through, as it does for ACORN.
+        let filter_id_map: Vec<u8> = (0..dataset.len())
+            .map(|i| if i % 3 == 0 { 255 } else { 0 })
+            .collect();
+        let result = dataset
+            .search_with_filter_map(&query_vector, &filter_id_map, 10, 16)
+            .unwrap();
+        assert_eq!(result[0].len(), 10);
+        assert!(result[0].iter().all(|(id, _)| id % 3 == 0));
+
+        let mask = Bitmask::from_predicate(dataset.get_metadata(), |attr| attr == 3);
+        let filter_id_map: Vec<u8> = mask.map.iter().map(|&bit| bit as u8).collect();
+        assert_eq!(
+            dataset
+                .search_with_filter_map(&query_vector, &filter_id_map, 10, 16)
+                .unwrap(),
+            dataset
+                .search(&query_vector, &Some(PredicateQuery::new(3)), 10, 16)
+                .unwrap()
+        );
+
+        assert_eq!(
+            dataset
+                .search_with_filter_map(&query_vector, &[1; 3], 10, 16)
+                .err(),
+            Some(SearchableError::FilterMapLengthMismatch {
+                expected: dataset.len(),
+                got: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_short_bitmask_is_rejected() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let queries = FlattenedVecs {
+            dimensionality: dataset.get_dimensionality(),
+            data: dataset.flat.get(0).unwrap().to_vec(),
+        };
+
+        let short = Bitmask::from(vec![1i8; 3]);
+        assert_eq!(
+            dataset.search_with_bitmask(&queries, &short, 10, 16).err(),
+            Some(SearchableError::FilterMapLengthMismatch {
+                expected: dataset.len(),
+                got: 3
+            })
+        );
+
+        // A mask built before vectors are added no longer covers the whole dataset.
+        let stale = Bitmask::new_full(&dataset);
+        dataset.add_vectors(&queries, &[1]).unwrap();
+        assert_eq!(
+            dataset.search_with_bitmask(&queries, &stale, 10, 16).err(),
+            Some(SearchableError::FilterMapLengthMismatch {
+                expected: dataset.len(),
+                got: dataset.len() - 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_remove_vectors() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let dimensionality = dataset.dimensionality;
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: dataset.flat.data[..dimensionality].to_vec(),
+        };
+        let before = dataset.search(&query_vector, &None, 5, 64).unwrap();
+        let removed: Vec<usize> = before[0].iter().take(3).map(|(id, _)| *id).collect();
+
+        // duplicates and out of range ids are not counted
+        let ids = [removed.clone(), vec![removed[0], dataset.len()]].concat();
+        assert_eq!(dataset.remove_vectors(&ids).unwrap(), 3);
+        assert_eq!(dataset.remove_vectors(&removed).unwrap(), 0);
+
+        let after = dataset.search(&query_vector, &None, 5, 64).unwrap();
+        assert!(after[0].iter().all(|(id, _)| !removed.contains(id)));
+
+        let full = Bitmask::new_full(&dataset);
+        let after = dataset
+            .search_with_bitmask(&query_vector, &full, 5, 64)
+            .unwrap();
+        assert!(after[0].iter().all(|(id, _)| !removed.contains(id)));
+    }
+
+    #[test]
+    fn test_f16_storage() {
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        let half = dataset.flat.to_f16();
+        assert_eq!(half.dimensionality, dataset.dimensionality);
+        assert_eq!(half.len(), dataset.len());
+
+        let relative_error = std::iter::zip(half.to_f32().data, &dataset.flat.data)
+            .map(|(a, b)| ((a - b) / b.abs().max(1.)).abs())
+            .fold(0., f32::max);
+        assert!(relative_error < 1e-3);
+
+        let index = AcornHnswIndex::new_from_chunks(
+            dataset.dimensionality as i32,
+            dataset.get_metadata(),
+            half.chunks_f32(64),
+            &OakIndexOptions::default(),
+        )
+        .unwrap();
+        let query_vector = half.chunks_f32(1).next().unwrap();
+        let mask = Bitmask::new_full(&dataset);
+        let result = index.search(&query_vector, &mask.map, 1, 64).unwrap();
+        assert_eq!(result[0][0], (0, 0.));
+    }
+
+    #[test]
+    fn test_index_stats() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        assert_eq!(
+            dataset.index_stats(),
+            Err(SearchableError::DatasetIsNotIndexed)
+        );
+
+        let opts = OakIndexOptions::default();
+        dataset.initialize(&opts).unwrap();
+        let stats = dataset.index_stats().unwrap();
+
+        assert_eq!(stats.nodes, dataset.len());
+        assert!(stats.levels >= 1);
+        assert!(stats.min_out_degree >= 1);
+        assert!(stats.min_out_degree <= stats.max_out_degree);
+        // NOTE: ACORN reserves `m_beta + 1.5 * m` neighbors per vector at the base level.
+        assert!(stats.max_out_degree <= (opts.m_beta + opts.m * 3 / 2) as usize);
+        assert!((stats.mean_out_degree - stats.edges as f64 / stats.nodes as f64).abs() < 1e-9);
+        // The index holds a copy of each vector along with its attribute.
+        assert_eq!(stats.bytes_per_vector, dataset.dimensionality * 4 + 4);
+    }
+
+    #[test]
+    fn test_export_graph() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        assert!(matches!(
+            dataset.export_graph(0),
+            Err(SearchableError::DatasetIsNotIndexed)
+        ));
+
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let stats = dataset.index_stats().unwrap();
+        let edges = dataset.graph_edges(0).unwrap();
+        assert_eq!(edges.len(), stats.edges);
+        assert!(edges
+            .iter()
+            .all(|&(from, to)| from < dataset.len() && to < dataset.len() && from != to));
+
+        let dot = dataset.export_graph(0).unwrap();
+        let lines: Vec<_> = dot.lines().collect();
+        assert_eq!(lines.len(), stats.edges + 2);
+        assert_eq!(lines[0], "digraph level_0 {");
+        assert_eq!(lines[1], format!("    {} -> {};", edges[0].0, edges[0].1));
+        assert_eq!(lines[lines.len() - 1], "}");
+
+        // Every vector at a higher level is also in the base level, and there are no edges above
+        // the highest level.
+        if stats.levels > 1 {
+            assert!(dataset.graph_edges(1).unwrap().len() < edges.len());
+        }
+        assert_eq!(
+            dataset.export_graph(stats.levels).unwrap(),
+            format!("digraph level_{} {{\n}}\n", stats.levels)
+        );
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        let unindexed = dataset.memory_usage();
+        assert_eq!(
+            unindexed,
+            dataset.flat.data.len() * 4
+                + dataset.metadata.rows().map(<[i64]>::len).sum::<usize>() * 8
+        );
+
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let stats = dataset.index_stats().unwrap();
+        assert_eq!(dataset.memory_usage(), unindexed + stats.estimated_bytes());
+        assert!(stats.estimated_bytes() > dataset.flat.data.len() * 4);
+
+        dataset.clear_index();
+        assert_eq!(dataset.memory_usage(), unindexed);
+    }
+
+    #[test]
+    fn test_initialize_with_report() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        let report = dataset
+            .initialize_with_report(&OakIndexOptions::default())
+            .unwrap();
+        let stats = dataset.index_stats().unwrap();
+
+        assert_eq!(report.num_vectors, dataset.len());
+        assert_eq!(report.num_edges, stats.edges);
+        assert!(report.duration > std::time::Duration::ZERO);
+        // At the very least, the index holds an f32 for each component of each vector.
+        assert!(report.peak_memory_estimate > dataset.flat.data.len() * 4);
+
+        // Compressing the vectors shrinks the estimate.
+        let compressed = dataset
+            .initialize_with_report(
+                &OakIndexOptions::builder()
+                    .product_quantization(32, 2)
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+        assert!(compressed.peak_memory_estimate < report.peak_memory_estimate);
+    }
+
+    #[test]
+    fn test_search_one() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let query = FvecsMmap::open(Path::new("data/sift_query.fvecs"))
+            .unwrap()
+            .get(3);
+        let result = dataset.search_one(&query, &None, 10, 16).unwrap();
+        let batch = dataset.search(&(&query).into(), &None, 10, 16).unwrap();
+        assert_eq!(result, batch[0]);
+        assert_eq!(result[0].0, 3);
+
+        let wrong = Fvec {
+            dimensionality: 3,
+            data: vec![0.; 3],
+        };
+        assert_eq!(
+            dataset.search_one(&wrong, &None, 10, 16),
+            Err(SearchableError::DimensionalityMismatch {
+                expected: dataset.get_dimensionality(),
+                got: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_kth_distance() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let query = FvecsMmap::open(Path::new("data/sift_query.fvecs"))
+            .unwrap()
+            .get(3);
+        let predicate = Some(PredicateQuery::new(1));
+        let neighbors = dataset
+            .search_one(&query, &predicate, 5, DEFAULT_EF_SEARCH)
+            .unwrap();
+        assert_eq!(
+            dataset.kth_distance(&query, 5, &predicate),
+            Ok(neighbors[4].1)
+        );
+        assert_eq!(dataset.kth_distance(&query, 1, &None), Ok(0.));
+
+        // The radius of the kth neighbor takes in at least the k nearest.
+        let radius = dataset.kth_distance(&query, 5, &None).unwrap();
+        let in_range = dataset
+            .range_search(&(&query).into(), radius * 1.0001, &None, DEFAULT_EF_SEARCH)
+            .unwrap();
+        assert!(in_range[0].len() >= 5);
+
+        let matching = dataset.count_matching(predicate.as_ref().unwrap()).unwrap();
+        for k in [0, matching + 1] {
+            assert!(matches!(
+                dataset.kth_distance(&query, k, &predicate),
+                Err(SearchableError::NotEnoughNeighbors { k: got, .. }) if got == k
+            ));
+        }
+    }
+
+    #[test]
+    fn test_search_with_stats() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let dimensionality = dataset.get_dimensionality();
+        let queries = FlattenedVecs {
+            dimensionality,
+            data: dataset.flat.data[..5 * dimensionality].to_vec(),
+        };
+        let (results, stats) = dataset.search_with_stats(&queries, &None, 10, 16).unwrap();
+        assert_eq!(results, dataset.search(&queries, &None, 10, 16).unwrap());
+        assert_eq!(stats.len(), 5);
+        assert!(stats.iter().all(|s| s.ndis > 0));
+    }
+
+    #[test]
+    fn test_non_finite_queries() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let dimensionality = dataset.dimensionality;
+        let mut queries = FlattenedVecs {
+            dimensionality,
+            data: dataset.flat.data[..2 * dimensionality].to_vec(),
+        };
+        queries.data[dimensionality + 3] = f32::NAN;
+        queries.data[dimensionality + 5] = f32::INFINITY;
+        assert_eq!(queries.find_non_finite(), Some((1, 3)));
+        assert_eq!(
+            dataset.search(&queries, &None, 10, 16),
+            Err(SearchableError::NonFiniteQuery {
+                query_index: 1,
+                component: 3
+            })
+        );
+
+        let opts = OakIndexOptions::builder()
+            .sanitize_non_finite_queries(true)
+            .build()
+            .unwrap();
+        dataset.initialize(&opts).unwrap();
+        let results = dataset.search(&queries, &None, 10, 16).unwrap();
+        queries.sanitize_non_finite();
+        assert_eq!(queries.find_non_finite(), None);
+        assert_eq!(queries.data[dimensionality + 3], 0.);
+        assert_eq!(results, dataset.search(&queries, &None, 10, 16).unwrap());
+    }
+
+    #[test]
+    fn test_search_batch_size() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let dimensionality = dataset.dimensionality;
+        let mut queries = FlattenedVecs {
+            dimensionality,
+            data: dataset.flat.data[..7 * dimensionality].to_vec(),
+        };
+        let expected = dataset.search(&queries, &None, 10, 16).unwrap();
+
+        let opts = OakIndexOptions::builder()
+            .search_batch_size(3)
+            .build()
+            .unwrap();
+        dataset.initialize(&opts).unwrap();
+        assert_eq!(dataset.search(&queries, &None, 10, 16).unwrap(), expected);
+
+        // Queries are numbered across the whole search, rather than within their batch.
+        queries.data[5 * dimensionality + 1] = f32::NAN;
+        assert_eq!(
+            dataset.search(&queries, &None, 10, 16),
+            Err(SearchableError::NonFiniteQuery {
+                query_index: 5,
+                component: 1
+            })
+        );
+
+        assert!(OakIndexOptions::builder()
+            .search_batch_size(0)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_search_rejects_mismatched_dimensionality() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let expected = dataset.get_dimensionality();
+        let got = expected - 1;
+        let query = FlattenedVecs {
+            dimensionality: got,
+            data: generate_random_vector(got * 2),
+        };
+        let mismatch = Err(SearchableError::DimensionalityMismatch { expected, got });
+
+        assert_eq!(dataset.search(&query, &None, 10, 16), mismatch);
+        let mask = Bitmask::new_full(&dataset);
+        assert_eq!(dataset.search_with_bitmask(&query, &mask, 10, 16), mismatch);
+        assert_eq!(dataset.range_search(&query, 1., &None, 16), mismatch);
+    }
+
+    #[cfg(feature = "ndarray")]
+    #[test]
+    fn test_ndarray_roundtrip() {
+        let arr = ndarray::array![[1., 2., 3.], [4., 5., 6.]];
+        let flat = FlattenedVecs::from_ndarray(&arr);
+        assert_eq!(flat.dimensionality, 3);
+        assert_eq!(flat.data, vec![1., 2., 3., 4., 5., 6.]);
+        assert_eq!(flat.to_ndarray(), arr);
+
+        // A transposed array is not in standard layout, but is still flattened row by row.
+        let transposed = arr.t().to_owned();
+        let flat = FlattenedVecs::from_ndarray(&transposed);
+        assert_eq!(flat.dimensionality, 2);
+        assert_eq!(flat.data, vec![1., 4., 2., 5., 3., 6.]);
+    }
+
+    #[test]
+    fn test_read_csv() {
+        let path = std::env::temp_dir().join("oak_test_read_csv.csv");
+        std::fs::write(&path, "0,1.5,2,3,7\n1,4,5,6.25,8\n").unwrap();
+
+        let (vecs, metadata) = read_csv(&path).unwrap();
+        assert_eq!(vecs.dimensionality, 3);
+        assert_eq!(vecs.data, vec![1.5, 2., 3., 4., 5., 6.25]);
+        assert_eq!(metadata.column(0), vec![7, 8]);
+
+        std::fs::write(&path, "0,1,2,3,7\n1,4,5,8\n").unwrap();
+        let err = read_csv(&path).err().unwrap().to_string();
+        assert!(
+            err.contains("line 2") && err.contains("dimensionality 2"),
+            "{err}"
+        );
+
+        std::fs::write(&path, "0,1,2,3,7\n1,4,5,6,7\n2,4,x,6,7\n").unwrap();
+        let err = read_csv(&path).err().unwrap().to_string();
+        assert!(err.contains("line 3") && err.contains("\"x\""), "{err}");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_csv_to_metadata_with_nulls() {
+        let path = std::env::temp_dir().join("oak_test_read_csv_to_metadata_nulls.csv");
+        std::fs::write(&path, "1,2\n,3\n4, \n").unwrap();
+
+        let metadata = read_csv_to_metadata(&path).unwrap();
+        assert_eq!(metadata.column(0), vec![1, 0, 4]);
+        assert!(metadata.is_null(1, 0) && metadata.is_null(2, 1));
+        assert!(!metadata.is_null(0, 0) && !metadata.is_null(1, 1));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_dataset_builder() {
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        let attrs: Vec<i64> = (&dataset.metadata).into_iter().collect();
+
+        let built = FvecsDataset::builder()
+            .vectors(FlattenedVecs::from(&dataset))
+            .attributes(attrs.clone())
+            .build()
+            .unwrap();
+        assert_eq!(built.flat.data, dataset.flat.data);
+        assert_eq!(built.mmap[..], dataset.mmap[..]);
+        assert_eq!(built.metadata.column(0), attrs);
+        assert!(built.index_stats().is_err());
+
+        let mut built = FvecsDataset::builder()
+            .vectors_from_file("data/sift_query.fvecs")
+            .metadata_from_csv("data/sift_query.csv")
+            .options(OakIndexOptions::default())
+            .build()
+            .unwrap();
+        assert_eq!(built.flat.data, dataset.flat.data);
+        assert_eq!(built.index_stats().unwrap().nodes, dataset.len());
+        built.verify().unwrap();
+
+        built = FvecsDataset::builder()
+            .vectors_from_mmap(map_file(Path::new("data/sift_query.fvecs")).unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(built.flat.data, dataset.flat.data);
+        assert!(built.metadata.is_empty());
+
+        assert!(FvecsDataset::builder().build().is_err());
+        let err = FvecsDataset::builder()
+            .vectors(FlattenedVecs::from(&dataset))
+            .attributes(attrs[1..].iter().copied())
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ConstructionError::MetadataLengthMismatch {
+                vectors: 200,
+                attrs: 199
+            })
+        ));
+        let ragged = FlattenedVecs {
+            dimensionality: 3,
+            data: vec![0.; 4],
+        };
+        assert!(FvecsDataset::builder().vectors(ragged).build().is_err());
+    }
+
+    #[test]
+    fn test_search_with_recall() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let queries = FlattenedVecs::from(&dataset);
+        let predicate = Some(PredicateQuery::range(2, 5));
+
+        let (results, estimate) = dataset
+            .search_with_recall(&queries, &predicate, 10, 64, 1., 7)
+            .unwrap();
+        assert_eq!(
+            results,
+            dataset.search(&queries, &predicate, 10, 64).unwrap()
+        );
+        assert_eq!(estimate.sampled, queries.len());
+        assert!(estimate.recall.unwrap() > 0.8, "{estimate:?}");
+
+        let (_, estimate) = dataset
+            .search_with_recall(&queries, &predicate, 10, 64, 0.25, 7)
+            .unwrap();
+        assert!(estimate.sampled > 0 && estimate.sampled < queries.len());
+
+        let (_, estimate) = dataset
+            .search_with_recall(&queries, &None, 10, 64, 0., 7)
+            .unwrap();
+        assert_eq!(estimate, crate::dataset::RecallEstimate::default());
+
+        let mut flat = crate::flat::FlatDataset::new(queries, dataset.metadata.clone()).unwrap();
+        flat.initialize(&OakIndexOptions::default()).unwrap();
+        let (_, estimate) = flat
+            .search_with_recall(&flat.flat, &predicate, 10, 64, 1., 7)
+            .unwrap();
+        assert_eq!(estimate.recall, Some(1.));
+
+        // The removed vectors are the nearest neighbors of themselves, so the recall would be
+        // under-reported if they were left in the ground truth.
+        let removed: Vec<usize> = (0..100).collect();
+        dataset.remove_vectors(&removed).unwrap();
+        let searchable = dataset.search_mask(&None).unwrap().bitcount();
+        assert_eq!(searchable, dataset.len() - removed.len());
+        let (results, estimate) = dataset
+            .search_with_recall(&flat.flat, &None, 10, 256, 1., 7)
+            .unwrap();
+        assert!(results.iter().flatten().all(|&(id, _)| id >= 100));
+        assert!(estimate.recall.unwrap() > 0.95, "{estimate:?}");
+    }
+
+    #[test]
+    fn test_search_u32() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let queries = FlattenedVecs::from(&dataset);
+        let predicate = Some(PredicateQuery::range(2, 5));
+
+        let compact = dataset.search_u32(&queries, &predicate, 10, 16).unwrap();
+        let results = dataset.search(&queries, &predicate, 10, 16).unwrap();
+        assert_eq!(compact.len(), results.len());
+        for (compact, neighbors) in std::iter::zip(compact, results) {
+            let widened: TopKSearchResult = compact
+                .into_iter()
+                .map(|(id, distance)| (id as usize, distance))
+                .collect();
+            assert_eq!(widened, neighbors);
+        }
+    }
+
+    #[test]
+    fn test_search_flat() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let queries = FlattenedVecs::from(&dataset);
+        // NOTE: only a few vectors match, so that some rows must be padded.
+        let predicate = Some(PredicateQuery::new(1));
+        let topk = dataset.count_matching(&predicate.clone().unwrap()).unwrap() + 2;
+
+        let flat = dataset.search_flat(&queries, &predicate, topk, 16).unwrap();
+        let results = dataset.search(&queries, &predicate, topk, 16).unwrap();
+        assert_eq!(flat.topk, topk);
+        assert_eq!(flat.num_queries(), results.len());
+        assert_eq!(flat.ids.len(), results.len() * topk);
+        assert_eq!(flat.distances.len(), results.len() * topk);
+        for (query, neighbors) in results.iter().enumerate() {
+            assert!(neighbors.len() < topk);
+            assert_eq!(&flat.get(query), neighbors);
+
+            let (ids, distances) = flat.row(query);
+            assert!(ids[neighbors.len()..]
+                .iter()
+                .all(|&id| id == FLAT_RESULT_PADDING_ID));
+            assert!(distances[neighbors.len()..].iter().all(|d| d.is_nan()));
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.verify().unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        dataset.verify().unwrap();
+
+        let metadata = std::mem::replace(
+            &mut dataset.metadata,
+            HybridSearchMetadata::new(1, vec![0; 3]),
+        );
+        assert!(matches!(
+            dataset.verify(),
+            Err(ConstructionError::MetadataLengthMismatch {
+                vectors: 200,
+                attrs: 3
+            })
+        ));
+        dataset.metadata = HybridSearchMetadata::new(2, vec![0; 401]);
+        assert!(matches!(
+            dataset.verify(),
+            Err(ConstructionError::RaggedAttributes {
+                total: 401,
+                dimensionality: 2
+            })
+        ));
+        dataset.metadata = metadata;
+
+        dataset.count -= 1;
+        assert!(matches!(
+            dataset.verify(),
+            Err(ConstructionError::VectorCountMismatch {
+                len: 199,
+                found: 200,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_search_with_params() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let queries = FlattenedVecs::from(&dataset);
+        let predicate = Some(PredicateQuery::range(2, 5));
+
+        let unbounded = dataset
+            .search_with_params(&queries, &predicate, 10, 64, &SearchParams::default())
+            .unwrap();
+        assert_eq!(
+            unbounded,
+            dataset
+                .search_with_stats(&queries, &predicate, 10, 64)
+                .unwrap()
+        );
+
+        let params = SearchParams {
+            max_filtered_visits: Some(10),
+            ..Default::default()
+        };
+        let (results, stats) = dataset
+            .search_with_params(&queries, &predicate, 10, 64, &params)
+            .unwrap();
+        let visits = |stats: &[SearchStats]| stats.iter().map(|s| s.ndis).sum::<usize>();
+        assert!(visits(&stats) < visits(&unbounded.1));
+        for neighbors in &results {
+            assert!(neighbors.len() <= 10);
+            assert!(neighbors
+                .iter()
+                .all(|(id, _)| (2..=5).contains(&dataset.metadata.attrs_for(*id)[0])));
+        }
+
+        // Only a fraction of the vectors pass the predicate, so the search is widened as if with
+        // a larger efsearch.
+        let params = SearchParams {
+            scale_efsearch_by_selectivity: Some(1024),
+            ..Default::default()
+        };
+        let passing = dataset.count_matching(predicate.as_ref().unwrap()).unwrap();
+        let widened = (16 * dataset.len()).div_ceil(passing) as i64;
+        assert!(widened > 16);
+        assert_eq!(
+            dataset
+                .search_with_params(&queries, &predicate, 10, 16, &params)
+                .unwrap(),
+            dataset
+                .search_with_stats(&queries, &predicate, 10, widened)
+                .unwrap()
+        );
+        assert_eq!(
+            dataset
+                .search_with_params(&queries, &None, 10, 16, &params)
+                .unwrap(),
+            dataset.search_with_stats(&queries, &None, 10, 16).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_true_l2_distances() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let queries = FlattenedVecs::from(&dataset);
+        let params = SearchParams {
+            true_l2_distances: true,
+            ..Default::default()
+        };
+
+        let squared = dataset.search(&queries, &None, 10, 16).unwrap();
+        let (rooted, _) = dataset
+            .search_with_params(&queries, &None, 10, 16, &params)
+            .unwrap();
+        let expected: TopKSearchResultBatch = squared
+            .iter()
+            .map(|neighbors| {
+                neighbors
+                    .iter()
+                    .map(|&(id, distance)| (id, distance.sqrt()))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(rooted, expected);
+
+        // A flat dataset takes the square root likewise, but other metrics are left as they are.
+        let mut flat =
+            crate::flat::FlatDataset::new(FlattenedVecs::from(&dataset), dataset.metadata.clone())
+                .unwrap();
+        flat.initialize(&OakIndexOptions::default()).unwrap();
+        let (rooted, _) = flat
+            .search_with_params(&queries, &None, 10, 16, &params)
+            .unwrap();
+        for (rooted, squared) in
+            std::iter::zip(rooted, flat.search(&queries, &None, 10, 16).unwrap())
+        {
+            for ((_, rooted), (_, squared)) in std::iter::zip(rooted, squared) {
+                assert_eq!(rooted, squared.sqrt());
+            }
+        }
+
+        let inner_product = OakIndexOptions {
+            metric: DistanceMetric::InnerProduct,
+            ..Default::default()
+        };
+        flat.initialize(&inner_product).unwrap();
+        assert_eq!(
+            flat.search_with_params(&queries, &None, 10, 16, &params)
+                .unwrap()
+                .0,
+            flat.search(&queries, &None, 10, 16).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_search_cancellable() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let queries = FlattenedVecs::from(&dataset);
+        let predicate = Some(PredicateQuery::range(2, 5));
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let results = dataset
+            .search_cancellable(&queries, &predicate, 5, 16, &cancel)
+            .unwrap();
+        assert_eq!(
+            results,
+            dataset.search(&queries, &predicate, 5, 16).unwrap()
+        );
+
+        let flag = Arc::clone(&cancel);
+        std::thread::spawn(move || flag.store(true, Ordering::Relaxed))
+            .join()
+            .unwrap();
+        assert_eq!(
+            dataset
+                .search_cancellable(&queries, &predicate, 5, 16, &cancel)
+                .err(),
+            Some(SearchableError::Cancelled)
+        );
+    }
+
+    #[test]
+    fn test_search_streaming() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        // More queries than fit in one chunk, so that the offsets must carry across chunks.
+        let dimensionality = dataset.get_dimensionality();
+        let num_queries = crate::dataset::STREAMING_CHUNK_SIZE + 3;
+        let queries = FlattenedVecs {
+            dimensionality,
+            data: generate_random_vector(dimensionality * num_queries),
+        };
+        let predicate = Some(PredicateQuery::new(3));
+
+        let expected = dataset.search(&queries, &predicate, 5, 16).unwrap();
+        let mut streamed = vec![];
+        dataset
+            .search_streaming(&queries, &predicate, 5, 16, |i, result| {
+                streamed.push((i, result))
+            })
+            .unwrap();
+
+        assert_eq!(streamed.len(), num_queries);
+        for (i, (idx, result)) in streamed.into_iter().enumerate() {
+            assert_eq!(i, idx);
+            assert_eq!(result, expected[i]);
+        }
+    }
+
+    #[test]
+    fn test_dedup_queries() {
+        let vectors = FlattenedVecs {
+            dimensionality: 2,
+            data: vec![1., 2., 0., 0., 1., 2., -0., 0., 0., 0.],
+        };
+        let (unique, copies) = vectors.dedup();
+        // Zeros of different signs have different bit patterns, so are not merged.
+        assert_eq!(unique.data, vec![1., 2., 0., 0., -0., 0.]);
+        assert_eq!(copies, vec![0, 1, 0, 2, 1]);
+
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset
+            .initialize(&OakIndexOptions::builder().seed(7).build().unwrap())
+            .unwrap();
+        let mut deduped = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        deduped
+            .initialize(
+                &OakIndexOptions::builder()
+                    .seed(7)
+                    .dedup_queries(true)
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+
+        let dimensionality = dataset.get_dimensionality();
+        let mut data = vec![];
+        for id in [3, 8, 3, 3, 11, 8] {
+            data.extend_from_slice(dataset.flat.get(id).unwrap());
+        }
+        let queries = FlattenedVecs {
+            dimensionality,
+            data,
+        };
+        let predicate = Some(PredicateQuery::range(2, 5));
+        let results = deduped.search(&queries, &predicate, 5, 16).unwrap();
+        assert_eq!(
+            results,
+            dataset.search(&queries, &predicate, 5, 16).unwrap()
+        );
+        assert_eq!(results[0], results[3]);
+
+        // An error names the query as it was given, rather than its position among the distinct.
+        let mut data = queries.data.clone();
+        data.extend_from_slice(&vec![f32::NAN; dimensionality]);
+        let queries = FlattenedVecs {
+            dimensionality,
+            data,
+        };
+        assert_eq!(
+            deduped.search(&queries, &None, 5, 16),
+            Err(SearchableError::NonFiniteQuery {
+                query_index: 6,
+                component: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_search_iter() {
+        let mut dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let dimensionality = dataset.get_dimensionality();
+        let num_queries = crate::dataset::STREAMING_CHUNK_SIZE + 3;
+        let queries = FlattenedVecs {
+            dimensionality,
+            data: generate_random_vector(dimensionality * num_queries),
+        };
+        let predicate = Some(PredicateQuery::new(3));
+
+        let expected = dataset.search(&queries, &predicate, 5, 16).unwrap();
+        let iterated = dataset
+            .search_iter(&queries, &predicate, 5, 16)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(iterated, expected);
+
+        // Taking only the first results leaves the later chunks unsearched.
+        let first: Vec<_> = dataset
+            .search_iter(&queries, &predicate, 5, 16)
+            .unwrap()
+            .take(2)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(first, expected[..2]);
+
+        assert_eq!(
+            dataset
+                .search_iter(
+                    &queries,
+                    &Some(PredicateQuery::new(1).on_attribute(1)),
+                    5,
+                    16
+                )
+                .err(),
+            Some(SearchableError::UnknownAttribute {
+                index: 1,
+                num_attributes: 1
+            })
+        );
+        let mismatched = FlattenedVecs {
+            dimensionality: dimensionality - 1,
+            data: vec![0.; dimensionality - 1],
+        };
+        let mut iter = dataset.search_iter(&mismatched, &None, 5, 16).unwrap();
+        assert!(matches!(
+            iter.next(),
+            Some(Err(SearchableError::DimensionalityMismatch { .. }))
+        ));
+        assert!(iter.next().is_none());
     }
 }
@@ -1,12 +1,21 @@
 pub mod acorn;
+#[cfg(feature = "hdf5")]
+pub mod ann_benchmarks;
+#[cfg(feature = "async-search")]
+pub mod async_search;
 pub mod bitmask;
 pub mod dataset;
+pub mod flat;
 pub mod fvecs;
+pub mod metrics;
 pub mod predicate;
 pub mod router;
 pub mod stubs;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 
 #[cxx::bridge(namespace = "faiss")]
+#[allow(clippy::missing_safety_doc, clippy::too_many_arguments)]
 pub mod ffi {
     unsafe extern "C++" {
         include!("oak/third_party/ACORN/faiss/IndexACORN.h");
@@ -19,13 +28,49 @@ pub mod ffi {
             gamma: i32,
             M_beta: i32,
             metadata: &Vec<i32>,
+            metric_type: i32, // the faiss MetricType of the index: 0 for inner product, 1 for L2, 2 for L1
         ) -> UniquePtr<IndexACORNFlat>;
 
+        fn set_index_seed(idx: &mut UniquePtr<IndexACORNFlat>, seed: u64);
+
+        fn set_index_pq(
+            idx: &mut UniquePtr<IndexACORNFlat>,
+            pq_m: i32,  // the number of subquantizers, which must divide the dimensionality
+            nbits: i32, // the number of bits in the code of each subquantizer
+        ) -> Result<()>;
+
+        fn set_index_refine_flat(idx: &mut UniquePtr<IndexACORNFlat>) -> Result<()>;
+
+        fn set_index_linear_transform(
+            idx: &mut UniquePtr<IndexACORNFlat>,
+            d_in: i32, // the dimensionality of the vectors, which are transformed to that of the index
+            matrix: &[f32], // the row-major matrix of the transform, with d_in columns
+            bias: &[f32], // the bias added after the matrix, or empty for none
+        ) -> Result<()>;
+
+        fn set_index_pca(
+            idx: &mut UniquePtr<IndexACORNFlat>,
+            d_in: i32, // the dimensionality of the vectors, which the PCA reduces to that of the index
+            whiten: bool, // whether the components are scaled to unit variance
+        ) -> Result<()>;
+
+        fn set_index_opq(
+            idx: &mut UniquePtr<IndexACORNFlat>,
+            opq_m: i32, // the number of subquantizers that the rotation is learned for
+        ) -> Result<()>;
+
         unsafe fn add_to_index(
             idx: &mut UniquePtr<IndexACORNFlat>,
             n: i64,        // number of vectors to be added
             x: *const f32, // raw pointer to the contiguous array of vectors
-        );
+        ) -> Result<()>;
+
+        unsafe fn append_to_index(
+            idx: &mut UniquePtr<IndexACORNFlat>,
+            n: i64,              // number of vectors to be added
+            x: *const f32,       // raw pointer to the contiguous array of vectors
+            metadata: &Vec<i32>, // the metadata for the vectors being added, one per vector
+        ) -> Result<()>;
 
         unsafe fn search_index(
             idx: &UniquePtr<IndexACORNFlat>,
@@ -38,6 +83,45 @@ pub mod ffi {
             // probably shouldn't be.
             filter_id_map: *mut c_char, // a bitmap of the IDs in the filter, an array of (n * N) bools, where N is the total number of vectors in the index, and a '1' represents that the vector at that index passes the predicate for that query.
             efsearch: i64,              // the search-time parameter to tweak recall
+            max_filtered_visits: i64, // the number of matching vectors after which the search of the base level stops, or negative for no limit
+            refine_k_factor: i64, // for an index with a refine storage, the multiple of k candidates that are refined
+        ) -> Result<i64>; // the number of distances computed over all of the queries
+
+        fn write_index_acorn(
+            idx: &UniquePtr<IndexACORNFlat>,
+            fname: &str, // path of the file to write the index to
         ) -> Result<()>;
+
+        fn read_index_acorn(
+            fname: &str,         // path of the file to read the index from
+            metadata: &Vec<i32>, // the metadata for the vectors in the index
+        ) -> Result<UniquePtr<IndexACORNFlat>>;
+
+        fn index_dimensionality(idx: &UniquePtr<IndexACORNFlat>) -> i32;
+        fn index_ntotal(idx: &UniquePtr<IndexACORNFlat>) -> i64;
+        fn index_m(idx: &UniquePtr<IndexACORNFlat>) -> i32;
+        fn index_gamma(idx: &UniquePtr<IndexACORNFlat>) -> i32;
+        fn index_m_beta(idx: &UniquePtr<IndexACORNFlat>) -> i32;
+        fn index_metric_type(idx: &UniquePtr<IndexACORNFlat>) -> i32;
+        fn index_pq_m(idx: &UniquePtr<IndexACORNFlat>) -> i32;
+        fn index_pq_nbits(idx: &UniquePtr<IndexACORNFlat>) -> i32;
+        fn index_has_refine_flat(idx: &UniquePtr<IndexACORNFlat>) -> bool;
+        fn index_pre_transform_kind(idx: &UniquePtr<IndexACORNFlat>) -> i32;
+        fn index_input_dimensionality(idx: &UniquePtr<IndexACORNFlat>) -> i32;
+
+        fn reconstruct_vectors(idx: &UniquePtr<IndexACORNFlat>, ids: &[i64]) -> Result<Vec<f32>>;
+
+        fn set_omp_threads(num_threads: i32) -> Result<()>;
+        fn omp_threads() -> i32;
+
+        fn index_max_level(idx: &UniquePtr<IndexACORNFlat>) -> i32;
+        fn index_out_degrees(
+            idx: &UniquePtr<IndexACORNFlat>,
+            level: i32, // the level of the graph, where 0 is the base level that contains every vector
+        ) -> Result<Vec<i32>>;
+        fn index_edges(
+            idx: &UniquePtr<IndexACORNFlat>,
+            level: i32, // the level of the graph, where 0 is the base level that contains every vector
+        ) -> Result<Vec<i64>>;
     }
 }
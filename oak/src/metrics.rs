@@ -0,0 +1,404 @@
+use crate::bitmask::Bitmask;
+use crate::dataset::{DistanceMetric, TopKSearchResult, TopKSearchResultBatch};
+use crate::fvecs::FlattenedVecs;
+use rayon::prelude::*;
+use std::collections::HashSet;
+
+/// The mean recall@k of a batch of search results, where `ground_truth[i]` holds the ids of the
+/// true nearest neighbors of query `i`, closest first (as in SIFT's `*_groundtruth.ivecs`).
+///
+/// For each query, the recall is the fraction of the first `k` distinct ground truth ids that are
+/// among the first `k` returned ids. Rows with fewer than `k` results are not padded, so any
+/// missing results simply count as misses, and duplicate ids are only counted once. Queries with
+/// no ground truth are skipped.
+///
+/// # Panics
+/// - Panics if `results` and `ground_truth` are for different numbers of queries.
+pub fn recall_at_k(results: &TopKSearchResultBatch, ground_truth: &[Vec<usize>], k: usize) -> f32 {
+    assert_eq!(
+        results.len(),
+        ground_truth.len(),
+        "There must be ground truth for each query in the results"
+    );
+
+    let recalls: Vec<f32> = std::iter::zip(results, ground_truth)
+        .filter_map(|(result, truth)| {
+            let mut seen = HashSet::new();
+            let truth: HashSet<usize> = truth
+                .iter()
+                .copied()
+                .filter(|id| seen.insert(*id))
+                .take(k)
+                .collect();
+            if truth.is_empty() {
+                return None;
+            }
+
+            let found: HashSet<usize> = result
+                .iter()
+                .take(k)
+                .map(|(id, _)| *id)
+                .filter(|id| truth.contains(id))
+                .collect();
+            Some(found.len() as f32 / truth.len() as f32)
+        })
+        .collect();
+
+    if recalls.is_empty() {
+        return 0.;
+    }
+    recalls.iter().sum::<f32>() / recalls.len() as f32
+}
+
+/// The exact `k` nearest neighbors in `data` of each of the `queries`, found by comparing each
+/// query against every vector. This is far too slow to serve searches, but gives the ground truth
+/// against which the results of an index can be measured (e.g. with `recall_at_k`).
+///
+/// The results take the same form as those of `SimilaritySearchable::search` over an index built
+/// with `metric`: the ids are positions in `data`, the distances are in the units of the metric,
+/// and each row holds the closest `k` (or all of `data`, if it holds fewer), closest first. Ties
+/// are broken by the lower id.
+///
+/// # Panics
+/// - Panics if `data` and `queries` have different dimensionalities.
+pub fn brute_force_knn(
+    data: &FlattenedVecs,
+    queries: &FlattenedVecs,
+    k: usize,
+    metric: DistanceMetric,
+) -> TopKSearchResultBatch {
+    brute_force_knn_where(data, queries, k, metric, |_| true)
+}
+
+/// Like `brute_force_knn`, but only the vectors that `bitmask` keeps are considered, so that each
+/// row holds their exact `k` nearest neighbors (or all of them, if fewer are kept). This needs no
+/// index, and so gives the ground truth of a filtered search, against which the results of
+/// `SimilaritySearchable::search_with_bitmask` (or of a search with the predicate that the mask
+/// was built from) can be measured.
+///
+/// # Panics
+/// - Panics if `data` and `queries` have different dimensionalities, or if `bitmask` is not of the
+///   same length as `data`.
+pub fn brute_force_search_with_bitmask(
+    data: &FlattenedVecs,
+    queries: &FlattenedVecs,
+    bitmask: &Bitmask,
+    k: usize,
+    metric: DistanceMetric,
+) -> TopKSearchResultBatch {
+    assert_eq!(
+        bitmask.map.len(),
+        data.len(),
+        "The bitmask must have an entry for each vector of the data"
+    );
+    brute_force_knn_where(data, queries, k, metric, |id| bitmask.map[id] != 0)
+}
+
+fn brute_force_knn_where(
+    data: &FlattenedVecs,
+    queries: &FlattenedVecs,
+    k: usize,
+    metric: DistanceMetric,
+    keep: impl Fn(usize) -> bool + Sync,
+) -> TopKSearchResultBatch {
+    assert_eq!(
+        data.dimensionality, queries.dimensionality,
+        "The queries must have the same dimensionality as the data"
+    );
+
+    let normalized;
+    let (data, queries) = if metric == DistanceMetric::Cosine {
+        normalized = (data.to_normalized(), queries.to_normalized());
+        (&normalized.0, &normalized.1)
+    } else {
+        (data, queries)
+    };
+
+    queries
+        .data
+        .par_chunks_exact(queries.dimensionality)
+        .map(|query| {
+            let mut neighbors: TopKSearchResult = data
+                .data
+                .chunks_exact(data.dimensionality)
+                .enumerate()
+                .filter(|(id, _)| keep(*id))
+                .map(|(id, vector)| (id, exact_distance(metric, query, vector)))
+                .collect();
+            sort_closest_first(metric, &mut neighbors);
+            neighbors.truncate(k);
+            neighbors
+        })
+        .collect()
+}
+
+/// Like `brute_force_knn`, but measures the distances between the vectors with a custom
+/// `distance`, e.g. to find the ground truth under a metric that ACORN does not support. The
+/// vectors are compared as they are, so a `distance` that expects normalized vectors must be
+/// given normalized `data` and `queries`.
+///
+/// # Panics
+/// - Panics if `data` and `queries` have different dimensionalities.
+pub fn brute_force_knn_with(
+    data: &FlattenedVecs,
+    queries: &FlattenedVecs,
+    k: usize,
+    distance: &dyn Distance,
+) -> TopKSearchResultBatch {
+    assert_eq!(
+        data.dimensionality, queries.dimensionality,
+        "The queries must have the same dimensionality as the data"
+    );
+
+    queries
+        .data
+        .par_chunks_exact(queries.dimensionality)
+        .map(|query| {
+            let mut neighbors: TopKSearchResult = data
+                .data
+                .chunks_exact(data.dimensionality)
+                .map(|vector| distance.distance(query, vector))
+                .enumerate()
+                .collect();
+            sort_closest_first_by(distance.larger_is_closer(), &mut neighbors);
+            neighbors.truncate(k);
+            neighbors
+        })
+        .collect()
+}
+
+/// A distance between two vectors, through which the brute-force and re-ranking paths (see
+/// `brute_force_knn_with` and `SimilaritySearchable::search_rerank_with`) can order neighbors by
+/// metrics other than those that ACORN supports. Smaller distances are closer, unless
+/// `larger_is_closer` says otherwise.
+///
+/// `DistanceMetric` implements this with the distances that ACORN reports, except that the
+/// distance under `Cosine` is the cosine similarity of the vectors as given, whether or not they
+/// are normalized. With the `anndists` feature, the distances of the anndists crate can be used
+/// through `AnnDistance`.
+pub trait Distance: Sync {
+    /// The distance between `a` and `b`, which have the same dimensionality.
+    fn distance(&self, a: &[f32], b: &[f32]) -> f32;
+
+    /// Whether larger distances are closer, as for a similarity such as the inner product.
+    fn larger_is_closer(&self) -> bool {
+        false
+    }
+}
+
+impl Distance for DistanceMetric {
+    fn distance(&self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            DistanceMetric::Cosine => {
+                let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+                let norms = norm(a) * norm(b);
+                exact_distance(*self, a, b) / norms.max(f32::MIN_POSITIVE)
+            }
+            _ => exact_distance(*self, a, b),
+        }
+    }
+
+    fn larger_is_closer(&self) -> bool {
+        matches!(self, DistanceMetric::InnerProduct | DistanceMetric::Cosine)
+    }
+}
+
+/// Adapts a distance of the anndists crate (such as `anndists::dist::DistL2`, or any other that
+/// implements its `Distance<f32>`, as hnsw_rs takes) into a `Distance`. All of anndists's
+/// distances are smaller for closer vectors.
+#[cfg(feature = "anndists")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AnnDistance<D>(pub D);
+
+#[cfg(feature = "anndists")]
+impl<D: anndists::dist::Distance<f32> + Sync> Distance for AnnDistance<D> {
+    fn distance(&self, a: &[f32], b: &[f32]) -> f32 {
+        self.0.eval(a, b)
+    }
+}
+
+// NOTE: anndists expects smaller distances to be closer, so the inner product and cosine
+// similarity are given as one minus them, as its `DistDot` and `DistCosine` are.
+#[cfg(feature = "anndists")]
+impl anndists::dist::Distance<f32> for DistanceMetric {
+    fn eval(&self, va: &[f32], vb: &[f32]) -> f32 {
+        let distance = Distance::distance(self, va, vb);
+        if self.larger_is_closer() {
+            1. - distance
+        } else {
+            distance
+        }
+    }
+}
+
+/// Sorts `results` so that the closest under `metric` come first, breaking ties by the lower id.
+pub(crate) fn sort_closest_first(metric: DistanceMetric, results: &mut TopKSearchResult) {
+    sort_closest_first_by(metric.larger_is_closer(), results);
+}
+
+/// Sorts `results` so that the closest come first, where larger distances are closer if
+/// `larger_is_closer`, breaking ties by the lower id.
+pub(crate) fn sort_closest_first_by(larger_is_closer: bool, results: &mut TopKSearchResult) {
+    results.sort_by(|(a_id, a), (b_id, b)| {
+        if larger_is_closer {
+            b.total_cmp(a).then(a_id.cmp(b_id))
+        } else {
+            a.total_cmp(b).then(a_id.cmp(b_id))
+        }
+    });
+}
+
+/// The distance between `a` and `b` as ACORN reports it: the squared distance for `L2`, and the
+/// Manhattan distance for `L1`, and the inner product otherwise (which is the cosine similarity for
+/// normalized vectors).
+pub(crate) fn exact_distance(metric: DistanceMetric, a: &[f32], b: &[f32]) -> f32 {
+    let pairs = std::iter::zip(a, b);
+    match metric {
+        DistanceMetric::L2 => pairs.map(|(x, y)| (x - y) * (x - y)).sum(),
+        DistanceMetric::L1 => pairs.map(|(x, y)| (x - y).abs()).sum(),
+        DistanceMetric::InnerProduct | DistanceMetric::Cosine => pairs.map(|(x, y)| x * y).sum(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recall_at_k() {
+        let results = vec![
+            vec![(1, 0.), (2, 0.1), (3, 0.2)],
+            // fewer results than k
+            vec![(4, 0.)],
+        ];
+        let ground_truth = vec![vec![1, 3, 5, 6], vec![4, 4, 7, 8]];
+
+        // query 0 finds {1, 3} of {1, 3, 5}; query 1 finds {4} of {4, 7, 8}
+        let recall = recall_at_k(&results, &ground_truth, 3);
+        assert!((recall - (2. / 3. + 1. / 3.) / 2.).abs() < 1e-6);
+
+        assert_eq!(recall_at_k(&results, &ground_truth, 1), 1.);
+    }
+
+    #[test]
+    fn test_brute_force_knn() {
+        let data = FlattenedVecs {
+            dimensionality: 2,
+            data: vec![0., 0., 1., 0., 0., 3., 2., 2.],
+        };
+        let queries = FlattenedVecs {
+            dimensionality: 2,
+            data: vec![1., 1., 0., 10.],
+        };
+
+        let l2 = brute_force_knn(&data, &queries, 3, DistanceMetric::L2);
+        // (0, 0) and (2, 2) are both at distance 2 from (1, 1), so the lower id comes first.
+        assert_eq!(l2[0], vec![(1, 1.), (0, 2.), (3, 2.)]);
+        assert_eq!(l2[1], vec![(2, 49.), (3, 68.), (0, 100.)]);
+
+        let l1 = brute_force_knn(&data, &queries, 2, DistanceMetric::L1);
+        assert_eq!(l1[0], vec![(1, 1.), (0, 2.)]);
+        // (0, 0) and (2, 2) are both at distance 10 from (0, 10).
+        assert_eq!(l1[1], vec![(2, 7.), (0, 10.)]);
+
+        let ip = brute_force_knn(&data, &queries, 2, DistanceMetric::InnerProduct);
+        assert_eq!(ip[0], vec![(3, 4.), (2, 3.)]);
+
+        let cosine = brute_force_knn(&data, &queries, 1, DistanceMetric::Cosine);
+        assert_eq!(cosine[1][0].0, 2);
+        assert!((cosine[1][0].1 - 1.).abs() < 1e-6);
+
+        // k may exceed the number of vectors.
+        assert_eq!(
+            brute_force_knn(&data, &queries, 10, DistanceMetric::L2)[0].len(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_brute_force_search_with_bitmask() {
+        let data = FlattenedVecs {
+            dimensionality: 2,
+            data: vec![0., 0., 1., 0., 0., 3., 2., 2.],
+        };
+        let queries = FlattenedVecs {
+            dimensionality: 2,
+            data: vec![1., 1.],
+        };
+
+        let bitmask = Bitmask::from(vec![1, 0, 1, 1]);
+        let filtered =
+            brute_force_search_with_bitmask(&data, &queries, &bitmask, 2, DistanceMetric::L2);
+        assert_eq!(filtered, vec![vec![(0, 2.), (3, 2.)]]);
+
+        // Rows hold every kept vector when fewer than `k` are kept.
+        let bitmask = Bitmask::from(vec![0, 0, 1, 0]);
+        let filtered =
+            brute_force_search_with_bitmask(&data, &queries, &bitmask, 3, DistanceMetric::L2);
+        assert_eq!(filtered, vec![vec![(2, 5.)]]);
+    }
+
+    #[test]
+    fn test_custom_distance() {
+        // The Chebyshev distance, which ACORN has no support for.
+        struct Chebyshev;
+        impl Distance for Chebyshev {
+            fn distance(&self, a: &[f32], b: &[f32]) -> f32 {
+                std::iter::zip(a, b)
+                    .map(|(x, y)| (x - y).abs())
+                    .fold(0., f32::max)
+            }
+        }
+
+        let data = FlattenedVecs {
+            dimensionality: 2,
+            data: vec![0., 0., 3., 3., 4., 0.5, -1., 6.],
+        };
+        let queries = FlattenedVecs {
+            dimensionality: 2,
+            data: vec![0., 3.],
+        };
+        // By L2 the closest is [3, 3], but by Chebyshev [0, 0] and [3, 3] tie at 3.
+        assert_eq!(
+            brute_force_knn_with(&data, &queries, 2, &Chebyshev),
+            vec![vec![(0, 3.), (1, 3.)]]
+        );
+
+        for metric in [
+            DistanceMetric::L2,
+            DistanceMetric::L1,
+            DistanceMetric::InnerProduct,
+        ] {
+            assert_eq!(
+                brute_force_knn_with(&data, &queries, 4, &metric),
+                brute_force_knn(&data, &queries, 4, metric)
+            );
+        }
+        // The cosine similarity is computed without normalizing the vectors first.
+        let cosine = brute_force_knn_with(&data, &queries, 4, &DistanceMetric::Cosine);
+        let normalized = brute_force_knn(&data, &queries, 4, DistanceMetric::Cosine);
+        assert_eq!(cosine.len(), normalized.len());
+        for (&(id, distance), &(expected_id, expected)) in
+            std::iter::zip(&cosine[0], &normalized[0])
+        {
+            assert_eq!(id, expected_id);
+            assert!((distance - expected).abs() < 1e-6);
+        }
+    }
+
+    #[cfg(feature = "anndists")]
+    #[test]
+    fn test_anndists() {
+        use anndists::dist::{DistL1, DistL2};
+
+        let a = [0., 1., 2.];
+        let b = [2., 1., 0.];
+        assert_eq!(AnnDistance(DistL2).distance(&a, &b), 8f32.sqrt());
+        assert_eq!(AnnDistance(DistL1).distance(&a, &b), 4.);
+
+        use anndists::dist::Distance as _;
+        assert_eq!(DistanceMetric::L2.eval(&a, &b), 8.);
+        assert_eq!(DistanceMetric::InnerProduct.eval(&a, &a), -4.);
+        assert!(DistanceMetric::Cosine.eval(&a, &a).abs() < 1e-6);
+    }
+}
@@ -1,11 +1,16 @@
+use crate::bitmask::Bitmask;
+use crate::dataset::{HybridSearchMetadata, SearchableError};
+
 /// ACORN specifies the predicates for queries as one bitmap per query, where the bitmap is an
 /// array of length N (the number of total entries in the database). This is presumably so that
 /// ACORN is capable of arbitrary predicates; but it does mean that the query language for the cpp
 /// API is somewhat unwieldy.
 ///
 /// Thus here we allow a more concise representation of predicates with a basic query language that
-/// we can develop as needed. For the POC, the query language _only_ accepts equality for a
-/// singular match. The assumption is also that each vector has one and only one attribute (a u8).
+/// we can develop as needed. The query language accepts equality for a singular match, an
+/// inclusive range, and membership of a set of values. Each predicate is evaluated over one
+/// attribute of each vector, which is the first unless another is chosen with
+/// `PredicateQuery::on_attribute`.
 /// At a first order of approximation, this language accepts constructs with two tokens,
 /// PredicateOp, and PredicateRhs. A basic example: to express the idea that we only want to
 /// retrieve queries "where the attribute matches '10'", we would construct a query as follows:
@@ -13,33 +18,62 @@
 /// query = PredicateQuery {
 ///     op: PredicateOp::Equals,
 ///     rhs: PredicateRhs::Number(10),
+///     attribute: 0,
 /// }
 ///
 /// Only u8 numbers are supported for names and predicate values at present, as the assumption is
 /// that a dataset will be loaded in with a CSV with columns whose titles are all u8 (the `lhs` of
 /// a PredicateQuery), and whose values are _also_ all u8s (the `rhs`).
 ///
+/// Ranges and sets are instead constructed with `PredicateQuery::range` and `PredicateQuery::in_set`,
+/// and their values may be any i64 (such as the hashes of string labels, see
+/// `HybridSearchMetadata::from_strings`). Any predicate can be inverted with
+/// `PredicateQuery::negate`. The `op` determines how the `rhs` is interpreted, and so each op must
+/// be paired with the matching kind of rhs.
+///
 /// If necessary, we can expand this later.
 #[derive(Clone)]
 pub struct PredicateQuery {
     pub op: PredicateOp,
     pub rhs: PredicateRhs,
+    /// The position of the attribute that the predicate is evaluated over. A negation is instead
+    /// evaluated over the attribute of the predicate that it negates.
+    pub attribute: usize,
 }
 
 #[derive(Clone)]
 pub enum PredicateOp {
+    /// Paired with `PredicateRhs::Number`.
     Equals,
+    /// Paired with `PredicateRhs::Range`.
+    Range,
+    /// Paired with `PredicateRhs::Set`.
+    InSet,
+    /// Paired with `PredicateRhs::Predicate`.
+    Not,
+    /// Paired with `PredicateRhs::Null`.
+    IsNull,
 }
 
 #[derive(Clone)]
 pub enum PredicateRhs {
     Number(u8),
+    /// The lowest and highest values (inclusive) in the range.
+    Range(i64, i64),
+    Set(Vec<i64>),
+    /// The predicate that is negated.
+    Predicate(Box<PredicateQuery>),
+    /// No value, as for `PredicateOp::IsNull`.
+    Null,
 }
 
-impl From<&PredicateRhs> for i32 {
-    fn from(value: &PredicateRhs) -> Self {
+impl TryFrom<&PredicateRhs> for i32 {
+    type Error = SearchableError;
+
+    fn try_from(value: &PredicateRhs) -> Result<Self, Self::Error> {
         match value {
-            PredicateRhs::Number(num) => *num as i32,
+            PredicateRhs::Number(num) => Ok(*num as i32),
+            _ => Err(SearchableError::PredicateSerializationError),
         }
     }
 }
@@ -50,6 +84,127 @@ impl PredicateQuery {
         Self {
             op: PredicateOp::Equals,
             rhs: PredicateRhs::Number(num),
+            attribute: 0,
+        }
+    }
+
+    /// Creates a new PredicateQuery where the attribute is at least `lo` and at most `hi`.
+    pub fn range(lo: i64, hi: i64) -> Self {
+        Self {
+            op: PredicateOp::Range,
+            rhs: PredicateRhs::Range(lo, hi),
+            attribute: 0,
+        }
+    }
+
+    /// Creates a new PredicateQuery where the attribute is any one of `values`.
+    pub fn in_set(values: Vec<i64>) -> Self {
+        Self {
+            op: PredicateOp::InSet,
+            rhs: PredicateRhs::Set(values),
+            attribute: 0,
+        }
+    }
+
+    /// Creates a new PredicateQuery where the attribute is null (see
+    /// `HybridSearchMetadata::from_nullable`). No other predicate matches a null attribute.
+    pub fn is_null() -> Self {
+        Self {
+            op: PredicateOp::IsNull,
+            rhs: PredicateRhs::Null,
+            attribute: 0,
+        }
+    }
+
+    /// Creates a new PredicateQuery where the attribute is not null, whatever its value.
+    pub fn is_not_null() -> Self {
+        Self::negate(Self::is_null())
+    }
+
+    /// Creates a new PredicateQuery that matches exactly the vectors that `inner` does not, among
+    /// those whose attribute is not null: a vector whose attribute is null matches neither `inner`
+    /// nor its negation.
+    pub fn negate(inner: PredicateQuery) -> Self {
+        Self {
+            op: PredicateOp::Not,
+            attribute: inner.attribute,
+            rhs: PredicateRhs::Predicate(Box::new(inner)),
+        }
+    }
+
+    /// Evaluates the predicate over the attribute at position `attribute` rather than the first.
+    /// For a negation, this is the attribute of the predicate that it negates.
+    pub fn on_attribute(mut self, attribute: usize) -> Self {
+        self.attribute = attribute;
+        if let PredicateRhs::Predicate(inner) = self.rhs {
+            self.rhs = PredicateRhs::Predicate(Box::new(inner.on_attribute(attribute)));
+        }
+        self
+    }
+
+    /// Checks that every attribute the predicate is evaluated over exists when vectors have
+    /// `num_attributes` attributes, returning an `UnknownAttribute` error if one does not.
+    pub fn validate(&self, num_attributes: usize) -> Result<(), SearchableError> {
+        if let (PredicateOp::Not, PredicateRhs::Predicate(inner)) = (&self.op, &self.rhs) {
+            return inner.validate(num_attributes);
+        }
+        if self.attribute >= num_attributes {
+            return Err(SearchableError::UnknownAttribute {
+                index: self.attribute,
+                num_attributes,
+            });
+        }
+        Ok(())
+    }
+
+    /// Evaluates the predicate over every vector that `metadata` describes, giving the same mask
+    /// that a search with the predicate would filter on. The mask can then be combined with
+    /// others (see the `Bitmask` combinators) or kept, and searched with
+    /// `SimilaritySearchable::search_with_bitmask`. An error is returned as by `Bitmask::try_new`.
+    pub fn to_bitmask(&self, metadata: &HybridSearchMetadata) -> Result<Bitmask, SearchableError> {
+        Bitmask::evaluate(self, metadata, metadata.len())
+    }
+
+    /// Whether a vector with the (non-null) attribute `attr` matches the predicate. An error is
+    /// returned if the `op` is paired with the wrong kind of `rhs`.
+    pub fn matches(&self, attr: i64) -> Result<bool, SearchableError> {
+        match (&self.op, &self.rhs) {
+            (PredicateOp::IsNull, PredicateRhs::Null) => Ok(false),
+            (PredicateOp::Equals, PredicateRhs::Number(num)) => Ok(attr == *num as i64),
+            (PredicateOp::Range, PredicateRhs::Range(lo, hi)) => Ok(*lo <= attr && attr <= *hi),
+            (PredicateOp::InSet, PredicateRhs::Set(values)) => Ok(values.contains(&attr)),
+            (PredicateOp::Not, PredicateRhs::Predicate(inner)) => Ok(!inner.matches(attr)?),
+            _ => Err(SearchableError::PredicateSerializationError),
+        }
+    }
+
+    /// Whether a vector with the attributes `attrs` matches the predicate, evaluating it over the
+    /// attribute it refers to. Unlike `matches`, each negated predicate is evaluated over its own
+    /// attribute.
+    ///
+    /// # Panics
+    /// - Panics if the predicate refers to an attribute that `attrs` does not have; use `validate`
+    ///   to check for this beforehand.
+    pub fn matches_attrs(&self, attrs: &[i64]) -> Result<bool, SearchableError> {
+        self.matches_row(attrs, &[])
+    }
+
+    /// Like `matches_attrs`, where `nulls` marks which of the `attrs` are null, or is empty if none
+    /// of them are.
+    pub(crate) fn matches_row(
+        &self,
+        attrs: &[i64],
+        nulls: &[bool],
+    ) -> Result<bool, SearchableError> {
+        let null = nulls.get(self.attribute).copied().unwrap_or_default();
+        match (&self.op, &self.rhs) {
+            (PredicateOp::IsNull, PredicateRhs::Null) => Ok(null),
+            (PredicateOp::Not, PredicateRhs::Predicate(inner)) => {
+                Ok(!inner.matches_row(attrs, nulls)? && !null)
+            }
+            // NOTE: the predicate is still evaluated over a null attribute, so that a malformed
+            // predicate is an error whatever the attributes.
+            _ => Ok(self.matches(attrs[self.attribute])? && !null),
         }
     }
 }
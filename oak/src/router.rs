@@ -1,7 +1,58 @@
 use crate::bitmask::Bitmask;
-use crate::dataset::SimilaritySearchable;
-use crate::fvecs::FvecsDatasetPartition;
-use slog_scope::debug;
+use crate::dataset::{
+    ConstructionError, SearchableError, SimilaritySearchable, TopKSearchResultBatch,
+};
+use crate::fvecs::{FlattenedVecs, FvecsDatasetPartition};
+use crate::metrics::sort_closest_first;
+use crate::predicate::PredicateQuery;
+use std::path::Path;
+
+/// Searches a corpus that is sharded across several datasets, by running every query against each
+/// of the `shards` and merging their results into the `topk` closest overall. The vectors of each
+/// shard are numbered after those of the shards before it, so the id of a vector in the results
+/// is its id within its shard plus the total `len` of the preceding shards. The `predicate_query`
+/// is evaluated over the metadata of each shard.
+///
+/// The distances from shards indexed with different metrics are not comparable, and so a
+/// `SearchableError::MetricMismatch` is returned if the shards do not all share one.
+pub fn search_federated(
+    shards: &[&dyn SimilaritySearchable],
+    query_vectors: &FlattenedVecs,
+    predicate_query: &Option<PredicateQuery>,
+    topk: usize,
+    efsearch: i64,
+) -> Result<TopKSearchResultBatch, SearchableError> {
+    let mut metrics = shards.iter().filter_map(|shard| shard.get_metric());
+    let metric = metrics.next();
+    if let Some(index) = metric {
+        if let Some(query) = metrics.find(|&other| other != index) {
+            return Err(SearchableError::MetricMismatch { index, query });
+        }
+    }
+
+    let mut merged = vec![vec![]; query_vectors.len()];
+    let mut offset = 0;
+    for shard in shards {
+        let results = shard.search(query_vectors, predicate_query, topk, efsearch)?;
+        for (merged, results) in std::iter::zip(&mut merged, results) {
+            merged.extend(
+                results
+                    .into_iter()
+                    .map(|(id, distance)| (id + offset, distance)),
+            );
+        }
+        offset += shard.len();
+    }
+
+    // NOTE: every shard returned an error above if it had not been indexed, so there is a metric.
+    if let Some(metric) = metric {
+        for merged in &mut merged {
+            sort_closest_first(metric, merged);
+            merged.truncate(topk);
+        }
+    }
+    Ok(merged)
+}
 
 pub struct Router<'a> {
     base: &'a dyn SimilaritySearchable,
@@ -40,6 +91,26 @@ impl SimilaritySearchable for Router<'_> {
         self.base.get_metadata()
     }
 
+    fn get_metric(&self) -> Option<crate::dataset::DistanceMetric> {
+        self.base.get_metric()
+    }
+
+    fn vectors(&self) -> Option<&crate::fvecs::FlattenedVecs> {
+        self.base.vectors()
+    }
+
+    fn reconstruct(&self, ids: &[usize]) -> Result<crate::fvecs::FlattenedVecs, SearchableError> {
+        self.base.reconstruct(ids)
+    }
+
+    fn index_stats(&self) -> Result<crate::dataset::IndexStats, SearchableError> {
+        self.base.index_stats()
+    }
+
+    fn graph_edges(&self, level: usize) -> Result<Vec<(usize, usize)>, SearchableError> {
+        self.base.graph_edges(level)
+    }
+
     fn initialize(
         &mut self,
         _opts: &crate::dataset::OakIndexOptions,
@@ -48,16 +119,56 @@ impl SimilaritySearchable for Router<'_> {
         Ok(())
     }
 
-    fn search(
+    fn clear_index(&mut self) {
+        // NOTE: the router only borrows the indexes of its datasets, so it has none to clear.
+    }
+
+    fn save_index(&self, _path: &Path) -> Result<(), ConstructionError> {
+        // The router only borrows the indexes it routes between, so they should be saved directly.
+        Err(ConstructionError::IndexNotOwned)
+    }
+
+    fn load_index(
+        &mut self,
+        _path: &Path,
+        _opts: &crate::dataset::OakIndexOptions,
+    ) -> Result<(), ConstructionError> {
+        Err(ConstructionError::IndexNotOwned)
+    }
+
+    fn search_with_stats(
         &self,
         query_vectors: &crate::fvecs::FlattenedVecs,
         predicate_query: &Option<crate::predicate::PredicateQuery>,
         topk: usize,
         efsearch: i64,
-    ) -> anyhow::Result<Vec<crate::dataset::TopKSearchResult>, crate::dataset::SearchableError>
-    {
+    ) -> anyhow::Result<
+        (
+            Vec<crate::dataset::TopKSearchResult>,
+            Vec<crate::dataset::SearchStats>,
+        ),
+        crate::dataset::SearchableError,
+    > {
+        self.base
+            .search_with_stats(query_vectors, predicate_query, topk, efsearch)
+    }
+
+    fn search_with_params(
+        &self,
+        query_vectors: &crate::fvecs::FlattenedVecs,
+        predicate_query: &Option<crate::predicate::PredicateQuery>,
+        topk: usize,
+        efsearch: i64,
+        params: &crate::dataset::SearchParams,
+    ) -> anyhow::Result<
+        (
+            Vec<crate::dataset::TopKSearchResult>,
+            Vec<crate::dataset::SearchStats>,
+        ),
+        crate::dataset::SearchableError,
+    > {
         self.base
-            .search(query_vectors, predicate_query, topk, efsearch)
+            .search_with_params(query_vectors, predicate_query, topk, efsearch, params)
     }
 
     fn search_with_bitmask(
@@ -99,6 +210,13 @@ impl SimilaritySearchable for Router<'_> {
         let score_threshold = 10.;
         if best_score > score_threshold {
             let (_, opp_index) = self.opportunistic[best_index];
+            // The distances from an index built with a different metric are not comparable to
+            // those the base index would have returned.
+            if let (Some(index), Some(query)) = (opp_index.get_metric(), self.base.get_metric()) {
+                if index != query {
+                    return Err(SearchableError::MetricMismatch { index, query });
+                }
+            }
             let search_mask = &self.search_masks[best_index];
             let search_results = opp_index
                 .search_with_bitmask(query_vectors, search_mask, topk, efsearch)
@@ -106,13 +224,13 @@ impl SimilaritySearchable for Router<'_> {
             // if it uses an opportunistic index, we need to map those inner indexes back to
             // indexes over the global search space
             let mapped_results: Vec<(usize, f32)> = search_results
-                .get(0)
+                .first()
                 .unwrap()
                 .iter()
                 .map(|(inner_index, distance)| {
                     (
-                        opp_index.original_indices.as_ref().unwrap()[inner_index.clone()],
-                        distance.clone(),
+                        opp_index.original_indices.as_ref().unwrap()[*inner_index],
+                        *distance,
                     )
                 })
                 .collect();
@@ -122,4 +240,72 @@ impl SimilaritySearchable for Router<'_> {
                 .search_with_bitmask(query_vectors, query_bitmask, topk, efsearch)
         }
     }
+
+    fn range_search(
+        &self,
+        query_vectors: &crate::fvecs::FlattenedVecs,
+        radius: f32,
+        predicate_query: &Option<crate::predicate::PredicateQuery>,
+        efsearch: i64,
+    ) -> anyhow::Result<Vec<crate::dataset::TopKSearchResult>, crate::dataset::SearchableError>
+    {
+        self.base
+            .range_search(query_vectors, radius, predicate_query, efsearch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataset::{DistanceMetric, OakIndexOptions};
+    use crate::flat::FlatDataset;
+    use crate::fvecs::FvecsDataset;
+
+    #[test]
+    fn test_search_federated() {
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        let vectors = FlattenedVecs::from(&dataset);
+        let dims = vectors.dimensionality;
+        let attrs: Vec<i64> = (&dataset.metadata).into_iter().collect();
+        let shard = |range: std::ops::Range<usize>| {
+            let vectors = FlattenedVecs {
+                dimensionality: dims,
+                data: vectors.data[range.start * dims..range.end * dims].to_vec(),
+            };
+            let metadata = attrs[range].iter().copied().collect();
+            let mut shard = FlatDataset::new(vectors, metadata).unwrap();
+            shard.initialize(&OakIndexOptions::default()).unwrap();
+            shard
+        };
+        let whole = shard(0..200);
+        let (first, mut second) = (shard(0..120), shard(120..200));
+
+        let queries = FlattenedVecs {
+            dimensionality: dims,
+            data: vectors.data[110 * dims..130 * dims].to_vec(),
+        };
+        let predicate = Some(PredicateQuery::range(2, 5));
+        for predicate in [None, predicate] {
+            let federated =
+                search_federated(&[&first, &second], &queries, &predicate, 10, 16).unwrap();
+            assert_eq!(
+                federated,
+                whole.search(&queries, &predicate, 10, 16).unwrap()
+            );
+        }
+
+        second
+            .initialize(&OakIndexOptions {
+                metric: DistanceMetric::InnerProduct,
+                ..OakIndexOptions::default()
+            })
+            .unwrap();
+        assert_eq!(
+            search_federated(&[&first, &second], &queries, &None, 10, 16),
+            Err(SearchableError::MetricMismatch {
+                index: DistanceMetric::L2,
+                query: DistanceMetric::InnerProduct,
+            })
+        );
+    }
 }
@@ -0,0 +1,84 @@
+use crate::dataset::TopKSearchResultBatch;
+
+/// Asserts that two batches of search results are the same, comparing ids exactly but distances
+/// only to within `epsilon`, as distances computed on different platforms (or by different code
+/// paths) rarely agree exactly.
+///
+/// # Panics
+/// - Panics with the query and rank of the first mismatch, if there is one.
+#[track_caller]
+pub fn assert_results_approx_eq(
+    actual: &TopKSearchResultBatch,
+    expected: &TopKSearchResultBatch,
+    epsilon: f32,
+) {
+    assert_eq!(
+        actual.len(),
+        expected.len(),
+        "There are results for {} queries, but {} were expected",
+        actual.len(),
+        expected.len()
+    );
+
+    for (query, (actual, expected)) in std::iter::zip(actual, expected).enumerate() {
+        assert_eq!(
+            actual.len(),
+            expected.len(),
+            "Query {query} has {} results, but {} were expected",
+            actual.len(),
+            expected.len()
+        );
+
+        for (rank, (&(actual_id, actual_distance), &(expected_id, expected_distance))) in
+            std::iter::zip(actual, expected).enumerate()
+        {
+            assert_eq!(
+                actual_id, expected_id,
+                "Result {rank} of query {query} has id {actual_id}, but {expected_id} was expected"
+            );
+            assert!(
+                (actual_distance - expected_distance).abs() <= epsilon,
+                "Result {rank} of query {query} has distance {actual_distance}, but \
+                 {expected_distance} was expected (to within {epsilon})"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assert_results_approx_eq() {
+        let expected = vec![vec![(1, 0.5), (2, 1.)], vec![(3, 0.)]];
+        assert_results_approx_eq(
+            &vec![vec![(1, 0.5001), (2, 0.9999)], vec![(3, 0.)]],
+            &expected,
+            1e-3,
+        );
+
+        for (actual, message) in [
+            (vec![vec![(1, 0.5), (2, 1.)]], "results for 1 queries"),
+            (
+                vec![vec![(1, 0.5), (2, 1.)], vec![]],
+                "Query 1 has 0 results",
+            ),
+            (
+                vec![vec![(1, 0.5), (4, 1.)], vec![(3, 0.)]],
+                "Result 1 of query 0 has id 4",
+            ),
+            (
+                vec![vec![(1, 0.5), (2, 1.1)], vec![(3, 0.)]],
+                "Result 1 of query 0 has distance 1.1",
+            ),
+        ] {
+            let panic = std::panic::catch_unwind(|| {
+                assert_results_approx_eq(&actual, &expected, 1e-3);
+            })
+            .unwrap_err();
+            let panic = panic.downcast_ref::<String>().unwrap();
+            assert!(panic.contains(message), "{panic}");
+        }
+    }
+}
@@ -10,12 +10,14 @@
 #include "oak/third_party/ACORN/faiss/IndexACORN.h"
 
 #include <omp.h>
+#include <algorithm>
 #include <cassert>
 #include <cinttypes>
 #include <cmath>
 #include <cstdio>
 #include <cstdlib>
 #include <cstring>
+#include <mutex>
 
 #include <queue>
 #include <unordered_set>
@@ -27,12 +29,15 @@
 #include "oak/third_party/ACORN/faiss/Index2Layer.h"
 #include "oak/third_party/ACORN/faiss/IndexFlat.h"
 #include "oak/third_party/ACORN/faiss/IndexIVFPQ.h"
+#include "oak/third_party/ACORN/faiss/IndexPQ.h"
 #include "oak/third_party/ACORN/faiss/impl/AuxIndexStructures.h"
 #include "oak/third_party/ACORN/faiss/impl/FaissAssert.h"
 #include "oak/third_party/ACORN/faiss/utils/Heap.h"
 #include "oak/third_party/ACORN/faiss/utils/distances.h"
 #include "oak/third_party/ACORN/faiss/utils/random.h"
 #include "oak/third_party/ACORN/faiss/utils/sorting.h"
+#include "oak/third_party/ACORN/faiss/index_io.h"
+#include "oak/third_party/ACORN/faiss/impl/io.h"
 
 // # added
 #include <sys/time.h>
@@ -71,6 +76,9 @@ using NodeDistFarther = ACORN::NodeDistFarther;
 
 ACORNStats acorn_stats;
 
+// OAK: searches may be run concurrently from Rust, so updates to the global stats are serialized.
+static std::mutex acorn_stats_mutex;
+
 /**************************************************************
  * add / search blocks of descriptors
  **************************************************************/
@@ -181,7 +189,7 @@ void acorn_add_vertices(
             max_level * index_acorn.d * acorn.efConstruction);
 
     { // perform add
-        RandomGenerator rng2(789);
+        RandomGenerator rng2(index_acorn.add_seed);
 
         int i1 = n;
 
@@ -198,7 +206,7 @@ void acorn_add_vertices(
 
             bool interrupt = false;
 
-#pragma omp parallel if (i1 > i0 + 100)
+#pragma omp parallel if (i1 > i0 + 100 && !index_acorn.deterministic)
             {
                 VisitedTable vt(ntotal);
 
@@ -280,6 +288,8 @@ IndexACORN::~IndexACORN() {
     if (own_fields) {
         delete storage;
     }
+    delete refine_storage;
+    delete pre_transform;
 }
 
 void IndexACORN::train(idx_t n, const float* x) {
@@ -299,7 +309,8 @@ void IndexACORN::search(
             float* distances,
             idx_t* labels,
             char* filter_id_map,
-            const SearchParameters* params_in) const {
+            const SearchParameters* params_in,
+            ACORNStats* stats_out) const {
     FAISS_THROW_IF_NOT(k > 0);
     FAISS_THROW_IF_NOT_MSG(
             storage,
@@ -321,7 +332,10 @@ void IndexACORN::search(
     for (idx_t i0 = 0; i0 < n; i0 += check_period) {
         idx_t i1 = std::min(i0 + check_period, n);
 
-#pragma omp parallel
+// OAK: Rust searches each query with its own call, from many rayon threads at once. A team of
+// threads (each with a VisitedTable over the whole index) for a single query would only leave all
+// but one of them idle, so the queries are only split over threads when there is more than one.
+#pragma omp parallel if (i1 - i0 > 1)
         {
             VisitedTable vt(ntotal);
             DistanceComputer* dis = storage_distance_computer(storage);
@@ -368,7 +382,14 @@ void IndexACORN::search(
         }
     }
 
-    acorn_stats.combine({n1, n2, n3, ndis, nreorder, candidates_loop, neighbors_loop, tuple_unwrap, skips, visits}); //added for profiling
+    ACORNStats stats(n1, n2, n3, ndis, nreorder, candidates_loop, neighbors_loop, tuple_unwrap, skips, visits); //added for profiling
+    if (stats_out) {
+        *stats_out = stats;
+    }
+    {
+        std::lock_guard<std::mutex> lock(acorn_stats_mutex);
+        acorn_stats.combine(stats);
+    }
 }
 
 // TODO figure out what do with this
@@ -434,7 +455,10 @@ void IndexACORN::search(
         }
     }
 
-    acorn_stats.combine({n1, n2, n3, ndis, nreorder});
+    {
+        std::lock_guard<std::mutex> lock(acorn_stats_mutex);
+        acorn_stats.combine({n1, n2, n3, ndis, nreorder});
+    }
 }
 
 // add n vectors of dimension d to the index, x is the matrix of vectors TODO
@@ -446,6 +470,9 @@ void IndexACORN::add(idx_t n, const float* x) {
     int n0 = ntotal;
     storage->add(n, x);
     ntotal = storage->ntotal;
+    if (refine_storage) {
+        refine_storage->add(n, x);
+    }
 
     acorn_add_vertices(*this, n0, n, x, verbose, acorn.levels.size() == ntotal);
 }
@@ -453,10 +480,18 @@ void IndexACORN::add(idx_t n, const float* x) {
 void IndexACORN::reset() {
     acorn.reset();
     storage->reset();
+    if (refine_storage) {
+        refine_storage->reset();
+    }
     ntotal = 0;
 }
 
 void IndexACORN::reconstruct(idx_t key, float* recons) const {
+    // OAK: the refine storage, if any, holds the vectors exactly.
+    if (refine_storage) {
+        refine_storage->reconstruct(key, recons);
+        return;
+    }
     storage->reconstruct(key, recons);
 }
 
@@ -493,31 +528,224 @@ std::unique_ptr<IndexACORNFlat> new_index_acorn(
   int M,
   int gamma,
   int M_beta,
-  const rust::Vec<int>& metadata
+  const rust::Vec<int>& metadata,
+  int metric_type
 ) {
   // Copy the elements to a C++ std::vector using STL algorithm.
   std::vector<int> metadata_cpp;
   std::copy(metadata.begin(), metadata.end(), std::back_inserter(metadata_cpp));
 
   std::unique_ptr<faiss::IndexACORNFlat> base_index(
-    new faiss::IndexACORNFlat(d, M, gamma, metadata_cpp, M_beta, METRIC_L2)
+    new faiss::IndexACORNFlat(d, M, gamma, metadata_cpp, M_beta, static_cast<MetricType>(metric_type))
   );
+  base_index->owned_metadata = std::move(metadata_cpp);
+  base_index->acorn.metadata = base_index->owned_metadata.data();
 
   base_index.get()->acorn.efSearch = 16; 
   return base_index;
 }
 
+// OAK: seeds the randomness used when vectors are added to an index, and adds them on a single
+// thread, so that building an index twice with the same seed and vectors gives the same graph.
+void set_index_seed(
+  std::unique_ptr<IndexACORNFlat>& idx,
+  uint64_t seed
+) {
+  idx->acorn.rng = RandomGenerator(static_cast<int64_t>(seed));
+  idx->add_seed = static_cast<int64_t>(seed);
+  idx->deterministic = true;
+}
+
+// OAK: replaces the storage of an empty index with a product quantizer, which (unlike the flat
+// storage) must be trained before vectors are added to it.
+void set_index_pq(
+  std::unique_ptr<IndexACORNFlat>& idx,
+  int pq_m,
+  int nbits
+) {
+  FAISS_THROW_IF_NOT_MSG(idx->ntotal == 0, "the storage of a non-empty index cannot be replaced");
+  FAISS_THROW_IF_NOT_MSG(pq_m > 0 && idx->d % pq_m == 0, "pq_m must divide the dimensionality");
+
+  Index* pq = new IndexPQ(idx->d, pq_m, nbits, idx->metric_type);
+  if (idx->own_fields) {
+    delete idx->storage;
+  }
+  idx->storage = pq;
+  idx->own_fields = true;
+  idx->is_trained = false;
+}
+
+// OAK: gives an empty index a flat copy of the vectors added to it, as IndexRefineFlat holds.
+void set_index_refine_flat(
+  std::unique_ptr<IndexACORNFlat>& idx
+) {
+  FAISS_THROW_IF_NOT_MSG(idx->ntotal == 0, "the refine storage of a non-empty index cannot be set");
+  delete idx->refine_storage;
+  idx->refine_storage = new IndexFlat(idx->d, idx->metric_type);
+}
+
+// OAK: gives an empty index a fixed linear transform, which needs no training.
+void set_index_linear_transform(
+  std::unique_ptr<IndexACORNFlat>& idx,
+  int d_in,
+  rust::Slice<const float> matrix,
+  rust::Slice<const float> bias
+) {
+  FAISS_THROW_IF_NOT_MSG(idx->ntotal == 0, "the pre-transform of a non-empty index cannot be set");
+  FAISS_THROW_IF_NOT_MSG(
+      matrix.size() == size_t(d_in) * idx->d, "the matrix must have d_in columns for each row");
+  FAISS_THROW_IF_NOT_MSG(
+      bias.empty() || bias.size() == size_t(idx->d), "the bias must have a row for each row");
+
+  LinearTransform* transform = new LinearTransform(d_in, idx->d, !bias.empty());
+  transform->A.assign(matrix.begin(), matrix.end());
+  transform->b.assign(bias.begin(), bias.end());
+  transform->is_trained = true;
+  transform->set_is_orthonormal();
+  delete idx->pre_transform;
+  idx->pre_transform = transform;
+}
+
+// OAK: gives an empty index a PCA down to its dimensionality, as IndexPreTransform applies.
+void set_index_pca(
+  std::unique_ptr<IndexACORNFlat>& idx,
+  int d_in,
+  bool whiten
+) {
+  FAISS_THROW_IF_NOT_MSG(idx->ntotal == 0, "the pre-transform of a non-empty index cannot be set");
+  FAISS_THROW_IF_NOT_MSG(d_in >= idx->d, "a PCA cannot increase the dimensionality");
+  delete idx->pre_transform;
+  idx->pre_transform = new PCAMatrix(d_in, idx->d, whiten ? -0.5 : 0);
+}
+
+// OAK: gives an empty index an OPQ rotation, as IndexPreTransform applies.
+void set_index_opq(
+  std::unique_ptr<IndexACORNFlat>& idx,
+  int opq_m
+) {
+  FAISS_THROW_IF_NOT_MSG(idx->ntotal == 0, "the pre-transform of a non-empty index cannot be set");
+  FAISS_THROW_IF_NOT_MSG(
+      opq_m > 0 && idx->d % opq_m == 0, "opq_m must divide the dimensionality");
+  delete idx->pre_transform;
+  idx->pre_transform = new OPQMatrix(idx->d, opq_m);
+}
+
+// OAK: the `n` vectors `x` transformed by the pre-transform of `idx`, if it has one, which must
+// then be trained. The transformed copy is held by `owned`.
+static const float* apply_pre_transform(
+  const IndexACORNFlat& idx,
+  idx_t n,
+  const float* x,
+  std::unique_ptr<const float[]>& owned
+) {
+  if (!idx.pre_transform) {
+    return x;
+  }
+  FAISS_THROW_IF_NOT_MSG(idx.pre_transform->is_trained, "the pre-transform has not been trained");
+  owned.reset(idx.pre_transform->apply(n, x));
+  return owned.get();
+}
+
+// OAK: replaces the `k_base` candidates of each of the `n` queries (padded with a label of -1) by
+// their exact distances in `refine`, and writes the closest `k` of them to `labels` and
+// `distances`, as IndexRefine does. Returns the number of distances computed.
+static int64_t refine_candidates(
+  const IndexFlat& refine,
+  idx_t n,
+  const float* x,
+  idx_t k,
+  idx_t* labels,
+  float* distances,
+  idx_t k_base,
+  const idx_t* base_labels
+) {
+  std::unique_ptr<DistanceComputer> dc(refine.get_distance_computer());
+  bool larger_is_closer = refine.metric_type == METRIC_INNER_PRODUCT;
+  int64_t ndis = 0;
+  for (idx_t i = 0; i < n; i++) {
+    dc->set_query(x + i * refine.d);
+    std::vector<std::pair<float, idx_t>> candidates;
+    for (idx_t j = 0; j < k_base; j++) {
+      idx_t label = base_labels[i * k_base + j];
+      if (label < 0) {
+        break;
+      }
+      float distance = (*dc)(label);
+      candidates.emplace_back(larger_is_closer ? -distance : distance, label);
+    }
+    ndis += candidates.size();
+
+    idx_t found = std::min<idx_t>(k, candidates.size());
+    std::partial_sort(candidates.begin(), candidates.begin() + found, candidates.end());
+    for (idx_t j = 0; j < k; j++) {
+      labels[i * k + j] = j < found ? candidates[j].second : -1;
+      distances[i * k + j] = j < found
+          ? (larger_is_closer ? -candidates[j].first : candidates[j].first)
+          : (larger_is_closer ? -HUGE_VALF : HUGE_VALF);
+    }
+  }
+  return ndis;
+}
+
 // OAK: standalone function to add vectors to an index from Rust over FFI.
+// OAK: ACORN reads the metadata of every vector while linking and searching the graph, so a
+// dataset that has no attributes yet (see `set_metadata` in Rust) is given placeholder zeros for
+// the vectors that its metadata does not cover. This is harmless, as the filter map of each search
+// is built from the attributes in Rust, not from this metadata.
+static void pad_metadata(IndexACORNFlat& idx, size_t n) {
+  if (idx.owned_metadata.size() < n) {
+    idx.owned_metadata.resize(n, 0);
+  }
+  idx.acorn.metadata = idx.owned_metadata.data();
+}
+
 void add_to_index(
   std::unique_ptr<IndexACORNFlat>& idx,
   idx_t n, 
   const float* x
 ) {
+  pad_metadata(*idx, idx->ntotal + n);
+
+  // OAK: the pre-transform, if any, is trained on the first vectors, as is the storage on the
+  // vectors once transformed.
+  if (idx->pre_transform && !idx->pre_transform->is_trained) {
+    idx->pre_transform->train(n, x);
+  }
+  std::unique_ptr<const float[]> transformed;
+  x = apply_pre_transform(*idx, n, x, transformed);
+
+  if (!idx->is_trained) {
+    idx->train(n, x);
+    // The graph is linked using the symmetric distances between codes, as in IndexHNSWPQ.
+    if (IndexPQ* pq = dynamic_cast<IndexPQ*>(idx->storage)) {
+      pq->pq.compute_sdc_table();
+    }
+  }
   idx->add(n, x);
 }
 
+// OAK: standalone function to add vectors (along with their metadata) to an index that already
+// contains vectors from Rust over FFI.
+void append_to_index(
+  std::unique_ptr<IndexACORNFlat>& idx,
+  idx_t n,
+  const float* x,
+  const rust::Vec<int>& metadata
+) {
+  FAISS_THROW_IF_NOT(x != nullptr);
+  FAISS_THROW_IF_NOT(metadata.size() == n);
+
+  // The metadata of the new vectors is read while linking them into the graph, so it must be in
+  // place before they are added. Growing the vector may reallocate it, so the pointer is reset.
+  std::copy(metadata.begin(), metadata.end(), std::back_inserter(idx->owned_metadata));
+  idx->acorn.metadata = idx->owned_metadata.data();
+
+  std::unique_ptr<const float[]> transformed;
+  idx->add(n, apply_pre_transform(*idx, n, x, transformed));
+}
+
 // OAK: standalone function to search vectors from an index from Rust over FFI.
-void search_index(
+int64_t search_index(
   const std::unique_ptr<IndexACORNFlat>& idx,
   idx_t n,            // number of query vectors
   const float* x,     // pointer to an array of the query vectors 
@@ -525,7 +753,9 @@ void search_index(
   float* distances,   // pointer to an array of (k*n) floats, each representing a distance of the result from the query vector 
   idx_t* labels,      // pointer to an array of (k*n) indices, each representing the ID of the query vector in idx 
   char* filter_id_map,// a bitmap of the IDs in the filter, an array of (n * N) bools, where N is the total number of vectors in the index, and a '1' represents that the vector at that index passes the predicate for that query.
-  idx_t efsearch      // an integer that can be tweaked to affect ACORN's recall/performance tradeoff dynamically
+  idx_t efsearch,     // an integer that can be tweaked to affect ACORN's recall/performance tradeoff dynamically
+  int64_t max_filtered_visits, // the number of matching vectors after which the search of the base level stops, or negative for no limit
+  int64_t refine_k_factor // for an index with a refine storage, the multiple of k candidates that are refined
 ) {
   FAISS_THROW_IF_NOT(x != nullptr);
   FAISS_THROW_IF_NOT(distances != nullptr);
@@ -533,9 +763,242 @@ void search_index(
   FAISS_THROW_IF_NOT(filter_id_map != nullptr);
   FAISS_THROW_IF_NOT(n > 0 && k > 0);
 
-  idx.get()->acorn.efSearch = efsearch;
+  // OAK: efSearch is passed as a parameter rather than set on the index, so that searches never
+  // write to the index and can safely run concurrently.
+  SearchParametersACORN params;
+  params.efSearch = efsearch;
+  params.max_filtered_visits = max_filtered_visits;
+
+  std::unique_ptr<const float[]> transformed;
+  x = apply_pre_transform(*idx, n, x, transformed);
+
+  ACORNStats stats;
+  if (!idx->refine_storage) {
+    idx->search(n, x, k, distances, labels, filter_id_map, &params, &stats);
+    // NOTE: the hybrid search counts the distances that it computes in n3, rather than in ndis.
+    return stats.n3;
+  }
+
+  // OAK: as in IndexRefineFlat, `k * refine_k_factor` candidates are found over the (compressed)
+  // storage, and the closest `k` of them by their exact distances are returned.
+  idx_t k_base = k * std::max<int64_t>(refine_k_factor, 1);
+  std::vector<idx_t> base_labels(n * k_base);
+  std::vector<float> base_distances(n * k_base);
+  idx->search(
+      n, x, k_base, base_distances.data(), base_labels.data(), filter_id_map, &params, &stats);
+  return stats.n3 + refine_candidates(
+      *idx->refine_storage, n, x, k, labels, distances, k_base, base_labels.data());
+}
+
+// OAK: the tags that introduce the parts of a file written by `write_index_acorn` that follow the
+// index itself.
+static const char REFINE_STORAGE_TAG[4] = {'O', 'A', 'K', 'r'};
+static const char PRE_TRANSFORM_TAG[4] = {'O', 'A', 'K', 't'};
 
-  idx->search(n, x, k, distances, labels, filter_id_map);
+// OAK: standalone function to serialize an index to a file from Rust over FFI.
+void write_index_acorn(
+  const std::unique_ptr<IndexACORNFlat>& idx,
+  rust::Str fname
+) {
+  FileIOWriter writer(std::string(fname).c_str());
+  write_index(idx.get(), &writer);
+  // OAK: the refine storage and pre-transform, if any, follow the index in the same file, each
+  // introduced by a tag (see `read_index_acorn`).
+  if (idx->refine_storage) {
+    writer(REFINE_STORAGE_TAG, 1, 4);
+    write_index(idx->refine_storage, &writer);
+  }
+  if (idx->pre_transform) {
+    writer(PRE_TRANSFORM_TAG, 1, 4);
+    write_VectorTransform(idx->pre_transform, &writer);
+  }
+}
+
+// OAK: standalone function to deserialize an index written by `write_index_acorn` from Rust over FFI.
+std::unique_ptr<IndexACORNFlat> read_index_acorn(
+  rust::Str fname,
+  const rust::Vec<int>& metadata
+) {
+  FileIOReader reader(std::string(fname).c_str());
+  std::unique_ptr<Index> index(read_index(&reader));
+  IndexACORNFlat* acorn_index = dynamic_cast<IndexACORNFlat*>(index.get());
+  FAISS_THROW_IF_NOT_MSG(acorn_index, "file does not contain an ACORN index");
+  index.release();
+
+  std::unique_ptr<IndexACORNFlat> loaded(acorn_index);
+  // OAK: the tagged parts that follow the index (see `write_index_acorn`).
+  char tag[4];
+  while (reader(tag, 1, 4) == 4) {
+    if (memcmp(tag, REFINE_STORAGE_TAG, 4) == 0) {
+      std::unique_ptr<Index> refine(read_index(&reader));
+      IndexFlat* refine_flat = dynamic_cast<IndexFlat*>(refine.get());
+      FAISS_THROW_IF_NOT_MSG(
+          refine_flat && refine_flat->ntotal == loaded->ntotal,
+          "file does not contain the refine storage of the index");
+      refine.release();
+      loaded->refine_storage = refine_flat;
+    } else if (memcmp(tag, PRE_TRANSFORM_TAG, 4) == 0) {
+      VectorTransform* pre_transform = read_VectorTransform(&reader);
+      FAISS_THROW_IF_NOT_MSG(
+          pre_transform->d_out == loaded->d, "file does not contain the pre-transform of the index");
+      loaded->pre_transform = pre_transform;
+    } else {
+      FAISS_THROW_MSG("file contains an unknown part after the index");
+    }
+  }
+  std::copy(metadata.begin(), metadata.end(), std::back_inserter(loaded->owned_metadata));
+  pad_metadata(*loaded, loaded->ntotal);
+  // The symmetric distance table of a product quantizer is not serialized, so it is recomputed
+  // for vectors that are appended to the loaded index.
+  if (IndexPQ* pq = dynamic_cast<IndexPQ*>(loaded->storage)) {
+    pq->pq.compute_sdc_table();
+  }
+  return loaded;
+}
+
+int index_dimensionality(const std::unique_ptr<IndexACORNFlat>& idx) {
+  return idx->d;
+}
+
+idx_t index_ntotal(const std::unique_ptr<IndexACORNFlat>& idx) {
+  return idx->ntotal;
+}
+
+int index_m(const std::unique_ptr<IndexACORNFlat>& idx) {
+  return idx->acorn.M;
+}
+
+int index_gamma(const std::unique_ptr<IndexACORNFlat>& idx) {
+  return idx->acorn.gamma;
+}
+
+int index_m_beta(const std::unique_ptr<IndexACORNFlat>& idx) {
+  return idx->acorn.M_beta;
+}
+
+int index_metric_type(const std::unique_ptr<IndexACORNFlat>& idx) {
+  return idx->metric_type;
+}
+
+int index_pq_m(const std::unique_ptr<IndexACORNFlat>& idx) {
+  const IndexPQ* pq = dynamic_cast<const IndexPQ*>(idx->storage);
+  return pq ? static_cast<int>(pq->pq.M) : 0;
+}
+
+int index_pq_nbits(const std::unique_ptr<IndexACORNFlat>& idx) {
+  const IndexPQ* pq = dynamic_cast<const IndexPQ*>(idx->storage);
+  return pq ? static_cast<int>(pq->pq.nbits) : 0;
+}
+
+bool index_has_refine_flat(const std::unique_ptr<IndexACORNFlat>& idx) {
+  return idx->refine_storage != nullptr;
+}
+
+int index_pre_transform_kind(const std::unique_ptr<IndexACORNFlat>& idx) {
+  if (const PCAMatrix* pca = dynamic_cast<const PCAMatrix*>(idx->pre_transform)) {
+    return pca->eigen_power == 0 ? 1 : 2;
+  }
+  if (dynamic_cast<const OPQMatrix*>(idx->pre_transform)) {
+    return 3;
+  }
+  return idx->pre_transform ? 4 : 0;
+}
+
+int index_input_dimensionality(const std::unique_ptr<IndexACORNFlat>& idx) {
+  return idx->pre_transform ? idx->pre_transform->d_in : idx->d;
+}
+
+void set_omp_threads(int num_threads) {
+  FAISS_THROW_IF_NOT(num_threads > 0);
+  omp_set_num_threads(num_threads);
+}
+
+int omp_threads() {
+  return omp_get_max_threads();
+}
+
+int index_max_level(const std::unique_ptr<IndexACORNFlat>& idx) {
+  return idx->acorn.max_level;
+}
+
+// OAK: copies the stored vectors with the given ids out of an index, in the order of the ids.
+rust::Vec<float> reconstruct_vectors(
+  const std::unique_ptr<IndexACORNFlat>& idx,
+  rust::Slice<const int64_t> ids
+) {
+  for (int64_t id : ids) {
+    FAISS_THROW_IF_NOT_FMT(
+        id >= 0 && id < idx->ntotal, "id %" PRId64 " is out of range", id);
+  }
+
+  int d = index_input_dimensionality(idx);
+  rust::Vec<float> recons;
+  recons.reserve(ids.size() * d);
+  for (size_t i = 0; i < ids.size() * d; i++) {
+    recons.push_back(0);
+  }
+  if (!idx->pre_transform) {
+    idx->reconstruct_batch(ids.size(), ids.data(), recons.data());
+    return recons;
+  }
+
+  std::vector<float> transformed(ids.size() * idx->d);
+  idx->reconstruct_batch(ids.size(), ids.data(), transformed.data());
+  idx->pre_transform->reverse_transform(ids.size(), transformed.data(), recons.data());
+  return recons;
+}
+
+// OAK: the number of neighbors of each of the vectors present at `level`, in order of their ids.
+rust::Vec<int> index_out_degrees(
+  const std::unique_ptr<IndexACORNFlat>& idx,
+  int level
+) {
+  const ACORN& acorn = idx->acorn;
+  FAISS_THROW_IF_NOT(level >= 0);
+
+  rust::Vec<int> degrees;
+  for (idx_t i = 0; i < idx->ntotal; i++) {
+    // NOTE: the levels are 1-based, so a vector is present at every level below its own.
+    if (acorn.levels[i] <= level) {
+      continue;
+    }
+    size_t begin, end;
+    acorn.neighbor_range(i, level, &begin, &end);
+    int degree = 0;
+    for (size_t j = begin; j < end; j++) {
+      if (acorn.neighbors[j] >= 0) {
+        degree++;
+      }
+    }
+    degrees.push_back(degree);
+  }
+  return degrees;
+}
+
+// OAK: the (directed) edges from each of the vectors present at `level` to its neighbors, in order
+// of the ids of the vectors, flattened into pairs of the id of a vector and that of its neighbor.
+rust::Vec<int64_t> index_edges(
+  const std::unique_ptr<IndexACORNFlat>& idx,
+  int level
+) {
+  const ACORN& acorn = idx->acorn;
+  FAISS_THROW_IF_NOT(level >= 0);
+
+  rust::Vec<int64_t> edges;
+  for (idx_t i = 0; i < idx->ntotal; i++) {
+    if (acorn.levels[i] <= level) {
+      continue;
+    }
+    size_t begin, end;
+    acorn.neighbor_range(i, level, &begin, &end);
+    for (size_t j = begin; j < end; j++) {
+      if (acorn.neighbors[j] >= 0) {
+        edges.push_back(i);
+        edges.push_back(acorn.neighbors[j]);
+      }
+    }
+  }
+  return edges;
 }
 
 
@@ -14,6 +14,7 @@
 #include "oak/third_party/ACORN/faiss/IndexFlat.h"
 #include "oak/third_party/ACORN/faiss/IndexPQ.h"
 #include "oak/third_party/ACORN/faiss/IndexScalarQuantizer.h"
+#include "oak/third_party/ACORN/faiss/VectorTransform.h"
 #include "oak/third_party/ACORN/faiss/impl/ACORN.h"
 #include "oak/third_party/ACORN/faiss/utils/utils.h"
 // #include "oak/src/lib.rs.h"
@@ -44,6 +45,27 @@ struct IndexACORN : Index {
     bool own_fields;
     Index* storage;
 
+    // OAK: `acorn.metadata` is a raw pointer, so the index keeps its own copy of the metadata
+    // for that pointer to refer to.
+    std::vector<int> owned_metadata;
+
+    // OAK: when set, vectors are added on a single thread in an order drawn from `add_seed`, so
+    // that the graph only depends on the seed and the vectors (see `set_index_seed`).
+    bool deterministic = false;
+    int64_t add_seed = 789;
+
+    // OAK: if set, an exact copy of the vectors held alongside compressed storage, against which
+    // the candidates of a search are refined, as by IndexRefineFlat (see `set_index_refine_flat`).
+    // It is owned by the index.
+    IndexFlat* refine_storage = nullptr;
+
+    // OAK: if set, a transform (such as a PCA) that the standalone functions below apply to the
+    // vectors before they are added to the index and to the queries before they are searched,
+    // as IndexPreTransform does (see `set_index_pca`). The graph and storage of the index hold
+    // the transformed vectors, of dimensionality `d`, so `pre_transform->d_in` is that of the
+    // vectors and queries passed in. It is owned by the index.
+    VectorTransform* pre_transform = nullptr;
+
 //     ReconstructFromNeighbors* reconstruct_from_neighbors;
 
     explicit IndexACORN(int d, int M, int gamma, std::vector<int>& metadata, int M_beta, MetricType metric = METRIC_L2); // defaults d = 0, M=32, gamma=1
@@ -76,7 +98,9 @@ struct IndexACORN : Index {
             float* distances,
             idx_t* labels,
             char* filter_id_map,
-            const SearchParameters* params = nullptr) const;
+            const SearchParameters* params = nullptr,
+            // OAK: if given, set to the stats of this search alone, as `acorn_stats` is shared
+            ACORNStats* stats_out = nullptr) const;
 
     void reconstruct(idx_t key, float* recons) const override;
 
@@ -142,18 +166,79 @@ std::unique_ptr<IndexACORNFlat> new_index_acorn(
   int M,
   int gamma,
   int M_beta,
-  const rust::Vec<int>& metadata
+  const rust::Vec<int>& metadata,
+  int metric_type     // the faiss MetricType of the index
 ); 
 
-// OAK: standalone function to add vectors to an index from Rust over FFI.
+// OAK: seeds the randomness used when vectors are added to an index, and adds them on a single
+// thread, so that building an index twice with the same seed and vectors gives the same graph.
+void set_index_seed(
+  std::unique_ptr<IndexACORNFlat>& idx,
+  uint64_t seed
+);
+
+// OAK: replaces the flat storage of an empty index with a product quantizer of `pq_m`
+// subquantizers of `nbits` bits each, so that the index stores compressed codes rather than the
+// vectors. The quantizer is trained on the first vectors added to the index.
+void set_index_pq(
+  std::unique_ptr<IndexACORNFlat>& idx,
+  int pq_m,
+  int nbits
+);
+
+// OAK: gives an empty index a flat copy of the vectors added to it, alongside its (compressed)
+// storage, so that searches can re-rank the candidates found over the storage by their exact
+// distances, as IndexRefineFlat does.
+void set_index_refine_flat(
+  std::unique_ptr<IndexACORNFlat>& idx
+);
+
+// OAK: gives an empty index a fixed linear transform, from vectors of dimensionality `d_in` to the
+// dimensionality of the index, which maps each vector x to `matrix * x + bias` (where `matrix` is
+// row-major and `bias` may be empty), and is applied to every vector added and query searched.
+void set_index_linear_transform(
+  std::unique_ptr<IndexACORNFlat>& idx,
+  int d_in,
+  rust::Slice<const float> matrix,
+  rust::Slice<const float> bias
+);
+
+// OAK: gives an empty index a PCA of vectors of dimensionality `d_in` down to the dimensionality of
+// the index, which is trained on the first vectors added to the index, and then applied to every
+// vector added and query searched. If `whiten`, the components are also scaled to unit variance.
+void set_index_pca(
+  std::unique_ptr<IndexACORNFlat>& idx,
+  int d_in,
+  bool whiten
+);
+
+// OAK: gives an empty index an OPQ rotation, trained (as `set_index_pca`) to suit a product
+// quantizer of `opq_m` subquantizers.
+void set_index_opq(
+  std::unique_ptr<IndexACORNFlat>& idx,
+  int opq_m
+);
+
+// OAK: standalone function to add vectors to an index from Rust over FFI. If the storage of the
+// index must be trained (as a product quantizer must), it is first trained on these vectors.
 void add_to_index(
   std::unique_ptr<IndexACORNFlat>& idx,
   idx_t n,        // number of vectors to add
   const float* x  // pointer to a contiguous array of the vectors to add
 );
 
-// OAK: standalone function to search vectors from an index from Rust over FFI.
-void search_index(
+// OAK: standalone function to add vectors (along with their metadata) to an index that already
+// contains vectors from Rust over FFI.
+void append_to_index(
+  std::unique_ptr<IndexACORNFlat>& idx,
+  idx_t n,                        // number of vectors to add
+  const float* x,                 // pointer to a contiguous array of the vectors to add
+  const rust::Vec<int>& metadata  // the metadata for the vectors to add, one per vector
+);
+
+// OAK: standalone function to search vectors from an index from Rust over FFI. Returns the number
+// of distances computed over all of the queries.
+int64_t search_index(
   const std::unique_ptr<IndexACORNFlat>& idx,
   idx_t n,            // number of query vectors
   const float* x,     // pointer to an array of the query vectors 
@@ -161,7 +246,63 @@ void search_index(
   float* distances,   // pointer to an array of (k*n) floats, each representing a distance of the result from the query vector 
   idx_t* labels,      // pointer to an array of (k*n) indices, each representing the ID of the query vector in idx 
   char* filter_id_map,// a bitmap of the IDs in the filter, an array of (n * N) bools, where N is the total number of vectors in the index, and a '1' represents that the vector at that index passes the predicate for that query.
-  idx_t efsearch      // an integer that can be tweaked to affect ACORN's recall/performance tradeoff dynamically
+  idx_t efsearch,     // an integer that can be tweaked to affect ACORN's recall/performance tradeoff dynamically
+  int64_t max_filtered_visits, // the number of matching vectors after which the search of the base level stops, or negative for no limit
+  int64_t refine_k_factor // for an index with a refine storage, the multiple of k candidates that are refined
+);
+
+// OAK: standalone function to serialize an index to a file from Rust over FFI.
+void write_index_acorn(
+  const std::unique_ptr<IndexACORNFlat>& idx,
+  rust::Str fname     // path of the file to write the index to
+);
+
+// OAK: standalone function to deserialize an index written by `write_index_acorn` from Rust over FFI.
+std::unique_ptr<IndexACORNFlat> read_index_acorn(
+  rust::Str fname,                // path of the file to read the index from
+  const rust::Vec<int>& metadata  // the metadata for the vectors in the index
+);
+
+// OAK: accessors for the parameters of an index, so that Rust can validate a loaded index.
+int index_dimensionality(const std::unique_ptr<IndexACORNFlat>& idx);
+idx_t index_ntotal(const std::unique_ptr<IndexACORNFlat>& idx);
+int index_m(const std::unique_ptr<IndexACORNFlat>& idx);
+int index_gamma(const std::unique_ptr<IndexACORNFlat>& idx);
+int index_m_beta(const std::unique_ptr<IndexACORNFlat>& idx);
+int index_metric_type(const std::unique_ptr<IndexACORNFlat>& idx);
+// OAK: the subquantizers and bits per subquantizer of an index stored with a product quantizer,
+// or 0 for an index that stores its vectors flat.
+int index_pq_m(const std::unique_ptr<IndexACORNFlat>& idx);
+int index_pq_nbits(const std::unique_ptr<IndexACORNFlat>& idx);
+// OAK: whether the index holds a flat copy of its vectors to refine searches against.
+bool index_has_refine_flat(const std::unique_ptr<IndexACORNFlat>& idx);
+// OAK: the kind of the pre-transform of an index: 0 for none, 1 for a PCA, 2 for a whitened PCA,
+// 3 for an OPQ rotation and 4 for a fixed linear transform; and the dimensionality of the vectors passed to (and reconstructed
+// from) the index, before they are transformed.
+int index_pre_transform_kind(const std::unique_ptr<IndexACORNFlat>& idx);
+int index_input_dimensionality(const std::unique_ptr<IndexACORNFlat>& idx);
+
+// OAK: copies the stored vectors with the given ids out of an index, in the order of the ids. The
+// vectors of an index with a pre-transform are transformed back, which fails for a transform that
+// cannot be reversed (such as a whitened PCA).
+rust::Vec<float> reconstruct_vectors(
+  const std::unique_ptr<IndexACORNFlat>& idx,
+  rust::Slice<const int64_t> ids  // the ids of the vectors to copy, each less than ntotal
+);
+
+// OAK: the number of threads in the OpenMP parallel regions started from the calling thread.
+void set_omp_threads(int num_threads);
+int omp_threads();
+
+// OAK: diagnostics over the structure of the ACORN graph of an index.
+int index_max_level(const std::unique_ptr<IndexACORNFlat>& idx);
+rust::Vec<int> index_out_degrees(
+  const std::unique_ptr<IndexACORNFlat>& idx,
+  int level           // the level of the graph, where 0 is the base level that contains every vector
+);
+rust::Vec<int64_t> index_edges(
+  const std::unique_ptr<IndexACORNFlat>& idx,
+  int level           // the level of the graph, where 0 is the base level that contains every vector
 );
 
 
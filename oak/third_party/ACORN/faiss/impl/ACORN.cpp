@@ -1058,7 +1058,9 @@ void ACORN::add_with_locks(
     float d_nearest = ptdis(nearest);
 
     // needed for backtracking in hybrid search - TODO DEPRECATED, remove backtracking things
-    std::vector<storage_idx_t> ep_per_level(max_level); // idx of nearest node per level
+    // OAK: there is an entry for each level up to and including max_level, which would otherwise
+    // be written out of bounds (e.g. while every vector added so far is at level 0).
+    std::vector<storage_idx_t> ep_per_level(max_level + 1); // idx of nearest node per level
     ep_per_level[level] = nearest;
 
     for (; level > pt_level; level--) {
@@ -1218,12 +1220,18 @@ int hybrid_search_from_candidates(
                                : hnsw.check_relative_distance;
     int efSearch = params ? params->efSearch : hnsw.efSearch;
     const IDSelector* sel = params ? params->sel : nullptr;
+    // OAK: the budget only applies to the base level, where the filter is evaluated.
+    int64_t max_filtered_visits =
+            params && level == 0 ? params->max_filtered_visits : -1;
 
     for (int i = 0; i < candidates.size(); i++) {
         idx_t v1 = candidates.ids[i];
         float d = candidates.dis[i];
         FAISS_ASSERT(v1 >= 0);
-        if (!sel || sel->is_member(v1)) {
+        // OAK: the initial candidates (such as the entry point) must also pass the filter, or
+        // else they are returned from the base level whether or not they match.
+        bool passes_filter = level > 0 || filter_map[v1];
+        if ((!sel || sel->is_member(v1)) && passes_filter) {
             if (nres < k) {
                 faiss::maxheap_push(++nres, D, I, d, v1);
             } else if (d < D[0]) {
@@ -1240,6 +1248,12 @@ int hybrid_search_from_candidates(
     double t1_candidates_loop = elapsed();
     
     while (candidates.size() > 0) { // candidates is heap of size max(efs, k)
+        // OAK: stop early once the budget of matching vectors has been spent. The budget is only
+        // checked between expansions, so it may be exceeded by up to one neighbor list.
+        if (max_filtered_visits >= 0 && ndis >= max_filtered_visits) {
+            break;
+        }
+
         float d0 = 0;
         int v0 = candidates.pop_min(&d0);
         // debug_search("--------visiting v0: %d, d0: %f, candidates_size: %d\n", v0, d0, candidates.size());
@@ -1519,6 +1533,8 @@ ACORNStats ACORN::hybrid_search(
     debug("%s\n", "reached");
     // debug_search("Hybrid Search, params -- k: %d, filter: %d\n", k, filter);
     ACORNStats stats;
+    // OAK: can be overridden by search params, as in the non-hybrid search
+    const int efSearch = params ? params->efSearch : this->efSearch;
     if (entry_point == -1) {
         return stats;
     }
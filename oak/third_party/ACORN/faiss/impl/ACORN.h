@@ -39,6 +39,9 @@ struct ACORNStats;
 struct SearchParametersACORN : SearchParameters {
     int efSearch = 16;
     bool check_relative_distance = true;
+    // OAK: the hybrid search of the base level stops once it has computed the distances to this
+    // many vectors that pass the filter, or is unbounded if this is negative.
+    int64_t max_filtered_visits = -1;
 
     ~SearchParametersACORN() {}
 };
@@ -966,11 +966,14 @@ Index* read_index(IOReader* f, int io_flags) {
         // IndexHNSWFlat* idxhnswhybrid = new IndexHNSWFlat();
         IndexACORN* idxacorn= nullptr;
         std::vector<int> metadata = {};
-        idxacorn = new IndexACORNFlat(0, 0, 0, metadata, 0);
+        // OAK: M = 0 makes set_default_probas loop forever, so construct with the defaults; the
+        // fields they initialize are all overwritten by read_ACORN.
+        idxacorn = new IndexACORNFlat(0, 32, 1, metadata, 64);
         // IndexACORNFlat* idxhnsw = new IndexACORNFlat();
         // IndexACORN* idxhnsw = new IndexACORNFlat();
         read_index_header(idxacorn, f);
         read_ACORN(&idxacorn->acorn, f);
+        delete idxacorn->storage;
         idxacorn->storage = read_index(f, io_flags);
         idxacorn->own_fields = true;
         idx = idxacorn;